@@ -0,0 +1,212 @@
+// Message bursts as a busy server sends them, stored one by one on the caller's thread
+// or received by a subscription the way the listener hands them over.
+// Run with `cargo bench -p ntfy-daemon`.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ntfy_daemon::message_repo::{Db, DbHandle};
+use ntfy_daemon::{models, ConnectionState, ListenerEvent};
+use tokio::sync::broadcast::error::RecvError;
+
+const TOPIC: &str = "bench";
+const BURSTS: [u64; 2] = [1_000, 5_000];
+// Messages of a subscription stored together, like the subscriptions do
+const BATCH: u64 = 64;
+
+fn db_path(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("ntfyr-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{name}.sqlite"));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+    path.display().to_string()
+}
+
+fn subscription() -> models::Subscription {
+    models::Subscription::builder(TOPIC.to_string())
+        .build()
+        .unwrap()
+}
+
+fn message(topic: &str, i: u64) -> String {
+    format!(
+        r#"{{"id":"m{i}","topic":"{topic}","time":{i},"event":"message","title":"Build {i}","message":"Deployed revision {i} to production"}}"#
+    )
+}
+
+// A server that sends `burst` messages as soon as a topic is subscribed, like one that
+// queued them while the client was away, then keeps the stream open
+fn serve_burst(burst: u64) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            std::thread::spawn(move || stream_burst(stream, burst));
+        }
+    });
+    format!("http://{addr}")
+}
+
+fn stream_burst(mut stream: TcpStream, burst: u64) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).unwrap() == 0 || header == "\r\n" {
+            break;
+        }
+    }
+    // GET /<topic>/json?since=... HTTP/1.1
+    let path = request_line.split(' ').nth(1).unwrap_or_default();
+    let topic = path.trim_start_matches('/').split('/').next().unwrap();
+    let mut body = format!(r#"{{"id":"open","time":0,"event":"open","topic":"{topic}"}}"#) + "\n";
+    // Reconnects resume after the last message, there's nothing new for them
+    if path.contains("since=0") {
+        for i in 0..burst {
+            body.push_str(&message(topic, i));
+            body.push('\n');
+        }
+    }
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n";
+    if stream.write_all(head.as_bytes()).is_err() || stream.write_all(body.as_bytes()).is_err() {
+        return;
+    }
+    // Until the client goes away
+    let _ = reader.read_to_end(&mut vec![]);
+}
+
+fn bench_inserts(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("insert_burst");
+    group.sample_size(10);
+
+    for burst in BURSTS {
+        group.throughput(Throughput::Elements(burst));
+
+        group.bench_with_input(
+            BenchmarkId::new("sequential", burst),
+            &burst,
+            |b, &burst| {
+                b.iter_custom(|iters| {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        let mut db = Db::connect(&db_path("sequential")).unwrap();
+                        db.insert_subscription(subscription()).unwrap();
+                        let start = Instant::now();
+                        for i in 0..burst {
+                            db.insert_message(models::DEFAULT_SERVER, &message(TOPIC, i))
+                                .unwrap();
+                        }
+                        total += start.elapsed();
+                    }
+                    total
+                })
+            },
+        );
+
+        // From the stream opening to the last message stored, through the listener and
+        // the subscription actor
+        let server = serve_burst(burst);
+        let ntfy = ntfy_daemon::start(
+            &db_path(&format!("subscription-{burst}")),
+            Arc::new(models::NullNotifier::new()),
+            Arc::new(models::NullNetworkMonitor::new()),
+        )
+        .unwrap();
+        let mut topics = 0;
+        group.bench_with_input(
+            BenchmarkId::new("subscription", burst),
+            &burst,
+            |b, &burst| {
+                b.iter_custom(|iters| {
+                    rt.block_on(async {
+                        let mut total = Duration::ZERO;
+                        for _ in 0..iters {
+                            topics += 1;
+                            let topic = format!("{TOPIC}{topics}");
+                            let mut events = ntfy.events();
+                            ntfy.subscribe(&server, &topic).await.unwrap();
+                            // The stream opens after a debounce, the clock starts with it
+                            let mut start = Instant::now();
+                            let mut stored = 0;
+                            while stored < burst {
+                                match events.recv().await {
+                                    Ok(event) if event.topic == topic => match event.event {
+                                        ListenerEvent::Message(_) => stored += 1,
+                                        ListenerEvent::ConnectionStateChanged(
+                                            ConnectionState::Connected,
+                                        ) => start = Instant::now(),
+                                        _ => {}
+                                    },
+                                    Ok(_) => {}
+                                    // The events of the missed messages were sent all the same
+                                    Err(RecvError::Lagged(skipped)) => stored += skipped,
+                                    Err(RecvError::Closed) => panic!("daemon stopped"),
+                                }
+                            }
+                            total += start.elapsed();
+                            ntfy.unsubscribe(&server, &topic).await.unwrap();
+                        }
+                        total
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+// Loading a page of messages queued behind a burst of a thousand inserts, sent by
+// subscriptions in batches
+fn bench_list_during_burst(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let db = rt.block_on(async {
+        let db = DbHandle::connect(&db_path("list")).unwrap();
+        db.call(|db| db.insert_subscription(subscription()))
+            .await
+            .unwrap();
+        db
+    });
+
+    let mut next = 0;
+    c.bench_function("list_page_during_burst", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                let batches = (0..1_000 / BATCH).map(|_| {
+                    let messages = (next..next + BATCH).map(|i| message(TOPIC, i)).collect();
+                    let last_id = format!("m{}", next + BATCH - 1);
+                    next += BATCH;
+                    let db = db.clone();
+                    async move {
+                        db.insert_messages(models::DEFAULT_SERVER, TOPIC, messages, &last_id)
+                            .await
+                    }
+                });
+                let list = db.call(|db| {
+                    db.list_messages(
+                        models::DEFAULT_SERVER,
+                        TOPIC,
+                        &models::MessageRange::newest(50),
+                    )
+                });
+                let (_, page) = futures::join!(futures::future::join_all(batches), list);
+                page.unwrap()
+            })
+        })
+    });
+}
+
+criterion_group!(benches, bench_inserts, bench_list_during_burst);
+criterion_main!(benches);
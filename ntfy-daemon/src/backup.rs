@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::keys::TopicKey;
+use crate::{crypto, models, Error, SubscriptionHandle};
+
+pub const BACKUP_VERSION: u32 = 1;
+
+// Everything needed to set Ntfyr up on another device, except the stored messages.
+// Passwords and topic keys are only included encrypted with a passphrase.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub created_at: u64,
+    // Servers added by hand in the app, they don't need a subscription
+    #[serde(default)]
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub server_settings: HashMap<String, models::ServerSettings>,
+    #[serde(default)]
+    pub subscriptions: Vec<models::Subscription>,
+    #[serde(default)]
+    pub accounts: Vec<models::Account>,
+    #[serde(default)]
+    pub secrets: Option<SealedSecrets>,
+}
+
+impl Backup {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("backup always serializes")
+    }
+
+    pub fn parse(json: &str) -> Result<Self, Error> {
+        let backup: Self =
+            serde_json::from_str(json).map_err(|e| Error::InvalidBackup(e.to_string()))?;
+        if backup.version > BACKUP_VERSION {
+            return Err(Error::InvalidBackup(format!(
+                "made by a newer version of Ntfyr (format {})",
+                backup.version
+            )));
+        }
+        Ok(backup)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Secrets {
+    // Account password by server
+    #[serde(default)]
+    pub passwords: HashMap<String, String>,
+    #[serde(default)]
+    pub keys: Vec<TopicKeyring>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicKeyring {
+    pub server: String,
+    pub topic: String,
+    pub keys: Vec<TopicKey>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedSecrets {
+    // Random salt of the key derived from the passphrase
+    pub salt: String,
+    // JWE of the JSON of the `Secrets`
+    pub data: String,
+}
+
+impl Secrets {
+    pub fn seal(&self, passphrase: &str) -> SealedSecrets {
+        let salt = URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; 16]>());
+        let key = crypto::derive_key(passphrase, &salt);
+        let json = serde_json::to_vec(self).expect("secrets always serialize");
+        SealedSecrets {
+            data: crypto::encrypt(&key, &json),
+            salt,
+        }
+    }
+}
+
+impl SealedSecrets {
+    pub fn open(&self, passphrase: &str) -> Result<Secrets, Error> {
+        let key = crypto::derive_key(passphrase, &self.salt);
+        let json = crypto::decrypt(&key, &self.data).map_err(|_| Error::BackupPassphrase)?;
+        serde_json::from_slice(&json).map_err(|e| Error::InvalidBackup(e.to_string()))
+    }
+}
+
+// What a restore added, subscriptions that already existed are left untouched
+pub struct RestoreReport {
+    pub subscriptions: Vec<SubscriptionHandle>,
+    pub accounts: usize,
+    pub keys: usize,
+    // Rules left out of the restored subscriptions, see `FilterAction::acts_outside_the_app`.
+    // The user adds them again if they trust the backup.
+    pub removed_rules: Vec<RemovedRule>,
+    // The backup has sealed secrets but no passphrase was given, so its account passwords and
+    // topic keys weren't restored
+    pub secrets_skipped: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemovedRule {
+    pub server: String,
+    pub topic: String,
+    pub name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_round_trip() {
+        let secrets = Secrets {
+            passwords: HashMap::from([("https://ntfy.sh".to_string(), "hunter2".to_string())]),
+            keys: vec![TopicKeyring {
+                server: "https://ntfy.sh".to_string(),
+                topic: "alerts".to_string(),
+                keys: vec![TopicKey {
+                    id: "k1".to_string(),
+                    secret: "topic password".to_string(),
+                    created_at: 1,
+                    expires_at: None,
+                    current: true,
+                }],
+            }],
+        };
+        let backup = Backup {
+            version: BACKUP_VERSION,
+            secrets: Some(secrets.seal("correct horse")),
+            ..Default::default()
+        };
+        let json = backup.to_json();
+        assert!(!json.contains("hunter2"));
+
+        let sealed = Backup::parse(&json).unwrap().secrets.unwrap();
+        assert_eq!(sealed.open("correct horse").unwrap(), secrets);
+        assert!(matches!(sealed.open("wrong"), Err(Error::BackupPassphrase)));
+    }
+
+    #[test]
+    fn test_parse_rejects_newer_versions() {
+        let json = format!(r#"{{"version":{},"created_at":0}}"#, BACKUP_VERSION + 1);
+        assert!(matches!(Backup::parse(&json), Err(Error::InvalidBackup(_))));
+        assert!(Backup::parse("[]").is_err());
+    }
+}
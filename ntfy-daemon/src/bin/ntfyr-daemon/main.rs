@@ -0,0 +1,412 @@
+// Runs the Ntfyr daemon without the GTK app, e.g. on servers or with a tiling window manager.
+// It reads the same database as the app unless `--db` says otherwise.
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use ntfy_daemon::credentials::Credentials;
+use ntfy_daemon::dbus::{self, DaemonClientProxy};
+use ntfy_daemon::message_repo::Db;
+use ntfy_daemon::models::{self, NotificationProxy};
+use ntfy_daemon::share::TopicShare;
+use ntfy_daemon::NtfyHandle;
+use tracing::{info, warn};
+
+mod network;
+mod notifier;
+
+const DB_FILE: &str = "io.github.tobagin.Ntfyr.sqlite";
+
+#[derive(Parser)]
+#[command(
+    name = "ntfyr-daemon",
+    version,
+    about = "Headless ntfy client sharing Ntfyr's subscriptions"
+)]
+struct Cli {
+    /// Database file, the one of the Ntfyr app by default
+    #[arg(long, global = true)]
+    db: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Listen to the subscribed topics and show their notifications
+    Run {
+        #[arg(long, value_enum, default_value_t = NotifierKind::Desktop)]
+        notifier: NotifierKind,
+        /// Seconds between two checks of the network interfaces
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+        network_poll: u64,
+    },
+    /// Subscribe to a topic, given by name or URL like https://ntfy.sh/alerts
+    Subscribe {
+        topic: String,
+        #[arg(long, default_value = models::DEFAULT_SERVER)]
+        server: String,
+    },
+    /// Unsubscribe from a topic, given by name or URL
+    Unsubscribe {
+        topic: String,
+        #[arg(long, default_value = models::DEFAULT_SERVER)]
+        server: String,
+    },
+    /// List the subscribed topics
+    List,
+    /// Publish a message to a topic, given by name or URL
+    Publish {
+        topic: String,
+        message: Option<String>,
+        #[arg(long, default_value = models::DEFAULT_SERVER)]
+        server: String,
+        #[arg(long)]
+        title: Option<String>,
+        /// From 1 (min) to 5 (max)
+        #[arg(long, value_parser = clap::value_parser!(i8).range(1..=5))]
+        priority: Option<i8>,
+        /// Comma separated tags or emoji shortcodes
+        #[arg(long, value_delimiter = ',')]
+        tags: Vec<String>,
+        /// URL opened when the notification is clicked
+        #[arg(long)]
+        click: Option<String>,
+        /// Encrypt with the current key of the topic
+        #[arg(long, value_enum)]
+        encrypt: Option<EncryptionKind>,
+    },
+    /// Manage the accounts used to log in to servers
+    Accounts {
+        #[command(subcommand)]
+        command: Option<AccountsCommand>,
+    },
+    /// Manage the apps registered for UnifiedPush
+    Push {
+        #[command(subcommand)]
+        command: Option<PushCommand>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AccountsCommand {
+    /// List the accounts
+    List,
+    /// Add an account, the password is read from the standard input
+    Add { server: String, username: String },
+    /// Remove the account of a server
+    Remove { server: String },
+}
+
+#[derive(Subcommand)]
+enum PushCommand {
+    /// List the registered apps and their endpoints
+    List,
+    /// Show the server new registrations get their topic on, or change it
+    Server { url: Option<String> },
+    /// Unregister an app by its token
+    Remove { token: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NotifierKind {
+    /// org.freedesktop.Notifications, or stdout without a session bus
+    Desktop,
+    /// One line per notification on the standard output
+    Stdout,
+    /// Only store the messages
+    None,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EncryptionKind {
+    /// Only the message body
+    Message,
+    /// Message, title, tags, click URL, attachment, icon and actions
+    Full,
+}
+
+impl From<EncryptionKind> for models::Encryption {
+    fn from(kind: EncryptionKind) -> Self {
+        match kind {
+            EncryptionKind::Message => Self::Message,
+            EncryptionKind::Full => Self::Full,
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    // Notifications may go to stdout, so the logs don't
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    let db = cli.db.unwrap_or_else(default_db_path);
+    let db = db.to_str().context("the database path isn't valid UTF-8")?;
+    info!(database_path = %db);
+
+    match cli.command {
+        Command::Run {
+            notifier,
+            network_poll,
+        } => run(db, notifier, Duration::from_secs(network_poll)),
+        command => {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            if command.reads_only() {
+                return rt.block_on(show(db, command));
+            }
+            // The app or `ntfyr-daemon run` wouldn't notice changes made behind its back
+            if command.changes_daemon() {
+                if let Some(daemon) = rt.block_on(running_daemon()) {
+                    return rt.block_on(send(&daemon, command));
+                }
+            }
+            let ntfy = ntfy_daemon::start_idle(db)?;
+            rt.block_on(execute(&ntfy, command))
+        }
+    }
+}
+
+impl Command {
+    fn reads_only(&self) -> bool {
+        matches!(
+            self,
+            Command::List
+                | Command::Accounts {
+                    command: None | Some(AccountsCommand::List)
+                }
+                | Command::Push {
+                    command: None | Some(PushCommand::List | PushCommand::Server { url: None })
+                }
+        )
+    }
+
+    // Whether a running daemon has to make the change itself
+    fn changes_daemon(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe { .. }
+                | Command::Unsubscribe { .. }
+                | Command::Accounts {
+                    command: Some(AccountsCommand::Add { .. } | AccountsCommand::Remove { .. })
+                }
+                | Command::Push {
+                    command: Some(PushCommand::Remove { .. })
+                }
+        )
+    }
+}
+
+fn run(db: &str, notifier: NotifierKind, network_poll: Duration) -> anyhow::Result<()> {
+    let notifier: Arc<dyn NotificationProxy> = match notifier {
+        NotifierKind::Desktop => match notifier::DesktopNotifier::connect() {
+            Ok(notifier) => Arc::new(notifier),
+            Err(e) => {
+                warn!(error = %e, "no notification server, printing the notifications instead");
+                Arc::new(notifier::StdoutNotifier)
+            }
+        },
+        NotifierKind::Stdout => Arc::new(notifier::StdoutNotifier),
+        NotifierKind::None => Arc::new(models::NullNotifier::new()),
+    };
+    let network = Arc::new(network::PollingNetworkMonitor::new(network_poll));
+    let ntfy = ntfy_daemon::start(db, notifier, network)?;
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let count = rt.block_on(ntfy.list_subscriptions())?.len();
+    info!(count, "listening to the subscribed topics");
+    // The app may own the name already, or there is no session bus at all
+    if let Err(e) = rt.block_on(dbus::serve(ntfy)) {
+        warn!(error = %e, "not serving {}", dbus::BUS_NAME);
+    }
+
+    // Everything runs on the daemon's threads from now on
+    loop {
+        std::thread::park();
+    }
+}
+
+async fn execute(ntfy: &NtfyHandle, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Run { .. } => unreachable!("handled by `run`"),
+        Command::Subscribe { topic, server } => {
+            let (server, topic) = resolve_topic(&server, &topic)?;
+            ntfy.subscribe(&server, &topic).await?;
+            println!("Subscribed to {server}/{topic}");
+        }
+        Command::Unsubscribe { topic, server } => {
+            let (server, topic) = resolve_topic(&server, &topic)?;
+            ntfy.unsubscribe(&server, &topic).await?;
+            println!("Unsubscribed from {server}/{topic}");
+        }
+        Command::Publish {
+            topic,
+            message,
+            server,
+            title,
+            priority,
+            tags,
+            click,
+            encrypt,
+        } => {
+            let (server, topic) = resolve_topic(&server, &topic)?;
+            let msg = models::OutgoingMessage {
+                topic,
+                message,
+                title,
+                tags,
+                priority,
+                click,
+                ..models::OutgoingMessage::default()
+            };
+            let encryption = encrypt.map(Into::into).unwrap_or_default();
+            ntfy.publish(&server, msg, encryption).await?;
+        }
+        Command::Accounts { command } => match command.unwrap_or(AccountsCommand::List) {
+            AccountsCommand::List => unreachable!("handled by `show`"),
+            AccountsCommand::Add { server, username } => {
+                let password = read_password()?;
+                ntfy.add_account(&server, &username, &password).await?;
+                println!("Added {username} on {server}");
+            }
+            AccountsCommand::Remove { server } => {
+                ntfy.remove_account(&server).await?;
+                println!("Removed the account on {server}");
+            }
+        },
+        Command::Push { command } => match command.unwrap_or(PushCommand::List) {
+            PushCommand::List | PushCommand::Server { url: None } => {
+                unreachable!("handled by `show`")
+            }
+            // Read by the running daemon on every registration
+            PushCommand::Server { url: Some(url) } => ntfy.set_push_server(&url).await?,
+            PushCommand::Remove { token } => ntfy.unregister_push(&token).await?,
+        },
+        Command::List => unreachable!("handled by `show`"),
+    }
+    Ok(())
+}
+
+// Read-only commands look at the database and the keyring directly, so they don't start
+// listening to every subscription
+async fn show(db: &str, command: Command) -> anyhow::Result<()> {
+    let mut db = Db::connect(db)?;
+    match command {
+        Command::List => {
+            for model in db.list_subscriptions()? {
+                let mut line = format!("{}/{}", model.server, model.topic);
+                if !model.display_name.is_empty() {
+                    line.push_str(&format!("\t{}", model.display_name));
+                }
+                if model.muted {
+                    line.push_str("\t(muted)");
+                }
+                println!("{line}");
+            }
+        }
+        Command::Accounts { .. } => {
+            let accounts = Credentials::new().await?.list_all();
+            let mut accounts: Vec<_> = accounts.into_iter().collect();
+            accounts.sort_by(|a, b| a.0.cmp(&b.0));
+            for (server, credential) in accounts {
+                println!("{server}\t{}", credential.username);
+            }
+        }
+        Command::Push {
+            command: Some(PushCommand::Server { url: None }),
+        } => println!("{}", db.get_push_server()?),
+        Command::Push { .. } => {
+            for reg in db.list_push_registrations()? {
+                println!("{}\t{}\t{}", reg.service, reg.token, reg.endpoint());
+            }
+        }
+        _ => unreachable!("only read-only commands are shown"),
+    }
+    Ok(())
+}
+
+// The app or `ntfyr-daemon run` serving the session bus, if any
+async fn running_daemon() -> Option<DaemonClientProxy<'static>> {
+    let conn = zbus::Connection::session().await.ok()?;
+    let bus = zbus::fdo::DBusProxy::new(&conn).await.ok()?;
+    let name = zbus::names::BusName::try_from(dbus::BUS_NAME).ok()?;
+    if !bus.name_has_owner(name).await.ok()? {
+        return None;
+    }
+    DaemonClientProxy::new(&conn).await.ok()
+}
+
+async fn send(daemon: &DaemonClientProxy<'_>, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Subscribe { topic, server } => {
+            let (server, topic) = resolve_topic(&server, &topic)?;
+            daemon.subscribe(&server, &topic).await?;
+            println!("Subscribed to {server}/{topic}");
+        }
+        Command::Unsubscribe { topic, server } => {
+            let (server, topic) = resolve_topic(&server, &topic)?;
+            daemon.unsubscribe(&server, &topic).await?;
+            println!("Unsubscribed from {server}/{topic}");
+        }
+        Command::Accounts {
+            command: Some(AccountsCommand::Add { server, username }),
+        } => {
+            let password = read_password()?;
+            daemon.add_account(&server, &username, &password).await?;
+            println!("Added {username} on {server}");
+        }
+        Command::Accounts {
+            command: Some(AccountsCommand::Remove { server }),
+        } => {
+            daemon.remove_account(&server).await?;
+            println!("Removed the account on {server}");
+        }
+        Command::Push {
+            command: Some(PushCommand::Remove { token }),
+        } => daemon.unregister_push(&token).await?,
+        _ => unreachable!("the daemon makes only the changes of `changes_daemon`"),
+    }
+    Ok(())
+}
+
+// Topic URLs carry their own server
+fn resolve_topic(server: &str, topic: &str) -> anyhow::Result<(String, String)> {
+    if topic.contains("://") {
+        let share = TopicShare::parse(topic)?;
+        return Ok((share.server, share.topic));
+    }
+    models::validate_topic(topic)?;
+    Ok((server.trim_end_matches('/').to_string(), topic.to_string()))
+}
+
+fn read_password() -> anyhow::Result<String> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        eprint!("Password: ");
+        std::io::stderr().flush()?;
+    }
+    let mut password = String::new();
+    stdin.lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    anyhow::ensure!(!password.is_empty(), "no password given");
+    Ok(password.to_string())
+}
+
+// Where the Ntfyr app keeps its database, following GLib's user data directory
+fn default_db_path() -> PathBuf {
+    let data_dir = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    data_dir.join(DB_FILE)
+}
@@ -0,0 +1,44 @@
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use ntfy_daemon::models::NetworkMonitorProxy;
+use tokio_stream::wrappers::IntervalStream;
+
+// Checks the network interfaces now and then instead of asking NetworkManager, which
+// servers often don't run. Like the GTK monitor, it only reports when the network
+// comes back.
+pub struct PollingNetworkMonitor {
+    interval: Duration,
+}
+
+impl PollingNetworkMonitor {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval }
+    }
+}
+
+impl NetworkMonitorProxy for PollingNetworkMonitor {
+    fn listen(&self) -> Pin<Box<dyn Stream<Item = ()>>> {
+        let mut online = is_online();
+        let ticks = IntervalStream::new(tokio::time::interval(self.interval));
+        Box::pin(ticks.filter_map(move |_| {
+            let was_online = std::mem::replace(&mut online, is_online());
+            futures::future::ready((online && !was_online).then_some(()))
+        }))
+    }
+}
+
+// Whether an interface other than loopback is up. Assumes it is when that can't be read.
+fn is_online() -> bool {
+    let Ok(entries) = std::fs::read_dir("/sys/class/net") else {
+        return true;
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name() != "lo")
+        .any(|entry| {
+            std::fs::read_to_string(entry.path().join("operstate"))
+                .is_ok_and(|state| state.trim() == "up")
+        })
+}
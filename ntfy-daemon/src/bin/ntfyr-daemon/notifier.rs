@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::mpsc;
+
+use anyhow::anyhow;
+use ntfy_daemon::models::{Notification, NotificationProxy};
+use tracing::warn;
+
+const APP_NAME: &str = "Ntfyr";
+const APP_ICON: &str = "io.github.tobagin.Ntfyr";
+
+// Shows the notifications through `org.freedesktop.Notifications` on the session bus.
+// Calls are made on a thread of their own, `send` is called from the actor threads.
+pub struct DesktopNotifier {
+    tx: mpsc::Sender<Notification>,
+}
+
+impl DesktopNotifier {
+    pub fn connect() -> anyhow::Result<Self> {
+        let conn = zbus::blocking::Connection::session()?;
+        let (tx, rx) = mpsc::channel::<Notification>();
+        std::thread::Builder::new()
+            .name("ntfyr-notify".to_string())
+            .spawn(move || {
+                let proxy = match zbus::blocking::Proxy::new(
+                    &conn,
+                    "org.freedesktop.Notifications",
+                    "/org/freedesktop/Notifications",
+                    "org.freedesktop.Notifications",
+                ) {
+                    Ok(proxy) => proxy,
+                    Err(e) => {
+                        warn!(error = %e, "can't reach the notification server");
+                        return;
+                    }
+                };
+                for n in rx {
+                    let hints: HashMap<&str, zbus::zvariant::Value> = HashMap::new();
+                    let res: zbus::Result<u32> = proxy.call(
+                        "Notify",
+                        &(
+                            APP_NAME,
+                            0u32,
+                            APP_ICON,
+                            n.title.as_str(),
+                            n.body.as_str(),
+                            Vec::<&str>::new(),
+                            hints,
+                            -1i32,
+                        ),
+                    );
+                    if let Err(e) = res {
+                        warn!(error = %e, "can't show the notification");
+                    }
+                }
+            })?;
+        Ok(Self { tx })
+    }
+}
+
+impl NotificationProxy for DesktopNotifier {
+    fn send(&self, n: Notification) -> anyhow::Result<()> {
+        self.tx
+            .send(n)
+            .map_err(|_| anyhow!("the notification thread stopped"))
+    }
+}
+
+// One line per notification, for scripts and systems without a notification server
+pub struct StdoutNotifier;
+
+impl NotificationProxy for StdoutNotifier {
+    fn send(&self, n: Notification) -> anyhow::Result<()> {
+        if n.title.is_empty() {
+            println!("{}", n.body);
+        } else {
+            println!("{}: {}", n.title, n.body);
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
+use anyhow::Context;
 use async_trait::async_trait;
 
 #[derive(Clone)]
@@ -130,6 +131,34 @@ impl NullableKeyring {
     }
 }
 
+// Stands in for the Secret Service where there is none, e.g. on a server without a
+// session keyring: nothing is stored and storing fails.
+pub struct MissingKeyring;
+
+#[async_trait]
+impl LightKeyring for MissingKeyring {
+    async fn search_items(
+        &self,
+        _attributes: HashMap<&str, &str>,
+    ) -> anyhow::Result<Vec<KeyringItem>> {
+        Ok(vec![])
+    }
+
+    async fn create_item(
+        &self,
+        _label: &str,
+        _attributes: HashMap<&str, &str>,
+        _secret: &str,
+        _replace: bool,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("no Secret Service to store the secret in")
+    }
+
+    async fn delete(&self, _attributes: HashMap<&str, &str>) -> anyhow::Result<()> {
+        anyhow::bail!("no Secret Service to delete the secret from")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Credential {
     pub username: String,
@@ -148,13 +177,20 @@ impl Credentials {
             keyring: Arc::new(RealKeyring {
                 keyring: oo7::Keyring::new()
                     .await
-                    .expect("Failed to start Secret Service"),
+                    .context("can't open the Secret Service")?,
             }),
             creds: Default::default(),
         };
         this.load().await?;
         Ok(this)
     }
+    // No account is known and none can be added
+    pub fn without_keyring() -> Self {
+        Self {
+            keyring: Arc::new(MissingKeyring),
+            creds: Default::default(),
+        }
+    }
     pub async fn new_nullable(credentials: Vec<Credential>) -> anyhow::Result<Self> {
         let mut this = Self {
             keyring: Arc::new(NullableKeyring::with_credentials(credentials)),
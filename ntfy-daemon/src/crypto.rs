@@ -0,0 +1,556 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::keys::TopicKey;
+use crate::models::{self, Decryption, Encryption};
+use crate::Error;
+
+// Value of the `encoding` field of messages encrypted with ntfy's E2E scheme
+pub const JWE_ENCODING: &str = "jwe";
+
+const KEY_DERIVATION_ITERATIONS: u32 = 50_000;
+// Content type of envelopes carrying the full message instead of only its body
+const JSON_CONTENT_TYPE: &str = "application/json";
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const LEGACY_VERSION: u8 = 1;
+
+pub type Key = [u8; 32];
+
+#[derive(Serialize, Deserialize)]
+struct JweHeader {
+    alg: String,
+    enc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cty: Option<String>,
+    // ID of the topic key, a hint for receivers with more than one key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+}
+
+impl JweHeader {
+    fn parse(token: &str) -> Option<Self> {
+        let (header, _) = token.trim().split_once('.')?;
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()
+    }
+}
+
+// Fields moved into the envelope in full encryption mode
+#[derive(Default, Serialize, Deserialize)]
+struct Protected {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    click: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attachment: Option<models::Attachment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    actions: Vec<models::Action>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    markdown: Option<bool>,
+}
+
+impl Protected {
+    fn take_from(msg: &mut models::OutgoingMessage) -> Self {
+        Self {
+            message: msg.message.take(),
+            title: msg.title.take(),
+            tags: std::mem::take(&mut msg.tags),
+            click: msg.click.take(),
+            attachment: msg.attachment.take(),
+            icon: msg.icon.take(),
+            filename: msg.filename.take(),
+            actions: std::mem::take(&mut msg.actions),
+            markdown: msg.markdown.take(),
+        }
+    }
+
+    // Returns the names of the restored fields
+    fn restore_into(self, msg: &mut models::ReceivedMessage) -> Vec<String> {
+        let mut restored = vec![];
+        let mut restore = |name: &str, present: bool| {
+            if present {
+                restored.push(name.to_string());
+            }
+            present
+        };
+        if restore("message", self.message.is_some()) {
+            msg.message = self.message;
+        }
+        if restore("title", self.title.is_some()) {
+            msg.title = self.title;
+        }
+        if restore("tags", !self.tags.is_empty()) {
+            msg.tags = self.tags;
+        }
+        if restore("click", self.click.is_some()) {
+            msg.click = self.click;
+        }
+        if restore("attachment", self.attachment.is_some()) {
+            msg.attachment = self.attachment;
+        }
+        if restore("icon", self.icon.is_some()) {
+            msg.icon = self.icon;
+        }
+        if restore("filename", self.filename.is_some()) {
+            msg.filename = self.filename;
+        }
+        if restore("actions", !self.actions.is_empty()) {
+            msg.actions = self.actions;
+        }
+        if restore("markdown", self.markdown.is_some()) {
+            msg.markdown = self.markdown;
+        }
+        restored
+    }
+}
+
+pub fn topic_url(server: &str, topic: &str) -> String {
+    format!("{}/{}", server.trim_end_matches('/'), topic)
+}
+
+// PBKDF2-SHA256 of the password, salted with the SHA-256 of the topic URL
+pub fn derive_key(password: &str, topic_url: &str) -> Key {
+    let salt = Sha256::digest(topic_url.as_bytes());
+    pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(
+        password.as_bytes(),
+        &salt,
+        KEY_DERIVATION_ITERATIONS,
+    )
+}
+
+fn random_iv() -> [u8; IV_LEN] {
+    let mut iv = [0u8; IV_LEN];
+    rand::thread_rng().fill(&mut iv);
+    iv
+}
+
+// JWE compact serialization with direct key agreement and AES-256-GCM
+fn seal(key: &Key, iv: &[u8; IV_LEN], header: &str, plaintext: &[u8]) -> String {
+    let header = URL_SAFE_NO_PAD.encode(header);
+    let cipher = Aes256Gcm::new(key.into());
+    let sealed = cipher
+        .encrypt(
+            Nonce::from_slice(iv),
+            Payload {
+                msg: plaintext,
+                aad: header.as_bytes(),
+            },
+        )
+        .expect("AES-GCM encryption of in-memory data can't fail");
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+
+    format!(
+        "{header}..{}.{}.{}",
+        URL_SAFE_NO_PAD.encode(iv),
+        URL_SAFE_NO_PAD.encode(ciphertext),
+        URL_SAFE_NO_PAD.encode(tag)
+    )
+}
+
+// JWE of any data with a key that isn't tied to a topic, e.g. the secrets of a backup
+pub fn encrypt(key: &Key, plaintext: &[u8]) -> String {
+    let header = JweHeader {
+        alg: "dir".to_string(),
+        enc: "A256GCM".to_string(),
+        cty: None,
+        kid: None,
+    };
+    let header = serde_json::to_string(&header).expect("JWE header always serializes");
+    seal(key, &random_iv(), &header, plaintext)
+}
+
+// Whether the text is a JWE produced by this scheme, for messages that lost their encoding field
+pub fn is_jwe(text: &str) -> bool {
+    JweHeader::parse(text).is_some_and(|h| h.alg == "dir")
+}
+
+pub fn decrypt(key: &Key, token: &str) -> Result<Vec<u8>, Error> {
+    let [header_b64, encrypted_key, iv, ciphertext, tag]: [&str; 5] = token
+        .trim()
+        .split('.')
+        .collect::<Vec<_>>()
+        .try_into()
+        .map_err(|_| Error::Decrypt("not a JWE compact serialization"))?;
+    let decode = |part: &str| {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|_| Error::Decrypt("invalid base64url"))
+    };
+
+    let header: JweHeader = serde_json::from_slice(&decode(header_b64)?)
+        .map_err(|_| Error::Decrypt("invalid JWE header"))?;
+    if header.alg != "dir" || header.enc != "A256GCM" || !encrypted_key.is_empty() {
+        return Err(Error::Decrypt("unsupported JWE algorithm"));
+    }
+    let iv = decode(iv)?;
+    if iv.len() != IV_LEN {
+        return Err(Error::Decrypt("invalid IV length"));
+    }
+    let mut sealed = decode(ciphertext)?;
+    sealed.extend(decode(tag)?);
+
+    Aes256Gcm::new(key.into())
+        .decrypt(
+            Nonce::from_slice(&iv),
+            Payload {
+                msg: &sealed,
+                aad: header_b64.as_bytes(),
+            },
+        )
+        .map_err(|_| Error::Decrypt("wrong key or corrupted message"))
+}
+
+pub fn encrypt_message(
+    key: &TopicKey,
+    server: &str,
+    msg: &mut models::OutgoingMessage,
+    mode: Encryption,
+) {
+    let mut header = JweHeader {
+        alg: "dir".to_string(),
+        enc: "A256GCM".to_string(),
+        cty: None,
+        kid: Some(key.id.clone()),
+    };
+    let plaintext = match mode {
+        Encryption::None => return,
+        Encryption::Message => msg.message.take().unwrap_or_default().into_bytes(),
+        Encryption::Full => {
+            header.cty = Some(JSON_CONTENT_TYPE.to_string());
+            serde_json::to_vec(&Protected::take_from(msg)).expect("protected fields always serialize")
+        }
+    };
+    let header = serde_json::to_string(&header).expect("JWE header always serializes");
+    let derived = derive_key(&key.secret, &topic_url(server, &msg.topic));
+    msg.message = Some(seal(&derived, &random_iv(), &header, &plaintext));
+    msg.encoding = Some(JWE_ENCODING.to_string());
+}
+
+// Decrypts the message in place by trying each key of the topic and records the outcome.
+// Messages that can't be decrypted are left untouched.
+pub fn decrypt_message(
+    keys: &[TopicKey],
+    server: &str,
+    msg: &mut models::ReceivedMessage,
+    legacy: bool,
+) -> Decryption {
+    let outcome = match open_message(keys, server, msg, legacy) {
+        Ok(outcome) => outcome,
+        Err(Error::Decrypt(reason)) => Decryption::Failed {
+            reason: reason.to_string(),
+        },
+        Err(e) => Decryption::Failed {
+            reason: e.to_string(),
+        },
+    };
+    msg.decryption = outcome.clone();
+    outcome
+}
+
+fn open_message(
+    keys: &[TopicKey],
+    server: &str,
+    msg: &mut models::ReceivedMessage,
+    legacy: bool,
+) -> Result<Decryption, Error> {
+    let Some(ciphertext) = msg.message.as_deref() else {
+        return Ok(Decryption::Plaintext);
+    };
+
+    // The encoding field is dropped by servers that don't know about it, so sniff the format too
+    let key_id = if msg.encoding.as_deref() == Some(JWE_ENCODING) || is_jwe(ciphertext) {
+        if keys.is_empty() {
+            return Ok(Decryption::NoKey);
+        }
+        let header = JweHeader::parse(ciphertext);
+        let kid = header.as_ref().and_then(|h| h.kid.as_deref());
+        let url = topic_url(server, &msg.topic);
+        // The key named by the sender first, the others for senders that don't name it
+        let mut candidates: Vec<_> = keys.iter().collect();
+        candidates.sort_by_key(|k| Some(k.id.as_str()) != kid);
+        let (key_id, plaintext) = candidates
+            .into_iter()
+            .find_map(|k| Some((&k.id, decrypt(&derive_key(&k.secret, &url), ciphertext).ok()?)))
+            .ok_or(Error::Decrypt("no key of the topic decrypts the message"))?;
+
+        if header.and_then(|h| h.cty).as_deref() == Some(JSON_CONTENT_TYPE) {
+            let protected: Protected = serde_json::from_slice(&plaintext)
+                .map_err(|_| Error::Decrypt("invalid encrypted message fields"))?;
+            msg.message = None;
+            msg.encrypted_fields = protected.restore_into(msg);
+        } else {
+            msg.message = Some(into_utf8(plaintext)?);
+            msg.encrypted_fields = vec!["message".to_string()];
+        }
+        key_id.clone()
+    } else if legacy && is_legacy(ciphertext) {
+        if keys.is_empty() {
+            return Ok(Decryption::NoKey);
+        }
+        let (key_id, plaintext) = keys
+            .iter()
+            .find_map(|k| Some((&k.id, decrypt_legacy(&k.secret, ciphertext).ok()?)))
+            .ok_or(Error::Decrypt("no key of the topic decrypts the message"))?;
+        msg.message = Some(into_utf8(plaintext)?);
+        msg.encrypted_fields = vec!["message".to_string()];
+        key_id.clone()
+    } else {
+        return Ok(Decryption::Plaintext);
+    };
+    msg.encoding = None;
+    Ok(Decryption::Decrypted { key_id })
+}
+
+fn into_utf8(plaintext: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(plaintext).map_err(|_| Error::Decrypt("plaintext is not valid UTF-8"))
+}
+
+fn is_legacy(data: &str) -> bool {
+    STANDARD
+        .decode(data.trim())
+        .is_ok_and(|raw| raw.len() >= 1 + IV_LEN + TAG_LEN && raw[0] == LEGACY_VERSION)
+}
+
+// Format used by earlier versions of this app: base64(version + nonce + ciphertext),
+// with the key itself being a base64 encoded 32-byte value
+pub fn decrypt_legacy(key: &str, data: &str) -> Result<Vec<u8>, Error> {
+    let key: Key = STANDARD
+        .decode(key)
+        .ok()
+        .and_then(|k| k.try_into().ok())
+        .ok_or(Error::Decrypt("legacy keys must be 32 base64 encoded bytes"))?;
+    let raw = STANDARD
+        .decode(data.trim())
+        .map_err(|_| Error::Decrypt("invalid base64"))?;
+    if raw.len() < 1 + IV_LEN + TAG_LEN {
+        return Err(Error::Decrypt("message too short"));
+    }
+    if raw[0] != LEGACY_VERSION {
+        return Err(Error::Decrypt("unsupported legacy version"));
+    }
+
+    Aes256Gcm::new(&key.into())
+        .decrypt(Nonce::from_slice(&raw[1..1 + IV_LEN]), &raw[1 + IV_LEN..])
+        .map_err(|_| Error::Decrypt("wrong key or corrupted message"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header without a key ID, as sent by other ntfy clients
+    const JWE_HEADER: &str = r#"{"alg":"dir","enc":"A256GCM"}"#;
+    const PASSWORD: &str = "correct horse battery staple";
+    const TOPIC_URL: &str = "https://ntfy.sh/mysecret";
+    // Produced independently with Python's hashlib and cryptography packages
+    const DERIVED_KEY: &str = "f40f8f32721550ab153dcd823aec1840fb0ee05b86706b4db73e55de6e1555cb";
+    const JWE_VECTOR: &str = "eyJhbGciOiJkaXIiLCJlbmMiOiJBMjU2R0NNIn0..AAECAwQFBgcICQoL.jZ8PTeKzndfHLVZExX1wFd5tBg.Eu7D6nfosjxPNeZ6syNbPw";
+    const JWE_PLAINTEXT: &str = "Backup finished ✅";
+    const LEGACY_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const LEGACY_VECTOR: &str = "AQcHBwcHBwcHBwcHB2MPwT0Mdveco/F/707+Q7XJVXLJ2rNd2TZpDW4=";
+
+    fn topic_key(id: &str, secret: &str) -> TopicKey {
+        TopicKey {
+            id: id.to_string(),
+            secret: secret.to_string(),
+            created_at: 0,
+            expires_at: None,
+            current: true,
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_derive_key() {
+        assert_eq!(hex(&derive_key(PASSWORD, TOPIC_URL)), DERIVED_KEY);
+        assert_eq!(topic_url("https://ntfy.sh/", "mysecret"), TOPIC_URL);
+    }
+
+    #[test]
+    fn test_jwe_vector() {
+        let key = derive_key(PASSWORD, TOPIC_URL);
+        let iv: [u8; IV_LEN] = std::array::from_fn(|i| i as u8);
+        assert_eq!(seal(&key, &iv, JWE_HEADER, JWE_PLAINTEXT.as_bytes()), JWE_VECTOR);
+        assert_eq!(decrypt(&key, JWE_VECTOR).unwrap(), JWE_PLAINTEXT.as_bytes());
+    }
+
+    #[test]
+    fn test_jwe_round_trip() {
+        let key = derive_key(PASSWORD, TOPIC_URL);
+        let token = seal(&key, &random_iv(), JWE_HEADER, b"hello");
+        assert_ne!(token, seal(&key, &random_iv(), JWE_HEADER, b"hello"));
+        assert_eq!(decrypt(&key, &token).unwrap(), b"hello");
+
+        let other = derive_key(PASSWORD, "https://ntfy.sh/other");
+        assert!(matches!(decrypt(&other, &token), Err(Error::Decrypt(_))));
+    }
+
+    #[test]
+    fn test_jwe_rejects_tampering() {
+        let key = derive_key(PASSWORD, TOPIC_URL);
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"dir","enc":"A128GCM"}"#);
+        let (_, rest) = JWE_VECTOR.split_once('.').unwrap();
+        assert!(decrypt(&key, &format!("{header}.{rest}")).is_err());
+        assert!(decrypt(&key, "plain text").is_err());
+        assert!(is_jwe(JWE_VECTOR));
+        assert!(!is_jwe("plain. text"));
+    }
+
+    #[test]
+    fn test_legacy_vector() {
+        assert_eq!(decrypt_legacy(LEGACY_KEY, LEGACY_VECTOR).unwrap(), b"legacy hello");
+        assert!(decrypt_legacy(LEGACY_KEY, JWE_VECTOR).is_err());
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut out = models::OutgoingMessage {
+            topic: "mysecret".to_string(),
+            message: Some("body".to_string()),
+            title: Some("title".to_string()),
+            priority: Some(4),
+            ..Default::default()
+        };
+        encrypt_message(&topic_key("k1", PASSWORD), "https://ntfy.sh", &mut out, Encryption::Message);
+        assert_eq!(out.title.as_deref(), Some("title"));
+        assert_eq!(out.encoding.as_deref(), Some(JWE_ENCODING));
+
+        let mut wire = serde_json::to_value(&out).unwrap();
+        wire["id"] = "m1".into();
+        let mut msg: models::ReceivedMessage = serde_json::from_value(wire).unwrap();
+        assert_eq!(
+            decrypt_message(&[topic_key("k1", PASSWORD)], "https://ntfy.sh", &mut msg, false),
+            Decryption::Decrypted { key_id: "k1".to_string() }
+        );
+        assert_eq!(msg.message.as_deref(), Some("body"));
+        assert_eq!(msg.encrypted_fields, ["message"]);
+        assert!(msg.encoding.is_none());
+    }
+
+    #[test]
+    fn test_full_message_round_trip() {
+        let mut out = models::OutgoingMessage {
+            topic: "mysecret".to_string(),
+            message: Some("body".to_string()),
+            title: Some("title".to_string()),
+            tags: vec!["warning".to_string()],
+            click: Some("https://example.com".to_string()),
+            priority: Some(4),
+            ..Default::default()
+        };
+        encrypt_message(&topic_key("k1", PASSWORD), "https://ntfy.sh", &mut out, Encryption::Full);
+        // Only what the server needs stays in the clear
+        let wire = serde_json::to_value(&out).unwrap();
+        assert!(wire.get("title").is_none());
+        assert!(wire.get("tags").is_none());
+        assert!(wire.get("click").is_none());
+        assert_eq!(wire["priority"], 4);
+
+        // The server drops the fields it doesn't know about
+        let mut msg: models::ReceivedMessage = serde_json::from_value(serde_json::json!({
+            "id": "m1",
+            "topic": "mysecret",
+            "message": wire["message"],
+            "priority": 4,
+        }))
+        .unwrap();
+        assert_eq!(
+            decrypt_message(&[topic_key("k1", PASSWORD)], "https://ntfy.sh", &mut msg, false),
+            Decryption::Decrypted { key_id: "k1".to_string() }
+        );
+        assert_eq!(msg.message.as_deref(), Some("body"));
+        assert_eq!(msg.title.as_deref(), Some("title"));
+        assert_eq!(msg.tags, ["warning"]);
+        assert_eq!(msg.click.as_deref(), Some("https://example.com"));
+        assert_eq!(msg.encrypted_fields, ["message", "title", "tags", "click"]);
+    }
+
+    #[test]
+    fn test_plain_message_is_left_alone() {
+        let mut msg = models::ReceivedMessage {
+            topic: "mysecret".to_string(),
+            message: Some("hello".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            decrypt_message(&[topic_key("k1", PASSWORD)], "https://ntfy.sh", &mut msg, true),
+            Decryption::Plaintext
+        );
+        assert_eq!(msg.message.as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn test_trial_decryption_with_retained_keys() {
+        let old = topic_key("old", "old password");
+        let new = topic_key("new", "new password");
+        let received = |out: models::OutgoingMessage| -> models::ReceivedMessage {
+            let mut wire = serde_json::to_value(&out).unwrap();
+            wire["id"] = "m1".into();
+            serde_json::from_value(wire).unwrap()
+        };
+        let encrypted = |key: &TopicKey| {
+            let mut out = models::OutgoingMessage {
+                topic: "mysecret".to_string(),
+                message: Some(format!("from {}", key.id)),
+                ..Default::default()
+            };
+            encrypt_message(key, "https://ntfy.sh", &mut out, Encryption::Message);
+            received(out)
+        };
+
+        // A sender still on the old key, and one without key IDs
+        let mut from_old = encrypted(&old);
+        let url = topic_url("https://ntfy.sh", "mysecret");
+        let mut anonymous = from_old.clone();
+        anonymous.message = Some(seal(
+            &derive_key(&old.secret, &url),
+            &random_iv(),
+            JWE_HEADER,
+            b"no kid",
+        ));
+        for (msg, expected) in [(&mut from_old, "from old"), (&mut anonymous, "no kid")] {
+            let keys = [new.clone(), old.clone()];
+            assert_eq!(
+                decrypt_message(&keys, "https://ntfy.sh", msg, false),
+                Decryption::Decrypted { key_id: "old".to_string() }
+            );
+            assert_eq!(msg.message.as_deref(), Some(expected));
+        }
+
+        // Once the old key is gone the message can't be read anymore
+        let mut from_old = encrypted(&old);
+        let ciphertext = from_old.message.clone();
+        assert!(matches!(
+            decrypt_message(&[new], "https://ntfy.sh", &mut from_old, false),
+            Decryption::Failed { .. }
+        ));
+        // It is kept as received, so it can be retried later
+        assert_eq!(from_old.message, ciphertext);
+        assert!(from_old.decryption.is_retryable());
+        assert_eq!(
+            decrypt_message(&[], "https://ntfy.sh", &mut from_old, false),
+            Decryption::NoKey
+        );
+    }
+}
@@ -0,0 +1,384 @@
+// The daemon on the session bus, for scripts and other apps. The interface is described
+// in ntfy-daemon/data/io.github.tobagin.Ntfyr.Daemon.xml, keep both in sync.
+
+use std::collections::HashMap;
+
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+use zbus::object_server::SignalEmitter;
+use zbus::{connection, fdo, interface, proxy, zvariant::OwnedValue, Connection};
+
+use crate::{models, unifiedpush, ConnectionState, ListenerEvent, NtfyHandle, SubscriptionEvent};
+
+pub const BUS_NAME: &str = "io.github.tobagin.Ntfyr.Daemon";
+pub const OBJECT_PATH: &str = "/io/github/tobagin/Ntfyr/Daemon";
+
+pub struct Daemon {
+    ntfy: NtfyHandle,
+}
+
+#[interface(name = "io.github.tobagin.Ntfyr.Daemon")]
+impl Daemon {
+    async fn subscribe(&self, server: &str, topic: &str) -> fdo::Result<()> {
+        self.ntfy.subscribe(server, topic).await.map_err(failed)?;
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, server: &str, topic: &str) -> fdo::Result<()> {
+        self.ntfy.unsubscribe(server, topic).await.map_err(failed)
+    }
+
+    // (server, topic, display name, muted) for each subscription
+    async fn list_subscriptions(&self) -> fdo::Result<Vec<(String, String, String, bool)>> {
+        let mut subs = vec![];
+        for sub in self.ntfy.list_subscriptions().await.map_err(failed)? {
+            let model = sub.model().await;
+            subs.push((model.server, model.topic, model.display_name, model.muted));
+        }
+        Ok(subs)
+    }
+
+    async fn publish(
+        &self,
+        server: &str,
+        topic: &str,
+        message: &str,
+        options: HashMap<String, OwnedValue>,
+    ) -> fdo::Result<()> {
+        let mut msg = models::OutgoingMessage {
+            topic: topic.to_string(),
+            message: (!message.is_empty()).then(|| message.to_string()),
+            ..models::OutgoingMessage::default()
+        };
+        let mut encryption = models::Encryption::default();
+        for (key, value) in options {
+            match key.as_str() {
+                "title" => msg.title = Some(option(&key, value)?),
+                "priority" => {
+                    let priority: u8 = option(&key, value)?;
+                    if !(1..=5).contains(&priority) {
+                        return Err(fdo::Error::InvalidArgs(format!(
+                            "priority {priority} isn't between 1 and 5"
+                        )));
+                    }
+                    msg.priority = Some(priority as i8);
+                }
+                "tags" => msg.tags = option(&key, value)?,
+                "click" => msg.click = Some(option(&key, value)?),
+                "encrypt" => {
+                    encryption = match option::<String>(&key, value)?.as_str() {
+                        "message" => models::Encryption::Message,
+                        "full" => models::Encryption::Full,
+                        other => {
+                            return Err(fdo::Error::InvalidArgs(format!(
+                                "unknown encryption {other:?}, expected \"message\" or \"full\""
+                            )))
+                        }
+                    }
+                }
+                _ => return Err(fdo::Error::InvalidArgs(format!("unknown option {key:?}"))),
+            }
+        }
+        self.ntfy
+            .publish(server, msg, encryption)
+            .await
+            .map_err(failed)
+    }
+
+    async fn add_account(&self, server: &str, username: &str, password: &str) -> fdo::Result<()> {
+        self.ntfy
+            .add_account(server, username, password)
+            .await
+            .map_err(failed)
+    }
+
+    async fn remove_account(&self, server: &str) -> fdo::Result<()> {
+        self.ntfy.remove_account(server).await.map_err(failed)
+    }
+
+    // Unlike `Distributor1.Unregister`, fails when no app has this token
+    async fn unregister_push(&self, token: &str) -> fdo::Result<()> {
+        self.ntfy.unregister_push(token).await.map_err(failed)
+    }
+
+    // `message` is the JSON of the received message, decrypted when the key is known
+    #[zbus(signal)]
+    async fn message_received(
+        emitter: &SignalEmitter<'_>,
+        server: &str,
+        topic: &str,
+        message: &str,
+    ) -> zbus::Result<()>;
+
+    // `state` is one of "uninitialized", "connected", "polling" or "reconnecting"
+    #[zbus(signal)]
+    async fn connection_state_changed(
+        emitter: &SignalEmitter<'_>,
+        server: &str,
+        topic: &str,
+        state: &str,
+    ) -> zbus::Result<()>;
+}
+
+// For clients like `ntfyr-daemon`, which hands its commands to the running service
+#[proxy(
+    interface = "io.github.tobagin.Ntfyr.Daemon",
+    default_service = "io.github.tobagin.Ntfyr.Daemon",
+    default_path = "/io/github/tobagin/Ntfyr/Daemon"
+)]
+pub trait DaemonClient {
+    fn subscribe(&self, server: &str, topic: &str) -> zbus::Result<()>;
+    fn unsubscribe(&self, server: &str, topic: &str) -> zbus::Result<()>;
+    fn list_subscriptions(&self) -> zbus::Result<Vec<(String, String, String, bool)>>;
+    fn publish(
+        &self,
+        server: &str,
+        topic: &str,
+        message: &str,
+        options: HashMap<&str, zbus::zvariant::Value<'_>>,
+    ) -> zbus::Result<()>;
+    fn add_account(&self, server: &str, username: &str, password: &str) -> zbus::Result<()>;
+    fn remove_account(&self, server: &str) -> zbus::Result<()>;
+    fn unregister_push(&self, token: &str) -> zbus::Result<()>;
+}
+
+fn failed(e: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("{e:#}"))
+}
+
+fn option<T>(key: &str, value: OwnedValue) -> fdo::Result<T>
+where
+    T: TryFrom<OwnedValue>,
+{
+    T::try_from(value)
+        .map_err(|_| fdo::Error::InvalidArgs(format!("option {key:?} has the wrong type")))
+}
+
+fn state_name(state: &ConnectionState) -> &'static str {
+    match state {
+        ConnectionState::Unitialized => "uninitialized",
+        ConnectionState::Connected => "connected",
+        ConnectionState::Polling { .. } => "polling",
+        ConnectionState::Reconnecting { .. } => "reconnecting",
+    }
+}
+
+// Owns `BUS_NAME` on the session bus, and the UnifiedPush distributor name when no
+// other distributor does. The service runs until the process exits.
+pub async fn serve(ntfy: NtfyHandle) -> zbus::Result<()> {
+    let conn = serve_on(connection::Builder::session()?.name(BUS_NAME)?, ntfy).await?;
+    if let Err(e) = conn.request_name(unifiedpush::BUS_NAME).await {
+        warn!(error = %e, "not acting as UnifiedPush distributor");
+    }
+    // The thread forwarding the events keeps the connection open
+    Ok(())
+}
+
+// Serves the interfaces on the connection being built, e.g. a peer-to-peer one. They're
+// registered before the connection starts, later they could miss the first calls.
+pub async fn serve_on(
+    builder: connection::Builder<'_>,
+    ntfy: NtfyHandle,
+) -> zbus::Result<Connection> {
+    let conn = builder
+        .serve_at(OBJECT_PATH, Daemon { ntfy: ntfy.clone() })?
+        .serve_at(
+            unifiedpush::OBJECT_PATH,
+            unifiedpush::Distributor::new(ntfy.clone()),
+        )?
+        .build()
+        .await?;
+    forward_events(conn.clone(), &ntfy)?;
+    Ok(conn)
+}
+
+// Turns the subscription events into signals and hands the push messages to the apps.
+// Runs on a thread of its own because callers may not have a runtime that outlives
+// this call, e.g. the GTK app.
+fn forward_events(conn: Connection, ntfy: &NtfyHandle) -> zbus::Result<()> {
+    let mut events = ntfy.events();
+    let push_events = ntfy.push_events();
+    let push_deliveries = ntfy.push_deliveries();
+    std::thread::Builder::new()
+        .name("ntfyr-dbus".to_string())
+        .spawn(move || {
+            let signals = async {
+                let emitter = match SignalEmitter::new(&conn, OBJECT_PATH) {
+                    Ok(emitter) => emitter,
+                    Err(e) => {
+                        warn!(error = %e, "can't emit D-Bus signals");
+                        return;
+                    }
+                };
+                loop {
+                    match events.recv().await {
+                        Ok(event) => {
+                            if let Err(e) = emit(&emitter, event).await {
+                                warn!(error = %e, "can't emit D-Bus signal");
+                            }
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!(skipped, "D-Bus clients missed subscription events");
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            };
+            futures::executor::block_on(futures::future::join(
+                signals,
+                unifiedpush::forward(&conn, push_events, push_deliveries),
+            ));
+        })?;
+    Ok(())
+}
+
+async fn emit(emitter: &SignalEmitter<'_>, event: SubscriptionEvent) -> zbus::Result<()> {
+    let SubscriptionEvent {
+        server,
+        topic,
+        event,
+    } = event;
+    match event {
+        ListenerEvent::Message(msg) => {
+            debug!(server, topic, id = msg.id, "emitting MessageReceived");
+            let json =
+                serde_json::to_string(&msg).map_err(|e| zbus::Error::Failure(e.to_string()))?;
+            Daemon::message_received(emitter, &server, &topic, &json).await
+        }
+        ListenerEvent::ConnectionStateChanged(state) => {
+            Daemon::connection_state_changed(emitter, &server, &topic, state_name(&state)).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use futures::StreamExt;
+    use zbus::{message, Guid, MatchRule, MessageStream};
+
+    use super::*;
+
+    // A bus of two peers, the service and its client
+    async fn private_bus(ntfy: NtfyHandle) -> (Connection, Connection) {
+        let (service, client) = UnixStream::pair().unwrap();
+        futures::try_join!(
+            serve_on(
+                connection::Builder::unix_stream(service)
+                    .server(Guid::generate())
+                    .unwrap()
+                    .p2p(),
+                ntfy,
+            ),
+            connection::Builder::unix_stream(client).p2p().build(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_subscribe_over_dbus() {
+        let ntfy = crate::ntfy::start_nullable();
+        futures::executor::block_on(async move {
+            let (_service, client) = private_bus(ntfy).await;
+            let daemon = DaemonClientProxy::new(&client).await.unwrap();
+            let server = "http://localhost:8000";
+
+            // Signals of a peer have no sender, so the proxy's own signal streams never match
+            let rule = MatchRule::builder()
+                .msg_type(message::Type::Signal)
+                .interface(BUS_NAME)
+                .unwrap()
+                .member("ConnectionStateChanged")
+                .unwrap()
+                .build();
+            let mut states = MessageStream::for_match_rule(rule, &client, None)
+                .await
+                .unwrap();
+            daemon.subscribe(server, "dbus_topic").await.unwrap();
+            let subs = daemon.list_subscriptions().await.unwrap();
+            assert_eq!(
+                subs,
+                vec![(
+                    server.to_string(),
+                    "dbus_topic".to_string(),
+                    String::new(),
+                    false
+                )]
+            );
+
+            // Connected or reconnecting, depending on whether a server runs on localhost
+            let signal = states.next().await.unwrap().unwrap();
+            let (signal_server, signal_topic, _state): (String, String, String) =
+                signal.body().deserialize().unwrap();
+            assert_eq!(
+                (signal_server.as_str(), signal_topic.as_str()),
+                (server, "dbus_topic")
+            );
+
+            daemon.unsubscribe(server, "dbus_topic").await.unwrap();
+            assert!(daemon.list_subscriptions().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_publish_rejects_bad_options() {
+        let ntfy = crate::ntfy::start_nullable();
+        futures::executor::block_on(async move {
+            let (_service, client) = private_bus(ntfy).await;
+            let daemon = DaemonClientProxy::new(&client).await.unwrap();
+
+            for (key, value) in [
+                ("priority", zbus::zvariant::Value::U8(9)),
+                ("encrypt", "twice".into()),
+                ("title", 1u8.into()),
+                ("color", "red".into()),
+            ] {
+                let err = daemon
+                    .publish(
+                        "http://localhost:8000",
+                        "dbus_topic",
+                        "hi",
+                        HashMap::from([(key, value)]),
+                    )
+                    .await
+                    .unwrap_err();
+                assert!(
+                    matches!(err, zbus::Error::MethodError(ref name, _, _) if name.as_str() == "org.freedesktop.DBus.Error.InvalidArgs"),
+                    "{key}: {err:?}"
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_introspection_matches_xml() {
+        let ntfy = crate::ntfy::start_nullable();
+        futures::executor::block_on(async move {
+            let (_service, client) = private_bus(ntfy).await;
+            let introspectable = fdo::IntrospectableProxy::builder(&client)
+                .destination(BUS_NAME)
+                .unwrap()
+                .path(OBJECT_PATH)
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            let served = introspectable.introspect().await.unwrap();
+
+            let xml = include_str!("../data/io.github.tobagin.Ntfyr.Daemon.xml");
+            let members: Vec<&str> = xml
+                .split("name=\"")
+                .skip(1)
+                .filter_map(|rest| rest.split('"').next())
+                .collect();
+            assert!(members.contains(&"ListSubscriptions"));
+            for member in members {
+                assert!(
+                    served.contains(&format!("name=\"{member}\"")),
+                    "{member} isn't served"
+                );
+            }
+        });
+    }
+}
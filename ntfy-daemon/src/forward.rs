@@ -0,0 +1,179 @@
+// Republishes the messages matching a `FilterAction::Forward` rule. Every forwarded message
+// gets a tag naming the topic it came from, a message is never forwarded to a topic it
+// already went through, so two topics forwarding to each other don't loop.
+
+use regex::{Captures, Regex};
+use tracing::{debug, info, warn};
+
+use crate::models::{ForwardTransform, OutgoingMessage, ReceivedMessage};
+use crate::{subscription, SharedEnv};
+
+const VIA_TAG: &str = "ntfyr-fwd:";
+// Topics a message can go through, in case the tags of a loop are lost on the way
+const MAX_HOPS: usize = 5;
+
+// Where a message is forwarded to
+pub(crate) struct Destination<'a> {
+    pub server: &'a str,
+    pub topic: &'a str,
+    pub transform: &'a ForwardTransform,
+}
+
+pub(crate) async fn forward(
+    env: &SharedEnv,
+    server: &str,
+    msg: &ReceivedMessage,
+    dest: Destination<'_>,
+) {
+    let Some(out) = forwarded_message(server, msg, &dest) else {
+        debug!(
+            id = msg.id,
+            topic = dest.topic,
+            "message already went through the destination"
+        );
+        return;
+    };
+    let encryption = dest.transform.encryption;
+    match subscription::publish(env, dest.server, out, encryption).await {
+        Ok(()) => info!(
+            id = msg.id,
+            server = dest.server,
+            topic = dest.topic,
+            "forwarded message"
+        ),
+        Err(e) => warn!(id = msg.id, topic = dest.topic, error = ?e, "can't forward message"),
+    }
+}
+
+// The message to publish, None if it already went through the destination
+pub(crate) fn forwarded_message(
+    server: &str,
+    msg: &ReceivedMessage,
+    dest: &Destination,
+) -> Option<OutgoingMessage> {
+    let dest_server = dest.server.trim_end_matches('/');
+    let server = server.trim_end_matches('/');
+    let hops: Vec<&str> = msg
+        .tags
+        .iter()
+        .filter_map(|tag| tag.strip_prefix(VIA_TAG))
+        .collect();
+    let dest_url = format!("{dest_server}/{}", dest.topic);
+    let source_url = format!("{server}/{}", msg.topic);
+    if dest_url == source_url || hops.contains(&dest_url.as_str()) || hops.len() >= MAX_HOPS {
+        return None;
+    }
+
+    let title = msg.title.as_deref().unwrap_or_default();
+    let message = msg.message.as_deref().unwrap_or_default();
+    let re = Regex::new(r"\{(title|message|server|topic)\}").unwrap();
+    let fill = |template: &str| {
+        re.replace_all(template, |caps: &Captures| match &caps[1] {
+            "title" => title.to_string(),
+            "message" => message.to_string(),
+            "server" => server.to_string(),
+            _ => msg.topic.clone(),
+        })
+        .into_owned()
+    };
+    let mut tags = msg.tags.clone();
+    tags.push(format!("{VIA_TAG}{source_url}"));
+
+    Some(OutgoingMessage {
+        topic: dest.topic.to_string(),
+        title: match &dest.transform.title {
+            Some(template) => Some(fill(template)).filter(|t| !t.is_empty()),
+            None => msg.title.clone(),
+        },
+        message: match &dest.transform.message {
+            Some(template) => Some(fill(template)),
+            None => msg.message.clone(),
+        },
+        tags,
+        priority: msg.priority,
+        icon: msg.icon.clone(),
+        click: msg.click.clone(),
+        actions: msg.actions.clone(),
+        markdown: msg.markdown,
+        ..OutgoingMessage::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "https://ntfy.sh";
+
+    fn message(topic: &str, tags: &[&str]) -> ReceivedMessage {
+        ReceivedMessage {
+            id: "m1".to_string(),
+            topic: topic.to_string(),
+            title: Some("Disk full".to_string()),
+            message: Some("sda1 at {message} 99%".to_string()),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            priority: Some(4),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_forwarded_message() {
+        let transform = ForwardTransform {
+            title: Some("[{topic}] {title}".to_string()),
+            ..Default::default()
+        };
+        let dest = Destination {
+            server: "https://ntfy.example.com/",
+            topic: "private",
+            transform: &transform,
+        };
+        let out = forwarded_message(SERVER, &message("alerts", &["warning"]), &dest).unwrap();
+        assert_eq!(out.topic, "private");
+        assert_eq!(out.title.as_deref(), Some("[alerts] Disk full"));
+        assert_eq!(out.message.as_deref(), Some("sda1 at {message} 99%"));
+        assert_eq!(out.priority, Some(4));
+        assert_eq!(out.tags, ["warning", "ntfyr-fwd:https://ntfy.sh/alerts"]);
+
+        let transform = ForwardTransform {
+            message: Some("{title}: {message}".to_string()),
+            ..Default::default()
+        };
+        let dest = Destination {
+            transform: &transform,
+            ..dest
+        };
+        let out = forwarded_message(SERVER, &message("alerts", &[]), &dest).unwrap();
+        assert_eq!(out.title.as_deref(), Some("Disk full"));
+        assert_eq!(
+            out.message.as_deref(),
+            Some("Disk full: sda1 at {message} 99%")
+        );
+    }
+
+    #[test]
+    fn test_forward_loops_are_stopped() {
+        let transform = ForwardTransform::default();
+        let back = Destination {
+            server: SERVER,
+            topic: "alerts",
+            transform: &transform,
+        };
+        // Coming back from the private server, or to the topic itself
+        let msg = message("private", &["ntfyr-fwd:https://ntfy.sh/alerts"]);
+        assert!(forwarded_message("https://ntfy.example.com", &msg, &back).is_none());
+        assert!(forwarded_message(SERVER, &message("alerts", &[]), &back).is_none());
+
+        // Going on to a third topic is fine, up to `MAX_HOPS`
+        let onward = Destination {
+            topic: "archive",
+            ..back
+        };
+        assert!(forwarded_message("https://ntfy.example.com", &msg, &onward).is_some());
+        let hops: Vec<String> = (0..MAX_HOPS)
+            .map(|i| format!("{VIA_TAG}{SERVER}/t{i}"))
+            .collect();
+        let hops: Vec<&str> = hops.iter().map(String::as_str).collect();
+        assert!(forwarded_message(SERVER, &message("t9", &hops), &onward).is_none());
+    }
+}
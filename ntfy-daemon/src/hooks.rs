@@ -0,0 +1,208 @@
+// Runs the programs of `FilterAction::Run` rules. The message is passed in `NTFY_*`
+// environment variables, the same ones `ntfy subscribe` sets, and as JSON on stdin.
+
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+use crate::models::{self, CommandHook, ReceivedMessage};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// Programs running at the same time, the next ones wait for their turn
+const MAX_RUNNING: usize = 4;
+// Longest stderr line kept as the error of a failed run
+const MAX_ERROR_LEN: usize = 200;
+
+#[derive(Clone)]
+pub(crate) struct Hooks {
+    running: Arc<Semaphore>,
+}
+
+impl Hooks {
+    pub(crate) fn new() -> Self {
+        Self {
+            running: Arc::new(Semaphore::new(MAX_RUNNING)),
+        }
+    }
+
+    pub(crate) async fn run(
+        &self,
+        rule: &str,
+        hook: &CommandHook,
+        server: &str,
+        msg: &ReceivedMessage,
+    ) -> models::HookRun {
+        let _permit = self.running.acquire().await.expect("never closed");
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let start = Instant::now();
+        debug!(program = hook.program, id = msg.id, "running command hook");
+
+        let (exit_code, error) = execute(hook, server, msg)
+            .await
+            .unwrap_or_else(|e| (None, Some(e)));
+        if let Some(error) = &error {
+            warn!(
+                program = hook.program,
+                ?exit_code,
+                error,
+                "command hook failed"
+            );
+        }
+        models::HookRun {
+            rule: rule.to_string(),
+            message_id: msg.id.clone(),
+            started_at,
+            duration_ms: start.elapsed().as_millis() as u64,
+            exit_code,
+            error,
+        }
+    }
+}
+
+async fn execute(
+    hook: &CommandHook,
+    server: &str,
+    msg: &ReceivedMessage,
+) -> Result<(Option<i32>, Option<String>), String> {
+    let input = serde_json::to_vec(msg).map_err(|e| e.to_string())?;
+    let mut child = Command::new(&hook.program)
+        .args(&hook.args)
+        .envs(message_env(server, msg))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("can't start {}: {e}", hook.program))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let write = async move {
+        // Programs that don't read their input close it early, that's fine
+        let _ = stdin.write_all(&input).await;
+    };
+
+    let timeout = hook
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT);
+    // Dropping the child on timeout kills it
+    let (_, output) = tokio::time::timeout(timeout, async {
+        futures::join!(write, child.wait_with_output())
+    })
+    .await
+    .map_err(|_| format!("timed out after {}s", timeout.as_secs()))?;
+    let output = output.map_err(|e| e.to_string())?;
+
+    let exit_code = output.status.code();
+    if output.status.success() {
+        return Ok((exit_code, None));
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let error = match stderr.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => line.trim().chars().take(MAX_ERROR_LEN).collect(),
+        None if exit_code.is_none() => "killed by a signal".to_string(),
+        None => format!("exited with {}", output.status),
+    };
+    Ok((exit_code, Some(error)))
+}
+
+fn message_env(server: &str, msg: &ReceivedMessage) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("NTFY_ID", msg.id.clone()),
+        ("NTFY_TIME", msg.time.to_string()),
+        ("NTFY_SERVER", server.to_string()),
+        ("NTFY_TOPIC", msg.topic.clone()),
+        ("NTFY_MESSAGE", msg.message.clone().unwrap_or_default()),
+        ("NTFY_TITLE", msg.title.clone().unwrap_or_default()),
+        ("NTFY_PRIORITY", msg.priority.unwrap_or(3).to_string()),
+        ("NTFY_TAGS", msg.tags.join(",")),
+    ];
+    if let Some(click) = &msg.click {
+        env.push(("NTFY_CLICK", click.clone()));
+    }
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(script: &str, timeout: Option<u64>) -> CommandHook {
+        CommandHook {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            timeout,
+        }
+    }
+
+    fn message() -> ReceivedMessage {
+        ReceivedMessage {
+            id: "m1".to_string(),
+            topic: "deploys".to_string(),
+            title: Some("Deploy".to_string()),
+            message: Some("done".to_string()),
+            tags: vec!["rocket".to_string(), "prod".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_passes_the_message() {
+        let hooks = Hooks::new();
+        let script = r#"[ "$NTFY_TITLE $NTFY_TAGS $NTFY_PRIORITY" = "Deploy rocket,prod 3" ] &&
+            grep -q '"id":"m1"'"#;
+        let run = hooks
+            .run(
+                "deploy",
+                &hook(script, None),
+                models::DEFAULT_SERVER,
+                &message(),
+            )
+            .await;
+        assert_eq!(run.error, None);
+        assert!(run.succeeded());
+        assert_eq!(
+            (run.rule.as_str(), run.message_id.as_str()),
+            ("deploy", "m1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_records_failures() {
+        let hooks = Hooks::new();
+        let server = models::DEFAULT_SERVER;
+
+        let run = hooks
+            .run(
+                "r",
+                &hook("echo oops >&2; exit 3", None),
+                server,
+                &message(),
+            )
+            .await;
+        assert_eq!(run.exit_code, Some(3));
+        assert_eq!(run.error.as_deref(), Some("oops"));
+
+        let run = hooks
+            .run("r", &hook("sleep 5", Some(1)), server, &message())
+            .await;
+        assert_eq!(run.exit_code, None);
+        assert_eq!(run.error.as_deref(), Some("timed out after 1s"));
+
+        let missing = CommandHook {
+            program: "/nonexistent/ntfyr-hook".to_string(),
+            args: vec![],
+            timeout: None,
+        };
+        let run = hooks.run("r", &missing, server, &message()).await;
+        assert!(!run.succeeded());
+        assert!(run.error.unwrap().starts_with("can't start"));
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use futures::StreamExt;
 use reqwest::{header::HeaderMap, Client, Request, RequestBuilder, Response};
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
@@ -96,6 +97,40 @@ impl HttpClient {
     }
 }
 
+/// One step of a streamed response body
+#[derive(Clone, Debug)]
+#[allow(dead_code)]
+pub enum Chunk {
+    /// Bytes sent as is, they don't need to end on a line boundary
+    Data(String),
+    /// Nothing is sent for this long
+    Delay(Duration),
+    /// The connection breaks with an I/O error
+    Error(String),
+    /// The connection stays open but nothing is sent anymore
+    Stall,
+}
+
+fn chunk_body(
+    chunks: impl futures::Stream<Item = Chunk> + Send + 'static,
+) -> reqwest::Body {
+    let chunks = chunks.filter_map(|chunk| async move {
+        match chunk {
+            Chunk::Data(data) => Some(Ok(data)),
+            Chunk::Delay(delay) => {
+                time::sleep(delay).await;
+                None
+            }
+            Chunk::Error(e) => Some(Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                e,
+            ))),
+            Chunk::Stall => futures::future::pending().await,
+        }
+    });
+    reqwest::Body::wrap_stream(chunks)
+}
+
 #[derive(Clone, Default)]
 #[allow(dead_code)]
 pub struct NullableClient {
@@ -171,6 +206,52 @@ impl NullableClientBuilder {
         self.response(url, response)
     }
 
+    /// Helper method to add a response that sends `body`, then stays open without sending anything
+    pub fn stalled_response(
+        self,
+        url: impl Into<String>,
+        status: u16,
+        body: impl Into<String>,
+    ) -> Self {
+        self.scripted_response(url, status, vec![Chunk::Data(body.into()), Chunk::Stall])
+    }
+
+    /// Add a streamed response whose body plays `chunks` in order, then ends
+    pub fn scripted_response(
+        self,
+        url: impl Into<String>,
+        status: u16,
+        chunks: Vec<Chunk>,
+    ) -> Self {
+        self.streamed_response(url, status, futures::stream::iter(chunks))
+    }
+
+    /// Add a streamed response whose body is fed by the test through `chunks`.
+    /// The body ends when the sender is dropped.
+    pub fn channel_response(
+        self,
+        url: impl Into<String>,
+        status: u16,
+        chunks: tokio::sync::mpsc::UnboundedReceiver<Chunk>,
+    ) -> Self {
+        let chunks = tokio_stream::wrappers::UnboundedReceiverStream::new(chunks);
+        self.streamed_response(url, status, chunks)
+    }
+
+    fn streamed_response(
+        self,
+        url: impl Into<String>,
+        status: u16,
+        chunks: impl futures::Stream<Item = Chunk> + Send + 'static,
+    ) -> Self {
+        let response = http::response::Builder::new()
+            .status(status)
+            .body(chunk_body(chunks))
+            .unwrap()
+            .into();
+        self.response(url, response)
+    }
+
     pub fn build(self) -> NullableClient {
         NullableClient {
             responses: Arc::new(RwLock::new(
@@ -326,4 +407,53 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scripted_response() -> Result<()> {
+        let client = NullableClient::builder()
+            .scripted_response(
+                "https://api.example.com/stream",
+                200,
+                vec![
+                    Chunk::Data("hel".into()),
+                    Chunk::Delay(Duration::from_secs(10)),
+                    Chunk::Data("lo".into()),
+                    Chunk::Error("reset".into()),
+                ],
+            )
+            .build();
+        let http_client = HttpClient::new_nullable(client);
+
+        let request = http_client.get("https://api.example.com/stream").build()?;
+        let mut body = http_client.execute(request).await?.bytes_stream();
+        let start = time::Instant::now();
+
+        assert_eq!(body.next().await.unwrap()?, "hel");
+        assert_eq!(body.next().await.unwrap()?, "lo");
+        assert!(start.elapsed() >= Duration::from_secs(10));
+        assert!(body.next().await.unwrap().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_channel_response() -> Result<()> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let client = NullableClient::builder()
+            .channel_response("https://api.example.com/stream", 200, rx)
+            .build();
+        let http_client = HttpClient::new_nullable(client);
+
+        let request = http_client.get("https://api.example.com/stream").build()?;
+        let mut body = http_client.execute(request).await?.bytes_stream();
+
+        tx.send(Chunk::Data("first".into()))?;
+        assert_eq!(body.next().await.unwrap()?, "first");
+        tx.send(Chunk::Data("second".into()))?;
+        drop(tx);
+        assert_eq!(body.next().await.unwrap()?, "second");
+        assert!(body.next().await.is_none());
+
+        Ok(())
+    }
 }
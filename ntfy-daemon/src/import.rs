@@ -0,0 +1,420 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::backup::{Backup, Secrets, BACKUP_VERSION};
+use crate::models;
+use crate::Error;
+
+// Value of the `magic` field of the settings backups of the Android app
+const ANDROID_MAGIC: &str = "ntfy2586";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Client {
+    Android,
+    Web,
+}
+
+impl Client {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Android => "ntfy Android app",
+            Self::Web => "ntfy web app",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientAccount {
+    pub server: String,
+    pub username: String,
+    pub password: String,
+}
+
+// Subscriptions and accounts read from the backup of another ntfy client
+#[derive(Clone, Debug)]
+pub struct ClientBackup {
+    pub client: Client,
+    pub subscriptions: Vec<models::Subscription>,
+    pub accounts: Vec<ClientAccount>,
+    // Entries that can't be used in Ntfyr, with the reason
+    pub skipped: Vec<String>,
+}
+
+impl ClientBackup {
+    // Detects whether the JSON comes from the Android app or the web app
+    pub fn parse(json: &str) -> Result<Self, Error> {
+        let value: Value =
+            serde_json::from_str(json).map_err(|e| Error::InvalidBackup(e.to_string()))?;
+        if value.get("magic").and_then(Value::as_str) == Some(ANDROID_MAGIC) {
+            let backup: AndroidBackup =
+                serde_json::from_value(value).map_err(|e| Error::InvalidBackup(e.to_string()))?;
+            return Ok(backup.into());
+        }
+        let tables = web_tables(value)
+            .ok_or_else(|| Error::InvalidBackup("not an ntfy app backup".to_string()))?;
+        Ok(tables.into())
+    }
+
+    // The passwords can be restored like the unsealed secrets of a backup
+    pub fn into_restore(self) -> (Backup, Secrets) {
+        let now = now();
+        let subscriptions = self
+            .subscriptions
+            .into_iter()
+            .map(|mut sub| {
+                // Old messages fetched on the first connection shouldn't show up as unread
+                sub.read_until = now;
+                sub
+            })
+            .collect();
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|a| models::Account {
+                server: a.server.clone(),
+                username: a.username.clone(),
+            })
+            .collect();
+        let secrets = Secrets {
+            passwords: self
+                .accounts
+                .into_iter()
+                .map(|a| (a.server, a.password))
+                .collect(),
+            keys: vec![],
+        };
+        let backup = Backup {
+            version: BACKUP_VERSION,
+            created_at: now,
+            subscriptions,
+            accounts,
+            ..Default::default()
+        };
+        (backup, secrets)
+    }
+
+    // What the import would leave untouched because the device already has it
+    pub fn conflicts(
+        &self,
+        subscriptions: &[models::Subscription],
+        accounts: &[models::Account],
+    ) -> Vec<Conflict> {
+        let subscribed = self
+            .subscriptions
+            .iter()
+            .filter(|sub| {
+                subscriptions
+                    .iter()
+                    .any(|s| s.server == sub.server && s.topic == sub.topic)
+            })
+            .map(|sub| Conflict::Subscribed {
+                server: sub.server.clone(),
+                topic: sub.topic.clone(),
+            });
+        let accounts = self.accounts.iter().filter_map(|account| {
+            accounts
+                .iter()
+                .find(|a| a.server == account.server)
+                .map(|existing| Conflict::Account {
+                    server: account.server.clone(),
+                    existing: existing.username.clone(),
+                    imported: account.username.clone(),
+                })
+        });
+        subscribed.chain(accounts).collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Conflict {
+    // The topic is already subscribed, its settings are kept
+    Subscribed { server: String, topic: String },
+    // The server already has an account, it is kept instead of the imported one
+    Account {
+        server: String,
+        existing: String,
+        imported: String,
+    },
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Subscribed { server, topic } => {
+                write!(f, "{server}/{topic} is already subscribed")
+            }
+            Self::Account {
+                server,
+                existing,
+                imported,
+            } => write!(
+                f,
+                "{server} already has the account {existing}, {imported} is not imported"
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AndroidBackup {
+    #[serde(default)]
+    subscriptions: Vec<AndroidSubscription>,
+    #[serde(default)]
+    users: Vec<ClientUser>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AndroidSubscription {
+    base_url: String,
+    topic: String,
+    // 0 when not muted, 1 when muted until unmuted, otherwise a timestamp
+    #[serde(default)]
+    muted_until: u64,
+    #[serde(default)]
+    display_name: Option<String>,
+    // Set for topics used by other apps through UnifiedPush
+    #[serde(default)]
+    up_app_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientUser {
+    base_url: String,
+    username: String,
+    password: String,
+}
+
+impl From<AndroidBackup> for ClientBackup {
+    fn from(backup: AndroidBackup) -> Self {
+        // Subscriptions that weren't instant were delivered through Firebase. Ntfyr keeps a
+        // connection open for them like for any other.
+        let mut this = Self::new(Client::Android, backup.users);
+        for sub in backup.subscriptions {
+            if let Some(app) = &sub.up_app_id {
+                this.skipped.push(format!("{}: used by the app {app}", sub.topic));
+                continue;
+            }
+            let server = normalize_server(&sub.base_url);
+            this.add_subscription(&server, &sub.topic, sub.display_name, sub.muted_until);
+        }
+        this
+    }
+}
+
+// Rows of the tables the web app keeps in the browser's IndexedDB
+#[derive(Deserialize)]
+struct WebTables {
+    #[serde(default)]
+    subscriptions: Vec<WebSubscription>,
+    #[serde(default)]
+    users: Vec<ClientUser>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebSubscription {
+    base_url: String,
+    topic: String,
+    #[serde(default)]
+    muted_until: u64,
+    #[serde(default)]
+    display_name: Option<String>,
+    // Created by the web app for itself, not by the user
+    #[serde(default)]
+    internal: bool,
+}
+
+impl From<WebTables> for ClientBackup {
+    fn from(tables: WebTables) -> Self {
+        let mut this = Self::new(Client::Web, tables.users);
+        for sub in tables.subscriptions.into_iter().filter(|sub| !sub.internal) {
+            let server = normalize_server(&sub.base_url);
+            this.add_subscription(&server, &sub.topic, sub.display_name, sub.muted_until);
+        }
+        this
+    }
+}
+
+// Accepts the tables as a plain object, or the export format of Dexie, the IndexedDB
+// library of the web app: `{"formatName": "dexie", "data": {"data": [{"tableName", "rows"}]}}`
+fn web_tables(value: Value) -> Option<WebTables> {
+    let value = match value.get("formatName").and_then(Value::as_str) {
+        Some("dexie") => {
+            let tables = value.get("data")?.get("data")?.as_array()?;
+            let tables: serde_json::Map<String, Value> = tables
+                .iter()
+                .filter_map(|table| {
+                    let name = table.get("tableName")?.as_str()?;
+                    Some((name.to_string(), table.get("rows")?.clone()))
+                })
+                .collect();
+            Value::Object(tables)
+        }
+        _ => value,
+    };
+    value.get("subscriptions")?;
+    serde_json::from_value(value).ok()
+}
+
+impl ClientBackup {
+    fn new(client: Client, users: Vec<ClientUser>) -> Self {
+        let accounts = users
+            .into_iter()
+            .map(|user| ClientAccount {
+                server: normalize_server(&user.base_url),
+                username: user.username,
+                password: user.password,
+            })
+            .collect();
+        Self {
+            client,
+            subscriptions: vec![],
+            accounts,
+            skipped: vec![],
+        }
+    }
+
+    fn add_subscription(
+        &mut self,
+        server: &str,
+        topic: &str,
+        display_name: Option<String>,
+        muted_until: u64,
+    ) {
+        let sub = models::Subscription::builder(topic.to_string())
+            .server(server.to_string())
+            .display_name(display_name.unwrap_or_default())
+            .muted(muted_until == 1 || muted_until > now())
+            .build();
+        match sub {
+            Ok(sub) => self.subscriptions.push(sub),
+            Err(e) => self.skipped.push(format!("{topic}: {e}")),
+        }
+    }
+}
+
+fn normalize_server(base_url: &str) -> String {
+    base_url.trim().trim_end_matches('/').to_string()
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_android_backup() {
+        let json = r#"{
+            "magic": "ntfy2586",
+            "version": 1,
+            "settings": {"theme": -1},
+            "subscriptions": [
+                {"id": 1, "baseUrl": "https://ntfy.sh", "topic": "alerts", "instant": false,
+                 "mutedUntil": 1, "displayName": "Alerts"},
+                {"id": 2, "baseUrl": "https://ntfy.example.com/", "topic": "builds", "instant": true,
+                 "mutedUntil": 0},
+                {"id": 3, "baseUrl": "https://ntfy.example.com", "topic": "deploys", "instant": false,
+                 "mutedUntil": 0},
+                {"id": 4, "baseUrl": "https://ntfy.sh", "topic": "upABC", "instant": true,
+                 "mutedUntil": 0, "upAppId": "im.fluffychat"}
+            ],
+            "notifications": [],
+            "users": [{"baseUrl": "https://ntfy.example.com/", "username": "phil", "password": "secret"}]
+        }"#;
+        let backup = ClientBackup::parse(json).unwrap();
+        assert_eq!(backup.client, Client::Android);
+
+        let subs: Vec<_> = backup
+            .subscriptions
+            .iter()
+            .map(|s| (s.server.as_str(), s.topic.as_str(), s.display_name.as_str(), s.muted))
+            .collect();
+        assert_eq!(
+            subs,
+            [
+                ("https://ntfy.sh", "alerts", "Alerts", true),
+                ("https://ntfy.example.com", "builds", "", false),
+                ("https://ntfy.example.com", "deploys", "", false),
+            ]
+        );
+        assert_eq!(backup.skipped.len(), 1);
+        assert_eq!(
+            backup.accounts,
+            [ClientAccount {
+                server: "https://ntfy.example.com".to_string(),
+                username: "phil".to_string(),
+                password: "secret".to_string(),
+            }]
+        );
+
+        let existing = models::Subscription::builder("alerts".to_string())
+            .build()
+            .unwrap();
+        let account = models::Account {
+            server: "https://ntfy.example.com".to_string(),
+            username: "admin".to_string(),
+        };
+        assert_eq!(
+            backup.conflicts(&[existing], &[account]),
+            [
+                Conflict::Subscribed {
+                    server: "https://ntfy.sh".to_string(),
+                    topic: "alerts".to_string(),
+                },
+                Conflict::Account {
+                    server: "https://ntfy.example.com".to_string(),
+                    existing: "admin".to_string(),
+                    imported: "phil".to_string(),
+                },
+            ]
+        );
+
+        let (restore, secrets) = backup.into_restore();
+        assert_eq!(restore.accounts[0].username, "phil");
+        assert_eq!(secrets.passwords["https://ntfy.example.com"], "secret");
+        // Subscriptions that weren't instant are streamed too
+        assert!(restore.server_settings.is_empty());
+    }
+
+    #[test]
+    fn test_parse_web_backup() {
+        let json = r#"{
+            "formatName": "dexie",
+            "formatVersion": 1,
+            "data": {
+                "databaseName": "ntfy",
+                "data": [
+                    {"tableName": "subscriptions", "rows": [
+                        {"id": "https://ntfy.sh/news", "baseUrl": "https://ntfy.sh", "topic": "news",
+                         "mutedUntil": 0, "displayName": "News", "internal": false},
+                        {"id": "https://ntfy.sh/abcd", "baseUrl": "https://ntfy.sh", "topic": "abcd",
+                         "mutedUntil": 0, "internal": true}
+                    ]},
+                    {"tableName": "users", "rows": [
+                        {"baseUrl": "https://ntfy.sh", "username": "phil", "password": "secret"}
+                    ]},
+                    {"tableName": "notifications", "rows": []}
+                ]
+            }
+        }"#;
+        let backup = ClientBackup::parse(json).unwrap();
+        assert_eq!(backup.client, Client::Web);
+        assert_eq!(backup.subscriptions.len(), 1);
+        assert_eq!(backup.subscriptions[0].display_name, "News");
+        assert_eq!(backup.accounts.len(), 1);
+
+        let plain = r#"{"subscriptions": [{"baseUrl": "https://ntfy.sh", "topic": "news"}]}"#;
+        assert_eq!(ClientBackup::parse(plain).unwrap().subscriptions.len(), 1);
+
+        assert!(ClientBackup::parse(r#"{"version": 1}"#).is_err());
+    }
+}
@@ -1,15 +1,74 @@
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
+use crate::credentials::{KeyringItem, LightKeyring, MissingKeyring, NullableKeyring, RealKeyring};
 
-use crate::credentials::{KeyringItem, LightKeyring, NullableKeyring, RealKeyring};
+// One entry of a topic keyring. The secret is the password the encryption key is derived from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicKey {
+    pub id: String,
+    pub secret: String,
+    pub created_at: u64,
+    // Keys past this time are dropped from the keyring
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    // The key used for publishing, exactly one per topic
+    #[serde(default)]
+    pub current: bool,
+}
+
+impl TopicKey {
+    fn new(secret: &str) -> Self {
+        Self {
+            id: format!("{:08x}", rand::thread_rng().gen::<u32>()),
+            secret: secret.to_string(),
+            created_at: now(),
+            expires_at: None,
+            current: true,
+        }
+    }
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+// What happens to the previous keys of a topic when a new one becomes current
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RotationPolicy {
+    // Keep them for decryption until removed by hand
+    #[default]
+    Retain,
+    // Keep them for decryption for the given number of seconds
+    ExpireAfter(u64),
+    // Remove them right away
+    Discard,
+}
+
+// Random password with the strength of a 256 bit key, safe to put in a link
+pub fn generate_secret() -> String {
+    URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Map<(server, topic), keys>, current key first and then newest first
+pub type TopicKeys = HashMap<(String, String), Vec<TopicKey>>;
 
 #[derive(Clone)]
 pub struct Keys {
     keyring: Arc<dyn LightKeyring + Send + Sync>,
-    // Map<(server, topic), key>
-    keys: Arc<RwLock<HashMap<(String, String), String>>>,
+    keys: Arc<RwLock<TopicKeys>>,
 }
 
 impl Keys {
@@ -18,7 +77,7 @@ impl Keys {
             keyring: Arc::new(RealKeyring {
                 keyring: oo7::Keyring::new()
                     .await
-                    .expect("Failed to start Secret Service"),
+                    .context("can't open the Secret Service")?,
             }),
             keys: Default::default(),
         };
@@ -26,19 +85,38 @@ impl Keys {
         Ok(this)
     }
 
-    pub fn new_nullable(mock_keys: HashMap<(String, String), String>) -> anyhow::Result<Self> {        
+    // No key is known and none can be added
+    pub fn without_keyring() -> Self {
+        Self {
+            keyring: Arc::new(MissingKeyring),
+            keys: Default::default(),
+        }
+    }
+
+    pub fn new_nullable(mock_keys: HashMap<(String, String), String>) -> anyhow::Result<Self> {
+        Self::new_nullable_keyring(
+            mock_keys
+                .into_iter()
+                .map(|(topic, secret)| (topic, vec![TopicKey::new(&secret)]))
+                .collect(),
+        )
+    }
+
+    pub fn new_nullable_keyring(mock_keys: TopicKeys) -> anyhow::Result<Self> {
         // Convert map to keyring items format for the mock
         let mut search_response = vec![];
-        for ((server, topic), key) in &mock_keys {
-             let attributes = HashMap::from([
-                ("type".to_string(), "topic_key".to_string()),
-                ("server".to_string(), server.clone()),
-                ("topic".to_string(), topic.clone()),
-            ]);
-            search_response.push(KeyringItem {
-                attributes,
-                secret: key.clone().into_bytes(),
-            });
+        for ((server, topic), keys) in &mock_keys {
+            for key in keys {
+                search_response.push(KeyringItem {
+                    attributes: HashMap::from([
+                        ("type".to_string(), "topic_key".to_string()),
+                        ("server".to_string(), server.clone()),
+                        ("topic".to_string(), topic.clone()),
+                        ("key_id".to_string(), key.id.clone()),
+                    ]),
+                    secret: serde_json::to_vec(key)?,
+                });
+            }
         }
 
         let this = Self {
@@ -46,7 +124,12 @@ impl Keys {
             keys: Default::default(),
         };
         // Pre-load the memory cache
-        *this.keys.write().unwrap() = mock_keys;
+        let mut lock = this.keys.write().unwrap();
+        for (topic, mut keys) in mock_keys {
+            sort_keys(&mut keys);
+            lock.insert(topic, keys);
+        }
+        drop(lock);
         Ok(this)
     }
 
@@ -54,42 +137,211 @@ impl Keys {
         let attrs = HashMap::from([("type", "topic_key")]);
         let values = self.keyring.search_items(attrs).await?;
 
-        let mut lock = self.keys.write().unwrap();
-        lock.clear();
+        let mut loaded = TopicKeys::new();
+        let mut unversioned = vec![];
         for item in values {
-        let attrs: HashMap<String, String> = item.attributes().await;
-        if let (Some(server), Some(topic)) = (attrs.get("server"), attrs.get("topic")) {
-             lock.insert(
-                (server.clone(), topic.clone()),
-                std::str::from_utf8(item.secret().await)?.to_string(),
-            );
+            let attrs: HashMap<String, String> = item.attributes().await;
+            let (Some(server), Some(topic)) = (attrs.get("server"), attrs.get("topic")) else {
+                continue;
+            };
+            let entry = (server.clone(), topic.clone());
+            let secret = std::str::from_utf8(item.secret().await)?;
+            match attrs.get("key_id") {
+                Some(_) => loaded
+                    .entry(entry)
+                    .or_default()
+                    .push(serde_json::from_str(secret)?),
+                // Stored before topics had more than one key
+                None => unversioned.push((entry, secret.to_string())),
+            }
         }
+
+        for ((server, topic), secret) in unversioned {
+            let keys = loaded.entry((server.clone(), topic.clone())).or_default();
+            let mut key = TopicKey::new(&secret);
+            key.current = keys.iter().all(|k| !k.current);
+            keys.push(key);
+            self.migrate(&server, &topic, keys).await?;
         }
-        Ok(())
+
+        {
+            let mut lock = self.keys.write().unwrap();
+            lock.clear();
+            for (topic, mut keys) in loaded {
+                sort_keys(&mut keys);
+                lock.insert(topic, keys);
+            }
+        }
+        self.prune_expired().await
     }
 
-    pub fn get(&self, server: &str, topic: &str) -> Option<String> {
-        self.keys.read().unwrap().get(&(server.to_string(), topic.to_string())).cloned()
+    // Rewrites all the keys of a topic with a key ID
+    async fn migrate(&self, server: &str, topic: &str, keys: &[TopicKey]) -> anyhow::Result<()> {
+        let attrs = HashMap::from([("type", "topic_key"), ("server", server), ("topic", topic)]);
+        self.keyring.delete(attrs).await?;
+        for key in keys {
+            self.store(server, topic, key).await?;
+        }
+        Ok(())
     }
 
-    pub async fn insert(&self, server: &str, topic: &str, key: &str) -> anyhow::Result<()> {
+    async fn store(&self, server: &str, topic: &str, key: &TopicKey) -> anyhow::Result<()> {
         let attrs = HashMap::from([
             ("type", "topic_key"),
             ("server", server),
             ("topic", topic),
+            ("key_id", key.id.as_str()),
         ]);
-        
         self.keyring
-            .create_item("Ntfyr Topic Key", attrs, key, true)
-            .await?;
+            .create_item(
+                "Ntfyr Topic Key",
+                attrs,
+                &serde_json::to_string(key)?,
+                true,
+            )
+            .await
+    }
+
+    // Secret of the key used for publishing
+    pub fn get(&self, server: &str, topic: &str) -> Option<String> {
+        self.current(server, topic).map(|k| k.secret)
+    }
+
+    pub fn current(&self, server: &str, topic: &str) -> Option<TopicKey> {
+        self.list(server, topic).into_iter().find(|k| k.current)
+    }
 
-        self.keys.write().unwrap().insert(
-            (server.to_string(), topic.to_string()),
-            key.to_string(),
-        );
+    // Keys usable for decryption, current key first and then newest first
+    pub fn list(&self, server: &str, topic: &str) -> Vec<TopicKey> {
+        let now = now();
+        self.keys
+            .read()
+            .unwrap()
+            .get(&(server.to_string(), topic.to_string()))
+            .map(|keys| keys.iter().filter(|k| !k.is_expired(now)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn list_all(&self) -> TopicKeys {
+        self.keys.read().unwrap().clone()
+    }
+
+    // Changes the secret of the current key, creating it if the topic has none
+    pub async fn insert(&self, server: &str, topic: &str, secret: &str) -> anyhow::Result<()> {
+        let key = match self.current(server, topic) {
+            Some(key) => TopicKey {
+                secret: secret.to_string(),
+                ..key
+            },
+            None => TopicKey::new(secret),
+        };
+        self.store(server, topic, &key).await?;
+        self.update_cache(server, topic, |keys| {
+            keys.retain(|k| k.id != key.id);
+            keys.push(key);
+        });
         Ok(())
     }
 
+    // Makes a new key current and applies the policy to the previous ones
+    pub async fn rotate(
+        &self,
+        server: &str,
+        topic: &str,
+        secret: &str,
+        policy: RotationPolicy,
+    ) -> anyhow::Result<TopicKey> {
+        let new_key = TopicKey::new(secret);
+        let now = now();
+        let previous = self.list(server, topic);
+
+        self.store(server, topic, &new_key).await?;
+        for mut key in previous.iter().cloned() {
+            match policy {
+                RotationPolicy::Discard => {
+                    self.delete_key_item(server, topic, &key.id).await?;
+                    continue;
+                }
+                RotationPolicy::ExpireAfter(secs) => {
+                    let expires_at = now + secs;
+                    key.expires_at = Some(key.expires_at.map_or(expires_at, |t| t.min(expires_at)));
+                }
+                RotationPolicy::Retain => {}
+            }
+            key.current = false;
+            self.store(server, topic, &key).await?;
+            self.update_cache(server, topic, |keys| {
+                keys.retain(|k| k.id != key.id);
+                keys.push(key);
+            });
+        }
+        if policy == RotationPolicy::Discard {
+            self.update_cache(server, topic, |keys| keys.clear());
+        }
+        self.update_cache(server, topic, |keys| keys.push(new_key.clone()));
+        Ok(new_key)
+    }
+
+    // Makes the secret current unless it already is, the previous keys stay for older messages
+    pub async fn adopt(&self, server: &str, topic: &str, secret: &str) -> anyhow::Result<()> {
+        if self.get(server, topic).as_deref() != Some(secret) {
+            self.rotate(server, topic, secret, RotationPolicy::Retain)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Makes a freshly generated key current
+    pub async fn generate(
+        &self,
+        server: &str,
+        topic: &str,
+        policy: RotationPolicy,
+    ) -> anyhow::Result<TopicKey> {
+        self.rotate(server, topic, &generate_secret(), policy).await
+    }
+
+    // Adds keys from a backup, the current key of the topic stays current if there is one.
+    // Returns how many keys were added.
+    pub async fn restore(
+        &self,
+        server: &str,
+        topic: &str,
+        restored: Vec<TopicKey>,
+    ) -> anyhow::Result<usize> {
+        let existing = self.list_all().remove(&(server.to_string(), topic.to_string()));
+        let existing = existing.unwrap_or_default();
+        let has_current = existing.iter().any(|k| k.current);
+        let mut added = 0;
+        for mut key in restored {
+            if existing.iter().any(|k| k.id == key.id) {
+                continue;
+            }
+            key.current &= !has_current;
+            self.store(server, topic, &key).await?;
+            self.update_cache(server, topic, |keys| keys.push(key));
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    pub async fn remove_key(&self, server: &str, topic: &str, id: &str) -> anyhow::Result<()> {
+        self.delete_key_item(server, topic, id).await?;
+        self.update_cache(server, topic, |keys| keys.retain(|k| k.id != id));
+        Ok(())
+    }
+
+    async fn delete_key_item(&self, server: &str, topic: &str, id: &str) -> anyhow::Result<()> {
+        let attrs = HashMap::from([
+            ("type", "topic_key"),
+            ("server", server),
+            ("topic", topic),
+            ("key_id", id),
+        ]);
+        self.keyring.delete(attrs).await
+    }
+
+    // Removes every key of the topic
     pub async fn delete(&self, server: &str, topic: &str) -> anyhow::Result<()> {
         let attrs = HashMap::from([
             ("type", "topic_key"),
@@ -97,12 +349,200 @@ impl Keys {
             ("topic", topic),
         ]);
         self.keyring.delete(attrs).await?;
-        
+
         self.keys
             .write()
             .unwrap()
             .remove(&(server.to_string(), topic.to_string()));
-            
+
         Ok(())
     }
+
+    pub async fn prune_expired(&self) -> anyhow::Result<()> {
+        let now = now();
+        let expired: Vec<_> = self
+            .list_all()
+            .into_iter()
+            .flat_map(|((server, topic), keys)| {
+                keys.into_iter()
+                    .filter(|k| k.is_expired(now))
+                    .map(move |k| (server.clone(), topic.clone(), k.id))
+            })
+            .collect();
+        for (server, topic, id) in expired {
+            self.remove_key(&server, &topic, &id).await?;
+        }
+        Ok(())
+    }
+
+    fn update_cache(&self, server: &str, topic: &str, f: impl FnOnce(&mut Vec<TopicKey>)) {
+        let mut lock = self.keys.write().unwrap();
+        let keys = lock
+            .entry((server.to_string(), topic.to_string()))
+            .or_default();
+        f(keys);
+        sort_keys(keys);
+    }
+}
+
+fn sort_keys(keys: &mut [TopicKey]) {
+    keys.sort_by(|a, b| {
+        b.current
+            .cmp(&a.current)
+            .then(b.created_at.cmp(&a.created_at))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str, created_at: u64, current: bool) -> TopicKey {
+        TopicKey {
+            id: id.to_string(),
+            secret: format!("secret-{id}"),
+            created_at,
+            expires_at: None,
+            current,
+        }
+    }
+
+    fn topic() -> (String, String) {
+        ("https://ntfy.sh".to_string(), "test".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_rotate_keeps_old_keys_for_decryption() {
+        let keys = Keys::new_nullable_keyring(HashMap::from([(topic(), vec![key("a", 1, true)])]))
+            .unwrap();
+
+        let new_key = keys
+            .rotate("https://ntfy.sh", "test", "new", RotationPolicy::Retain)
+            .await
+            .unwrap();
+
+        assert_eq!(keys.get("https://ntfy.sh", "test").as_deref(), Some("new"));
+        let ids: Vec<_> = keys
+            .list("https://ntfy.sh", "test")
+            .into_iter()
+            .map(|k| (k.id, k.current))
+            .collect();
+        assert_eq!(ids, [(new_key.id, true), ("a".to_string(), false)]);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_expires_or_discards_old_keys() {
+        let keys = Keys::new_nullable_keyring(HashMap::from([(
+            topic(),
+            vec![key("a", 1, true), key("b", 0, false)],
+        )]))
+        .unwrap();
+
+        keys.rotate("https://ntfy.sh", "test", "new", RotationPolicy::ExpireAfter(0))
+            .await
+            .unwrap();
+        // Expired keys are no longer tried and get pruned
+        assert_eq!(keys.list("https://ntfy.sh", "test").len(), 1);
+        keys.prune_expired().await.unwrap();
+        assert_eq!(keys.list_all()[&topic()].len(), 1);
+
+        keys.rotate("https://ntfy.sh", "test", "newer", RotationPolicy::Discard)
+            .await
+            .unwrap();
+        let all = &keys.list_all()[&topic()];
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].secret, "newer");
+    }
+
+    #[tokio::test]
+    async fn test_insert_changes_current_key() {
+        let keys = Keys::new_nullable_keyring(HashMap::from([(
+            topic(),
+            vec![key("a", 2, true), key("b", 1, false)],
+        )]))
+        .unwrap();
+
+        keys.insert("https://ntfy.sh", "test", "edited").await.unwrap();
+
+        let current = keys.current("https://ntfy.sh", "test").unwrap();
+        assert_eq!((current.id.as_str(), current.secret.as_str()), ("a", "edited"));
+        assert_eq!(keys.list("https://ntfy.sh", "test").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_adopt_keeps_previous_key() {
+        let keys = Keys::new_nullable_keyring(HashMap::from([(topic(), vec![key("a", 2, true)])]))
+            .unwrap();
+
+        keys.adopt("https://ntfy.sh", "test", "shared").await.unwrap();
+        keys.adopt("https://ntfy.sh", "test", "shared").await.unwrap();
+
+        let listed = keys.list("https://ntfy.sh", "test");
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].secret, "shared");
+        assert!(listed[0].current);
+        assert!(listed.iter().any(|k| k.id == "a" && !k.current));
+    }
+
+    #[tokio::test]
+    async fn test_generate_makes_random_current_key() {
+        let keys = Keys::new_nullable_keyring(TopicKeys::new()).unwrap();
+
+        let first = keys
+            .generate("https://ntfy.sh", "test", RotationPolicy::Retain)
+            .await
+            .unwrap();
+        let second = keys
+            .generate("https://ntfy.sh", "test", RotationPolicy::Retain)
+            .await
+            .unwrap();
+
+        assert_eq!(first.secret.len(), 43);
+        assert_ne!(first.secret, second.secret);
+        assert_eq!(keys.get("https://ntfy.sh", "test"), Some(second.secret));
+        assert_eq!(keys.list("https://ntfy.sh", "test").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_restore_keeps_current_key() {
+        let keys = Keys::new_nullable_keyring(HashMap::from([(topic(), vec![key("a", 2, true)])]))
+            .unwrap();
+
+        let added = keys
+            .restore(
+                "https://ntfy.sh",
+                "test",
+                vec![key("a", 2, true), key("b", 3, true)],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(added, 1);
+        let ids: Vec<_> = keys
+            .list("https://ntfy.sh", "test")
+            .into_iter()
+            .map(|k| (k.id, k.current))
+            .collect();
+        assert_eq!(ids, [("a".to_string(), true), ("b".to_string(), false)]);
+    }
+
+    #[tokio::test]
+    async fn test_load_migrates_single_keys() {
+        let mut keys = Keys {
+            keyring: Arc::new(NullableKeyring::new(vec![KeyringItem {
+                attributes: HashMap::from([
+                    ("type".to_string(), "topic_key".to_string()),
+                    ("server".to_string(), "https://ntfy.sh".to_string()),
+                    ("topic".to_string(), "test".to_string()),
+                ]),
+                secret: b"old password".to_vec(),
+            }])),
+            keys: Default::default(),
+        };
+        keys.load().await.unwrap();
+
+        let current = keys.current("https://ntfy.sh", "test").unwrap();
+        assert_eq!(current.secret, "old password");
+        assert_eq!(current.id.len(), 8);
+    }
 }
@@ -1,31 +1,47 @@
 mod actor_utils;
+pub mod backup;
 pub mod credentials;
+mod crypto;
+pub mod dbus;
+mod forward;
+pub mod hooks;
 pub mod keys;
 mod http_client;
+pub mod import;
 mod listener;
 pub mod message_repo;
 pub mod models;
 mod ntfy;
 mod output_tracker;
 pub mod retry;
+pub mod share;
 mod subscription;
+pub mod unifiedpush;
 
 pub use listener::*;
-pub use ntfy::start;
+pub use ntfy::{start, start_idle};
 pub use ntfy::NtfyHandle;
 use std::sync::Arc;
-pub use subscription::SubscriptionHandle;
+pub use subscription::{SubscriptionChange, SubscriptionEvent, SubscriptionHandle};
 
 use http_client::HttpClient;
 
 #[derive(Clone)]
 pub struct SharedEnv {
-    db: message_repo::Db,
+    db: message_repo::DbHandle,
     notifier: Arc<dyn models::NotificationProxy>,
     http_client: HttpClient,
     network_monitor: Arc<dyn models::NetworkMonitorProxy>,
     credentials: credentials::Credentials,
     keys: keys::Keys,
+    hooks: hooks::Hooks,
+    // Events of every subscription, for the D-Bus service
+    events: tokio::sync::broadcast::Sender<SubscriptionEvent>,
+    subscription_changes: tokio::sync::broadcast::Sender<SubscriptionChange>,
+    push_events: tokio::sync::broadcast::Sender<unifiedpush::PushEvent>,
+    // Push messages waiting for the D-Bus service, which takes them from `push_queue`
+    push_deliveries: async_channel::Sender<unifiedpush::PushDelivery>,
+    push_queue: async_channel::Receiver<unifiedpush::PushDelivery>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -44,6 +60,26 @@ pub enum Error {
     InvalidMessage(String, #[source] serde_json::Error),
     #[error("database error")]
     Db(#[from] rusqlite::Error),
+    #[error("message not found")]
+    MessageNotFound,
     #[error("subscription not found while {0}")]
     SubscriptionNotFound(String),
+    #[error("no UnifiedPush registration with token {0:?}")]
+    PushRegistrationNotFound(String),
+    #[error("nothing received from the server for {0:?}")]
+    KeepaliveTimeout(std::time::Duration),
+    #[error("the server closed the stream")]
+    StreamClosed,
+    #[error("can't decrypt message: {0}")]
+    Decrypt(&'static str),
+    #[error("{0:?} is not a topic link")]
+    InvalidShareUri(String),
+    #[error("can't read or write the file")]
+    Io(#[from] std::io::Error),
+    #[error("can't write the CSV file")]
+    Csv(#[from] csv::Error),
+    #[error("invalid backup: {0}")]
+    InvalidBackup(String),
+    #[error("wrong passphrase or damaged backup")]
+    BackupPassphrase,
 }
@@ -0,0 +1,1176 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::task::{spawn_local, LocalSet};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+};
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::credentials::Credentials;
+use crate::http_client::HttpClient;
+use crate::{crypto, models, Error};
+
+mod transport;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum ServerEvent {
+    #[serde(rename = "open")]
+    Open {
+        id: String,
+        time: usize,
+        expires: Option<usize>,
+        topic: String,
+    },
+    #[serde(rename = "message")]
+    Message(models::ReceivedMessage),
+    #[serde(rename = "keepalive")]
+    KeepAlive {
+        id: String,
+        time: usize,
+        expires: Option<usize>,
+        topic: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum ListenerEvent {
+    Message(models::ReceivedMessage),
+    ConnectionStateChanged(ConnectionState),
+}
+
+#[derive(Clone)]
+pub struct ListenerConfig {
+    pub(crate) http_client: HttpClient,
+    pub(crate) credentials: Credentials,
+    pub(crate) keys: crate::keys::Keys,
+    pub(crate) endpoint: String,
+    pub(crate) settings: models::ServerSettings,
+}
+
+#[derive(Debug)]
+pub enum ListenerCommand {
+    Restart,
+    Shutdown,
+    AddTopic {
+        topic: String,
+        since: u64,
+        last_id: Option<String>,
+        filter: models::ServerFilter,
+        event_tx: async_channel::Sender<ListenerEvent>,
+    },
+    RemoveTopic {
+        topic: String,
+    },
+    UpdateTopicFilter {
+        topic: String,
+        filter: models::ServerFilter,
+    },
+    UpdateSettings(models::ServerSettings),
+}
+
+// Keepalive interval of ntfy.sh and of the default server configuration
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(45);
+// Keepalives buffered by a proxy can arrive together, don't learn a shorter interval
+const MIN_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+// Missed keepalives before the connection is considered dead
+const KEEPALIVE_TOLERANCE: u32 = 3;
+
+// Wait a bit after the set of topics changes before reopening the stream,
+// so that subscribing to many topics at startup results in a single request.
+const TOPIC_CHANGE_DEBOUNCE: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug)]
+pub enum ConnectionState {
+    Unitialized,
+    Connected,
+    // No open connection, the server is polled every `interval`
+    Polling {
+        interval: Duration,
+    },
+    Reconnecting {
+        retry_count: u64,
+        delay: Duration,
+        error: Option<Arc<anyhow::Error>>,
+    },
+}
+
+// Per-topic bookkeeping of a server listener
+struct TopicState {
+    // Time of the newest event seen for this topic, used to compute the `since` of the stream
+    since: u64,
+    // ID of the newest message seen for this topic, preferred over `since` to resume
+    last_id: Option<String>,
+    // Server-side filter of the subscription
+    filter: models::ServerFilter,
+    event_tx: async_channel::Sender<ListenerEvent>,
+}
+
+// Listens to every topic of one server over a single streaming connection
+// and forwards each message to the channel of its topic.
+pub struct ListenerActor {
+    pub commands_rx: Option<mpsc::Receiver<ListenerCommand>>,
+    pub config: ListenerConfig,
+    pub state_tx: watch::Sender<ConnectionState>,
+    topics: HashMap<String, TopicState>,
+    topics_changed: bool,
+    // Time of the message the current stream was resumed from, when resumed by ID
+    resumed_from: Option<u64>,
+    // When the last keepalive of the current stream arrived
+    last_keepalive: Option<tokio::time::Instant>,
+    // Time between the last two keepalives, when not configured for the server
+    learned_keepalive_interval: Option<Duration>,
+}
+
+impl ListenerActor {
+    pub async fn run_loop(mut self) {
+        let span = tracing::info_span!("listener_loop", endpoint = %self.config.endpoint);
+        async {
+            let mut commands_rx = self.commands_rx.take().unwrap();
+            loop {
+                select! {
+                    _ = self.run_supervised_loop() => {
+                        info!("supervised loop ended");
+                        break;
+                    },
+                    cmd = commands_rx.recv() => {
+                        match cmd {
+                            Some(ListenerCommand::Restart) => {
+                                info!("restarting listener");
+                                continue;
+                            }
+                            Some(ListenerCommand::Shutdown) => {
+                                info!("shutting down listener");
+                                break;
+                            }
+                            Some(ListenerCommand::AddTopic { topic, since, last_id, filter, event_tx }) => {
+                                info!(topic = %topic, since, last_id = ?last_id, "adding topic to listener");
+                                self.topics.insert(topic, TopicState { since, last_id, filter, event_tx });
+                                self.topics_changed = true;
+                            }
+                            Some(ListenerCommand::RemoveTopic { topic }) => {
+                                info!(topic = %topic, "removing topic from listener");
+                                if self.topics.remove(&topic).is_some() {
+                                    self.topics_changed = true;
+                                }
+                            }
+                            Some(ListenerCommand::UpdateTopicFilter { topic, filter }) => {
+                                info!(topic = %topic, filter = ?filter, "updating topic filter");
+                                if let Some(state) = self.topics.get_mut(&topic) {
+                                    state.filter = filter;
+                                    self.topics_changed = true;
+                                }
+                            }
+                            Some(ListenerCommand::UpdateSettings(settings)) => {
+                                info!(transport = ?settings.transport, "updating server settings");
+                                self.config.settings = settings;
+                            }
+                            None => {
+                                error!("command channel closed");
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+
+    async fn set_state(&mut self, state: ConnectionState) {
+        self.state_tx.send_replace(state.clone());
+        for topic in self.topics.values() {
+            let _ = topic
+                .event_tx
+                .send(ListenerEvent::ConnectionStateChanged(state.clone()))
+                .await;
+        }
+    }
+    async fn run_supervised_loop(&mut self) {
+        let span = tracing::info_span!("supervised_loop");
+        async {
+            if self.topics.is_empty() {
+                debug!("no topics to listen to, waiting");
+                futures::future::pending::<()>().await;
+            }
+            if self.topics_changed {
+                tokio::time::sleep(TOPIC_CHANGE_DEBOUNCE).await;
+                self.topics_changed = false;
+            }
+
+            let retrier = || {
+                crate::retry::WaitExponentialRandom::builder()
+                    .min(Duration::from_secs(1))
+                    .max(Duration::from_secs(5 * 60))
+                    .build()
+            };
+            let mut retry = retrier();
+            loop {
+                let start_time = std::time::Instant::now();
+                let poll_interval = self.config.settings.poll_interval();
+                let res = match poll_interval {
+                    Some(interval) => self.poll_and_forward(interval).await,
+                    // A server restarting or a proxy closing the connection ends the stream
+                    // cleanly, reconnect like after any other error
+                    None => self
+                        .recv_and_forward_loop()
+                        .await
+                        .and_then(|()| Err(crate::Error::StreamClosed.into())),
+                };
+
+                if let Err(e) = res {
+                    let uptime = std::time::Instant::now().duration_since(start_time);
+                    // Reset retry delay to minimum if uptime was decent enough
+                    if uptime > Duration::from_secs(60 * 4) {
+                        debug!("resetting retry delay due to sufficient uptime");
+                        retry = retrier();
+                    }
+                    error!(error = ?e, "connection error");
+                    self.set_state(ConnectionState::Reconnecting {
+                        retry_count: retry.count(),
+                        delay: retry.next_delay(),
+                        error: Some(Arc::new(e)),
+                    })
+                    .await;
+                    info!(delay = ?retry.next_delay(), "waiting before reconnect attempt");
+                    retry.wait().await;
+                } else if let Some(interval) = poll_interval {
+                    retry = retrier();
+                    // Network changes restart the listener, which polls right away
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+
+    // All the topics of this listener, joined the way ntfy expects them in the URL path
+    fn joined_topics(&self) -> String {
+        let mut topics: Vec<&str> = self.topics.keys().map(|t| t.as_str()).collect();
+        topics.sort_unstable();
+        topics.join(",")
+    }
+
+    // The stream has a single `since`, so ask for the oldest position of all topics.
+    // Message IDs are global to the server, so the last message of the topic that is
+    // the most behind covers every topic. If a topic has no known ID, fall back to time.
+    // Messages a topic has already seen are dropped when forwarding.
+    fn stream_since(&mut self) -> String {
+        let oldest = self.topics.values().min_by_key(|t| t.since);
+        match oldest {
+            Some(TopicState {
+                since,
+                last_id: Some(id),
+                ..
+            }) if self.topics.values().all(|t| t.last_id.is_some()) => {
+                self.resumed_from = Some(*since);
+                id.clone()
+            }
+            _ => {
+                self.resumed_from = None;
+                oldest.map(|t| t.since).unwrap_or(0).to_string()
+            }
+        }
+    }
+
+    // Topics sharing the stream may have different filters: only what they have
+    // in common is sent to the server, the rest is checked when forwarding
+    fn stream_filter(&self) -> models::ServerFilter {
+        models::ServerFilter::common(self.topics.values().map(|t| &t.filter))
+    }
+
+    // How long the stream can stay silent before it is considered dead.
+    // Half-open connections (e.g. behind a NAT) never error, they just go quiet.
+    fn silence_timeout(&self) -> Duration {
+        let interval = self
+            .config
+            .settings
+            .keepalive_interval()
+            .or(self.learned_keepalive_interval)
+            .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL);
+        interval * KEEPALIVE_TOLERANCE
+    }
+
+    async fn recv_and_forward_loop(&mut self) -> anyhow::Result<()> {
+        let topics = self.joined_topics();
+        let since = self.stream_since();
+        let filter = self.stream_filter();
+        let span = tracing::info_span!("receive_loop",
+            endpoint = %self.config.endpoint,
+            topics = %topics,
+            since = %since
+        );
+        async {
+            debug!(transport = ?self.config.settings.transport, "opening stream");
+            let mut stream = transport::for_kind(self.config.settings.transport)
+                .open(&self.config, &topics, &since, &filter)
+                .await?;
+
+            self.set_state(ConnectionState::Connected).await;
+            info!("connection established");
+
+            info!(topics = %topics, "listening");
+            self.last_keepalive = None;
+            loop {
+                let timeout = self.silence_timeout();
+                let msg = match tokio::time::timeout(timeout, stream.next()).await {
+                    Ok(Some(msg)) => msg?,
+                    Ok(None) => break,
+                    Err(_) => {
+                        warn!(?timeout, "no keepalive received, dropping the connection");
+                        return Err(Error::KeepaliveTimeout(timeout).into());
+                    }
+                };
+                self.handle_event(&msg).await?;
+            }
+
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn poll_and_forward(&mut self, interval: Duration) -> anyhow::Result<()> {
+        let topics = self.joined_topics();
+        let since = self.stream_since();
+        let filter = self.stream_filter();
+        let span = tracing::info_span!("poll",
+            endpoint = %self.config.endpoint,
+            topics = %topics,
+            since = %since
+        );
+        async {
+            debug!("polling");
+            let mut stream = transport::poll(&self.config, &topics, &since, &filter).await?;
+            while let Some(msg) = stream.next().await {
+                self.handle_event(&msg?).await?;
+            }
+            self.set_state(ConnectionState::Polling { interval }).await;
+            info!(next_poll = ?interval, "poll done");
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn handle_event(&mut self, msg: &str) -> anyhow::Result<()> {
+        serde_json::from_str::<models::MinMessage>(msg)
+            .map_err(|e| Error::InvalidMinMessage(msg.to_string(), e))?;
+
+        let event =
+            serde_json::from_str(msg).map_err(|e| Error::InvalidMessage(msg.to_string(), e))?;
+
+        match event {
+            ServerEvent::Message(mut msg) => {
+                if self.resumed_from.is_some_and(|time| msg.time < time) {
+                    // ntfy sends everything it has when it doesn't know the ID anymore
+                    warn!(id = %msg.id, "last message ID expired, falling back to time");
+                    self.resumed_from = None;
+                    for topic in self.topics.values_mut() {
+                        topic.last_id = None;
+                    }
+                }
+                let Some(topic) = self.topics.get_mut(&msg.topic) else {
+                    warn!(id = %msg.id, topic = %msg.topic, "message for unknown topic");
+                    return Ok(());
+                };
+                if msg.time < topic.since || topic.last_id.as_ref() == Some(&msg.id) {
+                    debug!(id = %msg.id, "topic already up to date, skipping message");
+                    return Ok(());
+                }
+                topic.since = msg.time;
+                topic.last_id = Some(msg.id.clone());
+                if !topic.filter.matches(&msg) {
+                    debug!(id = %msg.id, "message filtered out");
+                    return Ok(());
+                }
+                Self::try_decrypt(&self.config, &mut msg);
+                debug!(id = %msg.id, topic = %msg.topic, "forwarding message");
+                if topic.event_tx.send(ListenerEvent::Message(msg)).await.is_err() {
+                    warn!("topic receiver dropped");
+                }
+            }
+            ServerEvent::KeepAlive { id, time, .. } => {
+                debug!(id = %id, "received keepalive");
+                let now = tokio::time::Instant::now();
+                if let Some(last) = self.last_keepalive.replace(now) {
+                    self.learned_keepalive_interval = Some((now - last).max(MIN_KEEPALIVE_INTERVAL));
+                }
+                // Everything up to the keepalive has been delivered for every topic
+                for topic in self.topics.values_mut() {
+                    topic.since = topic.since.max(time as u64);
+                }
+            }
+            ServerEvent::Open { id, .. } => {
+                debug!(id = %id, "received open event");
+            }
+        }
+        Ok(())
+    }
+
+    fn try_decrypt(config: &ListenerConfig, msg: &mut models::ReceivedMessage) {
+        let keys = config.keys.list(&config.endpoint, &msg.topic);
+        let legacy = config.settings.legacy_encryption;
+        match crypto::decrypt_message(&keys, &config.endpoint, msg, legacy) {
+            models::Decryption::Plaintext => {}
+            models::Decryption::Decrypted { key_id } => {
+                debug!(topic = %msg.topic, key_id = %key_id, "decrypted message")
+            }
+            models::Decryption::Failed { reason } => {
+                warn!(topic = %msg.topic, reason = %reason, "failed to decrypt message")
+            }
+            models::Decryption::NoKey => {
+                warn!(topic = %msg.topic, "received encrypted message without a key")
+            }
+        }
+    }
+}
+
+// Reliable listener implementation, one per server
+#[derive(Clone)]
+pub struct ListenerHandle {
+    pub config: ListenerConfig,
+    pub commands: mpsc::Sender<ListenerCommand>,
+    state_rx: watch::Receiver<ConnectionState>,
+}
+
+impl std::fmt::Debug for ListenerHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ListenerHandle")
+            .field("endpoint", &self.config.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ListenerHandle {
+    pub fn new(config: ListenerConfig) -> ListenerHandle {
+        let (commands_tx, commands_rx) = mpsc::channel(1);
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Unitialized);
+
+        let config_clone = config.clone();
+
+        // use a new local set to isolate panics
+        let local_set = LocalSet::new();
+        local_set.spawn_local(async move {
+            let this = ListenerActor {
+                commands_rx: Some(commands_rx),
+                config: config_clone,
+                state_tx,
+                topics: HashMap::new(),
+                topics_changed: false,
+                resumed_from: None,
+                last_keepalive: None,
+                learned_keepalive_interval: None,
+            };
+
+            this.run_loop().await;
+        });
+        spawn_local(local_set);
+
+        Self {
+            config,
+            commands: commands_tx,
+            state_rx,
+        }
+    }
+
+    // Adds a topic to the shared stream. The returned channel receives the messages
+    // of this topic and every connection state change of the server.
+    // `last_id` is the ID of the newest message already received, if any.
+    pub async fn add_topic(
+        &self,
+        topic: &str,
+        since: u64,
+        last_id: Option<String>,
+        filter: models::ServerFilter,
+    ) -> anyhow::Result<async_channel::Receiver<ListenerEvent>> {
+        let (event_tx, event_rx) = async_channel::bounded(64);
+        self.commands
+            .send(ListenerCommand::AddTopic {
+                topic: topic.to_string(),
+                since,
+                last_id,
+                filter,
+                event_tx,
+            })
+            .await?;
+        Ok(event_rx)
+    }
+
+    // Whether the listener stopped, after a shutdown or a panic
+    pub fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    pub async fn remove_topic(&self, topic: &str) -> anyhow::Result<()> {
+        self.commands
+            .send(ListenerCommand::RemoveTopic {
+                topic: topic.to_string(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_topic_filter(
+        &self,
+        topic: &str,
+        filter: models::ServerFilter,
+    ) -> anyhow::Result<()> {
+        self.commands
+            .send(ListenerCommand::UpdateTopicFilter {
+                topic: topic.to_string(),
+                filter,
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state_rx.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use models::{Subscription, Transport};
+    use serde_json::json;
+    use tokio::task::LocalSet;
+
+    use crate::http_client::{Chunk, NullableClient};
+    use crate::keys::Keys;
+
+    use super::*;
+
+    async fn config(http_client: HttpClient) -> ListenerConfig {
+        ListenerConfig {
+            http_client,
+            credentials: Credentials::new_nullable(vec![]).await.unwrap(),
+            keys: Keys::new_nullable(HashMap::new()).unwrap(),
+            endpoint: "http://localhost".to_string(),
+            settings: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listener_reconnects_on_http_status_500() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let nullable = NullableClient::builder()
+                        .text_response(url.clone(), 500, "failed")
+                        .json_response(url, 200, json!({"id":"SLiKI64DOt","time":1635528757,"event":"open","topic":"mytopic"})).unwrap()
+                        .build();
+                    nullable
+                });
+
+                let listener = ListenerHandle::new(config(http_client).await);
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(2).collect().await;
+
+                dbg!(&items);
+                assert!(matches!(
+                    &items[..],
+                    &[
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { .. }),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected { .. }),
+                    ]
+                ));
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_reconnects_on_invalid_message() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let nullable = NullableClient::builder()
+                        .text_response(url.clone(), 200, "invalid message")
+                        .json_response(url, 200, json!({"id":"SLiKI64DOt","time":1635528757,"event":"open","topic":"mytopic"})).unwrap()
+                        .build();
+                    nullable
+                });
+
+                let listener = ListenerHandle::new(config(http_client).await);
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(3).collect().await;
+
+                dbg!(&items);
+                assert!(matches!(
+                    &items[..],
+                    &[
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected { .. }),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { .. }),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected { .. }),
+                    ]
+                ));
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_reconnects_when_stream_ends() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let open = json!({"id":"SLiKI64DOt","time":1635528757,"event":"open","topic":"test"});
+                    NullableClient::builder()
+                        .json_response(url.clone(), 200, open.clone()).unwrap()
+                        .json_response(url, 200, open).unwrap()
+                        .build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client).await);
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(3).collect().await;
+
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { error: Some(e), .. }),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                    ] if matches!(e.downcast_ref(), Some(crate::Error::StreamClosed))
+                ));
+                assert!(!listener.is_closed());
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_multiplexes_topics_of_a_server() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "a,b", 0).unwrap();
+                    let body = [
+                        json!({"id":"open","time":10,"event":"open","topic":"a,b"}),
+                        json!({"id":"m1","time":11,"event":"message","topic":"a","message":"for a"}),
+                        json!({"id":"m2","time":12,"event":"message","topic":"b","message":"for b"}),
+                    ]
+                    .map(|v| v.to_string())
+                    .join("\n");
+                    NullableClient::builder().text_response(url, 200, body).build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client.clone()).await);
+                let tracker = http_client.request_tracker().await;
+                let a = listener.add_topic("a", 0, None, Default::default()).await.unwrap();
+                let b = listener.add_topic("b", 0, None, Default::default()).await.unwrap();
+
+                let a_items: Vec<_> = a.take(2).collect().await;
+                let b_items: Vec<_> = b.take(2).collect().await;
+
+                assert!(matches!(
+                    &a_items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(msg),
+                    ] if msg.id == "m1"
+                ));
+                assert!(matches!(
+                    &b_items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(msg),
+                    ] if msg.id == "m2"
+                ));
+                // Both topics were subscribed in a single request
+                let requests = tracker.items().await;
+                assert_eq!(requests.len(), 1);
+                assert_eq!(requests[0].url, "http://localhost/a,b/json?since=0");
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_skips_messages_older_than_topic_since() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "a,b", 5).unwrap();
+                    let body = [
+                        json!({"id":"old","time":6,"event":"message","topic":"b","message":"seen"}),
+                        json!({"id":"new_a","time":7,"event":"message","topic":"a","message":"new"}),
+                        json!({"id":"new_b","time":20,"event":"message","topic":"b","message":"new"}),
+                    ]
+                    .map(|v| v.to_string())
+                    .join("\n");
+                    NullableClient::builder().text_response(url, 200, body).build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client).await);
+                let _a = listener.add_topic("a", 5, None, Default::default()).await.unwrap();
+                let b = listener.add_topic("b", 10, None, Default::default()).await.unwrap();
+
+                let b_items: Vec<_> = b.take(2).collect().await;
+                assert!(matches!(
+                    &b_items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(msg),
+                    ] if msg.id == "new_b"
+                ));
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_polls_in_poll_mode() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_poll_url("http://localhost", "test", "0", &Default::default()).unwrap();
+                    NullableClient::builder()
+                        .json_response(url, 200, json!({"id":"m1","time":11,"event":"message","topic":"test","message":"polled"})).unwrap()
+                        .build()
+                });
+
+                let mut config = config(http_client.clone()).await;
+                config.settings.poll = true;
+                let listener = ListenerHandle::new(config);
+                let tracker = http_client.request_tracker().await;
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(2).collect().await;
+
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::Message(msg),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Polling { interval }),
+                    ] if msg.id == "m1" && *interval == Duration::from_secs(15 * 60)
+                ));
+                let requests = tracker.items().await;
+                assert_eq!(requests.len(), 1);
+                assert_eq!(requests[0].url, "http://localhost/test/json?since=0&poll=1");
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_resumes_from_last_message_id() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_stream_url("http://localhost", "a,b", "b5", Transport::Ndjson, &Default::default()).unwrap();
+                    let body = [
+                        json!({"id":"a10","time":10,"event":"message","topic":"a","message":"seen"}),
+                        json!({"id":"b11","time":11,"event":"message","topic":"b","message":"new"}),
+                    ]
+                    .map(|v| v.to_string())
+                    .join("\n");
+                    NullableClient::builder().text_response(url, 200, body).build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client.clone()).await);
+                let tracker = http_client.request_tracker().await;
+                let a = listener.add_topic("a", 10, Some("a10".into()), Default::default()).await.unwrap();
+                let b = listener.add_topic("b", 5, Some("b5".into()), Default::default()).await.unwrap();
+
+                let b_items: Vec<_> = b.take(2).collect().await;
+                assert!(matches!(
+                    &b_items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(msg),
+                    ] if msg.id == "b11"
+                ));
+                // The last message of `a` was already received
+                assert!(matches!(
+                    a.recv().await,
+                    Ok(ListenerEvent::ConnectionStateChanged(ConnectionState::Connected))
+                ));
+                assert!(a.is_empty());
+                let requests = tracker.items().await;
+                assert_eq!(requests[0].url, "http://localhost/a,b/json?since=b5");
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_falls_back_to_time_when_id_expired() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let by_id = Subscription::build_stream_url("http://localhost", "test", "gone", Transport::Ndjson, &Default::default()).unwrap();
+                    // The server doesn't know the ID anymore and sends all it has
+                    let body = [
+                        json!({"id":"old","time":1,"event":"message","topic":"test","message":"old"}),
+                        json!({"id":"new","time":20,"event":"message","topic":"test","message":"new"}),
+                    ]
+                    .map(|v| v.to_string())
+                    .join("\n");
+                    let by_time = Subscription::build_url("http://localhost", "test", 20).unwrap();
+                    NullableClient::builder()
+                        .text_response(by_id, 200, body + "\ninvalid message")
+                        .json_response(by_time, 200, json!({"id":"o","time":21,"event":"open","topic":"test"})).unwrap()
+                        .build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client.clone()).await);
+                let tracker = http_client.request_tracker().await;
+                let events = listener.add_topic("test", 10, Some("gone".into()), Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(4).collect().await;
+
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(msg),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { .. }),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                    ] if msg.id == "new"
+                ));
+                let requests = tracker.items().await;
+                assert_eq!(requests[1].url, "http://localhost/test/json?since=20");
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_sends_common_filter_and_checks_the_rest() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let url = "http://localhost/a,b/json?since=0&priority=4%2C5";
+                let http_client = HttpClient::new_nullable({
+                    let body = [
+                        json!({"id":"m1","time":1,"event":"message","topic":"a","priority":4,"message":"no tag"}),
+                        json!({"id":"m2","time":2,"event":"message","topic":"a","priority":5,"tags":["prod"],"message":"tagged"}),
+                        json!({"id":"m3","time":3,"event":"message","topic":"b","priority":4,"message":"no tag"}),
+                    ]
+                    .map(|v| v.to_string())
+                    .join("\n");
+                    NullableClient::builder().text_response(url, 200, body).build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client.clone()).await);
+                let tracker = http_client.request_tracker().await;
+                let a_filter = models::ServerFilter {
+                    priority: vec![4, 5],
+                    tags: vec!["prod".into()],
+                    ..Default::default()
+                };
+                let b_filter = models::ServerFilter {
+                    priority: vec![4, 5],
+                    ..Default::default()
+                };
+                let a = listener.add_topic("a", 0, None, a_filter).await.unwrap();
+                let b = listener.add_topic("b", 0, None, b_filter).await.unwrap();
+
+                let a_items: Vec<_> = a.take(2).collect().await;
+                let b_items: Vec<_> = b.take(2).collect().await;
+                assert!(matches!(
+                    &a_items[..],
+                    [ListenerEvent::ConnectionStateChanged(_), ListenerEvent::Message(msg)] if msg.id == "m2"
+                ));
+                assert!(matches!(
+                    &b_items[..],
+                    [ListenerEvent::ConnectionStateChanged(_), ListenerEvent::Message(msg)] if msg.id == "m3"
+                ));
+                assert_eq!(tracker.items().await[0].url, url);
+            });
+        local_set.await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_listener_reconnects_when_stream_goes_quiet() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let open = json!({"id":"o","time":1,"event":"open","topic":"test"}).to_string();
+                    NullableClient::builder()
+                        .stalled_response(url.clone(), 200, open.clone() + "\n")
+                        .stalled_response(url, 200, open + "\n")
+                        .build()
+                });
+
+                let mut config = config(http_client).await;
+                config.settings.keepalive_interval = Some(10);
+                let listener = ListenerHandle::new(config);
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let start = tokio::time::Instant::now();
+                let items: Vec<_> = events.take(3).collect().await;
+
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { error: Some(e), .. }),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                    ] if matches!(e.downcast_ref(), Some(Error::KeepaliveTimeout(t)) if *t == Duration::from_secs(30))
+                ));
+                assert!(start.elapsed() >= Duration::from_secs(30));
+            });
+        local_set.await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_listener_learns_keepalive_interval() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let keepalive = json!({"id":"k","time":1,"event":"keepalive","topic":"test"}).to_string() + "\n";
+                    NullableClient::builder()
+                        .scripted_response(url, 200, vec![
+                            Chunk::Data(keepalive.clone()),
+                            Chunk::Delay(Duration::from_secs(20)),
+                            Chunk::Data(keepalive),
+                            Chunk::Stall,
+                        ])
+                        .build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client).await);
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(2).collect().await;
+
+                // The stream is dropped after 3 times the learned interval instead of the default one
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { error: Some(e), .. }),
+                    ] if matches!(e.downcast_ref(), Some(Error::KeepaliveTimeout(t)) if *t == Duration::from_secs(20) * KEEPALIVE_TOLERANCE)
+                ));
+            });
+        local_set.await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_listener_assembles_lines_split_across_chunks() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let msg = json!({"id":"m1","time":1,"event":"message","topic":"test","message":"split"}).to_string() + "\n";
+                    let (head, tail) = msg.split_at(10);
+                    NullableClient::builder()
+                        .scripted_response(url, 200, vec![
+                            Chunk::Data(head.to_string()),
+                            Chunk::Delay(Duration::from_secs(1)),
+                            Chunk::Data(tail.to_string()),
+                            Chunk::Stall,
+                        ])
+                        .build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client).await);
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(2).collect().await;
+
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(msg),
+                    ] if msg.id == "m1" && msg.message.as_deref() == Some("split")
+                ));
+            });
+        local_set.await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_listener_resumes_after_keepalive_on_disconnect() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let keepalive = json!({"id":"k","time":50,"event":"keepalive","topic":"test"}).to_string();
+                    let resumed = Subscription::build_url("http://localhost", "test", 50).unwrap();
+                    NullableClient::builder()
+                        .scripted_response(url, 200, vec![
+                            Chunk::Data(keepalive + "\n"),
+                            Chunk::Delay(Duration::from_secs(5)),
+                            Chunk::Error("connection reset by peer".into()),
+                        ])
+                        .stalled_response(resumed, 200, "")
+                        .build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client.clone()).await);
+                let tracker = http_client.request_tracker().await;
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(3).collect().await;
+
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { .. }),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                    ]
+                ));
+                // Nothing was missed up to the keepalive
+                assert_eq!(tracker.items().await[1].url, "http://localhost/test/json?since=50");
+            });
+        local_set.await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_listener_drops_partial_line_on_disconnect() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let m1 = json!({"id":"m1","time":10,"event":"message","topic":"test","message":"one"}).to_string();
+                    let m2 = json!({"id":"m2","time":11,"event":"message","topic":"test","message":"two"}).to_string();
+                    let resumed = Subscription::build_stream_url("http://localhost", "test", "m1", Transport::Ndjson, &Default::default()).unwrap();
+                    NullableClient::builder()
+                        .scripted_response(url, 200, vec![
+                            Chunk::Data(m1 + "\n"),
+                            Chunk::Data(m2[..15].to_string()),
+                            Chunk::Error("connection reset by peer".into()),
+                        ])
+                        .stalled_response(resumed, 200, m2 + "\n")
+                        .build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client.clone()).await);
+                let tracker = http_client.request_tracker().await;
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(5).collect().await;
+
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(m1),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { .. }),
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(m2),
+                    ] if m1.id == "m1" && m2.id == "m2"
+                ));
+                assert_eq!(tracker.items().await[1].url, "http://localhost/test/json?since=m1");
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_forwards_messages_as_they_arrive() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "a,b", 0).unwrap();
+                    NullableClient::builder().channel_response(url, 200, rx).build()
+                });
+
+                let listener = ListenerHandle::new(config(http_client).await);
+                let a = listener.add_topic("a", 0, None, Default::default()).await.unwrap();
+                let b = listener.add_topic("b", 0, None, Default::default()).await.unwrap();
+                assert!(matches!(a.recv().await, Ok(ListenerEvent::ConnectionStateChanged(ConnectionState::Connected))));
+                assert!(matches!(b.recv().await, Ok(ListenerEvent::ConnectionStateChanged(ConnectionState::Connected))));
+
+                let send = |id: &str, time: u64, topic: &str| {
+                    let msg = json!({"id":id,"time":time,"event":"message","topic":topic,"message":"hi"});
+                    tx.send(Chunk::Data(msg.to_string() + "\n")).unwrap();
+                };
+                send("m1", 1, "b");
+                assert!(matches!(b.recv().await, Ok(ListenerEvent::Message(msg)) if msg.id == "m1"));
+                send("m2", 2, "a");
+                assert!(matches!(a.recv().await, Ok(ListenerEvent::Message(msg)) if msg.id == "m2"));
+                send("m3", 3, "b");
+                assert!(matches!(b.recv().await, Ok(ListenerEvent::Message(msg)) if msg.id == "m3"));
+                assert!(a.is_empty());
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_decrypts_jwe_messages() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let mut out = models::OutgoingMessage {
+                    topic: "test".to_string(),
+                    message: Some("secret".to_string()),
+                    ..Default::default()
+                };
+                let keys = Keys::new_nullable(HashMap::from([(
+                    ("http://localhost".to_string(), "test".to_string()),
+                    "pw".to_string(),
+                )]))
+                .unwrap();
+                let key = keys.current("http://localhost", "test").unwrap();
+                crypto::encrypt_message(&key, "http://localhost", &mut out, models::Encryption::Message);
+                let http_client = HttpClient::new_nullable({
+                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let body = json!({
+                        "id":"m1","time":1,"event":"message","topic":"test",
+                        "message": out.message,
+                        "encoding": out.encoding,
+                    });
+                    NullableClient::builder().text_response(url, 200, body.to_string()).build()
+                });
+
+                let mut config = config(http_client).await;
+                config.keys = keys;
+                let listener = ListenerHandle::new(config);
+                let events = listener.add_topic("test", 0, None, Default::default()).await.unwrap();
+                let items: Vec<_> = events.take(2).collect().await;
+
+                assert!(matches!(
+                    &items[..],
+                    [
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(msg),
+                    ] if msg.message.as_deref() == Some("secret")
+                        && matches!(msg.decryption, models::Decryption::Decrypted { .. })
+                ));
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_legacy_decryption_is_behind_a_flag() {
+        let mut config = config(HttpClient::new_nullable(NullableClient::builder().build())).await;
+        config.keys = Keys::new_nullable(HashMap::from([(
+            ("http://localhost".to_string(), "test".to_string()),
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=".to_string(),
+        )]))
+        .unwrap();
+        let legacy = models::ReceivedMessage {
+            topic: "test".to_string(),
+            message: Some("AQcHBwcHBwcHBwcHB2MPwT0Mdveco/F/707+Q7XJVXLJ2rNd2TZpDW4=".to_string()),
+            ..Default::default()
+        };
+
+        let mut msg = legacy.clone();
+        ListenerActor::try_decrypt(&config, &mut msg);
+        assert_eq!(msg.message, legacy.message);
+
+        config.settings.legacy_encryption = true;
+        let mut msg = legacy.clone();
+        ListenerActor::try_decrypt(&config, &mut msg);
+        assert_eq!(msg.message.as_deref(), Some("legacy hello"));
+    }
+}
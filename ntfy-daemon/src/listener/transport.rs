@@ -0,0 +1,318 @@
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use futures::{Stream, StreamExt, TryStreamExt};
+use tokio::io::AsyncBufReadExt;
+use tokio_stream::wrappers::LinesStream;
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+use tracing::debug;
+
+use super::ListenerConfig;
+use crate::models::{self, ServerFilter, Transport};
+
+// Every item is the JSON of one ntfy event (open, keepalive, message...)
+pub type EventStream = Pin<Box<dyn Stream<Item = anyhow::Result<String>>>>;
+
+#[async_trait(?Send)]
+pub trait EventTransport {
+    // `topics` is a comma separated list of topics of `config.endpoint`
+    async fn open(
+        &self,
+        config: &ListenerConfig,
+        topics: &str,
+        since: &str,
+        filter: &ServerFilter,
+    ) -> anyhow::Result<EventStream>;
+}
+
+pub fn for_kind(transport: Transport) -> Box<dyn EventTransport> {
+    match transport {
+        Transport::Ndjson => Box::new(Ndjson),
+        Transport::WebSocket => Box::new(WebSocket),
+        Transport::Sse => Box::new(Sse),
+    }
+}
+
+fn http_request(
+    config: &ListenerConfig,
+    topics: &str,
+    since: &str,
+    transport: Transport,
+    filter: &ServerFilter,
+) -> anyhow::Result<reqwest::Request> {
+    let url =
+        models::Subscription::build_stream_url(&config.endpoint, topics, since, transport, filter)?;
+    authenticated_get(config, url)
+}
+
+fn authenticated_get(config: &ListenerConfig, url: url::Url) -> anyhow::Result<reqwest::Request> {
+    let mut req = config.http_client.get(url.as_str());
+    if let Some(creds) = config.credentials.get(&config.endpoint) {
+        req = req.basic_auth(creds.username, Some(creds.password));
+    }
+    Ok(req.build()?)
+}
+
+async fn response_lines(
+    config: &ListenerConfig,
+    req: reqwest::Request,
+) -> anyhow::Result<impl Stream<Item = Result<String, std::io::Error>>> {
+    let res = config.http_client.execute(req).await?;
+    let res = res.error_for_status()?;
+    let reader = tokio_util::io::StreamReader::new(
+        res.bytes_stream()
+            .map_err(|e| std::io::Error::other(e.to_string())),
+    );
+    Ok(LinesStream::new(reader.lines()))
+}
+
+// `/<topic>/json?poll=1`: the messages since `since`, then the response ends
+pub async fn poll(
+    config: &ListenerConfig,
+    topics: &str,
+    since: &str,
+    filter: &ServerFilter,
+) -> anyhow::Result<EventStream> {
+    let url = models::Subscription::build_poll_url(&config.endpoint, topics, since, filter)?;
+    let lines = response_lines(config, authenticated_get(config, url)?).await?;
+    Ok(Box::pin(lines.map_err(anyhow::Error::from)))
+}
+
+// `/<topic>/json`: one JSON event per line of a chunked response
+pub struct Ndjson;
+
+#[async_trait(?Send)]
+impl EventTransport for Ndjson {
+    async fn open(
+        &self,
+        config: &ListenerConfig,
+        topics: &str,
+        since: &str,
+        filter: &ServerFilter,
+    ) -> anyhow::Result<EventStream> {
+        let mut req = http_request(config, topics, since, Transport::Ndjson, filter)?;
+        req.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/x-ndjson"),
+        );
+        req.headers_mut().insert(
+            http::header::TRANSFER_ENCODING,
+            http::HeaderValue::from_static("chunked"),
+        );
+        let lines = response_lines(config, req).await?;
+        Ok(Box::pin(lines.map_err(anyhow::Error::from)))
+    }
+}
+
+// `/<topic>/sse`: the JSON event is in the `data` field of each server-sent event
+pub struct Sse;
+
+#[async_trait(?Send)]
+impl EventTransport for Sse {
+    async fn open(
+        &self,
+        config: &ListenerConfig,
+        topics: &str,
+        since: &str,
+        filter: &ServerFilter,
+    ) -> anyhow::Result<EventStream> {
+        let mut req = http_request(config, topics, since, Transport::Sse, filter)?;
+        req.headers_mut().insert(
+            http::header::ACCEPT,
+            http::HeaderValue::from_static("text/event-stream"),
+        );
+        let lines = response_lines(config, req).await?;
+        let events = lines
+            .map_err(anyhow::Error::from)
+            .scan(String::new(), |data, line| {
+                let out = match line {
+                    Err(e) => Some(Err(e)),
+                    // A blank line terminates the event
+                    Ok(line) if line.is_empty() => {
+                        (!data.is_empty()).then(|| Ok(std::mem::take(data)))
+                    }
+                    Ok(line) => {
+                        if let Some(value) = line.strip_prefix("data:") {
+                            if !data.is_empty() {
+                                data.push('\n');
+                            }
+                            data.push_str(value.strip_prefix(' ').unwrap_or(value));
+                        }
+                        // `event:`, `id:` and `:` comments are redundant with the JSON payload
+                        None
+                    }
+                };
+                futures::future::ready(Some(out))
+            })
+            .filter_map(futures::future::ready);
+        Ok(Box::pin(events))
+    }
+}
+
+// `/<topic>/ws`: one JSON event per text frame
+pub struct WebSocket;
+
+#[async_trait(?Send)]
+impl EventTransport for WebSocket {
+    async fn open(
+        &self,
+        config: &ListenerConfig,
+        topics: &str,
+        since: &str,
+        filter: &ServerFilter,
+    ) -> anyhow::Result<EventStream> {
+        let url = models::Subscription::build_stream_url(
+            &config.endpoint,
+            topics,
+            since,
+            Transport::WebSocket,
+            filter,
+        )?;
+        let mut req = url.as_str().into_client_request()?;
+        if let Some(creds) = config.credentials.get(&config.endpoint) {
+            let token = general_purpose::STANDARD
+                .encode(format!("{}:{}", creds.username, creds.password));
+            req.headers_mut()
+                .insert(http::header::AUTHORIZATION, format!("Basic {token}").parse()?);
+        }
+
+        let (socket, res) = tokio_tungstenite::connect_async(req).await?;
+        debug!(status = %res.status(), "websocket connected");
+
+        let events = socket
+            .map_err(anyhow::Error::from)
+            .try_filter_map(|msg| {
+                futures::future::ready(match msg {
+                    tungstenite::Message::Text(text) => Ok(Some(text.to_string())),
+                    tungstenite::Message::Binary(bytes) => String::from_utf8(bytes.to_vec())
+                        .map(Some)
+                        .map_err(anyhow::Error::from),
+                    // Pings are answered by tungstenite itself
+                    _ => Ok(None),
+                })
+            });
+        Ok(Box::pin(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures::SinkExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+
+    use super::*;
+    use crate::credentials::Credentials;
+    use crate::http_client::HttpClient;
+    use crate::keys::Keys;
+
+    const OPEN: &str = r#"{"id":"o","time":1,"event":"open","topic":"a,b"}"#;
+    const MESSAGE: &str = r#"{"id":"m","time":2,"event":"message","topic":"a","message":"hi"}"#;
+
+    async fn config(endpoint: String) -> ListenerConfig {
+        ListenerConfig {
+            http_client: HttpClient::new(crate::ntfy::build_client().unwrap()),
+            credentials: Credentials::new_nullable(vec![]).await.unwrap(),
+            keys: Keys::new_nullable(HashMap::new()).unwrap(),
+            endpoint,
+            settings: Default::default(),
+        }
+    }
+
+    // Stand-in ntfy server answering a single HTTP request with `body`.
+    // Returns the endpoint and a receiver for the request line.
+    async fn serve_http(
+        content_type: &'static str,
+        body: String,
+    ) -> (String, tokio::sync::oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                head.extend_from_slice(&buf[..n]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            let _ = tx.send(head.lines().next().unwrap().to_string());
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nConnection: close\r\n\r\n{body}"
+            );
+            socket.write_all(res.as_bytes()).await.unwrap();
+        });
+        (endpoint, rx)
+    }
+
+    async fn collect(stream: EventStream) -> Vec<String> {
+        stream.map(|item| item.unwrap()).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_transport() {
+        let (endpoint, request) =
+            serve_http("application/x-ndjson", format!("{OPEN}\n{MESSAGE}\n")).await;
+        let stream = Ndjson
+            .open(&config(endpoint).await, "a,b", "0", &ServerFilter::default())
+            .await
+            .unwrap();
+
+        assert_eq!(collect(stream).await, vec![OPEN, MESSAGE]);
+        assert_eq!(request.await.unwrap(), "GET /a,b/json?since=0 HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn test_sse_transport() {
+        let body = format!(
+            ": comment\n\nevent: open\ndata: {OPEN}\n\nid: m\nevent: message\ndata: {MESSAGE}\n\n"
+        );
+        let (endpoint, request) = serve_http("text/event-stream", body).await;
+        let stream = Sse
+            .open(&config(endpoint).await, "a,b", "5", &ServerFilter::default())
+            .await
+            .unwrap();
+
+        assert_eq!(collect(stream).await, vec![OPEN, MESSAGE]);
+        assert_eq!(request.await.unwrap(), "GET /a,b/sse?since=5 HTTP/1.1");
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)] // the handshake callback signature is set by tungstenite
+    async fn test_websocket_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, request) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let callback = |req: &Request, res: Response| -> Result<Response, ErrorResponse> {
+                let _ = tx.send(req.uri().to_string());
+                Ok(res)
+            };
+            let mut ws = tokio_tungstenite::accept_hdr_async(socket, callback)
+                .await
+                .unwrap();
+            ws.send(tungstenite::Message::text(OPEN)).await.unwrap();
+            ws.send(tungstenite::Message::Ping(Default::default()))
+                .await
+                .unwrap();
+            ws.send(tungstenite::Message::text(MESSAGE)).await.unwrap();
+            ws.close(None).await.unwrap();
+            // Wait for the client to acknowledge the close
+            while ws.next().await.is_some() {}
+        });
+
+        let stream = WebSocket
+            .open(&config(endpoint).await, "a,b", "0", &ServerFilter::default())
+            .await
+            .unwrap();
+
+        assert_eq!(collect(stream).await, vec![OPEN, MESSAGE]);
+        assert_eq!(request.await.unwrap(), "/a,b/ws?since=0");
+    }
+}
@@ -0,0 +1,330 @@
+use std::io::{BufRead, Write};
+
+use rusqlite::params;
+
+use super::Db;
+use crate::models::{self, HistoryEntry, HistoryFormat, ReceivedMessage};
+use crate::Error;
+
+const CSV_HEADER: [&str; 10] = [
+    "server",
+    "topic",
+    "id",
+    "time",
+    "priority",
+    "title",
+    "message",
+    "tags",
+    "click",
+    "attachment",
+];
+
+impl Db {
+    // The messages of each selected topic oldest first. Deleted messages are left out.
+    pub fn list_history_entries(
+        &mut self,
+        selection: &models::HistorySelection,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        let topics = if selection.topics.is_empty() {
+            self.list_subscriptions()?
+                .into_iter()
+                .map(|sub| (sub.server, sub.topic))
+                .collect()
+        } else {
+            selection.topics.clone()
+        };
+        let range = models::MessageRange::between(selection.since, selection.until);
+
+        let mut entries = vec![];
+        for (server, topic) in topics {
+            for data in self.list_messages(&server, &topic, &range)? {
+                let message =
+                    serde_json::from_str(&data).map_err(|e| Error::InvalidMessage(data, e))?;
+                entries.push(HistoryEntry {
+                    server: Some(server.clone()),
+                    message,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    // Adds each message to the subscription with the same server and topic. Messages without a
+    // server go to the only subscription with their topic, and are skipped when several servers
+    // have one. Messages that are already stored are recognized by their ID.
+    pub fn import_history(
+        &mut self,
+        entries: Vec<HistoryEntry>,
+    ) -> Result<models::ImportReport, Error> {
+        let tx = self.conn.transaction()?;
+        let mut report = models::ImportReport::default();
+        {
+            let mut subscribed = tx.prepare(
+                "SELECT server.id
+                    FROM subscription
+                    JOIN server ON server.id = subscription.server
+                    WHERE (?1 IS NULL OR server.endpoint = ?1) AND subscription.topic = ?2",
+            )?;
+            let mut insert = tx.prepare(
+                "INSERT INTO message (server, data) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+            )?;
+            for entry in entries {
+                let servers = subscribed
+                    .query_map(params![entry.server, entry.message.topic], |row| {
+                        row.get::<_, i64>(0)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let [server_id] = servers[..] else {
+                    report.skipped += 1;
+                    continue;
+                };
+                let data = serde_json::to_string(&entry.message)
+                    .map_err(|e| Error::InvalidMessage(entry.message.id.clone(), e))?;
+                if insert.execute(params![server_id, data])? == 0 {
+                    report.duplicates += 1;
+                } else {
+                    report.imported += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(report)
+    }
+}
+
+// Writes the entries in `format`, returning how many were written
+pub fn write_history(
+    entries: &[HistoryEntry],
+    format: HistoryFormat,
+    out: impl Write,
+) -> Result<usize, Error> {
+    match format {
+        HistoryFormat::JsonLines => {
+            let mut out = out;
+            for entry in entries {
+                serde_json::to_writer(&mut out, entry).map_err(std::io::Error::from)?;
+                out.write_all(b"\n")?;
+            }
+            out.flush()?;
+        }
+        HistoryFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(CSV_HEADER)?;
+            for entry in entries {
+                writer.write_record(csv_record(
+                    entry.server.as_deref().unwrap_or_default(),
+                    &entry.message,
+                ))?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(entries.len())
+}
+
+// Reads a JSON Lines export
+pub fn read_history(input: impl BufRead) -> Result<Vec<HistoryEntry>, Error> {
+    let mut entries = vec![];
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| Error::InvalidMessage(line, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn csv_record(server: &str, msg: &ReceivedMessage) -> [String; 10] {
+    let time = chrono::DateTime::from_timestamp(msg.time as i64, 0)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default();
+    [
+        server.to_string(),
+        msg.topic.clone(),
+        msg.id.clone(),
+        time,
+        msg.priority.map(|p| p.to_string()).unwrap_or_default(),
+        msg.title.clone().unwrap_or_default(),
+        msg.message.clone().unwrap_or_default(),
+        msg.tags.join(","),
+        msg.click.clone().unwrap_or_default(),
+        msg.attachment
+            .as_ref()
+            .map(|a| a.url.to_string())
+            .unwrap_or_default(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(db: &mut Db, server: &str, topic: &str, id: &str, time: u64) {
+        db.insert_message(
+            server,
+            &format!(r#"{{"id":"{id}","topic":"{topic}","time":{time},"message":"msg, {id}"}}"#),
+        )
+        .unwrap();
+    }
+
+    fn export(
+        db: &mut Db,
+        selection: &models::HistorySelection,
+        format: HistoryFormat,
+    ) -> (usize, Vec<u8>) {
+        let entries = db.list_history_entries(selection).unwrap();
+        let mut out = vec![];
+        let count = write_history(&entries, format, &mut out).unwrap();
+        (count, out)
+    }
+
+    #[test]
+    fn test_export_import_history() {
+        let server = models::DEFAULT_SERVER;
+        let mut db = Db::connect(":memory:").unwrap();
+        for topic in ["alerts", "builds"] {
+            db.insert_subscription(
+                models::Subscription::builder(topic.to_string())
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        }
+        insert(&mut db, server, "alerts", "a1", 10);
+        insert(&mut db, server, "alerts", "a2", 20);
+        insert(&mut db, server, "alerts", "a3", 30);
+        insert(&mut db, server, "builds", "b1", 20);
+        db.delete_message(server, "a3").unwrap();
+
+        let selection = models::HistorySelection {
+            topics: vec![(server.to_string(), "alerts".to_string())],
+            since: Some(15),
+            until: None,
+        };
+        let (count, out) = export(&mut db, &selection, HistoryFormat::JsonLines);
+        assert_eq!(count, 1);
+        // Each line is a message with the server next to its fields
+        let lines = String::from_utf8(out).unwrap();
+        let msg: ReceivedMessage = serde_json::from_str(lines.trim_end()).unwrap();
+        assert_eq!(msg.id, "a2");
+        let line: serde_json::Value = serde_json::from_str(lines.trim_end()).unwrap();
+        assert_eq!(line["server"], server);
+
+        let (count, out) = export(&mut db, &Default::default(), HistoryFormat::Csv);
+        assert_eq!(count, 3);
+        let csv = String::from_utf8(out).unwrap();
+        let mut rows = csv.lines();
+        assert_eq!(rows.next().unwrap(), CSV_HEADER.join(","));
+        assert_eq!(
+            rows.next().unwrap(),
+            format!("{server},alerts,a1,1970-01-01T00:00:10+00:00,,,\"msg, a1\",,,")
+        );
+
+        // Export everything, then import it into a database that has only some of it
+        let (_, out) = export(&mut db, &Default::default(), HistoryFormat::JsonLines);
+        let mut other = Db::connect(":memory:").unwrap();
+        other
+            .insert_subscription(
+                models::Subscription::builder("alerts".to_string())
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        insert(&mut other, server, "alerts", "a1", 10);
+        let report = other
+            .import_history(read_history(&out[..]).unwrap())
+            .unwrap();
+        assert_eq!(
+            report,
+            models::ImportReport {
+                imported: 1,
+                duplicates: 1,
+                skipped: 1,
+            }
+        );
+        assert_eq!(
+            other
+                .list_messages(server, "alerts", &Default::default())
+                .unwrap()
+                .len(),
+            2
+        );
+
+        assert!(read_history(&b"not json\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_import_history_keeps_the_server_of_each_message() {
+        let other_server = "https://ntfy.example.com";
+        let mut db = Db::connect(":memory:").unwrap();
+        for server in [models::DEFAULT_SERVER, other_server] {
+            db.insert_subscription(
+                models::Subscription::builder("alerts".to_string())
+                    .server(server.to_string())
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        }
+        insert(&mut db, models::DEFAULT_SERVER, "alerts", "a1", 10);
+        insert(&mut db, other_server, "alerts", "e1", 20);
+        let (_, out) = export(&mut db, &Default::default(), HistoryFormat::JsonLines);
+
+        // Only the other server's topic is subscribed here
+        let mut other = Db::connect(":memory:").unwrap();
+        other
+            .insert_subscription(
+                models::Subscription::builder("alerts".to_string())
+                    .server(other_server.to_string())
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        let report = other
+            .import_history(read_history(&out[..]).unwrap())
+            .unwrap();
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.skipped, 1);
+        let stored = other
+            .list_messages(other_server, "alerts", &Default::default())
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert!(stored[0].contains("\"e1\""));
+    }
+
+    #[test]
+    fn test_import_history_without_server() {
+        let other_server = "https://ntfy.example.com";
+        let mut db = Db::connect(":memory:").unwrap();
+        for (server, topic) in [
+            (models::DEFAULT_SERVER, "alerts"),
+            (models::DEFAULT_SERVER, "builds"),
+            (other_server, "builds"),
+        ] {
+            db.insert_subscription(
+                models::Subscription::builder(topic.to_string())
+                    .server(server.to_string())
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        }
+
+        // As returned by ntfy's /json?poll=1
+        let input = br#"{"id":"a1","time":10,"expires":100,"event":"message","topic":"alerts","message":"hi"}
+{"id":"b1","time":20,"expires":100,"event":"message","topic":"builds","message":"which server?"}
+"#;
+        let report = db
+            .import_history(read_history(&input[..]).unwrap())
+            .unwrap();
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.skipped, 1);
+        let stored = db
+            .list_messages(models::DEFAULT_SERVER, "alerts", &Default::default())
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert!(stored[0].contains("\"a1\""));
+    }
+}
@@ -0,0 +1 @@
+ALTER TABLE server ADD COLUMN settings TEXT;
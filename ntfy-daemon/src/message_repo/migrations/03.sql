@@ -0,0 +1 @@
+ALTER TABLE subscription ADD COLUMN last_message_id TEXT;
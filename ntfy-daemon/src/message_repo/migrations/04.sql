@@ -0,0 +1 @@
+ALTER TABLE subscription ADD COLUMN server_filter TEXT;
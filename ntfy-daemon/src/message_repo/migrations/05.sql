@@ -0,0 +1,42 @@
+-- Full-text index over the searchable fields of the messages, kept in sync by triggers.
+-- Rows share the rowid of their message.
+CREATE VIRTUAL TABLE IF NOT EXISTS message_fts USING fts5 (
+  title,
+  message,
+  tags,
+  tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER IF NOT EXISTS message_fts_insert AFTER INSERT ON message BEGIN
+  INSERT INTO message_fts (rowid, title, message, tags)
+  VALUES (
+    new.rowid,
+    new.data ->> '$.title',
+    new.data ->> '$.message',
+    (SELECT group_concat(value, ' ') FROM json_each(new.data, '$.tags'))
+  );
+END;
+
+CREATE TRIGGER IF NOT EXISTS message_fts_delete AFTER DELETE ON message BEGIN
+  DELETE FROM message_fts WHERE rowid = old.rowid;
+END;
+
+-- Decrypting a stored message makes its text searchable
+CREATE TRIGGER IF NOT EXISTS message_fts_update AFTER UPDATE OF data ON message BEGIN
+  DELETE FROM message_fts WHERE rowid = old.rowid;
+  INSERT INTO message_fts (rowid, title, message, tags)
+  VALUES (
+    new.rowid,
+    new.data ->> '$.title',
+    new.data ->> '$.message',
+    (SELECT group_concat(value, ' ') FROM json_each(new.data, '$.tags'))
+  );
+END;
+
+INSERT INTO message_fts (rowid, title, message, tags)
+SELECT
+  rowid,
+  data ->> '$.title',
+  data ->> '$.message',
+  (SELECT group_concat(value, ' ') FROM json_each(data, '$.tags'))
+FROM message;
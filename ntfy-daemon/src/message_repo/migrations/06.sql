@@ -0,0 +1,6 @@
+ALTER TABLE subscription ADD COLUMN retention TEXT;
+
+CREATE TABLE IF NOT EXISTS setting (
+  key TEXT PRIMARY KEY,
+  value TEXT
+);
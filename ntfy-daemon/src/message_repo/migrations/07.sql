@@ -0,0 +1,15 @@
+-- Flags set locally on single messages. A NULL `read` falls back to the `read_until`
+-- of the subscription. Deleted messages stay in the message table so that the server
+-- can't deliver them again.
+CREATE TABLE IF NOT EXISTS message_state (
+  server INTEGER NOT NULL REFERENCES server(id),
+  id TEXT NOT NULL,
+  read INTEGER,
+  starred INTEGER NOT NULL DEFAULT 0,
+  deleted INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (server, id)
+);
+
+CREATE TRIGGER IF NOT EXISTS message_state_delete AFTER DELETE ON message BEGIN
+  DELETE FROM message_state WHERE server = old.server AND id = old.data ->> '$.id';
+END;
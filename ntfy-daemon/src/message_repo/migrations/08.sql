@@ -0,0 +1,4 @@
+-- Pages of a topic are read newest first by (time, id), index them in that order so that
+-- loading one doesn't scan every stored message
+CREATE INDEX IF NOT EXISTS message_by_topic_and_time
+  ON message (server, topic, data ->> '$.time', data ->> '$.id');
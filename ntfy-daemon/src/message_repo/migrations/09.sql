@@ -0,0 +1,12 @@
+-- Apps registered through UnifiedPush, each with a topic of its own. Their messages are
+-- handed to the app and not stored, the last one only tells where to resume the stream.
+CREATE TABLE IF NOT EXISTS push_registration (
+  token TEXT PRIMARY KEY,
+  service TEXT NOT NULL,
+  description TEXT NOT NULL DEFAULT '',
+  server INTEGER NOT NULL REFERENCES server(id),
+  topic TEXT NOT NULL,
+  last_message_id TEXT,
+  last_message_time INTEGER NOT NULL DEFAULT 0,
+  UNIQUE (server, topic)
+);
@@ -0,0 +1,13 @@
+-- Log of the commands run by the filter rules of a subscription, only the latest runs are kept
+CREATE TABLE IF NOT EXISTS hook_run (
+  id INTEGER PRIMARY KEY,
+  server INTEGER NOT NULL REFERENCES server(id),
+  topic TEXT NOT NULL,
+  rule TEXT NOT NULL,
+  message_id TEXT NOT NULL,
+  started_at INTEGER NOT NULL,
+  duration_ms INTEGER NOT NULL,
+  exit_code INTEGER,
+  error TEXT
+);
+CREATE INDEX IF NOT EXISTS hook_run_topic ON hook_run (server, topic, id);
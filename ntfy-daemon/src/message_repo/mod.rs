@@ -1,4 +1,4 @@
-use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
 
 use rusqlite::{params, Connection, Result};
 use tracing::info;
@@ -6,27 +6,44 @@ use tracing::info;
 use crate::models;
 use crate::Error;
 
-#[derive(Clone, Debug)]
+mod history;
+mod worker;
+
+pub use history::{read_history, write_history};
+pub use worker::DbHandle;
+
+// Stored data of the message `m` with its local state, if any, under `$.state`
+const MESSAGE_DATA: &str = "
+    CASE WHEN st.id IS NULL THEN m.data ELSE json_set(m.data, '$.state', json_object(
+        'read', json(CASE st.read WHEN 1 THEN 'true' WHEN 0 THEN 'false' ELSE 'null' END),
+        'starred', json(CASE WHEN st.starred THEN 'true' ELSE 'false' END)
+    )) END";
+const MESSAGE_STATE_JOIN: &str =
+    "LEFT JOIN message_state st ON st.server = m.server AND st.id = m.data ->> '$.id'";
+// Command runs kept in the log of each subscription
+const HOOK_LOG_SIZE: u32 = 50;
+
+#[derive(Debug)]
 pub struct Db {
-    conn: Arc<RwLock<Connection>>,
+    conn: Connection,
 }
 
 impl Db {
     pub fn connect(path: &str) -> Result<Self> {
         let mut this = Self {
-            conn: Arc::new(RwLock::new(Connection::open(path)?)),
+            conn: Connection::open(path)?,
         };
-        {
-            this.conn.read().unwrap().execute_batch(
-                "PRAGMA foreign_keys = ON;
+        // The vacuum mode only applies to new databases, see `reclaim_space` for older ones
+        this.conn.execute_batch(
+            "PRAGMA auto_vacuum = INCREMENTAL;
+        PRAGMA foreign_keys = ON;
         PRAGMA journal_mode = wal;",
-            )?;
-        }
+        )?;
         this.migrate()?;
         Ok(this)
     }
     fn migrate(&mut self) -> Result<()> {
-        let conn = self.conn.write().unwrap();
+        let conn = &self.conn;
         let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
         
         if version < 1 {
@@ -37,10 +54,46 @@ impl Db {
             conn.execute_batch(include_str!("./migrations/01.sql"))?;
             conn.pragma_update(None, "user_version", 2)?;
         }
+        if version < 3 {
+            conn.execute_batch(include_str!("./migrations/02.sql"))?;
+            conn.pragma_update(None, "user_version", 3)?;
+        }
+        if version < 4 {
+            conn.execute_batch(include_str!("./migrations/03.sql"))?;
+            conn.pragma_update(None, "user_version", 4)?;
+        }
+        if version < 5 {
+            conn.execute_batch(include_str!("./migrations/04.sql"))?;
+            conn.pragma_update(None, "user_version", 5)?;
+        }
+        if version < 6 {
+            conn.execute_batch(include_str!("./migrations/05.sql"))?;
+            conn.pragma_update(None, "user_version", 6)?;
+        }
+        if version < 7 {
+            conn.execute_batch(include_str!("./migrations/06.sql"))?;
+            conn.pragma_update(None, "user_version", 7)?;
+        }
+        if version < 8 {
+            conn.execute_batch(include_str!("./migrations/07.sql"))?;
+            conn.pragma_update(None, "user_version", 8)?;
+        }
+        if version < 9 {
+            conn.execute_batch(include_str!("./migrations/08.sql"))?;
+            conn.pragma_update(None, "user_version", 9)?;
+        }
+        if version < 10 {
+            conn.execute_batch(include_str!("./migrations/09.sql"))?;
+            conn.pragma_update(None, "user_version", 10)?;
+        }
+        if version < 11 {
+            conn.execute_batch(include_str!("./migrations/10.sql"))?;
+            conn.pragma_update(None, "user_version", 11)?;
+        }
         Ok(())
     }
     fn get_or_insert_server(&mut self, server: &str) -> Result<i64> {
-        let mut conn = self.conn.write().unwrap();
+        let conn = &mut self.conn;
         let tx = conn.transaction()?;
         let mut res = tx.query_row(
             "SELECT id
@@ -64,50 +117,194 @@ impl Db {
     }
     pub fn insert_message(&mut self, server: &str, json_data: &str) -> Result<(), Error> {
         let server_id = self.get_or_insert_server(server)?;
-        let res = self.conn.read().unwrap().execute(
+        let res = self.conn.execute(
             "INSERT INTO message (server, data) VALUES (?1, ?2)",
             params![server_id, json_data],
         );
-        match res {
-            Err(rusqlite::Error::SqliteFailure(_, Some(text)))
-                if text.starts_with("UNIQUE constraint failed") =>
-            {
-                Err(Error::DuplicateMessage)
+        insert_result(res)
+    }
+    // Stores `(server, data)` pairs in a single transaction, with the result of each message.
+    // The `(server, topic, id)` stream positions of the subscriptions move in the same one.
+    pub fn insert_messages(
+        &mut self,
+        messages: &[(String, String)],
+        last_ids: &[(String, String, String)],
+    ) -> Result<Vec<Result<(), Error>>, Error> {
+        let mut server_ids = HashMap::new();
+        for server in messages.iter().map(|m| &m.0).chain(last_ids.iter().map(|l| &l.0)) {
+            if !server_ids.contains_key(server.as_str()) {
+                server_ids.insert(server.as_str(), self.get_or_insert_server(server)?);
             }
-            Err(e) => Err(Error::Db(e)),
-            Ok(_) => Ok(()),
         }
+        let conn = &mut self.conn;
+        let tx = conn.transaction()?;
+        let results = {
+            let mut insert =
+                tx.prepare_cached("INSERT INTO message (server, data) VALUES (?1, ?2)")?;
+            messages
+                .iter()
+                .map(|(server, data)| {
+                    insert_result(insert.execute(params![server_ids[server.as_str()], data]))
+                })
+                .collect()
+        };
+        {
+            // A subscription removed in the meantime has no position to move
+            let mut update = tx.prepare_cached(
+                "UPDATE subscription SET last_message_id = ?3 WHERE server = ?1 AND topic = ?2",
+            )?;
+            for (server, topic, id) in last_ids {
+                update.execute(params![server_ids[server.as_str()], topic, id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(results)
     }
+    // Oldest message first
     pub fn list_messages(
         &self,
         server: &str,
         topic: &str,
-        since: u64,
+        range: &models::MessageRange,
     ) -> Result<Vec<String>, rusqlite::Error> {
-        let conn = self.conn.read().unwrap();
-        let mut stmt = conn.prepare(
+        // Walk from the end of the range that the limit keeps
+        let oldest_first = range.after.is_some() && range.before.is_none();
+        let order = if oldest_first { "ASC" } else { "DESC" };
+        let conn = &self.conn;
+        let mut stmt = conn.prepare(&format!(
             "
-            SELECT data
+            SELECT {MESSAGE_DATA}
             FROM subscription sub
             JOIN server s ON sub.server = s.id
             JOIN message m ON m.server = sub.server AND m.topic = sub.topic
-            WHERE s.endpoint = ?1 AND m.topic = ?2 AND m.data ->> 'time' >= ?3
+            {MESSAGE_STATE_JOIN}
+            WHERE s.endpoint = ?1 AND m.topic = ?2 AND NOT coalesce(st.deleted, 0)
+                AND (?3 IS NULL OR (m.data ->> '$.time', m.data ->> '$.id') < (?3, ?4))
+                AND (?5 IS NULL OR (m.data ->> '$.time', m.data ->> '$.id') > (?5, ?6))
+            ORDER BY m.data ->> '$.time' {order}, m.data ->> '$.id' {order}
+            LIMIT ?7
+        "
+        ))?;
+        let before = range.before.as_ref();
+        let after = range.after.as_ref();
+        let msgs: Result<Vec<String>, _> = stmt
+            .query_map(
+                params![
+                    server,
+                    topic,
+                    before.map(|c| c.time),
+                    before.map(|c| &c.id),
+                    after.map(|c| c.time),
+                    after.map(|c| &c.id),
+                    range.limit.map_or(-1, i64::from)
+                ],
+                |row| row.get(0),
+            )?
+            .collect();
+        let mut msgs = msgs?;
+        if !oldest_first {
+            msgs.reverse();
+        }
+        Ok(msgs)
+    }
+    // Messages stored without being decrypted, because of a missing or wrong key
+    pub fn list_undecrypted_messages(
+        &self,
+        server: &str,
+        topic: &str,
+    ) -> Result<Vec<String>, rusqlite::Error> {
+        let conn = &self.conn;
+        let mut stmt = conn.prepare(
+            "
+            SELECT data
+            FROM message m
+            JOIN server s ON m.server = s.id
+            WHERE s.endpoint = ?1 AND m.topic = ?2
+                AND m.data ->> '$.decryption.status' IN ('failed', 'no_key')
             ORDER BY m.data ->> 'time'
         ",
         )?;
         let msgs: Result<Vec<String>, _> = stmt
-            .query_map(params![server, topic, since], |row| row.get(0))?
+            .query_map(params![server, topic], |row| row.get(0))?
             .collect();
         msgs
     }
+    // Best matches first. Server and topic narrow the search down to one subscription.
+    pub fn search_messages(
+        &self,
+        query: &str,
+        server: Option<&str>,
+        topic: Option<&str>,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<models::SearchHit>, Error> {
+        let Some(query) = fts_query(query) else {
+            return Ok(vec![]);
+        };
+        let conn = &self.conn;
+        let mut stmt = conn.prepare(&format!(
+            "
+            SELECT s.endpoint, {MESSAGE_DATA}, snippet(message_fts, -1, ?2, ?3, '…', 16)
+            FROM message_fts f
+            JOIN message m ON m.rowid = f.rowid
+            JOIN server s ON m.server = s.id
+            {MESSAGE_STATE_JOIN}
+            WHERE message_fts MATCH ?1
+                AND (?4 IS NULL OR s.endpoint = ?4)
+                AND (?5 IS NULL OR m.topic = ?5)
+                AND NOT coalesce(st.deleted, 0)
+            ORDER BY f.rank, m.data ->> 'time' DESC
+            LIMIT ?6 OFFSET ?7
+        "
+        ))?;
+        let rows = stmt.query_map(
+            params![
+                query,
+                models::HIGHLIGHT_START.to_string(),
+                models::HIGHLIGHT_END.to_string(),
+                server,
+                topic,
+                limit,
+                offset
+            ],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get(2)?)),
+        )?;
+        let mut hits = vec![];
+        for row in rows {
+            let (server, data, snippet) = row?;
+            let message = serde_json::from_str(&data)
+                .map_err(|e| Error::InvalidMessage(data.clone(), e))?;
+            hits.push(models::SearchHit {
+                server,
+                message,
+                snippet,
+            });
+        }
+        Ok(hits)
+    }
+    // Replaces the stored message with the same ID
+    pub fn update_message(&mut self, server: &str, json_data: &str) -> Result<(), Error> {
+        let server_id = self.get_or_insert_server(server)?;
+        let res = self.conn.execute(
+            "UPDATE message SET data = ?2
+            WHERE server = ?1 AND data ->> '$.id' = ?2 ->> '$.id'",
+            params![server_id, json_data],
+        )?;
+        if res == 0 {
+            return Err(Error::MessageNotFound);
+        }
+        Ok(())
+    }
     pub fn insert_subscription(&mut self, sub: models::Subscription) -> Result<(), Error> {
         let server_id = self.get_or_insert_server(&sub.server)?;
         // Create JSON strings for new fields
         let rules = serde_json::to_string(&sub.rules).unwrap_or_default();
         let schedule = serde_json::to_string(&sub.schedule).unwrap_or_default();
+        let server_filter = serde_json::to_string(&sub.server_filter).unwrap_or_default();
+        let retention = serde_json::to_string(&sub.retention).unwrap_or_default();
 
-        self.conn.read().unwrap().execute(
-            "INSERT INTO subscription (server, topic, display_name, reserved, muted, archived, read_until, rules, schedule) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        self.conn.execute(
+            "INSERT INTO subscription (server, topic, display_name, reserved, muted, archived, read_until, rules, schedule, server_filter, retention) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
             params![
                 server_id,
                 sub.topic,
@@ -117,14 +314,16 @@ impl Db {
                 sub.archived,
                 sub.read_until,
                 rules,
-                schedule
+                schedule,
+                server_filter,
+                retention
             ],
         )?;
         Ok(())
     }
     pub fn remove_subscription(&mut self, server: &str, topic: &str) -> Result<(), Error> {
         let server_id = self.get_or_insert_server(server)?;
-        let res = self.conn.read().unwrap().execute(
+        let res = self.conn.execute(
             "DELETE FROM subscription
             WHERE server = ?1 AND topic = ?2",
             params![server_id, topic],
@@ -135,9 +334,9 @@ impl Db {
         Ok(())
     }
     pub fn list_subscriptions(&mut self) -> Result<Vec<models::Subscription>, Error> {
-        let conn = self.conn.read().unwrap();
+        let conn = &self.conn;
         let mut stmt = conn.prepare(
-            "SELECT server.endpoint, sub.topic, sub.display_name, sub.reserved, sub.muted, sub.archived, sub.symbolic_icon, sub.read_until, sub.rules, sub.schedule
+            "SELECT server.endpoint, sub.topic, sub.display_name, sub.reserved, sub.muted, sub.archived, sub.symbolic_icon, sub.read_until, sub.rules, sub.schedule, sub.server_filter, sub.retention
             FROM subscription sub
             JOIN server ON server.id = sub.server
             ORDER BY server.endpoint, sub.display_name, sub.topic
@@ -146,6 +345,8 @@ impl Db {
         let rows = stmt.query_map(params![], |row| {
             let rules_str: Option<String> = row.get(8)?;
             let schedule_str: Option<String> = row.get(9)?;
+            let server_filter_str: Option<String> = row.get(10)?;
+            let retention_str: Option<String> = row.get(11)?;
             
             Ok(models::Subscription {
                 server: row.get(0)?,
@@ -158,6 +359,8 @@ impl Db {
                 read_until: row.get(7)?,
                 rules: rules_str.and_then(|s| serde_json::from_str(&s).ok()),
                 schedule: schedule_str.and_then(|s| serde_json::from_str(&s).ok()),
+                server_filter: server_filter_str.and_then(|s| serde_json::from_str(&s).ok()),
+                retention: retention_str.and_then(|s| serde_json::from_str(&s).ok()),
             })
         })?;
         let subs: Result<Vec<_>, rusqlite::Error> = rows.collect();
@@ -168,10 +371,12 @@ impl Db {
         let server_id = self.get_or_insert_server(&sub.server)?;
         let rules = serde_json::to_string(&sub.rules).unwrap_or_default();
         let schedule = serde_json::to_string(&sub.schedule).unwrap_or_default();
+        let server_filter = serde_json::to_string(&sub.server_filter).unwrap_or_default();
+        let retention = serde_json::to_string(&sub.retention).unwrap_or_default();
 
-        let res = self.conn.read().unwrap().execute(
+        let res = self.conn.execute(
             "UPDATE subscription
-            SET display_name = ?1, reserved = ?2, muted = ?3, archived = ?4, read_until = ?5, rules = ?8, schedule = ?9
+            SET display_name = ?1, reserved = ?2, muted = ?3, archived = ?4, read_until = ?5, rules = ?8, schedule = ?9, server_filter = ?10, retention = ?11
             WHERE server = ?6 AND topic = ?7",
             params![
                 sub.display_name,
@@ -182,7 +387,9 @@ impl Db {
                 server_id,
                 sub.topic,
                 rules,
-                schedule
+                schedule,
+                server_filter,
+                retention
             ],
         )?;
         if res == 0 {
@@ -199,10 +406,10 @@ impl Db {
         value: u64,
     ) -> Result<(), Error> {
         let server_id = self.get_or_insert_server(server).unwrap();
-        let conn = self.conn.read().unwrap();
+        let conn = &self.conn;
         let res = conn.execute(
             "UPDATE subscription
-            SET read_until = ?3
+            SET read_until = max(read_until, ?3)
             WHERE topic = ?2 AND server = ?1
             ",
             params![server_id, topic, value],
@@ -212,9 +419,83 @@ impl Db {
         }
         Ok(())
     }
+    // Like `update_read_until`, and the messages marked unread on their own become read too
+    pub fn mark_all_read(&mut self, server: &str, topic: &str, value: u64) -> Result<(), Error> {
+        self.update_read_until(server, topic, value)?;
+        let server_id = self.get_or_insert_server(server)?;
+        self.conn.execute(
+            "UPDATE message_state
+            SET read = NULL
+            WHERE server = ?1 AND id IN (
+                SELECT data ->> '$.id' FROM message
+                WHERE server = ?1 AND topic = ?2 AND data ->> '$.time' <= ?3
+            )
+            ",
+            params![server_id, topic, value],
+        )?;
+        Ok(())
+    }
+    pub fn set_message_read(&mut self, server: &str, id: &str, read: bool) -> Result<(), Error> {
+        self.update_message_state(server, id, "read", read)
+    }
+    pub fn set_message_starred(
+        &mut self,
+        server: &str,
+        id: &str,
+        starred: bool,
+    ) -> Result<(), Error> {
+        self.update_message_state(server, id, "starred", starred)
+    }
+    // Hides the message, it's kept stored so it isn't received again
+    pub fn delete_message(&mut self, server: &str, id: &str) -> Result<(), Error> {
+        self.update_message_state(server, id, "deleted", true)
+    }
+    fn update_message_state(
+        &mut self,
+        server: &str,
+        id: &str,
+        column: &'static str,
+        value: bool,
+    ) -> Result<(), Error> {
+        let server_id = self.get_or_insert_server(server)?;
+        let conn = &self.conn;
+        let res = conn.execute(
+            &format!(
+                "INSERT INTO message_state (server, id, {column})
+                SELECT server, data ->> '$.id', ?3 FROM message
+                WHERE server = ?1 AND data ->> '$.id' = ?2
+                ON CONFLICT (server, id) DO UPDATE SET {column} = excluded.{column}"
+            ),
+            params![server_id, id, value],
+        )?;
+        if res == 0 {
+            return Err(Error::MessageNotFound);
+        }
+        Ok(())
+    }
+    // Messages newer than `read_until` or marked unread, except the ones marked read
+    pub fn count_unread_messages(&self, server: &str, topic: &str) -> Result<u32, Error> {
+        let conn = &self.conn;
+        let count = conn.query_row(
+            &format!(
+                "
+                SELECT count(*)
+                FROM subscription sub
+                JOIN server s ON sub.server = s.id
+                JOIN message m ON m.server = sub.server AND m.topic = sub.topic
+                {MESSAGE_STATE_JOIN}
+                WHERE s.endpoint = ?1 AND m.topic = ?2 AND NOT coalesce(st.deleted, 0)
+                    AND coalesce(st.read = 0, m.data ->> '$.time' > sub.read_until)
+            "
+            ),
+            params![server, topic],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
     pub fn delete_messages(&mut self, server: &str, topic: &str) -> Result<(), Error> {
         let server_id = self.get_or_insert_server(server).unwrap();
-        let conn = self.conn.read().unwrap();
+        let conn = &self.conn;
         let res = conn.execute(
             "DELETE FROM message
             WHERE topic = ?2 AND server = ?1
@@ -227,14 +508,127 @@ impl Db {
         Ok(())
     }
 
+    // Stored messages that the policy would remove, as (ID, size in bytes)
+    pub fn list_prunable_messages(
+        &self,
+        server: &str,
+        topic: &str,
+        policy: &models::RetentionPolicy,
+        now: u64,
+    ) -> Result<Vec<(String, u64)>, Error> {
+        let conn = &self.conn;
+        // Starred messages are kept, and don't count towards the limits either
+        let mut stmt = conn.prepare(&format!(
+            "
+            SELECT id, size
+            FROM (
+                SELECT m.data ->> '$.id' AS id,
+                    length(m.data) AS size,
+                    m.data ->> '$.time' AS time,
+                    m.data ->> '$.expires' AS expires,
+                    row_number() OVER newest AS position,
+                    sum(length(m.data)) OVER newest AS total_size
+                FROM message m
+                JOIN server s ON m.server = s.id
+                {MESSAGE_STATE_JOIN}
+                WHERE s.endpoint = ?1 AND m.topic = ?2 AND NOT coalesce(st.starred, 0)
+                WINDOW newest AS (ORDER BY m.data ->> '$.time' DESC, m.rowid DESC)
+            )
+            WHERE (?7 AND expires > 0 AND expires <= ?3)
+                OR time < ?3 - ?4
+                OR position > ?5
+                OR total_size > ?6
+        "
+        ))?;
+        let rows = stmt.query_map(
+            params![
+                server,
+                topic,
+                now,
+                policy.max_age,
+                policy.max_count,
+                policy.max_size,
+                policy.drops_expired(),
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let messages: Result<Vec<_>, _> = rows.collect();
+        Ok(messages?)
+    }
+
+    pub fn prune_messages(
+        &mut self,
+        server: &str,
+        topic: &str,
+        policy: &models::RetentionPolicy,
+        now: u64,
+    ) -> Result<models::PruneReport, Error> {
+        let prunable = self.list_prunable_messages(server, topic, policy, now)?;
+        let server_id = self.get_or_insert_server(server)?;
+        let conn = &mut self.conn;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt =
+                tx.prepare("DELETE FROM message WHERE server = ?1 AND data ->> '$.id' = ?2")?;
+            for (id, _) in &prunable {
+                stmt.execute(params![server_id, id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(models::PruneReport {
+            server: server.to_string(),
+            topic: topic.to_string(),
+            count: prunable.len(),
+            bytes: prunable.iter().map(|(_, size)| size).sum(),
+        })
+    }
+
+    // Gives the pages freed by deleted messages back to the file system. Databases created
+    // before incremental vacuuming are switched to it once, which takes a full VACUUM.
+    pub fn reclaim_space(&self, full: bool) -> Result<(), Error> {
+        let conn = &self.conn;
+        let mode: i32 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+        if full || mode == 0 {
+            conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+        } else {
+            conn.execute_batch("PRAGMA incremental_vacuum")?;
+        }
+        Ok(())
+    }
+
+    // Applies to the subscriptions without a policy of their own
+    pub fn get_retention_policy(&self) -> Result<models::RetentionPolicy, Error> {
+        let conn = &self.conn;
+        let res = conn.query_row(
+            "SELECT value FROM setting WHERE key = 'retention'",
+            [],
+            |row| row.get::<_, String>(0),
+        );
+        match res {
+            Ok(value) => Ok(serde_json::from_str(&value).unwrap_or_default()),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(Default::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn set_retention_policy(&mut self, policy: &models::RetentionPolicy) -> Result<(), Error> {
+        let value = serde_json::to_string(policy).unwrap_or_default();
+        self.conn.execute(
+            "INSERT INTO setting (key, value) VALUES ('retention', ?1)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![value],
+        )?;
+        Ok(())
+    }
+
     pub fn get_last_message_time(
         &self,
         server: &str,
         topic: &str,
     ) -> Result<Option<u64>, Error> {
-        let conn = self.conn.read().unwrap();
+        let conn = &self.conn;
         let mut stmt = conn.prepare(
-            "SELECT MAX(m.data ->> 'time')
+            "SELECT MAX(m.data ->> '$.time')
             FROM message m
             JOIN server s ON m.server = s.id
             WHERE s.endpoint = ?1 AND m.topic = ?2
@@ -247,4 +641,613 @@ impl Db {
             Ok(None)
         }
     }
+
+    // ID of the newest message received by the subscription, used to resume its stream
+    pub fn get_last_message_id(&self, server: &str, topic: &str) -> Result<Option<String>, Error> {
+        let conn = &self.conn;
+        let res = conn.query_row(
+            "SELECT sub.last_message_id
+            FROM subscription sub
+            JOIN server s ON sub.server = s.id
+            WHERE s.endpoint = ?1 AND sub.topic = ?2",
+            params![server, topic],
+            |row| row.get(0),
+        );
+        match res {
+            Ok(id) => Ok(id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn update_last_message_id(
+        &mut self,
+        server: &str,
+        topic: &str,
+        id: &str,
+    ) -> Result<(), Error> {
+        let server_id = self.get_or_insert_server(server)?;
+        let res = self.conn.execute(
+            "UPDATE subscription
+            SET last_message_id = ?3
+            WHERE server = ?1 AND topic = ?2",
+            params![server_id, topic, id],
+        )?;
+        if res == 0 {
+            return Err(Error::SubscriptionNotFound("updating last_message_id".into()));
+        }
+        Ok(())
+    }
+
+    // Adds the run to the log of the topic, dropping the oldest ones past `HOOK_LOG_SIZE`
+    pub fn insert_hook_run(
+        &mut self,
+        server: &str,
+        topic: &str,
+        run: &models::HookRun,
+    ) -> Result<(), Error> {
+        let server_id = self.get_or_insert_server(server)?;
+        let conn = &mut self.conn;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO hook_run (server, topic, rule, message_id, started_at, duration_ms, exit_code, error)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                server_id,
+                topic,
+                run.rule,
+                run.message_id,
+                run.started_at,
+                run.duration_ms,
+                run.exit_code,
+                run.error
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM hook_run
+            WHERE server = ?1 AND topic = ?2 AND id NOT IN (
+                SELECT id FROM hook_run
+                WHERE server = ?1 AND topic = ?2
+                ORDER BY id DESC
+                LIMIT ?3
+            )",
+            params![server_id, topic, HOOK_LOG_SIZE],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Newest first
+    pub fn list_hook_runs(&self, server: &str, topic: &str) -> Result<Vec<models::HookRun>, Error> {
+        let conn = &self.conn;
+        let mut stmt = conn.prepare(
+            "SELECT r.rule, r.message_id, r.started_at, r.duration_ms, r.exit_code, r.error
+            FROM hook_run r
+            JOIN server s ON s.id = r.server
+            WHERE s.endpoint = ?1 AND r.topic = ?2
+            ORDER BY r.id DESC",
+        )?;
+        let rows = stmt.query_map(params![server, topic], |row| {
+            Ok(models::HookRun {
+                rule: row.get(0)?,
+                message_id: row.get(1)?,
+                started_at: row.get(2)?,
+                duration_ms: row.get(3)?,
+                exit_code: row.get(4)?,
+                error: row.get(5)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn insert_push_registration(&mut self, reg: &models::PushRegistration) -> Result<(), Error> {
+        let server_id = self.get_or_insert_server(&reg.server)?;
+        self.conn.execute(
+            "INSERT INTO push_registration (token, service, description, server, topic, last_message_id, last_message_time)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                reg.token,
+                reg.service,
+                reg.description,
+                server_id,
+                reg.topic,
+                reg.last_message_id,
+                reg.last_message_time
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_push_registrations(&self) -> Result<Vec<models::PushRegistration>, Error> {
+        let conn = &self.conn;
+        let mut stmt = conn.prepare(
+            "SELECT reg.token, reg.service, reg.description, s.endpoint, reg.topic, reg.last_message_id, reg.last_message_time
+            FROM push_registration reg
+            JOIN server s ON s.id = reg.server
+            ORDER BY reg.service, reg.token",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(models::PushRegistration {
+                token: row.get(0)?,
+                service: row.get(1)?,
+                description: row.get(2)?,
+                server: row.get(3)?,
+                topic: row.get(4)?,
+                last_message_id: row.get(5)?,
+                last_message_time: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn remove_push_registration(&mut self, token: &str) -> Result<(), Error> {
+        let res = self.conn.execute(
+            "DELETE FROM push_registration WHERE token = ?1",
+            params![token],
+        )?;
+        if res == 0 {
+            return Err(Error::PushRegistrationNotFound(token.to_string()));
+        }
+        Ok(())
+    }
+
+    // Remembers the last message handed to the app, its stream resumes from there
+    pub fn update_push_delivery(&mut self, token: &str, id: &str, time: u64) -> Result<(), Error> {
+        let res = self.conn.execute(
+            "UPDATE push_registration
+            SET last_message_id = ?2, last_message_time = ?3
+            WHERE token = ?1",
+            params![token, id, time],
+        )?;
+        if res == 0 {
+            return Err(Error::PushRegistrationNotFound(token.to_string()));
+        }
+        Ok(())
+    }
+
+    // Server of the topics created for new UnifiedPush registrations
+    pub fn get_push_server(&self) -> Result<String, Error> {
+        let conn = &self.conn;
+        let res = conn.query_row(
+            "SELECT value FROM setting WHERE key = 'push_server'",
+            [],
+            |row| row.get::<_, String>(0),
+        );
+        match res {
+            Ok(server) => Ok(server),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(models::DEFAULT_SERVER.to_string()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn set_push_server(&mut self, server: &str) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO setting (key, value) VALUES ('push_server', ?1)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![server],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_server_settings(&self, server: &str) -> Result<models::ServerSettings, Error> {
+        let conn = &self.conn;
+        let res = conn.query_row(
+            "SELECT settings FROM server WHERE endpoint = ?1",
+            params![server],
+            |row| row.get::<_, Option<String>>(0),
+        );
+        let settings = match res {
+            Ok(settings) => settings,
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(settings
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default())
+    }
+
+    pub fn update_server_settings(
+        &mut self,
+        server: &str,
+        settings: &models::ServerSettings,
+    ) -> Result<(), Error> {
+        let server_id = self.get_or_insert_server(server)?;
+        let settings = serde_json::to_string(settings).unwrap_or_default();
+        self.conn.execute(
+            "UPDATE server SET settings = ?2 WHERE id = ?1",
+            params![server_id, settings],
+        )?;
+        Ok(())
+    }
+}
+
+fn insert_result(res: Result<usize>) -> Result<(), Error> {
+    match res {
+        Err(rusqlite::Error::SqliteFailure(_, Some(text)))
+            if text.starts_with("UNIQUE constraint failed") =>
+        {
+            Err(Error::DuplicateMessage)
+        }
+        Err(e) => Err(Error::Db(e)),
+        Ok(_) => Ok(()),
+    }
+}
+
+// Turns what the user typed into an FTS5 query that can't have syntax errors:
+// every word must match, the last one as a prefix since it may not be complete yet
+fn fts_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+    Some(words.join(" ") + "*")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_registrations() {
+        let mut db = Db::connect(":memory:").unwrap();
+        assert_eq!(db.get_push_server().unwrap(), models::DEFAULT_SERVER);
+        db.set_push_server("http://localhost:8000").unwrap();
+        let server = db.get_push_server().unwrap();
+
+        let reg = models::PushRegistration::new("org.example.App", "t1", "Example", &server);
+        assert!(reg.endpoint().starts_with("http://localhost:8000/up"));
+        db.insert_push_registration(&reg).unwrap();
+        db.update_push_delivery("t1", "m1", 10).unwrap();
+
+        let stored = db.list_push_registrations().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].topic, reg.topic);
+        assert_eq!(stored[0].last_message_id.as_deref(), Some("m1"));
+        assert_eq!(stored[0].last_message_time, 10);
+
+        db.remove_push_registration("t1").unwrap();
+        assert!(db.list_push_registrations().unwrap().is_empty());
+        assert!(matches!(
+            db.remove_push_registration("t1"),
+            Err(Error::PushRegistrationNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_hook_runs() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let server = models::DEFAULT_SERVER;
+        let run = |i: u32| models::HookRun {
+            rule: "deploy".to_string(),
+            message_id: format!("m{i}"),
+            started_at: i as u64,
+            duration_ms: 5,
+            exit_code: Some(i as i32 % 2),
+            error: None,
+        };
+        for i in 0..HOOK_LOG_SIZE + 2 {
+            db.insert_hook_run(server, "test", &run(i)).unwrap();
+        }
+        db.insert_hook_run(server, "other", &run(0)).unwrap();
+
+        let runs = db.list_hook_runs(server, "test").unwrap();
+        assert_eq!(runs.len(), HOOK_LOG_SIZE as usize);
+        assert_eq!(runs[0], run(HOOK_LOG_SIZE + 1));
+        assert_eq!(runs.last().unwrap(), &run(2));
+        assert_eq!(db.list_hook_runs(server, "other").unwrap(), vec![run(0)]);
+    }
+
+    #[test]
+    fn test_update_undecrypted_message() {
+        let mut db = Db::connect(":memory:").unwrap();
+        db.insert_subscription(models::Subscription::builder("test".to_string()).build().unwrap())
+            .unwrap();
+        let server = models::DEFAULT_SERVER;
+        let failed = r#"{"id":"m1","topic":"test","time":1,"message":"x","decryption":{"status":"failed","reason":"wrong key"}}"#;
+        db.insert_message(server, failed).unwrap();
+        db.insert_message(server, r#"{"id":"m2","topic":"test","time":2,"message":"plain"}"#)
+            .unwrap();
+
+        assert_eq!(db.list_undecrypted_messages(server, "test").unwrap(), [failed]);
+
+        let decrypted = r#"{"id":"m1","topic":"test","time":1,"message":"secret","decryption":{"status":"decrypted","key_id":"k1"}}"#;
+        db.update_message(server, decrypted).unwrap();
+        assert!(db.list_undecrypted_messages(server, "test").unwrap().is_empty());
+        assert_eq!(db.list_messages(server, "test", &Default::default()).unwrap()[0], decrypted);
+
+        let missing = r#"{"id":"m3","topic":"test","time":3}"#;
+        assert!(matches!(db.update_message(server, missing), Err(Error::MessageNotFound)));
+    }
+
+    #[test]
+    fn test_list_message_pages() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let server = models::DEFAULT_SERVER;
+        db.insert_subscription(models::Subscription::builder("test".to_string()).build().unwrap())
+            .unwrap();
+        // Two messages in the same second are told apart by their ID
+        for (id, time) in [("a", 1), ("b", 2), ("c", 2), ("d", 3), ("e", 4)] {
+            let msg = format!(r#"{{"id":"{id}","topic":"test","time":{time}}}"#);
+            db.insert_message(server, &msg).unwrap();
+        }
+        let ids = |range: models::MessageRange| -> Vec<String> {
+            db.list_messages(server, "test", &range)
+                .unwrap()
+                .into_iter()
+                .map(|data| serde_json::from_str::<models::ReceivedMessage>(&data).unwrap().id)
+                .collect()
+        };
+        let cursor = |time, id: &str| models::MessageCursor {
+            time,
+            id: id.to_string(),
+        };
+
+        assert_eq!(ids(Default::default()), ["a", "b", "c", "d", "e"]);
+        assert_eq!(ids(models::MessageRange::newest(2)), ["d", "e"]);
+        assert_eq!(ids(models::MessageRange::before(cursor(3, "d"), 2)), ["b", "c"]);
+        assert_eq!(ids(models::MessageRange::before(cursor(2, "c"), 2)), ["a", "b"]);
+        assert_eq!(ids(models::MessageRange::before(cursor(1, "a"), 2)), Vec::<String>::new());
+        let after = models::MessageRange {
+            after: Some(cursor(2, "b")),
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(ids(after), ["c", "d"]);
+        let between = models::MessageRange {
+            before: Some(cursor(4, "e")),
+            after: Some(cursor(1, "a")),
+            limit: None,
+        };
+        assert_eq!(ids(between), ["b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_message_state() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let server = models::DEFAULT_SERVER;
+        db.insert_subscription(models::Subscription::builder("test".to_string()).build().unwrap())
+            .unwrap();
+        for (id, time) in [("a", 1), ("b", 2), ("c", 3)] {
+            let msg = format!(r#"{{"id":"{id}","topic":"test","time":{time},"message":"hi {id}"}}"#);
+            db.insert_message(server, &msg).unwrap();
+        }
+        let list = |db: &Db| -> Vec<models::ReceivedMessage> {
+            db.list_messages(server, "test", &Default::default())
+                .unwrap()
+                .iter()
+                .map(|data| serde_json::from_str(data).unwrap())
+                .collect()
+        };
+        assert_eq!(db.count_unread_messages(server, "test").unwrap(), 3);
+
+        db.update_read_until(server, "test", 2).unwrap();
+        assert_eq!(db.count_unread_messages(server, "test").unwrap(), 1);
+
+        db.set_message_read(server, "a", false).unwrap();
+        db.set_message_read(server, "c", true).unwrap();
+        db.set_message_starred(server, "b", true).unwrap();
+        assert_eq!(db.count_unread_messages(server, "test").unwrap(), 1);
+        let msgs = list(&db);
+        assert_eq!(msgs[0].state.read, Some(false));
+        assert!(msgs[0].is_unread(2));
+        assert_eq!(msgs[1].state, models::MessageState { read: None, starred: true });
+        assert!(!msgs[2].is_unread(2));
+
+        // Viewing the messages keeps the ones marked unread, and read_until never goes back
+        db.update_read_until(server, "test", 3).unwrap();
+        db.update_read_until(server, "test", 1).unwrap();
+        assert_eq!(db.count_unread_messages(server, "test").unwrap(), 1);
+        assert_eq!(list(&db)[0].state.read, Some(false));
+
+        // Marking everything read drops the flags of the older messages
+        db.mark_all_read(server, "test", 3).unwrap();
+        assert_eq!(db.count_unread_messages(server, "test").unwrap(), 0);
+        assert_eq!(list(&db)[0].state.read, None);
+
+        db.delete_message(server, "b").unwrap();
+        let ids: Vec<String> = list(&db).into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, ["a", "c"]);
+        assert_eq!(db.search_messages("hi", None, None, 10, 0).unwrap().len(), 2);
+        // Still stored, so the server can't deliver it again
+        assert!(matches!(
+            db.insert_message(server, r#"{"id":"b","topic":"test","time":2}"#),
+            Err(Error::DuplicateMessage)
+        ));
+        assert!(matches!(
+            db.set_message_starred(server, "missing", true),
+            Err(Error::MessageNotFound)
+        ));
+
+        // The state goes away with the message
+        db.delete_messages(server, "test").unwrap();
+        let conn = &db.conn;
+        let left: u32 = conn
+            .query_row("SELECT count(*) FROM message_state", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn test_search_messages() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let server = models::DEFAULT_SERVER;
+        for topic in ["alerts", "builds"] {
+            db.insert_subscription(models::Subscription::builder(topic.to_string()).build().unwrap())
+                .unwrap();
+        }
+        db.insert_message(server, r#"{"id":"m1","topic":"alerts","time":1,"title":"Disk full","message":"Server db01 is out of space","tags":["warning"]}"#)
+            .unwrap();
+        db.insert_message(server, r#"{"id":"m2","topic":"builds","time":2,"message":"Build of \"server\" failed"}"#)
+            .unwrap();
+        db.insert_message(server, r#"{"id":"m3","topic":"alerts","time":3,"message":"All good"}"#)
+            .unwrap();
+
+        let ids = |hits: Vec<models::SearchHit>| -> Vec<String> {
+            hits.into_iter().map(|h| h.message.id).collect()
+        };
+        let mut all = ids(db.search_messages("serv", None, None, 10, 0).unwrap());
+        all.sort();
+        assert_eq!(all, ["m1", "m2"]);
+        assert_eq!(ids(db.search_messages("warning", None, None, 10, 0).unwrap()), ["m1"]);
+        assert_eq!(ids(db.search_messages("server", None, Some("builds"), 10, 0).unwrap()), ["m2"]);
+        assert_eq!(db.search_messages("server", None, None, 1, 1).unwrap().len(), 1);
+        // Operators and quotes are searched for literally
+        assert!(db.search_messages("\"server AND", None, None, 10, 0).unwrap().is_empty());
+        assert!(db.search_messages("  ", None, None, 10, 0).unwrap().is_empty());
+
+        let hit = &db.search_messages("disk", None, None, 10, 0).unwrap()[0];
+        assert_eq!(hit.server, server);
+        assert_eq!(hit.snippet, "\u{2}Disk\u{3} full");
+
+        // Decrypted text becomes searchable and removed messages disappear
+        db.update_message(server, r#"{"id":"m3","topic":"alerts","time":3,"message":"Decrypted secret"}"#)
+            .unwrap();
+        assert_eq!(ids(db.search_messages("secret", None, None, 10, 0).unwrap()), ["m3"]);
+        db.delete_messages(server, "alerts").unwrap();
+        assert_eq!(ids(db.search_messages("server", None, None, 10, 0).unwrap()), ["m2"]);
+    }
+
+    #[test]
+    fn test_prune_messages() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let server = models::DEFAULT_SERVER;
+        db.insert_subscription(models::Subscription::builder("test".to_string()).build().unwrap())
+            .unwrap();
+        for (id, time, expires) in [("m1", 100, 0), ("m2", 200, 250), ("m3", 300, 0), ("m4", 400, 0)] {
+            let msg = format!(r#"{{"id":"{id}","topic":"test","time":{time},"expires":{expires}}}"#);
+            db.insert_message(server, &msg).unwrap();
+        }
+        let ids = |db: &Db, policy: models::RetentionPolicy| -> Vec<String> {
+            let mut ids: Vec<_> = db
+                .list_prunable_messages(server, "test", &policy, 300)
+                .unwrap()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        // An empty policy keeps everything, even expired messages
+        assert_eq!(ids(&db, Default::default()), Vec::<String>::new());
+        let policy = models::RetentionPolicy {
+            drop_expired: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(ids(&db, policy), ["m2"]);
+        let policy = models::RetentionPolicy {
+            max_age: Some(150),
+            ..Default::default()
+        };
+        assert_eq!(ids(&db, policy), ["m1"]);
+        let policy = models::RetentionPolicy {
+            max_count: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(ids(&db, policy), ["m1"]);
+        let policy = models::RetentionPolicy {
+            drop_expired: Some(true),
+            ..Default::default()
+        };
+        let size = db.list_prunable_messages(server, "test", &policy, 300).unwrap()[0].1;
+        let policy = models::RetentionPolicy {
+            max_size: Some(size * 2),
+            ..Default::default()
+        };
+        assert_eq!(ids(&db, policy), ["m1", "m2"]);
+
+        let policy = models::RetentionPolicy {
+            max_count: Some(1),
+            ..Default::default()
+        };
+        let report = db.prune_messages(server, "test", &policy, 300).unwrap();
+        assert_eq!(report.count, 3);
+        db.reclaim_space(false).unwrap();
+        let left: Vec<String> = db.list_messages(server, "test", &Default::default()).unwrap();
+        assert_eq!(left.len(), 1);
+        assert!(left[0].contains("m4"));
+        assert_eq!(ids(&db, Default::default()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_reclaim_space_switches_to_incremental_vacuum() {
+        let db = Db::connect(":memory:").unwrap();
+        let mode = |db: &Db| -> i32 {
+            db.conn
+                .query_row("PRAGMA auto_vacuum", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(mode(&db), 2);
+
+        // Like a database created by an older version
+        db.conn
+            .execute_batch("PRAGMA auto_vacuum = NONE; VACUUM;")
+            .unwrap();
+        assert_eq!(mode(&db), 0);
+        db.reclaim_space(false).unwrap();
+        assert_eq!(mode(&db), 2);
+    }
+
+    #[test]
+    fn test_prune_keeps_starred_messages() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let server = models::DEFAULT_SERVER;
+        db.insert_subscription(models::Subscription::builder("test".to_string()).build().unwrap())
+            .unwrap();
+        for (id, time, expires) in [("m1", 100, 150), ("m2", 200, 0), ("m3", 300, 0)] {
+            let msg = format!(r#"{{"id":"{id}","topic":"test","time":{time},"expires":{expires}}}"#);
+            db.insert_message(server, &msg).unwrap();
+        }
+        db.set_message_starred(server, "m1", true).unwrap();
+        db.set_message_starred(server, "m3", true).unwrap();
+
+        // Neither expired nor taking the place of newer messages
+        let policy = models::RetentionPolicy {
+            max_count: Some(1),
+            drop_expired: Some(true),
+            ..Default::default()
+        };
+        let report = db.prune_messages(server, "test", &policy, 300).unwrap();
+        assert_eq!(report.count, 0);
+
+        db.set_message_starred(server, "m3", false).unwrap();
+        let report = db.prune_messages(server, "test", &policy, 300).unwrap();
+        assert_eq!(report.count, 1);
+        let left: Vec<String> = db.list_messages(server, "test", &Default::default()).unwrap();
+        assert_eq!(left.len(), 2);
+        assert!(left.iter().all(|msg| !msg.contains("\"m2\"")));
+    }
+
+    #[test]
+    fn test_retention_policy_settings() {
+        let mut db = Db::connect(":memory:").unwrap();
+        assert!(db.get_retention_policy().unwrap().is_empty());
+
+        let global = models::RetentionPolicy {
+            max_age: Some(60),
+            max_count: Some(10),
+            max_size: None,
+            drop_expired: Some(true),
+        };
+        db.set_retention_policy(&global).unwrap();
+        assert_eq!(db.get_retention_policy().unwrap(), global);
+
+        let own = models::RetentionPolicy {
+            max_count: Some(5),
+            ..Default::default()
+        };
+        let sub = models::Subscription::builder("test".to_string())
+            .retention(Some(own.clone()))
+            .build()
+            .unwrap();
+        db.insert_subscription(sub).unwrap();
+        let stored = db.list_subscriptions().unwrap().remove(0);
+        assert_eq!(stored.retention.as_ref(), Some(&own));
+        assert_eq!(own.or(&global).max_count, Some(5));
+        assert_eq!(own.or(&global).max_age, Some(60));
+        assert!(own.or(&global).drops_expired());
+    }
 }
@@ -0,0 +1,253 @@
+use std::sync::mpsc;
+
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+use super::Db;
+use crate::Error;
+
+// Most jobs taken off the queue at once, inserts among them share a transaction
+const MAX_BATCH: usize = 256;
+
+enum Job {
+    Call(Box<dyn FnOnce(&mut Db) + Send>),
+    Insert {
+        server: String,
+        data: Vec<String>,
+        // The topic whose stream position moves to this message ID
+        last_id: Option<(String, String)>,
+        resp_tx: oneshot::Sender<Vec<Result<(), Error>>>,
+    },
+}
+
+// Runs the queries on a thread of its own so that a slow one doesn't block the actors.
+// Jobs run in the order they are sent, and the messages inserted during a burst are
+// written in a single transaction.
+#[derive(Clone)]
+pub struct DbHandle {
+    job_tx: mpsc::Sender<Job>,
+}
+
+impl DbHandle {
+    // The connection is opened on the worker thread, it stops once every handle is dropped
+    pub fn connect(path: &str) -> Result<Self, Error> {
+        let path = path.to_string();
+        let (job_tx, job_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+        std::thread::Builder::new()
+            .name("ntfy-db".to_string())
+            .spawn(move || match Db::connect(&path) {
+                Ok(db) => {
+                    let _ = ready_tx.send(Ok(()));
+                    run(db, job_rx);
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                }
+            })?;
+        ready_rx
+            .recv()
+            .expect("database worker stopped before connecting")?;
+        Ok(Self { job_tx })
+    }
+
+    pub async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Db) -> T + Send + 'static,
+    ) -> T {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.send(Job::Call(Box::new(move |db| {
+            let _ = resp_tx.send(f(db));
+        })));
+        resp_rx.await.expect("database worker stopped")
+    }
+
+    pub async fn insert_message(&self, server: &str, json_data: &str) -> Result<(), Error> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.send(Job::Insert {
+            server: server.to_string(),
+            data: vec![json_data.to_string()],
+            last_id: None,
+            resp_tx,
+        });
+        let mut results = resp_rx.await.expect("database worker stopped");
+        results.pop().expect("one result per message")
+    }
+
+    // Stores messages received on `topic` and moves its stream position to `last_id`,
+    // with the result of each message
+    pub async fn insert_messages(
+        &self,
+        server: &str,
+        topic: &str,
+        messages: Vec<String>,
+        last_id: &str,
+    ) -> Vec<Result<(), Error>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.send(Job::Insert {
+            server: server.to_string(),
+            data: messages,
+            last_id: Some((topic.to_string(), last_id.to_string())),
+            resp_tx,
+        });
+        resp_rx.await.expect("database worker stopped")
+    }
+
+    fn send(&self, job: Job) {
+        self.job_tx.send(job).expect("database worker stopped");
+    }
+}
+
+fn run(mut db: Db, job_rx: mpsc::Receiver<Job>) {
+    let mut inserts = vec![];
+    while let Ok(job) = job_rx.recv() {
+        for job in std::iter::once(job).chain(job_rx.try_iter().take(MAX_BATCH - 1)) {
+            match job {
+                Job::Insert {
+                    server,
+                    data,
+                    last_id,
+                    resp_tx,
+                } => inserts.push(PendingInsert {
+                    server,
+                    data,
+                    last_id,
+                    resp_tx,
+                }),
+                Job::Call(f) => {
+                    // Earlier inserts must be visible to the queries sent after them
+                    flush_inserts(&mut db, &mut inserts);
+                    f(&mut db);
+                }
+            }
+        }
+        flush_inserts(&mut db, &mut inserts);
+    }
+    debug!("database worker stopped");
+}
+
+struct PendingInsert {
+    server: String,
+    data: Vec<String>,
+    last_id: Option<(String, String)>,
+    resp_tx: oneshot::Sender<Vec<Result<(), Error>>>,
+}
+
+fn flush_inserts(db: &mut Db, inserts: &mut Vec<PendingInsert>) {
+    if inserts.is_empty() {
+        return;
+    }
+    let messages: Vec<(String, String)> = inserts
+        .iter()
+        .flat_map(|insert| {
+            let server = &insert.server;
+            insert
+                .data
+                .iter()
+                .map(|data| (server.clone(), data.clone()))
+        })
+        .collect();
+    let last_ids: Vec<(String, String, String)> = inserts
+        .iter()
+        .filter_map(|insert| {
+            let (topic, id) = insert.last_id.clone()?;
+            Some((insert.server.clone(), topic, id))
+        })
+        .collect();
+    match db.insert_messages(&messages, &last_ids) {
+        Ok(results) => {
+            let mut results = results.into_iter();
+            for insert in inserts.drain(..) {
+                let count = insert.data.len();
+                let _ = insert.resp_tx.send(results.by_ref().take(count).collect());
+            }
+        }
+        Err(e) => {
+            // One bad message shouldn't cost the others, store them one by one
+            warn!(error = ?e, count = messages.len(), "can't store the messages in a batch");
+            for insert in inserts.drain(..) {
+                let results = insert
+                    .data
+                    .iter()
+                    .map(|data| db.insert_message(&insert.server, data))
+                    .collect();
+                if let Some((topic, id)) = &insert.last_id {
+                    if let Err(e) = db.update_last_message_id(&insert.server, topic, id) {
+                        warn!(error = ?e, topic, "can't store the last message id");
+                    }
+                }
+                let _ = insert.resp_tx.send(results);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+
+    #[tokio::test]
+    async fn test_worker_batches_inserts_in_order() {
+        let db = DbHandle::connect(":memory:").unwrap();
+        db.call(|db| {
+            db.insert_subscription(models::Subscription::builder("a".to_string()).build()?)
+        })
+        .await
+        .unwrap();
+
+        let server = models::DEFAULT_SERVER;
+        let inserts = (0..500).map(|i| {
+            let data = format!(r#"{{"id":"{i}","topic":"a","time":{i}}}"#);
+            let db = db.clone();
+            async move { db.insert_message(server, &data).await }
+        });
+        let results = futures::future::join_all(inserts).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert!(matches!(
+            db.insert_message(server, r#"{"id":"0","topic":"a","time":0}"#)
+                .await,
+            Err(Error::DuplicateMessage)
+        ));
+
+        let stored = db
+            .call(|db| db.list_messages(server, "a", &Default::default()))
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 500);
+    }
+
+    #[tokio::test]
+    async fn test_worker_moves_the_stream_position_with_the_inserts() {
+        let db = DbHandle::connect(":memory:").unwrap();
+        db.call(|db| {
+            db.insert_subscription(models::Subscription::builder("a".to_string()).build()?)
+        })
+        .await
+        .unwrap();
+
+        let server = models::DEFAULT_SERVER;
+        let messages = (0..3)
+            .map(|i| format!(r#"{{"id":"{i}","topic":"a","time":{i}}}"#))
+            .collect();
+        let results = db.insert_messages(server, "a", messages, "3").await;
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(Result::is_ok));
+
+        // The newest message may have been discarded, its ID is the position anyway
+        let results = db
+            .insert_messages(
+                server,
+                "a",
+                vec![r#"{"id":"2","topic":"a","time":2}"#.into()],
+                "4",
+            )
+            .await;
+        assert!(matches!(results[..], [Err(Error::DuplicateMessage)]));
+        let last_id = db
+            .call(move |db| db.get_last_message_id(server, "a"))
+            .await
+            .unwrap();
+        assert_eq!(last_id.as_deref(), Some("4"));
+    }
+}
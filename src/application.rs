@@ -491,6 +491,14 @@ impl NtfyrApplication {
         }
         let proxies = std::sync::Arc::new(Proxies { notification: s });
         let ntfy = ntfy_daemon::start(dbpath.to_str().unwrap(), proxies.clone(), proxies).unwrap();
+
+        // Scripts and other apps reach the daemon through the session bus
+        let dbus_ntfy = ntfy.clone();
+        crate::async_utils::RUNTIME.spawn(async move {
+            if let Err(e) = ntfy_daemon::dbus::serve(dbus_ntfy).await {
+                warn!(error = %e, "not serving {}", ntfy_daemon::dbus::BUS_NAME);
+            }
+        });
         self.imp()
             .ntfy
             .set(ntfy)
@@ -3,6 +3,7 @@ mod application;
 mod config;
 mod async_utils;
 pub mod error;
+mod qr;
 mod subscription;
 pub mod widgets;
 mod tray;
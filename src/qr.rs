@@ -0,0 +1,63 @@
+use anyhow::{anyhow, Result};
+use gtk::prelude::*;
+use gtk::{gdk, glib};
+
+// Pixels per QR module, large enough to stay sharp when the picture is scaled down
+const MODULE_SIZE: usize = 8;
+// Light border around the code required by scanners, in modules
+const QUIET_ZONE: usize = 4;
+
+pub fn texture(text: &str) -> Result<gdk::Texture> {
+    let code = qrcode::QrCode::new(text.as_bytes())?;
+    let modules = code.width();
+    let colors = code.to_colors();
+
+    let size = (modules + 2 * QUIET_ZONE) * MODULE_SIZE;
+    let mut pixels = vec![0xff; size * size * 3];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != qrcode::Color::Dark {
+            continue;
+        }
+        let x0 = (i % modules + QUIET_ZONE) * MODULE_SIZE;
+        let y0 = (i / modules + QUIET_ZONE) * MODULE_SIZE;
+        for y in y0..y0 + MODULE_SIZE {
+            let row = (y * size + x0) * 3;
+            pixels[row..row + MODULE_SIZE * 3].fill(0);
+        }
+    }
+
+    let texture = gdk::MemoryTexture::new(
+        size as i32,
+        size as i32,
+        gdk::MemoryFormat::R8g8b8,
+        &glib::Bytes::from_owned(pixels),
+        size * 3,
+    );
+    Ok(texture.upcast())
+}
+
+// Text of the first QR code found in the image file
+pub fn decode_file(path: &std::path::Path) -> Result<String> {
+    let pixbuf = gdk_pixbuf::Pixbuf::from_file(path)?;
+    let bytes = pixbuf.read_pixel_bytes();
+    let stride = pixbuf.rowstride() as usize;
+    let channels = pixbuf.n_channels() as usize;
+
+    let mut image = rqrr::PreparedImage::prepare_from_greyscale(
+        pixbuf.width() as usize,
+        pixbuf.height() as usize,
+        |x, y| {
+            let p = &bytes[y * stride + x * channels..];
+            if channels < 3 {
+                return p[0];
+            }
+            ((p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000) as u8
+        },
+    );
+    image
+        .detect_grids()
+        .into_iter()
+        .find_map(|grid| grid.decode().ok())
+        .map(|(_, text)| text)
+        .ok_or_else(|| anyhow!("No QR code found in the image"))
+}
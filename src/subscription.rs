@@ -9,12 +9,17 @@ use gtk::{gio, glib};
 use ntfy_daemon::{models, ConnectionState, ListenerEvent};
 use tracing::{error, instrument};
 
+// Stored messages fetched at once when scrolling past the oldest loaded one or reloading
+const PAGE_SIZE: u32 = 50;
+
 #[repr(u16)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Status {
     Down = 0,
     Degraded = 1,
     Up = 2,
+    // Not connected on purpose, the server is polled periodically
+    Polling = 3,
 }
 
 impl From<u16> for Status {
@@ -23,6 +28,7 @@ impl From<u16> for Status {
             0 => Status::Down,
             1 => Status::Degraded,
             2 => Status::Up,
+            3 => Status::Polling,
             _ => panic!("Invalid value for Status"),
         }
     }
@@ -50,14 +56,25 @@ mod imp {
         pub server: RefCell<String>,
         #[property(get = Self::get_status, type = u8)]
         pub status: Rc<Cell<Status>>,
+        // Human readable connection state, e.g. "Polling every 15 min"
+        #[property(get)]
+        pub status_text: RefCell<String>,
         #[property(get)]
         pub muted: Cell<bool>,
         #[property(get)]
         pub unread_count: Cell<u32>,
+        // Messages up to this time are read, unless marked unread on their own
+        #[property(get)]
         pub read_until: Cell<u64>,
         pub rules: RefCell<Option<Vec<models::FilterRule>>>,
         pub schedule: RefCell<Option<models::Schedule>>,
+        pub server_filter: RefCell<Option<models::ServerFilter>>,
+        pub retention: RefCell<Option<models::RetentionPolicy>>,
         pub messages: gio::ListStore,
+        // Whether the database may hold messages older than the loaded ones
+        #[property(get)]
+        pub has_older: Cell<bool>,
+        pub loading_older: Cell<bool>,
         pub client: OnceCell<ntfy_daemon::SubscriptionHandle>,
         #[property(get)]
         pub reserved: Cell<bool>,
@@ -83,12 +100,17 @@ mod imp {
                 muted: Default::default(),
                 server: Default::default(),
                 status: Rc::new(Cell::new(Status::Down)),
+                status_text: Default::default(),
                 messages: gio::ListStore::new::<glib::BoxedAnyObject>(),
+                has_older: Cell::new(true),
+                loading_older: Default::default(),
                 client: Default::default(),
                 unread_count: Default::default(),
                 read_until: Default::default(),
                 rules: Default::default(),
                 schedule: Default::default(),
+                server_filter: Default::default(),
+                retention: Default::default(),
                 reserved: Default::default(),
                 has_rules: Default::default(),
                 has_schedule: Default::default(),
@@ -139,6 +161,8 @@ impl Subscription {
         display_name: &str,
         rules: Option<Vec<models::FilterRule>>,
         schedule: Option<models::Schedule>,
+        server_filter: Option<models::ServerFilter>,
+        retention: Option<models::RetentionPolicy>,
         reserved: bool,
     ) {
         let imp = self.imp();
@@ -159,6 +183,8 @@ impl Subscription {
         // Move objects
         imp.rules.replace(rules);
         imp.schedule.replace(schedule);
+        imp.server_filter.replace(server_filter);
+        imp.retention.replace(retention);
         
         self._set_display_name(display_name.to_string());
         
@@ -186,14 +212,22 @@ impl Subscription {
                 &model.display_name,
                 model.rules,
                 model.schedule,
+                model.server_filter,
+                model.retention,
                 model.reserved,
             );
 
-            let (prev_msgs, mut rx) = remote_subscription.attach().await;
+            let (prev_events, mut rx) = remote_subscription.attach().await;
 
-            for msg in prev_msgs {
-                this.handle_event(msg);
+            let mut prev_msgs = vec![];
+            for ev in prev_events {
+                match ev {
+                    ListenerEvent::Message(msg) => prev_msgs.push(glib::BoxedAnyObject::new(msg)),
+                    ev => this.handle_event(ev),
+                }
             }
+            this.imp().messages.extend_from_slice(&prev_msgs);
+            this.update_unread_count();
 
             while let Ok(ev) = rx.recv().await {
                 this.handle_event(ev);
@@ -215,11 +249,18 @@ impl Subscription {
     }
 
     fn set_connection_state(&self, state: ConnectionState) {
-        let status = match state {
-            ConnectionState::Unitialized => Status::Degraded,
-            ConnectionState::Connected => Status::Up,
-            ConnectionState::Reconnecting { .. } => Status::Degraded,
+        let (status, text) = match state {
+            ConnectionState::Unitialized => (Status::Degraded, "Connecting...".to_string()),
+            ConnectionState::Connected => (Status::Up, "Connected".to_string()),
+            ConnectionState::Polling { interval } => (
+                Status::Polling,
+                format!("Polling every {} min", interval.as_secs().div_ceil(60)),
+            ),
+            ConnectionState::Reconnecting { .. } => {
+                (Status::Degraded, "Reconnecting...".to_string())
+            }
         };
+        self.imp().status_text.replace(text);
         self.imp().status.set(status);
         dbg!(status);
         self.notify_status();
@@ -256,6 +297,8 @@ impl Subscription {
                     .muted(imp.muted.get())
                     .rules(imp.rules.borrow().clone())
                     .schedule(imp.schedule.borrow().clone())
+                    .server_filter(imp.server_filter.borrow().clone())
+                    .retention(imp.retention.borrow().clone())
                     .build()
                     .map_err(|e| anyhow::anyhow!("invalid subscription data {:?}", e))?,
             )
@@ -297,6 +340,36 @@ impl Subscription {
         }
     }
 
+    pub fn get_server_filter(&self) -> Option<models::ServerFilter> {
+        self.imp().server_filter.borrow().clone()
+    }
+
+    pub fn set_server_filter(
+        &self,
+        server_filter: Option<models::ServerFilter>,
+    ) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            this.imp().server_filter.replace(server_filter);
+            this.send_updated_info().await
+        }
+    }
+
+    pub fn get_retention(&self) -> Option<models::RetentionPolicy> {
+        self.imp().retention.borrow().clone()
+    }
+
+    pub fn set_retention(
+        &self,
+        retention: Option<models::RetentionPolicy>,
+    ) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            this.imp().retention.replace(retention);
+            this.send_updated_info().await
+        }
+    }
+
     fn last_message(list: &gio::ListStore) -> Option<models::ReceivedMessage> {
         let n = list.n_items();
         let last = list
@@ -305,25 +378,78 @@ impl Subscription {
         let last = last.borrow::<models::ReceivedMessage>();
         Some(last.clone())
     }
+    // Counted in the database, older messages may not be loaded
     fn update_unread_count(&self) {
-        let imp = self.imp();
-        let read_until = imp.read_until.get();
-        
-        // Count messages that are newer than read_until
-        let mut unread = 0u32;
-        for i in 0..imp.messages.n_items() {
-            if let Some(item) = imp.messages.item(i) {
-                if let Some(obj) = item.downcast_ref::<glib::BoxedAnyObject>() {
-                    let msg = obj.borrow::<models::ReceivedMessage>();
-                    if msg.time > read_until {
-                        unread += 1;
-                    }
+        let this = self.clone();
+        glib::MainContext::default().spawn_local(async move {
+            let imp = this.imp();
+            match imp.client.get().unwrap().unread_count().await {
+                Ok(unread) => {
+                    imp.unread_count.set(unread);
+                    this.notify_unread_count();
                 }
+                Err(e) => error!(error = %e, "counting unread messages"),
             }
+        });
+    }
+    fn message_position(&self, id: &str) -> Option<u32> {
+        let messages = &self.imp().messages;
+        (0..messages.n_items()).find(|i| {
+            messages
+                .item(*i)
+                .and_downcast::<glib::BoxedAnyObject>()
+                .is_some_and(|obj| obj.borrow::<models::ReceivedMessage>().id == id)
+        })
+    }
+    pub fn has_message(&self, id: &str) -> bool {
+        self.message_position(id).is_some()
+    }
+    // Replaces the loaded copy of the message so its row gets rebuilt
+    fn update_loaded_message(&self, id: &str, f: impl FnOnce(&mut models::ReceivedMessage)) {
+        let messages = &self.imp().messages;
+        let Some(i) = self.message_position(id) else {
+            return;
+        };
+        let Some(obj) = messages.item(i).and_downcast::<glib::BoxedAnyObject>() else {
+            return;
+        };
+        let mut msg = obj.borrow::<models::ReceivedMessage>().clone();
+        f(&mut msg);
+        messages.splice(i, 1, &[glib::BoxedAnyObject::new(msg)]);
+    }
+    pub async fn set_message_read(&self, id: String, read: bool) -> anyhow::Result<()> {
+        let imp = self.imp();
+        imp.client
+            .get()
+            .unwrap()
+            .set_message_read(id.clone(), read)
+            .await?;
+        self.update_loaded_message(&id, |msg| msg.state.read = Some(read));
+        self.update_unread_count();
+        Ok(())
+    }
+    pub async fn set_message_starred(&self, id: String, starred: bool) -> anyhow::Result<()> {
+        let imp = self.imp();
+        imp.client
+            .get()
+            .unwrap()
+            .set_message_starred(id.clone(), starred)
+            .await?;
+        self.update_loaded_message(&id, |msg| msg.state.starred = starred);
+        Ok(())
+    }
+    // Newest first
+    pub async fn list_hook_runs(&self) -> anyhow::Result<Vec<models::HookRun>> {
+        self.imp().client.get().unwrap().list_hook_runs().await
+    }
+    pub async fn delete_message(&self, id: String) -> anyhow::Result<()> {
+        let imp = self.imp();
+        imp.client.get().unwrap().delete_message(id.clone()).await?;
+        if let Some(i) = self.message_position(&id) {
+            imp.messages.remove(i);
         }
-        
-        imp.unread_count.set(unread);
-        self.notify_unread_count();
+        self.update_unread_count();
+        Ok(())
     }
 
     pub fn set_muted(&self, value: bool) -> impl Future<Output = anyhow::Result<()>> {
@@ -335,31 +461,126 @@ impl Subscription {
             Ok(())
         }
     }
+    // Called once the user has seen the messages, the ones marked unread on their own stay so
     pub async fn flag_all_as_read(&self) -> anyhow::Result<()> {
         let imp = self.imp();
+        let read_until = imp.read_until.get();
         let Some(value) = Self::last_message(&imp.messages)
             .map(|last| last.time)
-            .filter(|time| *time > self.imp().read_until.get())
+            .filter(|time| *time > read_until)
         else {
             return Ok(());
         };
 
-        let this = self.clone();
-        this.imp()
+        imp.client.get().unwrap().update_read_until(value).await?;
+        imp.read_until.set(value);
+        // Rebuild the rows that lose their unread chip
+        for id in self.loaded_message_ids(|msg| msg.is_unread(read_until) && !msg.is_unread(value))
+        {
+            self.update_loaded_message(&id, |_| ());
+        }
+        self.update_unread_count();
+
+        Ok(())
+    }
+    pub async fn mark_all_read(&self) -> anyhow::Result<()> {
+        let imp = self.imp();
+        let Some(value) =
+            Self::last_message(&imp.messages).map(|last| last.time.max(imp.read_until.get()))
+        else {
+            return Ok(());
+        };
+
+        imp.client.get().unwrap().mark_all_read(value).await?;
+        let read_until = imp.read_until.replace(value);
+        // The daemon dropped the read flags of the messages up to `value`
+        let marked = self.loaded_message_ids(|msg| {
+            msg.time <= value && (msg.state.read.is_some() || msg.is_unread(read_until))
+        });
+        for id in marked {
+            self.update_loaded_message(&id, |msg| msg.state.read = None);
+        }
+        self.update_unread_count();
+
+        Ok(())
+    }
+    fn loaded_message_ids(&self, f: impl Fn(&models::ReceivedMessage) -> bool) -> Vec<String> {
+        let messages = &self.imp().messages;
+        (0..messages.n_items())
+            .filter_map(|i| messages.item(i).and_downcast::<glib::BoxedAnyObject>())
+            .map(|obj| obj.borrow::<models::ReceivedMessage>().clone())
+            .filter(|msg| f(msg))
+            .map(|msg| msg.id)
+            .collect()
+    }
+    pub async fn publish_msg(&self, mut msg: models::OutgoingMessage, encryption: models::Encryption) -> anyhow::Result<()> {
+        let imp = self.imp();
+        msg.topic = self.topic();
+        imp.client.get().unwrap().publish(msg, encryption).await?;
+        Ok(())
+    }
+    // Returns how many stored messages could now be decrypted
+    pub async fn retry_decryption(&self) -> anyhow::Result<usize> {
+        let imp = self.imp();
+        let updated = imp.client.get().unwrap().retry_decryption().await?;
+        let mut decrypted = 0;
+        for msg in updated {
+            if matches!(msg.decryption, models::Decryption::Decrypted { .. }) {
+                decrypted += 1;
+            }
+            let id = msg.id.clone();
+            self.update_loaded_message(&id, |loaded| {
+                let state = std::mem::take(&mut loaded.state);
+                *loaded = msg;
+                loaded.state = state;
+            });
+        }
+        Ok(decrypted)
+    }
+    // Prepends the page of stored messages preceding the oldest loaded one
+    pub async fn load_older_messages(&self) -> anyhow::Result<()> {
+        let imp = self.imp();
+        if !imp.has_older.get() || imp.loading_older.get() {
+            return Ok(());
+        }
+        let Some(oldest) = imp.messages.item(0).and_downcast::<glib::BoxedAnyObject>() else {
+            return Ok(());
+        };
+        let cursor = models::MessageCursor::from(&*oldest.borrow::<models::ReceivedMessage>());
+
+        imp.loading_older.set(true);
+        let res = imp
             .client
             .get()
             .unwrap()
-            .update_read_until(value)
-            .await?;
-        this.imp().read_until.set(value);
-        this.update_unread_count();
+            .list_older_messages(cursor, PAGE_SIZE)
+            .await;
+        imp.loading_older.set(false);
+        let msgs = res?;
 
+        if (msgs.len() as u32) < PAGE_SIZE {
+            imp.has_older.set(false);
+        }
+        let items: Vec<glib::BoxedAnyObject> =
+            msgs.into_iter().map(glib::BoxedAnyObject::new).collect();
+        imp.messages.splice(0, 0, &items);
+        self.update_unread_count();
         Ok(())
     }
-    pub async fn publish_msg(&self, mut msg: models::OutgoingMessage, encrypt: bool) -> anyhow::Result<()> {
+    // Shows the newest stored messages again, e.g. after importing some
+    pub async fn reload_messages(&self) -> anyhow::Result<()> {
         let imp = self.imp();
-        msg.topic = self.topic();
-        imp.client.get().unwrap().publish(msg, encrypt).await?;
+        let msgs = imp
+            .client
+            .get()
+            .unwrap()
+            .list_messages(models::MessageRange::newest(PAGE_SIZE))
+            .await?;
+        imp.has_older.set(msgs.len() as u32 == PAGE_SIZE);
+        let items: Vec<glib::BoxedAnyObject> =
+            msgs.into_iter().map(glib::BoxedAnyObject::new).collect();
+        imp.messages.splice(0, imp.messages.n_items(), &items);
+        self.update_unread_count();
         Ok(())
     }
     #[instrument(skip_all)]
@@ -367,6 +588,7 @@ impl Subscription {
         let imp = self.imp();
         imp.client.get().unwrap().clear_notifications().await?;
         self.imp().messages.remove_all();
+        imp.has_older.set(false);
 
         Ok(())
     }
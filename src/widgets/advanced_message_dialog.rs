@@ -7,6 +7,14 @@ use gtk::{gio, glib};
 
 use crate::error::*;
 use crate::subscription::Subscription;
+use ntfy_daemon::models::Encryption;
+
+// Same order as the rows of the encryption combo
+const ENCRYPTION_MODES: [(Encryption, &str); 3] = [
+    (Encryption::None, "None"),
+    (Encryption::Message, "Message Only"),
+    (Encryption::Full, "Message, Title, Tags and Actions"),
+];
 
 mod imp {
     use super::*;
@@ -176,9 +184,10 @@ impl AdvancedMessageDialog {
                                     }
                                 },
                             },
-                            append: encrypt_switch = &adw::SwitchRow {
-                                set_title: "Encrypt Message",
-                                set_subtitle: "Encrypts the message body using the topic key",
+                            append: encryption_row = &adw::ComboRow {
+                                set_title: "Encryption",
+                                set_subtitle: "End-to-end encrypt using the topic password",
+                                set_model: Some(&gtk::StringList::new(&ENCRYPTION_MODES.map(|(_, label)| label))),
                             },
                             append = &gtk::Button {
                                 set_margin_top: 8,
@@ -186,10 +195,13 @@ impl AdvancedMessageDialog {
                                 add_css_class: "suggested-action",
                                 add_css_class: "pill",
                                 set_label: "Send",
-                                connect_clicked[this, toast_overlay, text_view, encrypt_switch] => move |_| {
+                                connect_clicked[this, toast_overlay, text_view, encryption_row] => move |_| {
                                     let thisc = this.clone();
                                     let text_viewc = text_view.clone();
-                                    let encrypt = encrypt_switch.is_active();
+                                    let encryption = ENCRYPTION_MODES
+                                        .get(encryption_row.selected() as usize)
+                                        .map(|(mode, _)| *mode)
+                                        .unwrap_or_default();
                                     let f = async move {
                                         let buffer = text_viewc.buffer();
                                         let msg = serde_json::from_str(&buffer.text(
@@ -198,7 +210,7 @@ impl AdvancedMessageDialog {
                                             true,
                                         ))?;
                                         thisc.imp().subscription.get().unwrap()
-                                            .publish_msg(msg, encrypt).await?;
+                                            .publish_msg(msg, encryption).await?;
                                         thisc.close();
                                         Ok(())
                                     };
@@ -1,7 +1,11 @@
 use adw::prelude::*;
 use adw::subclass::prelude::*;
 use gtk::glib;
-use ntfy_daemon::models::{FilterRule, FilterAction};
+use ntfy_daemon::models::{self, CommandHook, FilterAction, FilterRule, ForwardTransform};
+
+// Rows of the action combo that need more details
+const RUN_COMMAND: u32 = 3;
+const FORWARD: u32 = 4;
 
 mod imp {
     use super::*;
@@ -17,6 +21,24 @@ mod imp {
         #[template_child]
         pub action_combo: TemplateChild<adw::ComboRow>,
         #[template_child]
+        pub command_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
+        pub command_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub timeout_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub forward_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
+        pub forward_server_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub forward_topic_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub forward_title_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub forward_message_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub forward_encrypt_switch: TemplateChild<adw::SwitchRow>,
+        #[template_child]
         pub add_btn: TemplateChild<gtk::Button>,
         #[template_child]
         pub cancel_btn: TemplateChild<gtk::Button>,
@@ -44,6 +66,13 @@ mod imp {
             let this = self.obj();
             
             // Connect signals
+            let command_group = self.command_group.clone();
+            let forward_group = self.forward_group.clone();
+            self.action_combo.connect_selected_notify(move |combo| {
+                command_group.set_visible(combo.selected() == RUN_COMMAND);
+                forward_group.set_visible(combo.selected() == FORWARD);
+            });
+
             let this_weak = this.downgrade();
             self.add_btn.connect_clicked(move |_| {
                 if let Some(this) = this_weak.upgrade() {
@@ -89,6 +118,8 @@ impl FilterRuleDialog {
             0 => FilterAction::Mute,
             1 => FilterAction::Discard,
             2 => FilterAction::MarkRead,
+            RUN_COMMAND => FilterAction::Run(self.command_hook()?),
+            FORWARD => self.forward_action()?,
             _ => FilterAction::Mute,
         };
 
@@ -99,6 +130,58 @@ impl FilterRuleDialog {
         })
     }
     
+    // Arguments are separated by spaces, without quoting. Anything more complex goes in a script.
+    fn command_hook(&self) -> Option<CommandHook> {
+        let imp = self.imp();
+        let command = imp.command_entry.text();
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words.next()?;
+        let timeout = imp
+            .timeout_entry
+            .text()
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|t| *t > 0);
+        Some(CommandHook {
+            program,
+            args: words.collect(),
+            timeout,
+        })
+    }
+
+    fn forward_action(&self) -> Option<FilterAction> {
+        let imp = self.imp();
+        let server = imp
+            .forward_server_entry
+            .text()
+            .trim()
+            .trim_end_matches('/')
+            .to_string();
+        let topic = imp.forward_topic_entry.text().trim().to_string();
+        if models::validate_topic(&topic).is_err()
+            || models::Subscription::build_url(&server, &topic, 0).is_err()
+        {
+            return None;
+        }
+        let template =
+            |entry: &adw::EntryRow| Some(entry.text().to_string()).filter(|t| !t.is_empty());
+        let encryption = if imp.forward_encrypt_switch.is_active() {
+            models::Encryption::Full
+        } else {
+            models::Encryption::None
+        };
+        Some(FilterAction::Forward {
+            server,
+            topic,
+            transform: ForwardTransform {
+                title: template(&imp.forward_title_entry),
+                message: template(&imp.forward_message_entry),
+                encryption,
+            },
+        })
+    }
+
     fn emit_rule_added(&self) {
         // Since we don't have a formal GSignal for this yet, we can use a closure/callback pattern
         // or standard GAction. For simplicity, we assume the caller will connect to "closed" 
@@ -67,12 +67,13 @@ glib::wrapper! {
 }
 
 impl MessageRow {
-    pub fn new(msg: models::ReceivedMessage) -> Self {
+    // `read_until` is the one of the subscription of the message
+    pub fn new(msg: models::ReceivedMessage, read_until: u64) -> Self {
         let this: Self = glib::Object::new();
-        this.build_ui(msg);
+        this.build_ui(msg, read_until);
         this
     }
-    fn build_ui(&self, msg: models::ReceivedMessage) {
+    fn build_ui(&self, msg: models::ReceivedMessage, read_until: u64) {
         self.set_margin_top(8);
         self.set_margin_bottom(8);
         self.set_margin_start(8);
@@ -96,6 +97,60 @@ impl MessageRow {
         time.add_css_class("caption");
         self.attach(&time, 0, row, 1, 1);
 
+        let chips = gtk::Box::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .spacing(4)
+            .halign(gtk::Align::End)
+            .build();
+        let unread = msg.is_unread(read_until);
+        if unread {
+            let unread = gtk::Label::builder().label("Unread").xalign(0.0).build();
+            unread.add_css_class("caption");
+            unread.add_css_class("chip");
+            chips.append(&unread);
+        }
+        if msg.state.starred {
+            let starred = gtk::Image::from_icon_name("starred-symbolic");
+            starred.set_tooltip_text(Some("Starred"));
+            chips.append(&starred);
+        }
+        match &msg.decryption {
+            models::Decryption::Plaintext => {}
+            models::Decryption::Decrypted { key_id } => {
+                let encrypted = gtk::Image::from_icon_name("channel-secure-symbolic");
+                encrypted.set_tooltip_text(Some(&format!(
+                    "End-to-end encrypted with key {key_id}: {}",
+                    msg.encrypted_fields.join(", ")
+                )));
+                chips.append(&encrypted);
+            }
+            models::Decryption::Failed { .. } | models::Decryption::NoKey => {
+                let (text, tooltip) = match &msg.decryption {
+                    models::Decryption::Failed { reason } => {
+                        ("Decryption Failed", format!("Can't decrypt this message: {reason}"))
+                    }
+                    _ => (
+                        "No Key",
+                        "This message is encrypted. Add the topic password in the subscription info to read it.".to_string(),
+                    ),
+                };
+                let failed = gtk::Label::builder().label(text).xalign(0.0).build();
+                failed.add_css_class("caption");
+                failed.add_css_class("chip");
+                failed.add_css_class("chip--warning");
+                failed.set_tooltip_text(Some(&tooltip));
+                chips.append(&failed);
+
+                let retry = gtk::Button::builder()
+                    .icon_name("view-refresh-symbolic")
+                    .tooltip_text("Retry Decryption")
+                    .action_name("win.retry-decryption")
+                    .valign(gtk::Align::Center)
+                    .css_classes(vec!["flat", "circular"])
+                    .build();
+                chips.append(&retry);
+            }
+        }
         if let Some(p) = msg.priority {
             let text = format!(
                 "Priority: {}",
@@ -116,9 +171,10 @@ impl MessageRow {
             } else if p == 4 {
                 priority.add_css_class("chip--warning")
             }
-            priority.set_halign(gtk::Align::End);
-            self.attach(&priority, 1, 0, 2, 1);
+            chips.append(&priority);
         }
+        chips.append(&self.build_menu_btn(&msg, unread));
+        self.attach(&chips, 1, 0, 2, 1);
         row += 1;
 
         if let Some(title) = msg.display_title() {
@@ -187,6 +243,55 @@ impl MessageRow {
             self.attach(&tags, 0, row, 3, 1);
         }
     }
+    // Also opened by a right click on the row
+    fn build_menu_btn(&self, msg: &models::ReceivedMessage, unread: bool) -> gtk::MenuButton {
+        let menu = gio::Menu::new();
+        let section = gio::Menu::new();
+        let (label, read) = if unread {
+            ("Mark as _Read", true)
+        } else {
+            ("Mark as _Unread", false)
+        };
+        let item = gio::MenuItem::new(Some(label), None);
+        item.set_action_and_target_value(
+            Some("win.mark-message-read"),
+            Some(&(msg.id.clone(), read).to_variant()),
+        );
+        section.append_item(&item);
+        let label = if msg.state.starred { "Un_star" } else { "_Star" };
+        let item = gio::MenuItem::new(Some(label), None);
+        item.set_action_and_target_value(
+            Some("win.star-message"),
+            Some(&(msg.id.clone(), !msg.state.starred).to_variant()),
+        );
+        section.append_item(&item);
+        menu.append_section(None, &section);
+
+        let section = gio::Menu::new();
+        let item = gio::MenuItem::new(Some("_Delete"), None);
+        item.set_action_and_target_value(Some("win.delete-message"), Some(&msg.id.to_variant()));
+        section.append_item(&item);
+        menu.append_section(None, &section);
+
+        let btn = gtk::MenuButton::builder()
+            .icon_name("view-more-symbolic")
+            .tooltip_text("Message Actions")
+            .menu_model(&menu)
+            .valign(gtk::Align::Center)
+            .css_classes(vec!["flat", "circular"])
+            .build();
+
+        let gesture = gtk::GestureClick::builder()
+            .button(gdk::BUTTON_SECONDARY)
+            .build();
+        let btn_clone = btn.clone();
+        gesture.connect_pressed(move |_, _, _, _| {
+            btn_clone.popup();
+        });
+        self.add_controller(gesture);
+
+        btn
+    }
     fn fetch_image_bytes(url: &str) -> anyhow::Result<Vec<u8>> {
         let path = glib::user_cache_dir().join("io.github.tobagin.Ntfyr").join(&url);
         let bytes = if path.exists() {
@@ -4,6 +4,7 @@ mod add_subscription_dialog;
 mod advanced_message_dialog;
 mod message_row;
 mod preferences;
+mod server_settings_dialog;
 mod subscription_info_dialog;
 mod filter_rule_dialog;
 mod lock_view;
@@ -14,6 +15,7 @@ pub use add_subscription_dialog::AddSubscriptionDialog;
 pub use advanced_message_dialog::*;
 pub use message_row::*;
 pub use preferences::*;
+pub use server_settings_dialog::ServerSettingsDialog;
 pub use subscription_info_dialog::SubscriptionInfoDialog;
 pub use lock_view::LockView;
 pub use window::*;
@@ -4,6 +4,21 @@ use adw::prelude::*;
 use adw::subclass::prelude::*;
 use gtk::prelude::*;
 use gtk::{gio, glib};
+use ntfy_daemon::models::RetentionPolicy;
+
+use crate::error::*;
+
+const DAY: u64 = 24 * 60 * 60;
+const MB: u64 = 1024 * 1024;
+// Same order as the rows of the retention age combos
+pub const RETENTION_AGES: [(Option<u64>, &str); 6] = [
+    (None, "Forever"),
+    (Some(DAY), "For 1 Day"),
+    (Some(7 * DAY), "For 1 Week"),
+    (Some(30 * DAY), "For 1 Month"),
+    (Some(90 * DAY), "For 3 Months"),
+    (Some(365 * DAY), "For 1 Year"),
+];
 
 mod imp {
     use ntfy_daemon::NtfyHandle;
@@ -29,6 +44,16 @@ mod imp {
         pub show_default_server_switch: TemplateChild<adw::SwitchRow>,
         #[template_child]
         pub change_password_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub retention_age_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub retention_count_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub retention_size_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub drop_expired_switch: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub clean_up_btn: TemplateChild<adw::ButtonRow>,
         pub notifier: OnceCell<NtfyHandle>,
     }
 
@@ -43,6 +68,11 @@ mod imp {
                 auto_lock_timeout: Default::default(),
                 show_default_server_switch: Default::default(),
                 change_password_row: Default::default(),
+                retention_age_row: Default::default(),
+                retention_count_row: Default::default(),
+                retention_size_row: Default::default(),
+                drop_expired_switch: Default::default(),
+                clean_up_btn: Default::default(),
 
                 notifier: Default::default(),
             };
@@ -379,8 +409,120 @@ impl NtfyrPreferences {
              });
         });
 
+        obj.init_retention_ui();
+
         obj
     }
 
+    fn init_retention_ui(&self) {
+        let imp = self.imp();
+        imp.retention_age_row.set_model(Some(&gtk::StringList::new(
+            &RETENTION_AGES.map(|(_, label)| label),
+        )));
+
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let imp = this.imp();
+            let policy = imp.notifier.get().unwrap().retention_policy().await?;
+            let age_idx = RETENTION_AGES
+                .iter()
+                .position(|(age, _)| *age == policy.max_age)
+                .unwrap_or(0);
+            imp.retention_age_row.set_selected(age_idx as u32);
+            imp.retention_count_row
+                .set_value(policy.max_count.unwrap_or(0) as f64);
+            imp.retention_size_row
+                .set_value((policy.max_size.unwrap_or(0) / MB) as f64);
+            imp.drop_expired_switch.set_active(policy.drops_expired());
+
+            // Connected after loading so that the loaded values aren't saved back
+            let debouncer = crate::async_utils::Debouncer::new();
+            let save = {
+                let this = this.clone();
+                move || {
+                    let this = this.clone();
+                    debouncer.call(std::time::Duration::from_millis(500), move || {
+                        this.update_retention_policy();
+                    });
+                }
+            };
+            imp.retention_age_row.connect_selected_notify({
+                let save = save.clone();
+                move |_| save()
+            });
+            imp.retention_count_row.connect_value_notify({
+                let save = save.clone();
+                move |_| save()
+            });
+            imp.retention_size_row.connect_value_notify({
+                let save = save.clone();
+                move |_| save()
+            });
+            imp.drop_expired_switch.connect_active_notify(move |_| save());
+            Ok(())
+        });
+
+        let this = self.clone();
+        imp.clean_up_btn
+            .connect_activated(move |_| this.show_clean_up_dialog());
+    }
+
+    fn update_retention_policy(&self) {
+        let imp = self.imp();
+        let positive = |value: f64| (value >= 1.0).then_some(value as u64);
+        let policy = RetentionPolicy {
+            max_age: RETENTION_AGES
+                .get(imp.retention_age_row.selected() as usize)
+                .and_then(|(age, _)| *age),
+            max_count: positive(imp.retention_count_row.value()).map(|count| count as u32),
+            max_size: positive(imp.retention_size_row.value()).map(|mb| mb * MB),
+            drop_expired: imp.drop_expired_switch.is_active().then_some(true),
+        };
+        let notifier = imp.notifier.get().unwrap().clone();
+        self.error_boundary()
+            .spawn(async move { notifier.set_retention_policy(policy).await });
+    }
 
+    // Shows what the retention policies would remove before removing it
+    fn show_clean_up_dialog(&self) {
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let notifier = this.imp().notifier.get().unwrap().clone();
+            let reports = notifier.preview_prune().await?;
+            let count: usize = reports.iter().map(|r| r.count).sum();
+            let bytes: u64 = reports.iter().map(|r| r.bytes).sum();
+
+            let dialog = adw::AlertDialog::builder()
+                .heading("Clean Up Messages?")
+                .build();
+            dialog.add_response("cancel", "Cancel");
+            dialog.set_close_response("cancel");
+            if count == 0 {
+                dialog.set_body("All stored messages are within the limits. Compacting the database may still free some space.");
+                dialog.add_response("clean", "Compact");
+                dialog.set_default_response(Some("clean"));
+            } else {
+                let topics: Vec<String> = reports
+                    .iter()
+                    .map(|r| format!("{}: {} messages", r.topic, r.count))
+                    .collect();
+                dialog.set_body(&format!(
+                    "{count} messages ({}) will be removed.\n\n{}",
+                    glib::format_size(bytes),
+                    topics.join("\n")
+                ));
+                dialog.add_response("clean", "Remove");
+                dialog.set_response_appearance("clean", adw::ResponseAppearance::Destructive);
+                dialog.set_default_response(Some("cancel"));
+            }
+
+            if dialog.choose_future(Some(&this)).await != "clean" {
+                return Ok(());
+            }
+            notifier.prune_messages().await?;
+            notifier.vacuum().await?;
+            this.add_toast(adw::Toast::new("Messages cleaned up"));
+            Ok(())
+        });
+    }
 }
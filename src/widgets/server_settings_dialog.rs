@@ -0,0 +1,197 @@
+use std::cell::RefCell;
+use adw::prelude::*;
+use adw::subclass::prelude::*;
+use glib::subclass::Signal;
+use gtk::gio;
+use gtk::glib;
+use ntfy_daemon::models::{ServerSettings, Transport};
+use once_cell::sync::Lazy;
+
+// Same order as the rows of the transport combo
+const TRANSPORTS: [(Transport, &str); 3] = [
+    (Transport::Ndjson, "JSON Stream"),
+    (Transport::WebSocket, "WebSocket"),
+    (Transport::Sse, "Server-Sent Events"),
+];
+
+#[derive(Default, Debug, Clone)]
+pub struct Widgets {
+    pub transport_row: adw::ComboRow,
+    pub poll_row: adw::SwitchRow,
+    pub poll_interval_row: adw::SpinRow,
+    pub keepalive_row: adw::SpinRow,
+    pub legacy_encryption_row: adw::SwitchRow,
+}
+
+mod imp {
+    pub use super::*;
+    #[derive(Debug, Default)]
+    pub struct ServerSettingsDialog {
+        pub widgets: RefCell<Widgets>,
+        pub server: RefCell<String>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for ServerSettingsDialog {
+        const NAME: &'static str = "ServerSettingsDialog";
+        type Type = super::ServerSettingsDialog;
+        type ParentType = adw::Dialog;
+    }
+
+    impl ObjectImpl for ServerSettingsDialog {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> =
+                Lazy::new(|| vec![Signal::builder("save").build()]);
+            SIGNALS.as_ref()
+        }
+    }
+    impl WidgetImpl for ServerSettingsDialog {}
+    impl AdwDialogImpl for ServerSettingsDialog {}
+}
+
+glib::wrapper! {
+    pub struct ServerSettingsDialog(ObjectSubclass<imp::ServerSettingsDialog>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gio::ActionMap, gio::ActionGroup, gtk::Root, gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Native, gtk::ShortcutManager;
+}
+
+impl ServerSettingsDialog {
+    pub fn new(server: &str, settings: &ServerSettings) -> Self {
+        let this: Self = glib::Object::builder().build();
+        this.imp().server.replace(server.to_string());
+        this.build_ui(server, settings);
+        this
+    }
+
+    fn build_ui(&self, server: &str, settings: &ServerSettings) {
+        let imp = self.imp();
+        let obj = self.clone();
+        obj.set_title("Server Settings");
+
+        let transports =
+            gtk::StringList::new(&TRANSPORTS.map(|(_, label)| label));
+        let selected = TRANSPORTS
+            .iter()
+            .position(|(t, _)| *t == settings.transport)
+            .unwrap_or(0);
+
+        relm4_macros::view! {
+            toolbar_view = adw::ToolbarView {
+                add_top_bar: &adw::HeaderBar::new(),
+                #[wrap(Some)]
+                set_content = &gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_spacing: 12,
+                    set_margin_end: 12,
+                    set_margin_start: 12,
+                    set_margin_top: 12,
+                    set_margin_bottom: 12,
+                    append = &gtk::Label {
+                        add_css_class: "dim-label",
+                        set_label: server,
+                        set_wrap: true,
+                        set_xalign: 0.0,
+                        set_halign: gtk::Align::Center,
+                    },
+                    append = &gtk::ListBox {
+                        add_css_class: "boxed-list",
+                        append: transport_row = &adw::ComboRow {
+                            set_title: "Connection",
+                            set_subtitle: "How messages are streamed from the server",
+                            set_model: Some(&transports),
+                            set_selected: selected as u32,
+                        },
+                        append: poll_row = &adw::SwitchRow {
+                            set_title: "Poll Periodically",
+                            set_subtitle: "Check for messages on an interval instead of staying connected",
+                            set_active: settings.poll,
+                        },
+                        append: poll_interval_row = &adw::SpinRow {
+                            set_title: "Poll Interval",
+                            set_subtitle: "Minutes between two checks",
+                            set_adjustment: Some(&gtk::Adjustment::new(
+                                (settings.poll_interval / 60) as f64,
+                                1.0,
+                                24.0 * 60.0,
+                                1.0,
+                                15.0,
+                                0.0,
+                            )),
+                        },
+                        append: keepalive_row = &adw::SpinRow {
+                            set_title: "Keepalive Interval",
+                            set_subtitle: "Seconds between keepalives sent by the server, 0 to detect it automatically",
+                            set_adjustment: Some(&gtk::Adjustment::new(
+                                settings.keepalive_interval.unwrap_or(0) as f64,
+                                0.0,
+                                60.0 * 60.0,
+                                1.0,
+                                15.0,
+                                0.0,
+                            )),
+                        },
+                        append: legacy_encryption_row = &adw::SwitchRow {
+                            set_title: "Read Legacy Encrypted Messages",
+                            set_subtitle: "Decrypt messages sent by older versions of Ntfyr with a base64 key",
+                            set_active: settings.legacy_encryption,
+                        },
+                    },
+                    append = &gtk::Button {
+                        set_label: "Save",
+                        add_css_class: "suggested-action",
+                        add_css_class: "pill",
+                        set_halign: gtk::Align::Center,
+                        connect_clicked[obj] => move |_| {
+                            obj.emit_by_name::<()>("save", &[]);
+                        }
+                    }
+                },
+            },
+        }
+
+        poll_row
+            .bind_property("active", &transport_row, "sensitive")
+            .invert_boolean()
+            .sync_create()
+            .build();
+        poll_row
+            .bind_property("active", &poll_interval_row, "sensitive")
+            .sync_create()
+            .build();
+        poll_row
+            .bind_property("active", &keepalive_row, "sensitive")
+            .invert_boolean()
+            .sync_create()
+            .build();
+
+        imp.widgets.replace(Widgets {
+            transport_row,
+            poll_row,
+            poll_interval_row,
+            keepalive_row,
+            legacy_encryption_row,
+        });
+
+        obj.set_content_width(400);
+        obj.set_child(Some(&toolbar_view));
+    }
+
+    pub fn server(&self) -> String {
+        self.imp().server.borrow().clone()
+    }
+
+    pub fn settings(&self) -> ServerSettings {
+        let w = self.imp().widgets.borrow();
+        let selected = w.transport_row.selected() as usize;
+        ServerSettings {
+            transport: TRANSPORTS
+                .get(selected)
+                .map(|(t, _)| *t)
+                .unwrap_or_default(),
+            poll: w.poll_row.is_active(),
+            poll_interval: w.poll_interval_row.value() as u64 * 60,
+            keepalive_interval: Some(w.keepalive_row.value() as u64).filter(|secs| *secs > 0),
+            legacy_encryption: w.legacy_encryption_row.is_active(),
+        }
+    }
+}
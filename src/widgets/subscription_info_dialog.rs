@@ -7,6 +7,19 @@ use gtk::gio;
 use gtk::glib;
 
 use crate::error::*;
+use crate::widgets::RETENTION_AGES;
+use ntfy_daemon::keys::{RotationPolicy, TopicKey};
+use ntfy_daemon::models::{FilterAction, HookRun};
+use ntfy_daemon::share::TopicShare;
+
+const MB: u64 = 1024 * 1024;
+
+// Same order as the rows of the rotation policy combo
+const ROTATION_POLICIES: [(RotationPolicy, &str); 3] = [
+    (RotationPolicy::Retain, "Keep Old Keys"),
+    (RotationPolicy::ExpireAfter(30 * 24 * 60 * 60), "Keep Old Keys for 30 Days"),
+    (RotationPolicy::Discard, "Remove Old Keys"),
+];
 
 mod imp {
     pub use super::*;
@@ -22,6 +35,15 @@ mod imp {
         pub muted_switch_row: TemplateChild<adw::SwitchRow>,
         #[template_child]
         pub encryption_key_entry: TemplateChild<adw::PasswordEntryRow>,
+        #[template_child]
+        pub key_history_row: TemplateChild<adw::ExpanderRow>,
+        #[template_child]
+        pub generate_key_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub rotate_key_btn: TemplateChild<adw::ButtonRow>,
+        #[template_child]
+        pub share_topic_btn: TemplateChild<adw::ButtonRow>,
+        pub key_rows: RefCell<Vec<adw::ActionRow>>,
         
         // Schedule
         #[template_child]
@@ -33,11 +55,31 @@ mod imp {
         #[template_child]
         pub schedule_days_box: TemplateChild<gtk::Box>,
 
+        // Server filters
+        #[template_child]
+        pub priority_filter_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub tags_filter_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub title_filter_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub message_filter_entry: TemplateChild<adw::EntryRow>,
+
+        // Retention
+        #[template_child]
+        pub retention_age_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub retention_count_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub retention_size_entry: TemplateChild<adw::EntryRow>,
+
         // Rules
         #[template_child]
         pub rules_list: TemplateChild<gtk::ListBox>,
         #[template_child]
         pub add_rule_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub hook_runs_list: TemplateChild<gtk::ListBox>,
     }
 
     #[glib::object_subclass]
@@ -69,8 +111,13 @@ mod imp {
             
             // Init Schedule
             this.init_schedule_ui(&sub);
+            // Init Server Filters
+            this.init_server_filter_ui(&sub);
+            // Init Retention
+            this.init_retention_ui(&sub);
              // Init Rules
             this.init_rules_ui(&sub);
+            this.init_hook_runs_ui(&sub);
              // Init Encryption
             this.init_encryption_ui(&sub);
 
@@ -127,6 +174,41 @@ mod imp {
                 i = child.next_sibling();
             }
 
+            // Server Filter Signals
+            for entry in [
+                &*self.priority_filter_entry,
+                &*self.tags_filter_entry,
+                &*self.title_filter_entry,
+                &*self.message_filter_entry,
+            ] {
+                let this_weak = this.downgrade();
+                let debouncer = debouncer.clone();
+                entry.connect_changed(move |_| {
+                    let Some(this) = this_weak.upgrade() else { return; };
+                    debouncer.call(std::time::Duration::from_millis(500), move || {
+                        this.update_server_filter();
+                    });
+                });
+            }
+
+            // Retention Signals
+            let this_weak = this.downgrade();
+            self.retention_age_row.connect_selected_notify(move |_| {
+                if let Some(this) = this_weak.upgrade() {
+                    this.update_retention();
+                }
+            });
+            for entry in [&*self.retention_count_entry, &*self.retention_size_entry] {
+                let this_weak = this.downgrade();
+                let debouncer = debouncer.clone();
+                entry.connect_changed(move |_| {
+                    let Some(this) = this_weak.upgrade() else { return; };
+                    debouncer.call(std::time::Duration::from_millis(500), move || {
+                        this.update_retention();
+                    });
+                });
+            }
+
             // Rules Signals
             let this_weak = this.downgrade();
             self.add_rule_btn.connect_clicked(move |_| {
@@ -141,16 +223,30 @@ mod imp {
                 }
             });
 
-            // Encryption Signal
-            let debouncer_clone = debouncer.clone();
+            // Encryption Signal, applied at once so that a half typed password doesn't
+            // become a key
             let this_weak = this.downgrade();
-            self.encryption_key_entry.connect_notify_local(Some("text"), move |entry, _| {
+            self.encryption_key_entry.connect_apply(move |entry| {
                 if let Some(this) = this_weak.upgrade() {
-                     let entry = entry.clone();
-                     let debouncer = debouncer_clone.clone();
-                     debouncer.call(std::time::Duration::from_millis(500), move || {
-                         this.update_encryption_key(&entry);
-                     });
+                    this.update_encryption_key(entry);
+                }
+            });
+            let this_weak = this.downgrade();
+            self.rotate_key_btn.connect_activated(move |_| {
+                if let Some(this) = this_weak.upgrade() {
+                    this.show_rotate_key_dialog();
+                }
+            });
+            let this_weak = this.downgrade();
+            self.generate_key_btn.connect_clicked(move |_| {
+                if let Some(this) = this_weak.upgrade() {
+                    this.generate_key();
+                }
+            });
+            let this_weak = this.downgrade();
+            self.share_topic_btn.connect_activated(move |_| {
+                if let Some(this) = this_weak.upgrade() {
+                    this.show_share_dialog();
                 }
             });
         }
@@ -189,6 +285,118 @@ impl SubscriptionInfoDialog {
         }
     }
 
+    fn init_server_filter_ui(&self, sub: &crate::subscription::Subscription) {
+        let imp = self.imp();
+        let filter = sub.get_server_filter().unwrap_or_default();
+        let priority: Vec<String> = filter.priority.iter().map(|p| p.to_string()).collect();
+        imp.priority_filter_entry.set_text(&priority.join(","));
+        imp.tags_filter_entry.set_text(&filter.tags.join(","));
+        imp.title_filter_entry
+            .set_text(filter.title.as_deref().unwrap_or_default());
+        imp.message_filter_entry
+            .set_text(filter.message.as_deref().unwrap_or_default());
+    }
+
+    fn update_server_filter(&self) {
+        let imp = self.imp();
+        let split = |text: glib::GString| -> Vec<String> {
+            text.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        };
+        let non_empty = |text: glib::GString| (!text.is_empty()).then(|| text.to_string());
+
+        let priority: Result<Vec<i8>, _> = split(imp.priority_filter_entry.text())
+            .iter()
+            .map(|p| p.parse::<i8>().ok().filter(|p| (1..=5).contains(p)).ok_or(()))
+            .collect();
+        let Ok(priority) = priority else {
+            imp.priority_filter_entry.add_css_class("error");
+            return;
+        };
+        imp.priority_filter_entry.remove_css_class("error");
+
+        let filter = ntfy_daemon::models::ServerFilter {
+            priority,
+            tags: split(imp.tags_filter_entry.text()),
+            title: non_empty(imp.title_filter_entry.text()),
+            message: non_empty(imp.message_filter_entry.text()),
+        };
+        let filter = (!filter.is_empty()).then_some(filter);
+
+        let sub = self.subscription().unwrap();
+        if sub.get_server_filter() == filter {
+            return;
+        }
+        self.error_boundary()
+            .spawn(async move { sub.set_server_filter(filter).await });
+    }
+
+    fn init_retention_ui(&self, sub: &crate::subscription::Subscription) {
+        let imp = self.imp();
+        let mut labels = vec!["Use Default"];
+        labels.extend(RETENTION_AGES[1..].iter().map(|(_, label)| *label));
+        imp.retention_age_row
+            .set_model(Some(&gtk::StringList::new(&labels)));
+
+        let retention = sub.get_retention().unwrap_or_default();
+        let age_idx = RETENTION_AGES
+            .iter()
+            .position(|(age, _)| age.is_some() && *age == retention.max_age)
+            .unwrap_or(0);
+        imp.retention_age_row.set_selected(age_idx as u32);
+        imp.retention_count_entry.set_text(
+            &retention
+                .max_count
+                .map(|count| count.to_string())
+                .unwrap_or_default(),
+        );
+        imp.retention_size_entry.set_text(
+            &retention
+                .max_size
+                .map(|size| (size / MB).to_string())
+                .unwrap_or_default(),
+        );
+    }
+
+    fn update_retention(&self) {
+        let imp = self.imp();
+        // None when empty, Err when not a number
+        fn parse<T: std::str::FromStr>(entry: &adw::EntryRow) -> Result<Option<T>, ()> {
+            let text = entry.text();
+            let text = text.trim();
+            entry.remove_css_class("error");
+            if text.is_empty() {
+                return Ok(None);
+            }
+            text.parse().map(Some).map_err(|_| entry.add_css_class("error"))
+        }
+        let sub = self.subscription().unwrap();
+        let (Ok(max_count), Ok(max_size)) = (
+            parse::<u32>(&imp.retention_count_entry),
+            parse::<u64>(&imp.retention_size_entry),
+        ) else {
+            return;
+        };
+
+        let retention = ntfy_daemon::models::RetentionPolicy {
+            max_age: RETENTION_AGES
+                .get(imp.retention_age_row.selected() as usize)
+                .and_then(|(age, _)| *age),
+            max_count,
+            max_size: max_size.map(|mb| mb * MB),
+            drop_expired: sub.get_retention().and_then(|r| r.drop_expired),
+        };
+        let retention = (!retention.is_empty()).then_some(retention);
+
+        if sub.get_retention() == retention {
+            return;
+        }
+        self.error_boundary()
+            .spawn(async move { sub.set_retention(retention).await });
+    }
+
     // Map UI index (0=Mon...6=Sun) to Model day (0=Sun...6=Sat)
     fn ui_idx_to_model_day(idx: i32) -> u8 {
         ((idx + 1) % 7) as u8
@@ -277,9 +485,20 @@ impl SubscriptionInfoDialog {
 
     fn add_rule_row(&self, rule: &ntfy_daemon::models::FilterRule) {
         let imp = self.imp();
+        let action = match &rule.action {
+            FilterAction::Run(hook) => {
+                let command: Vec<&str> = std::iter::once(&hook.program)
+                    .chain(&hook.args)
+                    .map(String::as_str)
+                    .collect();
+                format!("Run {}", command.join(" "))
+            }
+            FilterAction::Forward { server, topic, .. } => format!("Forward to {server}/{topic}"),
+            action => format!("{:?}", action),
+        };
         let row = adw::ActionRow::builder()
             .title(&rule.name)
-            .subtitle(format!("Regex: {} -> Action: {:?}", rule.regex, rule.action))
+            .subtitle(format!("Regex: {} -> Action: {}", rule.regex, action))
             .build();
         
         // Add delete button
@@ -301,6 +520,50 @@ impl SubscriptionInfoDialog {
         imp.rules_list.append(&row);
     }
     
+    fn init_hook_runs_ui(&self, sub: &crate::subscription::Subscription) {
+        let this = self.clone();
+        let sub = sub.clone();
+        self.error_boundary().spawn(async move {
+            let runs = sub.list_hook_runs().await?;
+            let list = &this.imp().hook_runs_list;
+            while let Some(row) = list.row_at_index(0) {
+                list.remove(&row);
+            }
+            for run in runs {
+                list.append(&Self::hook_run_row(&run));
+            }
+            Ok(())
+        });
+    }
+
+    fn hook_run_row(run: &HookRun) -> adw::ActionRow {
+        let time = chrono::DateTime::from_timestamp(run.started_at as i64, 0)
+            .map(|t| {
+                t.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        let mut subtitle = format!("{time}, took {} ms", run.duration_ms);
+        if let Some(error) = &run.error {
+            subtitle.push_str(&format!("\n{error}"));
+        }
+        let row = adw::ActionRow::builder()
+            .title(&run.rule)
+            .subtitle(subtitle)
+            .subtitle_lines(2)
+            .build();
+
+        let status = match run.exit_code {
+            Some(code) => format!("Exit {code}"),
+            None => "Failed".to_string(),
+        };
+        let label = gtk::Label::new(Some(&status));
+        label.add_css_class(if run.succeeded() { "success" } else { "error" });
+        row.add_suffix(&label);
+        row
+    }
+
     fn delete_rule(&self, rule_to_delete: &ntfy_daemon::models::FilterRule) {
          let sub = self.subscription().unwrap();
          if let Some(mut rules) = sub.get_rules() {
@@ -351,11 +614,211 @@ impl SubscriptionInfoDialog {
                  if let Some(key) = key {
                      this_inner.imp().encryption_key_entry.set_text(&key);
                  }
+                 let keys = notifier.list_keys(sub.server().as_str(), sub.topic().as_str()).await?;
+                 this_inner.show_key_history(&keys);
             }
             Ok(())
         });
     }
 
+    fn show_key_history(&self, keys: &[TopicKey]) {
+        let imp = self.imp();
+        for row in imp.key_rows.take() {
+            imp.key_history_row.remove(&row);
+        }
+        imp.key_history_row.set_sensitive(!keys.is_empty());
+
+        let format_time = |secs: u64| {
+            chrono::DateTime::from_timestamp(secs as i64, 0)
+                .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d").to_string())
+                .unwrap_or_default()
+        };
+        let mut rows = vec![];
+        for key in keys {
+            let mut subtitle = format!("Created {}", format_time(key.created_at));
+            if let Some(expires_at) = key.expires_at {
+                subtitle.push_str(&format!(", expires {}", format_time(expires_at)));
+            }
+            let row = adw::ActionRow::builder()
+                .title(format!("Key {}", key.id))
+                .subtitle(subtitle)
+                .build();
+
+            if key.current {
+                let label = gtk::Label::new(Some("Current"));
+                label.add_css_class("dim-label");
+                row.add_suffix(&label);
+            } else {
+                let btn = gtk::Button::builder()
+                    .icon_name("user-trash-symbolic")
+                    .valign(gtk::Align::Center)
+                    .tooltip_text("Remove Key")
+                    .css_classes(vec!["flat"])
+                    .build();
+                let this_weak = self.downgrade();
+                let id = key.id.clone();
+                btn.connect_clicked(move |_| {
+                    if let Some(this) = this_weak.upgrade() {
+                        this.remove_key(&id);
+                    }
+                });
+                row.add_suffix(&btn);
+            }
+            imp.key_history_row.add_row(&row);
+            rows.push(row);
+        }
+        imp.key_rows.replace(rows);
+    }
+
+    fn remove_key(&self, id: &str) {
+        let Some(sub) = self.subscription() else { return };
+        let Some(window) = self.root().and_downcast::<crate::widgets::NtfyrWindow>() else {
+            return;
+        };
+        let this = self.clone();
+        let id = id.to_string();
+        self.error_boundary().spawn(async move {
+            let notifier = window.notifier();
+            notifier.remove_topic_key(&sub.server(), &sub.topic(), &id).await?;
+            this.show_key_history(&notifier.list_keys(&sub.server(), &sub.topic()).await?);
+            Ok(())
+        });
+    }
+
+    fn show_rotate_key_dialog(&self) {
+        relm4_macros::view! {
+            content = &gtk::ListBox {
+                add_css_class: "boxed-list",
+                set_selection_mode: gtk::SelectionMode::None,
+                append: key_entry = &adw::PasswordEntryRow {
+                    set_title: "New Password",
+                },
+                append: policy_row = &adw::ComboRow {
+                    set_title: "Previous Keys",
+                    set_model: Some(&gtk::StringList::new(&ROTATION_POLICIES.map(|(_, label)| label))),
+                },
+            }
+        }
+        let dialog = adw::AlertDialog::builder()
+            .heading("Rotate Key?")
+            .body("New messages are encrypted with the new key. Senders must switch to the same password.")
+            .extra_child(&content)
+            .build();
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("rotate", "Rotate");
+        dialog.set_response_appearance("rotate", adw::ResponseAppearance::Suggested);
+        dialog.set_response_enabled("rotate", false);
+        dialog.set_default_response(Some("rotate"));
+        dialog.set_close_response("cancel");
+        key_entry.connect_changed({
+            let dialog = dialog.clone();
+            move |entry| dialog.set_response_enabled("rotate", !entry.text().is_empty())
+        });
+
+        let this = self.clone();
+        dialog.choose(Some(self), gio::Cancellable::NONE, move |response| {
+            if response != "rotate" {
+                return;
+            }
+            let Some(sub) = this.subscription() else { return };
+            let Some(window) = this.root().and_downcast::<crate::widgets::NtfyrWindow>() else {
+                return;
+            };
+            let key = key_entry.text().to_string();
+            let policy = ROTATION_POLICIES
+                .get(policy_row.selected() as usize)
+                .map(|(policy, _)| *policy)
+                .unwrap_or_default();
+            let this = this.clone();
+            this.clone().error_boundary().spawn(async move {
+                let notifier = window.notifier();
+                notifier.rotate_key(&sub.server(), &sub.topic(), &key, policy).await?;
+                this.imp().encryption_key_entry.set_text(&key);
+                this.show_key_history(&notifier.list_keys(&sub.server(), &sub.topic()).await?);
+                Ok(())
+            });
+        });
+    }
+
+    // Old keys are kept so that messages sent before the change still decrypt
+    fn generate_key(&self) {
+        let Some(sub) = self.subscription() else { return };
+        let Some(window) = self.root().and_downcast::<crate::widgets::NtfyrWindow>() else {
+            return;
+        };
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let notifier = window.notifier();
+            let key = notifier
+                .generate_key(&sub.server(), &sub.topic(), RotationPolicy::Retain)
+                .await?;
+            this.imp().encryption_key_entry.set_text(&key.secret);
+            this.show_key_history(&notifier.list_keys(&sub.server(), &sub.topic()).await?);
+            Ok(())
+        });
+    }
+
+    fn show_share_dialog(&self) {
+        let Some(sub) = self.subscription() else { return };
+        let Some(window) = self.root().and_downcast::<crate::widgets::NtfyrWindow>() else {
+            return;
+        };
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let key = window.notifier().get_key(&sub.server(), &sub.topic()).await?;
+            let has_key = key.is_some();
+            let uri = TopicShare {
+                server: sub.server().to_string(),
+                topic: sub.topic().to_string(),
+                key,
+            }
+            .to_uri()?;
+            let texture = crate::qr::texture(&uri)?;
+
+            relm4_macros::view! {
+                content = &gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_spacing: 12,
+                    append = &gtk::Picture {
+                        set_paintable: Some(&texture),
+                        set_size_request: (240, 240),
+                        set_halign: gtk::Align::Center,
+                        set_can_shrink: true,
+                    },
+                    append = &gtk::Label {
+                        set_label: &uri,
+                        set_selectable: true,
+                        set_wrap: true,
+                        set_wrap_mode: gtk::pango::WrapMode::Char,
+                        add_css_class: "monospace",
+                    },
+                }
+            }
+            let body = if has_key {
+                "Scan the code or open the link on another device. Anyone with it can read the messages of this topic."
+            } else {
+                "Scan the code or open the link on another device. The topic has no encryption key."
+            };
+            let dialog = adw::AlertDialog::builder()
+                .heading("Share Topic")
+                .body(body)
+                .extra_child(&content)
+                .build();
+            dialog.add_response("close", "Close");
+            dialog.add_response("copy", "Copy Link");
+            dialog.set_default_response(Some("copy"));
+            dialog.set_close_response("close");
+
+            let parent = this.clone();
+            dialog.choose(Some(&parent), gio::Cancellable::NONE, move |response| {
+                if response == "copy" {
+                    this.clipboard().set_text(&uri);
+                }
+            });
+            Ok(())
+        });
+    }
+
     fn update_encryption_key(&self, entry: &impl IsA<gtk::Editable>) {
         if let Some(sub) = self.subscription() {
             let key = entry.text().to_string();
@@ -364,6 +827,7 @@ impl SubscriptionInfoDialog {
             // We need to access the application to get the notifier handle
             // Or add a method to Subscription wrapper to set key
             if let Some(window) = self.root().and_downcast::<crate::widgets::NtfyrWindow>() {
+                 let this = self.clone();
                  window.error_boundary().spawn(async move {
                      let notifier = window.notifier();
                      if key.is_empty() {
@@ -371,6 +835,7 @@ impl SubscriptionInfoDialog {
                      } else {
                          notifier.add_key(sub.server().as_str(), sub.topic().as_str(), &key).await?;
                      }
+                     this.show_key_history(&notifier.list_keys(&sub.server(), &sub.topic()).await?);
                      Ok(())
                  });
             }
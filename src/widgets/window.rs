@@ -5,8 +5,12 @@ use adw::prelude::*;
 use adw::subclass::prelude::*;
 
 use gtk::{gio, glib};
+use ntfy_daemon::backup::Backup;
+use ntfy_daemon::import::ClientBackup;
 use ntfy_daemon::models;
-use ntfy_daemon::NtfyHandle;
+use ntfy_daemon::share::TopicShare;
+use ntfy_daemon::{NtfyHandle, SubscriptionChange};
+use tokio::sync::broadcast;
 use tracing::{info, warn};
 
 use crate::application::NtfyrApplication;
@@ -17,6 +21,22 @@ use crate::subscription::Status;
 use crate::subscription::Subscription;
 use crate::widgets::*;
 
+// Search hits loaded at a time
+const SEARCH_PAGE_SIZE: u32 = 50;
+
+const DAY: u64 = 24 * 60 * 60;
+// Time ranges offered when exporting the message history, as how far back they go
+const HISTORY_RANGES: [(Option<u64>, &str); 4] = [
+    (None, "All Time"),
+    (Some(DAY), "Last Day"),
+    (Some(7 * DAY), "Last Week"),
+    (Some(30 * DAY), "Last Month"),
+];
+const HISTORY_FORMATS: [(models::HistoryFormat, &str); 2] = [
+    (models::HistoryFormat::JsonLines, "JSON Lines"),
+    (models::HistoryFormat::Csv, "CSV"),
+];
+
 mod imp {
     use super::*;
 
@@ -65,8 +85,20 @@ mod imp {
         #[template_child]
         pub unified_inbox_view: TemplateChild<adw::ToolbarView>,
         #[template_child]
+        pub unified_message_scroll: TemplateChild<gtk::ScrolledWindow>,
+        #[template_child]
         pub unified_message_list: TemplateChild<gtk::ListBox>,
 
+        // Search
+        #[template_child]
+        pub search_view: TemplateChild<adw::ToolbarView>,
+        #[template_child]
+        pub search_entry: TemplateChild<gtk::SearchEntry>,
+        #[template_child]
+        pub search_results_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub search_status: TemplateChild<adw::StatusPage>,
+
         pub notifier: OnceCell<NtfyHandle>,
         pub conn: OnceCell<gio::SocketConnection>,
         pub settings: gio::Settings,
@@ -96,7 +128,12 @@ mod imp {
                 banner: Default::default(),
                content_stack: Default::default(),
                 unified_inbox_view: Default::default(),
+                unified_message_scroll: Default::default(),
                 unified_message_list: Default::default(),
+                search_view: Default::default(),
+                search_entry: Default::default(),
+                search_results_list: Default::default(),
+                search_status: Default::default(),
                 subscription_list_model: gio::ListStore::new::<Subscription>(),
                 settings: gio::Settings::new(APP_ID),
                 notifier: Default::default(),
@@ -176,11 +213,81 @@ mod imp {
                         .spawn(async move { sub.clear_notifications().await });
                 });
             });
+            klass.install_action("win.mark-all-read", None, |this, _, _| {
+                this.selected_subscription().map(|sub| {
+                    this.error_boundary()
+                        .spawn(async move { sub.mark_all_read().await });
+                });
+            });
+            klass.install_action("win.retry-decryption", None, |this, _, _| {
+                this.retry_decryption();
+            });
+            klass.install_action(
+                "win.mark-message-read",
+                Some(glib::VariantTy::new("(sb)").unwrap()),
+                |this, _, param| {
+                    let Some((id, read)) = param.and_then(|p| p.get::<(String, bool)>()) else {
+                        return;
+                    };
+                    let Some(sub) = this.message_subscription(&id) else {
+                        return;
+                    };
+                    this.error_boundary()
+                        .spawn(async move { sub.set_message_read(id, read).await });
+                },
+            );
+            klass.install_action(
+                "win.star-message",
+                Some(glib::VariantTy::new("(sb)").unwrap()),
+                |this, _, param| {
+                    let Some((id, starred)) = param.and_then(|p| p.get::<(String, bool)>())
+                    else {
+                        return;
+                    };
+                    let Some(sub) = this.message_subscription(&id) else {
+                        return;
+                    };
+                    this.error_boundary()
+                        .spawn(async move { sub.set_message_starred(id, starred).await });
+                },
+            );
+            klass.install_action(
+                "win.delete-message",
+                Some(glib::VariantTy::STRING),
+                |this, _, param| {
+                    let Some(id) = param.and_then(|p| p.get::<String>()) else {
+                        return;
+                    };
+                    let Some(sub) = this.message_subscription(&id) else {
+                        return;
+                    };
+                    this.error_boundary()
+                        .spawn(async move { sub.delete_message(id).await });
+                },
+            );
             klass.install_action("win.add-topic", None, |this, _, _| {
                 this.imp().show_add_topic(&gtk::Button::new());
             });
+            klass.install_action("win.import-topic", None, |this, _, _| {
+                this.show_import_topic_dialog();
+            });
+            klass.install_action("win.export-history", None, |this, _, _| {
+                this.show_export_history_dialog();
+            });
+            klass.install_action("win.import-history", None, |this, _, _| {
+                this.show_import_history_dialog();
+            });
+            klass.install_action("win.backup", None, |this, _, _| {
+                this.show_backup_dialog();
+            });
+            klass.install_action("win.restore", None, |this, _, _| {
+                this.restore_backup();
+            });
+            klass.install_action("win.import-ntfy", None, |this, _, _| {
+                this.import_ntfy_backup();
+            });
             klass.install_action("win.search", None, |this, _, _| {
-                this.imp().entry.grab_focus();
+                this.show_search();
             });
             //klass.bind_template_instance_callbacks();
         }
@@ -246,11 +353,14 @@ impl NtfyrWindow {
         obj.bind_message_list();
         obj.connect_entry_and_send_btn();
         obj.connect_code_btn();
+        obj.connect_search();
         obj.connect_items_changed();
         obj.connect_settings_changed();
         obj.connect_server_changes();
         obj.selected_subscription_changed(None);
         obj.bind_flag_read();
+        obj.bind_load_older();
+        obj.follow_subscription_changes();
 
         obj
     }
@@ -272,7 +382,7 @@ impl NtfyrWindow {
                 .publish_msg(models::OutgoingMessage {
                     message: Some(entry.text().as_str().to_string()),
                     ..models::OutgoingMessage::default()
-                }, false)
+                }, models::Encryption::None)
                 .await?;
             entry.set_text("");
             Ok(())
@@ -289,6 +399,136 @@ impl NtfyrWindow {
             });
         });
     }
+    fn connect_search(&self) {
+        let imp = self.imp();
+        let this = self.clone();
+        imp.search_entry
+            .connect_search_changed(move |entry| this.search(entry.text().to_string(), 0));
+        let this = self.clone();
+        imp.search_entry.connect_stop_search(move |_| {
+            let imp = this.imp();
+            if imp.subscription_list_model.n_items() == 0 {
+                imp.content_stack.set_visible_child(&*imp.welcome_view);
+            } else {
+                imp.content_stack.set_visible_child(&*imp.subscription_view);
+            }
+        });
+    }
+
+    fn show_search(&self) {
+        let imp = self.imp();
+        imp.content_stack.set_visible_child(&*imp.search_view);
+        imp.navigation_split_view.set_show_content(true);
+        imp.search_entry.grab_focus();
+    }
+
+    // Shows the hits from `offset` on, replacing the previous ones when starting over
+    fn search(&self, query: String, offset: u32) {
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let hits = if query.trim().is_empty() {
+                vec![]
+            } else {
+                this.notifier()
+                    .search_messages(&query, None, None, SEARCH_PAGE_SIZE, offset)
+                    .await?
+            };
+            let imp = this.imp();
+            // Results of a query that was typed over
+            if imp.search_entry.text() != query {
+                return Ok(());
+            }
+
+            let list = &imp.search_results_list;
+            // Everything from `offset` on, i.e. the "Show More" row of the previous page
+            while let Some(row) = list.row_at_index(offset as i32) {
+                list.remove(&row);
+            }
+            if query.trim().is_empty() {
+                imp.search_status.set_title("Search Messages");
+                imp.search_status
+                    .set_description(Some("Find messages of all topics by title, text or tag"));
+            } else {
+                imp.search_status.set_title("No Results Found");
+                imp.search_status.set_description(None);
+            }
+
+            let has_more = hits.len() == SEARCH_PAGE_SIZE as usize;
+            for hit in &hits {
+                list.append(&this.build_search_hit_row(hit));
+            }
+            if has_more {
+                let row = adw::ButtonRow::builder().title("Show More").build();
+                let this = this.clone();
+                let next = offset + SEARCH_PAGE_SIZE;
+                row.connect_activated(move |_| this.search(query.clone(), next));
+                list.append(&row);
+            }
+            Ok(())
+        });
+    }
+
+    fn build_search_hit_row(&self, hit: &models::SearchHit) -> adw::ActionRow {
+        let msg = &hit.message;
+        let sub = self
+            .imp()
+            .subscription_list_model
+            .iter::<Subscription>()
+            .filter_map(Result::ok)
+            .find(|s| s.server() == hit.server && s.topic() == msg.topic);
+        let topic = sub
+            .map(|s| s.display_name())
+            .filter(|name| !name.is_empty())
+            .unwrap_or(msg.topic.clone());
+
+        let snippet = glib::markup_escape_text(&hit.snippet)
+            .replace(models::HIGHLIGHT_START, "<b>")
+            .replace(models::HIGHLIGHT_END, "</b>");
+        let time = chrono::DateTime::from_timestamp(msg.time as i64, 0)
+            .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let row = adw::ActionRow::builder()
+            .title(glib::markup_escape_text(
+                &msg.display_title().unwrap_or(topic.clone()),
+            ))
+            .subtitle(snippet)
+            .subtitle_lines(3)
+            .activatable(true)
+            .build();
+        let label = gtk::Label::builder()
+            .label(format!("{topic}\n{time}"))
+            .justify(gtk::Justification::Right)
+            .build();
+        label.add_css_class("dim-label");
+        label.add_css_class("caption");
+        row.add_suffix(&label);
+
+        let this = self.clone();
+        let server = hit.server.clone();
+        let topic = msg.topic.clone();
+        row.connect_activated(move |_| this.select_topic(&server, &topic));
+        row
+    }
+
+    // Selects the topic in the sidebar, as if it was clicked
+    fn select_topic(&self, server: &str, topic: &str) {
+        let list = &self.imp().subscription_list;
+        let mut child = list.first_child();
+        while let Some(widget) = child {
+            child = widget.next_sibling();
+            let Some(row) = widget.downcast_ref::<gtk::ListBoxRow>() else {
+                continue;
+            };
+            let row_topic = unsafe { row.data::<String>("topic").map(|s| s.as_ref().clone()) };
+            let row_server = unsafe { row.data::<String>("server").map(|s| s.as_ref().clone()) };
+            if row_topic.as_deref() == Some(topic) && row_server.as_deref() == Some(server) {
+                list.select_row(Some(row));
+                row.activate();
+                return;
+            }
+        }
+    }
+
     fn show_subscription_info(&self) {
         let sub = SubscriptionInfoDialog::new(self.selected_subscription().unwrap());
         sub.present(Some(self));
@@ -337,29 +577,621 @@ impl NtfyrWindow {
         let this = self.clone();
         self.error_boundary().spawn(async move {
             let sub = this.notifier().subscribe(&sub.server, &sub.topic).await?;
-            let imp = this.imp();
+            this.append_subscription(sub).await;
+            Ok(())
+        });
+    }
+
+    // Subscribes to the topic of a shared link, together with its key
+    fn import_shared_topic(&self, share: TopicShare) {
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let already_subscribed = this
+                .imp()
+                .subscription_list_model
+                .iter::<Subscription>()
+                .filter_map(Result::ok)
+                .any(|s| s.server() == share.server && s.topic() == share.topic);
+            let sub = this.notifier().subscribe_shared(share).await?;
+            if !already_subscribed {
+                this.append_subscription(sub).await;
+            }
+            this.imp()
+                .toast_overlay
+                .add_toast(adw::Toast::new("Topic imported"));
+            Ok(())
+        });
+    }
+
+    fn has_subscription(&self, handle: &ntfy_daemon::SubscriptionHandle) -> bool {
+        self.imp()
+            .subscription_list_model
+            .iter::<Subscription>()
+            .filter_map(Result::ok)
+            .any(|s| s.imp().client.get().is_some_and(|c| c.same(handle)))
+    }
+
+    // Subscriptions can also be added and removed over D-Bus or by the CLI
+    fn follow_subscription_changes(&self) {
+        let mut changes = self.notifier().subscription_changes();
+        let this = self.downgrade();
+        glib::MainContext::default().spawn_local(async move {
+            loop {
+                let change = match changes.recv().await {
+                    Ok(change) => change,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Some(this) = this.upgrade() else {
+                    break;
+                };
+                match change {
+                    SubscriptionChange::Added(handle) => {
+                        glib::MainContext::default().spawn_local(async move {
+                            this.append_subscription(handle).await;
+                        });
+                    }
+                    SubscriptionChange::Removed { server, topic } => {
+                        this.remove_subscription(&server, &topic);
+                    }
+                }
+            }
+        });
+    }
 
+    fn remove_subscription(&self, server: &str, topic: &str) {
+        let imp = self.imp();
+        let Some(i) = imp
+            .subscription_list_model
+            .iter::<Subscription>()
+            .position(|s| s.is_ok_and(|s| s.server() == server && s.topic() == topic))
+        else {
+            return;
+        };
+        imp.subscription_list_model.remove(i as u32);
+        self.rebuild_subscription_list();
+        if imp.subscription_list_model.n_items() == 0 {
+            self.selected_subscription_changed(None);
+        }
+    }
+
+    async fn append_subscription(&self, sub: ntfy_daemon::SubscriptionHandle) {
+        self.append_subscriptions(vec![sub]).await;
+    }
+
+    async fn append_subscriptions(&self, subs: Vec<ntfy_daemon::SubscriptionHandle>) {
+        let imp = self.imp();
+
+        for sub in subs {
+            if self.has_subscription(&sub) {
+                continue;
+            }
             // Subscription::new will use the pipelined client to retrieve info about the subscription
             let subscription = Subscription::new(sub);
             // We want to still check if there were any errors adding the subscription.
 
-            this.attach_sort_trigger(&subscription);
-            
+            self.attach_sort_trigger(&subscription);
+
             imp.subscription_list_model.append(&subscription);
-            
-            // Wait for info to load
-            glib::timeout_future_seconds(1).await;
-            
-            // Rebuild the UI list
-            this.rebuild_subscription_list();
-            
-            // TODO: Select the newly added subscription? 
-            // For now let's just ensure it appears.
-            
+        }
+        
+        // Wait for info to load
+        glib::timeout_future_seconds(1).await;
+        
+        // Rebuild the UI list
+        self.rebuild_subscription_list();
+        
+        // TODO: Select the newly added subscription? 
+        // For now let's just ensure it appears.
+    }
+
+    // Asks for a topic link, typed in or read from a QR code image
+    fn show_import_topic_dialog(&self) {
+        relm4_macros::view! {
+            content = &gtk::ListBox {
+                add_css_class: "boxed-list",
+                set_selection_mode: gtk::SelectionMode::None,
+                append: link_entry = &adw::EntryRow {
+                    set_title: "Topic Link",
+                    set_activates_default: true,
+                    add_suffix: scan_btn = &gtk::Button {
+                        set_icon_name: "image-x-generic-symbolic",
+                        set_tooltip_text: Some("Read QR Code Image"),
+                        set_valign: gtk::Align::Center,
+                        add_css_class: "flat",
+                    },
+                },
+            }
+        }
+        let dialog = adw::AlertDialog::builder()
+            .heading("Import Topic")
+            .body("Paste an ntfy:// link or choose an image of its QR code")
+            .extra_child(&content)
+            .build();
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("subscribe", "Subscribe");
+        dialog.set_response_appearance("subscribe", adw::ResponseAppearance::Suggested);
+        dialog.set_response_enabled("subscribe", false);
+        dialog.set_default_response(Some("subscribe"));
+        dialog.set_close_response("cancel");
+        link_entry.connect_changed({
+            let dialog = dialog.clone();
+            move |entry| {
+                let valid = TopicShare::parse(&entry.text()).is_ok();
+                entry.remove_css_class("error");
+                if !valid && !entry.text().is_empty() {
+                    entry.add_css_class("error");
+                }
+                dialog.set_response_enabled("subscribe", valid);
+            }
+        });
+        scan_btn.connect_clicked({
+            let link_entry = link_entry.clone();
+            move |btn| {
+                let filter = gtk::FileFilter::new();
+                filter.add_pixbuf_formats();
+                let filters = gio::ListStore::new::<gtk::FileFilter>();
+                filters.append(&filter);
+                let file_dialog = gtk::FileDialog::builder()
+                    .title("Choose QR Code Image")
+                    .filters(&filters)
+                    .build();
+                let link_entry = link_entry.clone();
+                let btn = btn.clone();
+                btn.clone().error_boundary().spawn(async move {
+                    let file = file_dialog
+                        .open_future(btn.root().and_downcast_ref::<gtk::Window>())
+                        .await?;
+                    let path = file
+                        .path()
+                        .ok_or_else(|| anyhow::anyhow!("Can't open the image"))?;
+                    let text = gio::spawn_blocking(move || crate::qr::decode_file(&path))
+                        .await
+                        .map_err(|_| anyhow::anyhow!("Can't read the image"))??;
+                    link_entry.set_text(&text);
+                    Ok(())
+                });
+            }
+        });
+
+        let this = self.clone();
+        dialog.choose(Some(self), gio::Cancellable::NONE, move |response| {
+            if response != "subscribe" {
+                return;
+            }
+            match TopicShare::parse(&link_entry.text()) {
+                Ok(share) => this.import_shared_topic(share),
+                Err(e) => warn!(error = %e, "trying to import invalid topic link"),
+            }
+        });
+    }
+
+    fn subscriptions(&self) -> Vec<Subscription> {
+        self.imp()
+            .subscription_list_model
+            .iter::<Subscription>()
+            .filter_map(Result::ok)
+            .collect()
+    }
+
+    fn show_export_history_dialog(&self) {
+        let subs = self.subscriptions();
+        let selected = self.selected_subscription();
+        relm4_macros::view! {
+            content = &gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+                set_spacing: 12,
+                append: topics_list = &gtk::ListBox {
+                    add_css_class: "boxed-list",
+                    set_selection_mode: gtk::SelectionMode::None,
+                },
+                append = &gtk::ListBox {
+                    add_css_class: "boxed-list",
+                    set_selection_mode: gtk::SelectionMode::None,
+                    append: range_row = &adw::ComboRow {
+                        set_title: "Time Range",
+                        set_model: Some(&gtk::StringList::new(&HISTORY_RANGES.map(|(_, label)| label))),
+                    },
+                    append: format_row = &adw::ComboRow {
+                        set_title: "Format",
+                        set_model: Some(&gtk::StringList::new(&HISTORY_FORMATS.map(|(_, label)| label))),
+                    },
+                },
+            }
+        }
+        // The selected topic, or all of them when none is selected
+        let topic_rows: Vec<(adw::SwitchRow, Subscription)> = subs
+            .into_iter()
+            .map(|sub| {
+                let row = adw::SwitchRow::builder()
+                    .title(sub.display_name())
+                    .subtitle(sub.server())
+                    .active(selected.as_ref().is_none_or(|s| s == &sub))
+                    .build();
+                topics_list.append(&row);
+                (row, sub)
+            })
+            .collect();
+
+        let dialog = adw::AlertDialog::builder()
+            .heading("Export History")
+            .body("Save the stored messages of the chosen topics to a file")
+            .extra_child(&content)
+            .build();
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("export", "Export…");
+        dialog.set_response_appearance("export", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("export"));
+        dialog.set_close_response("cancel");
+
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            if dialog.choose_future(Some(&this)).await != "export" {
+                return Ok(());
+            }
+            let topics: Vec<(String, String)> = topic_rows
+                .iter()
+                .filter(|(row, _)| row.is_active())
+                .map(|(_, sub)| (sub.server(), sub.topic()))
+                .collect();
+            if topics.is_empty() {
+                return Err(anyhow::anyhow!("No topic chosen for the export"));
+            }
+            let now = chrono::Local::now().timestamp() as u64;
+            let since = HISTORY_RANGES[range_row.selected() as usize]
+                .0
+                .map(|range| now.saturating_sub(range));
+            let format = HISTORY_FORMATS[format_row.selected() as usize].0;
+
+            let file_dialog = gtk::FileDialog::builder()
+                .title("Export History")
+                .initial_name(format!("ntfyr-history.{}", format.extension()))
+                .build();
+            let file = file_dialog.save_future(Some(&this)).await?;
+            let path = file
+                .path()
+                .ok_or_else(|| anyhow::anyhow!("Can't write to the chosen file"))?;
+
+            let selection = models::HistorySelection {
+                topics,
+                since,
+                until: None,
+            };
+            let count = this
+                .notifier()
+                .export_messages(selection, format, path)
+                .await?;
+            this.imp()
+                .toast_overlay
+                .add_toast(adw::Toast::new(&format!("{count} messages exported")));
             Ok(())
         });
     }
 
+    fn show_import_history_dialog(&self) {
+        let subs = self.subscriptions();
+        if subs.is_empty() {
+            self.imp()
+                .toast_overlay
+                .add_toast(adw::Toast::new("Subscribe to a topic before importing its history"));
+            return;
+        }
+
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some("JSON Lines"));
+            filter.add_suffix("jsonl");
+            filter.add_suffix("ndjson");
+            filter.add_suffix("json");
+            let filters = gio::ListStore::new::<gtk::FileFilter>();
+            filters.append(&filter);
+            let file_dialog = gtk::FileDialog::builder()
+                .title("Import History")
+                .filters(&filters)
+                .build();
+            let file = file_dialog.open_future(Some(&this)).await?;
+            let path = file
+                .path()
+                .ok_or_else(|| anyhow::anyhow!("Can't open the chosen file"))?;
+
+            let report = this.notifier().import_messages(path).await?;
+            for sub in subs.iter() {
+                sub.reload_messages().await?;
+            }
+            let mut text = format!("{} messages imported", report.imported);
+            if report.duplicates > 0 {
+                text.push_str(&format!(", {} already stored", report.duplicates));
+            }
+            if report.skipped > 0 {
+                text.push_str(&format!(", {} from other topics skipped", report.skipped));
+            }
+            this.imp().toast_overlay.add_toast(adw::Toast::new(&text));
+            Ok(())
+        });
+    }
+
+    fn show_backup_dialog(&self) {
+        relm4_macros::view! {
+            content = &gtk::ListBox {
+                add_css_class: "boxed-list",
+                set_selection_mode: gtk::SelectionMode::None,
+                append: passphrase_entry = &adw::PasswordEntryRow {
+                    set_title: "Passphrase (Optional)",
+                },
+                append: confirm_entry = &adw::PasswordEntryRow {
+                    set_title: "Confirm Passphrase",
+                    set_activates_default: true,
+                },
+            }
+        }
+        let dialog = adw::AlertDialog::builder()
+            .heading("Back Up Ntfyr")
+            .body("Subscriptions with their rules and schedules, servers and accounts are saved to a file. Enter a passphrase to also include account passwords and topic keys, encrypted.")
+            .extra_child(&content)
+            .build();
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("backup", "Back Up…");
+        dialog.set_response_appearance("backup", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("backup"));
+        dialog.set_close_response("cancel");
+        let check_match = {
+            let dialog = dialog.clone();
+            let passphrase_entry = passphrase_entry.clone();
+            let confirm_entry = confirm_entry.clone();
+            move || {
+                let matching = passphrase_entry.text() == confirm_entry.text();
+                confirm_entry.remove_css_class("error");
+                if !matching && !confirm_entry.text().is_empty() {
+                    confirm_entry.add_css_class("error");
+                }
+                dialog.set_response_enabled("backup", matching);
+            }
+        };
+        passphrase_entry.connect_changed({
+            let check_match = check_match.clone();
+            move |_| check_match()
+        });
+        confirm_entry.connect_changed(move |_| check_match());
+
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            if dialog.choose_future(Some(&this)).await != "backup" {
+                return Ok(());
+            }
+            let passphrase = passphrase_entry.text();
+            let passphrase = Some(passphrase.as_str()).filter(|p| !p.is_empty());
+
+            let file_dialog = gtk::FileDialog::builder()
+                .title("Back Up Ntfyr")
+                .initial_name("ntfyr-backup.json")
+                .build();
+            let file = file_dialog.save_future(Some(&this)).await?;
+
+            let mut backup = this.notifier().create_backup(passphrase).await?;
+            let settings = gio::Settings::new(APP_ID);
+            backup.servers = settings
+                .strv("custom-servers")
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect();
+            file.replace_contents_future(
+                backup.to_json().into_bytes(),
+                None,
+                false,
+                gio::FileCreateFlags::REPLACE_DESTINATION,
+            )
+            .await
+            .map_err(|(_, e)| e)?;
+
+            let text = if passphrase.is_some() {
+                "Backup saved with passwords and keys"
+            } else {
+                "Backup saved without passwords and keys"
+            };
+            this.imp().toast_overlay.add_toast(adw::Toast::new(text));
+            Ok(())
+        });
+    }
+
+    fn restore_backup(&self) {
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some("Ntfyr Backup"));
+            filter.add_suffix("json");
+            let filters = gio::ListStore::new::<gtk::FileFilter>();
+            filters.append(&filter);
+            let file_dialog = gtk::FileDialog::builder()
+                .title("Restore Backup")
+                .filters(&filters)
+                .build();
+            let file = file_dialog.open_future(Some(&this)).await?;
+            let (contents, _) = file.load_contents_future().await?;
+            let backup = Backup::parse(std::str::from_utf8(&contents)?)?;
+
+            let passphrase = if backup.secrets.is_some() {
+                let Some(passphrase) = this.ask_backup_passphrase().await else {
+                    return Ok(());
+                };
+                passphrase
+            } else {
+                None
+            };
+
+            // Servers without subscriptions only exist in the app settings
+            let settings = gio::Settings::new(APP_ID);
+            let mut servers: Vec<String> = settings
+                .strv("custom-servers")
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect();
+            for server in &backup.servers {
+                if !servers.contains(server) {
+                    servers.push(server.clone());
+                }
+            }
+            settings.set_strv(
+                "custom-servers",
+                servers.iter().map(|s| s.as_str()).collect::<Vec<&str>>().as_slice(),
+            )?;
+
+            let report = this
+                .notifier()
+                .restore_backup(backup, passphrase.as_deref())
+                .await?;
+            let mut text = format!(
+                "Restored {} subscriptions, {} accounts and {} keys",
+                report.subscriptions.len(),
+                report.accounts,
+                report.keys
+            );
+            if report.secrets_skipped {
+                text.push_str(", passwords and keys need the passphrase");
+            }
+            this.append_subscriptions(report.subscriptions).await;
+            this.imp().toast_overlay.add_toast(adw::Toast::new(&text));
+
+            if !report.removed_rules.is_empty() {
+                let mut body = "These rules run programs or forward messages, they were left out. \
+                    Add them again in the subscription settings if you trust the backup:"
+                    .to_string();
+                for rule in &report.removed_rules {
+                    body.push_str(&format!("\n• {} of {}/{}", rule.name, rule.server, rule.topic));
+                }
+                let dialog = adw::AlertDialog::builder()
+                    .heading("Rules Not Restored")
+                    .body(body)
+                    .build();
+                dialog.add_response("close", "Close");
+                dialog.choose_future(Some(&this)).await;
+            }
+            Ok(())
+        });
+    }
+
+    // Reads the settings backup of the Android app or the exported storage of the web app
+    fn import_ntfy_backup(&self) {
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some("ntfy Backup"));
+            filter.add_suffix("json");
+            let filters = gio::ListStore::new::<gtk::FileFilter>();
+            filters.append(&filter);
+            let file_dialog = gtk::FileDialog::builder()
+                .title("Import From ntfy App")
+                .filters(&filters)
+                .build();
+            let file = file_dialog.open_future(Some(&this)).await?;
+            let (contents, _) = file.load_contents_future().await?;
+            let import = ClientBackup::parse(std::str::from_utf8(&contents)?)?;
+
+            let conflicts = this.notifier().check_client_import(import.clone()).await?;
+            let mut body = format!(
+                "Found {} subscriptions and {} accounts from the {}.",
+                import.subscriptions.len(),
+                import.accounts.len(),
+                import.client.name()
+            );
+            if !conflicts.is_empty() {
+                body.push_str("\n\nThese are kept as they are:");
+                for conflict in &conflicts {
+                    body.push_str(&format!("\n• {conflict}"));
+                }
+            }
+            if !import.skipped.is_empty() {
+                body.push_str("\n\nThese can't be imported:");
+                for skipped in &import.skipped {
+                    body.push_str(&format!("\n• {skipped}"));
+                }
+            }
+            let dialog = adw::AlertDialog::builder()
+                .heading("Import From ntfy App")
+                .body(body)
+                .build();
+            dialog.add_response("cancel", "Cancel");
+            dialog.add_response("import", "Import");
+            dialog.set_response_appearance("import", adw::ResponseAppearance::Suggested);
+            dialog.set_default_response(Some("import"));
+            dialog.set_close_response("cancel");
+            if dialog.choose_future(Some(&this)).await != "import" {
+                return Ok(());
+            }
+
+            let report = this.notifier().import_client_backup(import).await?;
+            let text = format!(
+                "Imported {} subscriptions and {} accounts",
+                report.subscriptions.len(),
+                report.accounts
+            );
+            this.append_subscriptions(report.subscriptions).await;
+            this.imp().toast_overlay.add_toast(adw::Toast::new(&text));
+            Ok(())
+        });
+    }
+
+    // None when cancelled, Some(None) to restore without the passwords and keys
+    async fn ask_backup_passphrase(&self) -> Option<Option<String>> {
+        relm4_macros::view! {
+            content = &gtk::ListBox {
+                add_css_class: "boxed-list",
+                set_selection_mode: gtk::SelectionMode::None,
+                append: passphrase_entry = &adw::PasswordEntryRow {
+                    set_title: "Passphrase",
+                    set_activates_default: true,
+                },
+            }
+        }
+        let dialog = adw::AlertDialog::builder()
+            .heading("Restore Backup")
+            .body("The passwords and topic keys in this backup are encrypted. Enter the passphrase used to back up, or restore without them.")
+            .extra_child(&content)
+            .build();
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("skip", "Skip Secrets");
+        dialog.add_response("restore", "Restore");
+        dialog.set_response_appearance("restore", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("restore"));
+        dialog.set_close_response("cancel");
+        match dialog.choose_future(Some(self)).await.as_str() {
+            "restore" => Some(Some(passphrase_entry.text().to_string())),
+            "skip" => Some(None),
+            _ => None,
+        }
+    }
+
+    // Works for the unified inbox too, where the selected topic isn't the one of the message
+    fn message_subscription(&self, id: &str) -> Option<Subscription> {
+        self.imp()
+            .subscription_list_model
+            .iter::<Subscription>()
+            .filter_map(Result::ok)
+            .find(|sub| sub.has_message(id))
+    }
+    // Tries the current keys on the stored messages of every subscription
+    fn retry_decryption(&self) {
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let subs: Vec<Subscription> = this
+                .imp()
+                .subscription_list_model
+                .iter::<Subscription>()
+                .filter_map(Result::ok)
+                .collect();
+            let mut decrypted = 0;
+            for sub in subs {
+                decrypted += sub.retry_decryption().await?;
+            }
+            let toast = adw::Toast::new(&match decrypted {
+                0 => "No message could be decrypted. Check the topic password.".to_string(),
+                1 => "1 message decrypted".to_string(),
+                n => format!("{n} messages decrypted"),
+            });
+            this.imp().toast_overlay.add_toast(toast);
+            Ok(())
+        });
+    }
     fn unsubscribe(&self) {
         let Some(sub) = self.selected_subscription() else {
             return;
@@ -373,20 +1205,7 @@ impl NtfyrWindow {
             {
                 warn!("Failed to unsubscribe from backend: {}", e);
             }
-
-            let imp = this.imp();
-            if let Some(i) = imp.subscription_list_model.find(&sub) {
-                imp.subscription_list_model.remove(i);
-                
-                // Rebuild the UI list
-                this.rebuild_subscription_list();
-                
-                // Clear selection if needed
-                let n_items = imp.subscription_list_model.n_items();
-                if n_items == 0 {
-                    this.selected_subscription_changed(None);
-                }
-            }
+            this.remove_subscription(&sub.server(), &sub.topic());
             Ok(())
         });
     }
@@ -512,10 +1331,16 @@ impl NtfyrWindow {
         let sorter: gtk::Sorter = sorter.upcast(); 
         let sort_model = gtk::SortListModel::new(Some(flatten_model), Some(sorter));
         
-        imp.unified_message_list.bind_model(Some(&sort_model), |obj| {
+        let subs = imp.subscription_list_model.clone();
+        imp.unified_message_list.bind_model(Some(&sort_model), move |obj| {
              let b = obj.downcast_ref::<glib::BoxedAnyObject>().unwrap();
              let msg = b.borrow::<models::ReceivedMessage>();
-             MessageRow::new(msg.clone()).upcast()
+             let read_until = subs
+                 .iter::<Subscription>()
+                 .filter_map(Result::ok)
+                 .find(|sub| sub.topic() == msg.topic && sub.has_message(&msg.id))
+                 .map_or(0, |sub| sub.read_until());
+             MessageRow::new(msg.clone(), read_until).upcast()
         });
         
         // Unified inbox selection is handled in subscription_list row_activated
@@ -598,6 +1423,9 @@ impl NtfyrWindow {
             glib::timeout_future_seconds(1).await;
             let list = this.notifier().list_subscriptions().await?;
             for sub in list {
+                if this.has_subscription(&sub) {
+                    continue;
+                }
                 let sub = Subscription::new(sub);
                 this.attach_sort_trigger(&sub);
                 this.imp().subscription_list_model.append(&sub);
@@ -757,6 +1585,18 @@ impl NtfyrWindow {
             });
         menu_box.append(&add_account_btn);
 
+        // Server Settings Item
+        let settings_btn = create_menu_row("Server Settings", "emblem-system-symbolic");
+        let server_clone = server.to_string();
+        let popover_clone = popover.clone();
+        settings_btn.connect_clicked(move |btn| {
+            popover_clone.popdown();
+            if let Some(window) = btn.root().and_downcast::<NtfyrWindow>() {
+                window.on_server_settings_clicked(&server_clone);
+            }
+        });
+        menu_box.append(&settings_btn);
+
         // Remove Server Item (only custom)
         if server != "https://ntfy.sh" {
             let remove_btn = create_menu_row("Remove Server", "user-trash-symbolic");
@@ -899,7 +1739,12 @@ impl NtfyrWindow {
         let imp = self.imp();
         if let Some(sub) = sub {
             match sub.nice_status() {
-                Status::Degraded | Status::Down => imp.banner.set_revealed(true),
+                Status::Degraded | Status::Down | Status::Polling => {
+                    let text = sub.status_text();
+                    imp.banner
+                        .set_title(if text.is_empty() { "Reconnecting..." } else { &text });
+                    imp.banner.set_revealed(true);
+                }
                 Status::Up => imp.banner.set_revealed(false),
             }
         } else {
@@ -943,12 +1788,13 @@ impl NtfyrWindow {
 
             let sort_model = gtk::SortListModel::new(Some(sub.imp().messages.clone()), Some(sorter));
 
+            let row_sub = sub.clone();
             imp.message_list
                 .bind_model(Some(&sort_model), move |obj| {
                     let b = obj.downcast_ref::<glib::BoxedAnyObject>().unwrap();
                     let msg = b.borrow::<models::ReceivedMessage>();
 
-                    MessageRow::new(msg.clone()).upcast()
+                    MessageRow::new(msg.clone(), row_sub.read_until()).upcast()
                 });
 
             let this = self.clone();
@@ -962,6 +1808,7 @@ impl NtfyrWindow {
             let this = self.clone();
             glib::idle_add_local_once(move || {
                 this.flag_read();
+                this.fill_message_list(false);
             });
         } else {
             set_sensitive(false);
@@ -1024,6 +1871,11 @@ impl NtfyrWindow {
                  status_clone.set_icon_name(Some("network-cellular-signal-weak-symbolic"));
                  status_clone.set_visible(true);
              }
+             Status::Polling => {
+                 status_clone.set_icon_name(Some("view-refresh-symbolic"));
+                 status_clone.set_tooltip_text(Some(&sub.status_text()));
+                 status_clone.set_visible(true);
+             }
              _ => status_clone.set_visible(false),
         });
         icon_box.append(&status);
@@ -1096,6 +1948,64 @@ impl NtfyrWindow {
         });
     }
 
+    // Older messages are at the top, or at the bottom when sorted newest first. Pages also load
+    // while the messages don't fill the view, as there is no edge to reach then.
+    fn bind_load_older(&self) {
+        let imp = self.imp();
+
+        for (scroll, unified) in [
+            (&imp.message_scroll, false),
+            (&imp.unified_message_scroll, true),
+        ] {
+            let this = self.clone();
+            scroll.connect_edge_reached(move |_, pos_type| {
+                let older_edge = if this.imp().settings.boolean("sort-descending") {
+                    gtk::PositionType::Bottom
+                } else {
+                    gtk::PositionType::Top
+                };
+                if pos_type == older_edge {
+                    this.load_older(unified);
+                }
+            });
+            // Emitted once the rows of a prepended page are laid out
+            let this = self.clone();
+            scroll.vadjustment().connect_changed(move |_| {
+                this.fill_message_list(unified);
+            });
+        }
+    }
+    fn fill_message_list(&self, unified: bool) {
+        let imp = self.imp();
+        let scroll = if unified {
+            &imp.unified_message_scroll
+        } else {
+            &imp.message_scroll
+        };
+        let vadj = scroll.vadjustment();
+        // A hidden list has no size yet
+        if scroll.is_mapped() && vadj.upper() <= vadj.page_size() {
+            self.load_older(unified);
+        }
+    }
+    // The unified inbox loads a page of every topic, so older messages of a topic can show up
+    // between the ones already shown
+    fn load_older(&self, unified: bool) {
+        let subs: Vec<Subscription> = if unified {
+            self.imp()
+                .subscription_list_model
+                .iter::<Subscription>()
+                .filter_map(Result::ok)
+                .collect()
+        } else {
+            self.selected_subscription().into_iter().collect()
+        };
+        for sub in subs.into_iter().filter(|sub| sub.has_older()) {
+            self.error_boundary()
+                .spawn(async move { sub.load_older_messages().await });
+        }
+    }
+
     fn load_window_size(&self) {
         let imp = self.imp();
 
@@ -1247,6 +2157,36 @@ impl NtfyrWindow {
         );
     }
 
+    pub fn on_server_settings_clicked(&self, server: &str) {
+        let this = self.clone();
+        let server = server.to_string();
+        self.error_boundary().spawn(async move {
+            let settings = this.notifier().server_settings(&server).await?;
+            let dialog = ServerSettingsDialog::new(&server, &settings);
+            dialog.present(Some(&this));
+
+            let this = this.clone();
+            dialog.connect_closure(
+                "save",
+                false,
+                glib::closure_local!(move |dialog: ServerSettingsDialog| {
+                    let this = this.clone();
+                    let (server, settings) = (dialog.server(), dialog.settings());
+                    dialog.close();
+                    this.error_boundary().spawn(async move {
+                        this.notifier()
+                            .update_server_settings(&server, settings)
+                            .await?;
+                        let toast = adw::Toast::new("Server settings saved");
+                        this.imp().toast_overlay.add_toast(toast);
+                        Ok(())
+                    });
+                }),
+            );
+            Ok(())
+        });
+    }
+
     fn setup_app_lock(&self) {
         let imp = self.imp();
         let is_locked = imp.settings.boolean("app-lock-enabled");